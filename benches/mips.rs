@@ -0,0 +1,96 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Interpreter throughput in millions of instructions per second, with and without the
+// decode cache: cargo bench --bench mips
+// The guest is a loop of ALU instructions, a load, a store and a branch that isn't taken,
+// run an instruction at a time, a basic block at a time and through a Machine like the
+// GUI and CLI run it. Without the cache step_block only runs single instructions.
+// With --features jit the Machine runs once more with hot blocks compiled.
+
+use std::time::Instant;
+use tiny_vm::asm::assemble;
+use tiny_vm::cpu::CPU;
+use tiny_vm::machine::{Machine, StopReason};
+
+const LOOP: u32 = 0x100;
+const INSTRUCTIONS: u64 = 20_000_000;
+const RUNS: usize = 5;
+
+const BODY: &str = "
+loop:
+    addi t1, t1, 1
+    add t2, t2, t1
+    xor t3, t2, t1
+    sw t2, 0x400(zero)
+    lw t4, 0x400(zero)
+    mul t5, t4, t3
+    srli t6, t5, 3
+    beq t1, zero, done
+    and a0, t6, t3
+    or a1, a0, t2
+done:
+    j loop
+";
+
+fn cpu(decode_cache: bool) -> CPU {
+    let program = assemble(BODY, LOOP).unwrap();
+    let mut cpu = CPU::new();
+    cpu.load_image(program.base, &program.image);
+    cpu.set_pc(LOOP);
+    cpu.set_decode_cache(decode_cache);
+    #[cfg(feature = "jit")]
+    cpu.set_jit(false);
+    cpu
+}
+
+// Best of a few runs, in MIPS
+fn measure(mut run: impl FnMut() -> u64) -> f64 {
+    (0..RUNS).map(|_| {
+        let start = Instant::now();
+        let retired = run();
+        retired as f64 / start.elapsed().as_secs_f64() / 1e6
+    }).fold(0.0, f64::max)
+}
+
+fn main() {
+    println!("{:<10} {:>12} {:>12} {:>8}", "", "uncached", "cached", "speedup");
+    let mut results = Vec::new();
+    for decode_cache in [false, true] {
+        let step = measure(|| {
+            let mut cpu = cpu(decode_cache);
+            for _ in 0..INSTRUCTIONS {
+                assert!(!cpu.step());
+            }
+            cpu.instret()
+        });
+        let block = measure(|| {
+            let mut cpu = cpu(decode_cache);
+            while cpu.instret() < INSTRUCTIONS {
+                assert!(!cpu.step_block(INSTRUCTIONS - cpu.instret()));
+            }
+            cpu.instret()
+        });
+        let machine = measure(|| {
+            let mut machine = Machine::new(cpu(decode_cache));
+            assert_eq!(machine.step(INSTRUCTIONS), StopReason::Stepped);
+            machine.retired()
+        });
+        results.push((step, block, machine));
+    }
+    let (uncached, cached) = (results[0], results[1]);
+    let rows = [("step", uncached.0, cached.0), ("step_block", uncached.1, cached.1), ("Machine", uncached.2, cached.2)];
+    for (name, before, after) in rows {
+        println!("{:<10} {:>7.1} MIPS {:>7.1} MIPS {:>7.2}x", name, before, after, after / before);
+    }
+    #[cfg(feature = "jit")]
+    {
+        let jit = measure(|| {
+            let mut cpu = cpu(true);
+            cpu.set_jit(true);
+            let mut machine = Machine::new(cpu);
+            assert_eq!(machine.step(INSTRUCTIONS), StopReason::Stepped);
+            machine.retired()
+        });
+        println!("{:<10} {:>12} {:>7.1} MIPS {:>7.2}x", "Machine+jit", "", jit, jit / uncached.2);
+    }
+}
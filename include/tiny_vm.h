@@ -0,0 +1,88 @@
+/* RISC-V Tiny VM - Ivi Ballou / Amechania */
+
+/*
+ * C API of the tiny_vm library, built as libtiny_vm.so (tiny_vm.dll, libtiny_vm.dylib)
+ * by `cargo build --release`. A machine has one RAM region and whatever devices the
+ * embedder registers with callbacks. Functions that can fail return TVM_OK or TVM_ERROR,
+ * tvm_last_error() then says what went wrong.
+ *
+ * A machine isn't thread safe: use each one from one thread at a time. Pointers passed
+ * in have to be valid for the lengths given, the machine doesn't keep them except for
+ * the user pointer of a device.
+ */
+
+#ifndef TINY_VM_H
+#define TINY_VM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TVM_OK 0
+#define TVM_ERROR (-1)
+
+/* What tvm_step() and tvm_run() return */
+#define TVM_STOP_STEPPED 0    /* tvm_step() ran all the instructions */
+#define TVM_STOP_BREAKPOINT 1
+#define TVM_STOP_WATCHPOINT 2
+#define TVM_STOP_TRAP 3       /* Illegal instruction or an access nothing is mapped at */
+#define TVM_STOP_HALTED 4     /* ecall, ebreak or a zero instruction */
+#define TVM_STOP_EXITED 5     /* Halted with an exit status, see tvm_exit_code() */
+#define TVM_STOP_BUDGET 6
+#define TVM_STOP_PAUSED 7
+
+/* Register number of pc for tvm_get_register() and tvm_set_register() */
+#define TVM_REG_PC 32
+
+typedef struct TvmMachine TvmMachine;
+
+/*
+ * Device callbacks get the offset into the device's window and the access size, 1, 2
+ * or 4 bytes. They're called on the thread running the machine.
+ */
+typedef uint32_t (*tvm_load_fn)(void *user, uint32_t offset, uint32_t size);
+typedef void (*tvm_store_fn)(void *user, uint32_t offset, uint32_t size, uint32_t value);
+
+/* RAM at ram_base, ram_size a multiple of 256 bytes. pc starts at ram_base. NULL on error. */
+TvmMachine *tvm_create(uint32_t ram_base, uint32_t ram_size);
+void tvm_destroy(TvmMachine *machine);
+
+/* Message of the last error, valid until the next call that fails */
+const char *tvm_last_error(const TvmMachine *machine);
+
+int tvm_load_image(TvmMachine *machine, uint32_t address, const uint8_t *data, size_t len);
+/* Loads the segments of an ELF image and sets pc to its entry point */
+int tvm_load_elf(TvmMachine *machine, const uint8_t *data, size_t len);
+
+/* A device at base..base + size, which can't overlap RAM or other devices */
+int tvm_add_device(TvmMachine *machine, const char *name, uint32_t base, uint32_t size,
+                   tvm_load_fn load, tvm_store_fn store, void *user);
+
+/* Runs at most count instructions */
+int tvm_step(TvmMachine *machine, uint64_t count);
+/* Runs until a breakpoint, trap or halt */
+int tvm_run(TvmMachine *machine);
+/* TVM_OK and the status if the guest reported one through HTIF or semihosting */
+int tvm_exit_code(const TvmMachine *machine, uint32_t *exit_code);
+/* Instructions retired so far */
+uint64_t tvm_instret(const TvmMachine *machine);
+
+/* Registers x0 to x31 or TVM_REG_PC */
+uint32_t tvm_get_register(const TvmMachine *machine, uint32_t reg);
+int tvm_set_register(TvmMachine *machine, uint32_t reg, uint32_t value);
+
+/* RAM and ROM only, devices aren't accessed */
+int tvm_read_memory(TvmMachine *machine, uint32_t address, uint8_t *data, size_t len);
+int tvm_write_memory(TvmMachine *machine, uint32_t address, const uint8_t *data, size_t len);
+
+void tvm_add_breakpoint(TvmMachine *machine, uint32_t address);
+void tvm_remove_breakpoint(TvmMachine *machine, uint32_t address);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
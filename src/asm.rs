@@ -0,0 +1,365 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Two pass assembler for GNU style RV32IM assembly.
+// The first pass lays out every section and collects the labels, the second
+// pass encodes. The result is a flat image: .text, then .data, then .bss.
+
+pub mod encoder;
+mod parser;
+
+use std::collections::HashMap;
+use std::fmt;
+use crate::asm::encoder::Encoder;
+use crate::asm::parser::{parse_line, parse_string, is_symbol, Statement};
+use crate::cpu::instruction::builder::InstructionBuilder;
+use crate::cpu::opcodes::F3_ADDI;
+
+#[derive(Debug)]
+pub struct Program {
+    pub base: u32,
+    pub image: Vec<u8>,
+    pub entry: u32, // _start if the program defines it, the base address otherwise
+    pub symbols: HashMap<String, u32>,
+}
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Text = 0,
+    Data = 1,
+    Bss = 2,
+}
+
+enum Content {
+    Instruction(String, Vec<String>),
+    Values(u32, Vec<String>), // Element size in bytes, expressions
+    Bytes(Vec<u8>),
+}
+
+struct Item {
+    line: usize,
+    section: Section,
+    offset: u32,
+    content: Content,
+}
+
+pub fn assemble(source: &str, base: u32) -> Result<Program, AsmError> {
+    let mut section = Section::Text;
+    let mut offsets = [0u32; 3];
+    let mut alignments = [4u32; 3];
+    let mut labels: HashMap<String, (Section, u32)> = HashMap::new();
+    let mut constants: HashMap<String, u32> = HashMap::new();
+    let mut items = Vec::new();
+    // Nothing can go past the end of the 32-bit address space. A section's size has to fit
+    // in 32 bits as well, which rules out all 4 GiB from 0.
+    let room = ((1u64 << 32) - base as u64).min(u32::MAX as u64);
+
+    // First pass: sizes and label offsets within their section
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: String| AsmError { line: line_number, message };
+        let grow = |offset: u32, size: u64| -> Result<u32, AsmError> {
+            match offset as u64 + size {
+                end if end <= room => Ok(end as u32),
+                _ => Err(error("Program runs past the end of the address space".to_string())),
+            }
+        };
+        for statement in parse_line(line).map_err(error)? {
+            let offset = offsets[section as usize];
+            let content = match statement {
+                Statement::Label(name) => {
+                    if labels.insert(name.clone(), (section, offset)).is_some() || constants.contains_key(&name) {
+                        return Err(error(format!("Symbol '{}' is already defined", name)));
+                    }
+                    continue;
+                }
+                Statement::Instruction(mnemonic, operands) => {
+                    let encoder = Encoder { constants: &constants, labels: None, pc: 0 };
+                    offsets[section as usize] = grow(offset, encoder.size(&mnemonic, &operands).map_err(error)? as u64)?;
+                    Content::Instruction(mnemonic, operands)
+                }
+                Statement::Directive(name, operands) => {
+                    let encoder = Encoder { constants: &constants, labels: None, pc: 0 };
+                    let constant = |index: usize| -> Result<u32, AsmError> {
+                        let operand = operands.get(index).ok_or_else(|| error(format!("{} is missing an operand", name)))?;
+                        encoder.eval_constant(operand).map(|value| value as u32).map_err(error)
+                    };
+                    match name.as_str() {
+                        ".text" => { section = Section::Text; continue; }
+                        ".data" | ".rodata" | ".sdata" => { section = Section::Data; continue; }
+                        ".bss" | ".sbss" => { section = Section::Bss; continue; }
+                        ".section" => {
+                            let target = operands.first().map(|operand| operand.as_str()).unwrap_or("");
+                            section = if target.starts_with(".text") {
+                                Section::Text
+                            } else if target.starts_with(".bss") || target.starts_with(".sbss") {
+                                Section::Bss
+                            } else {
+                                Section::Data
+                            };
+                            continue;
+                        }
+                        ".equ" | ".set" => {
+                            let symbol = operands.first().map(|operand| operand.as_str()).unwrap_or("");
+                            if !is_symbol(symbol) || labels.contains_key(symbol) {
+                                return Err(error(format!("Invalid symbol '{}' for {}", symbol, name)));
+                            }
+                            let value = constant(1)?;
+                            constants.insert(symbol.to_string(), value);
+                            continue;
+                        }
+                        ".word" | ".long" | ".4byte" | ".half" | ".short" | ".2byte" | ".byte" | ".1byte" => {
+                            let size = match name.as_str() {
+                                ".word" | ".long" | ".4byte" => 4,
+                                ".half" | ".short" | ".2byte" => 2,
+                                _ => 1,
+                            };
+                            offsets[section as usize] = grow(offset, size as u64 * operands.len() as u64)?;
+                            Content::Values(size, operands)
+                        }
+                        ".ascii" | ".asciz" | ".string" => {
+                            let mut bytes = Vec::new();
+                            for operand in &operands {
+                                bytes.extend(parse_string(operand).map_err(error)?);
+                                if name != ".ascii" {
+                                    bytes.push(0);
+                                }
+                            }
+                            offsets[section as usize] = grow(offset, bytes.len() as u64)?;
+                            Content::Bytes(bytes)
+                        }
+                        ".zero" | ".space" | ".skip" => {
+                            let count = constant(0)?;
+                            let fill = if operands.len() > 1 { constant(1)? as u8 } else { 0 };
+                            // Checked before the bytes are allocated
+                            offsets[section as usize] = grow(offset, count as u64)?;
+                            Content::Bytes(vec![fill; count as usize])
+                        }
+                        ".align" | ".p2align" | ".balign" => {
+                            let value = constant(0)?;
+                            let alignment = if name == ".balign" { value } else { 1u32.checked_shl(value).unwrap_or(0) };
+                            if !alignment.is_power_of_two() {
+                                return Err(error(format!("Invalid alignment {}", value)));
+                            }
+                            alignments[section as usize] = alignments[section as usize].max(alignment);
+                            let padding = (alignment - offset % alignment) % alignment;
+                            offsets[section as usize] = grow(offset, padding as u64)?;
+                            Content::Bytes(padding_bytes(section, padding))
+                        }
+                        // Only matter to a linker
+                        ".globl" | ".global" | ".local" | ".weak" | ".type" | ".size" | ".file" | ".ident" | ".option" | ".attribute" => continue,
+                        _ => return Err(error(format!("Unknown directive '{}'", name))),
+                    }
+                }
+            };
+            items.push(Item { line: line_number, section, offset, content });
+        }
+    }
+
+    // Lay the sections out one after another
+    let mut bases = [base as u64; 3];
+    for i in 1..3 {
+        let end = bases[i - 1] + offsets[i - 1] as u64;
+        bases[i] = end.next_multiple_of(alignments[i] as u64);
+    }
+    let end = bases[2] + offsets[2] as u64;
+    if end > 1 << 32 {
+        let line = items.last().map_or(0, |item: &Item| item.line);
+        return Err(AsmError { line, message: "Program runs past the end of the address space".to_string() });
+    }
+    let bases = bases.map(|base| base as u32);
+    let symbols: HashMap<String, u32> = labels.iter()
+        .map(|(name, (section, offset))| (name.clone(), bases[*section as usize].wrapping_add(*offset)))
+        .collect();
+
+    // Second pass: encode everything now that all labels are known
+    let mut image = vec![0u8; (end - base as u64) as usize];
+    for item in items {
+        let error = |message: String| AsmError { line: item.line, message };
+        let address = bases[item.section as usize].wrapping_add(item.offset);
+        let encoder = Encoder { constants: &constants, labels: Some(&symbols), pc: address };
+        let bytes: Vec<u8> = match item.content {
+            Content::Instruction(mnemonic, operands) => {
+                encoder.encode(&mnemonic, &operands).map_err(error)?
+                    .iter().flat_map(|word| word.to_le_bytes()).collect()
+            }
+            Content::Values(size, operands) => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    let value = encoder.eval(operand).map_err(error)? as u32;
+                    bytes.extend_from_slice(&value.to_le_bytes()[..size as usize]);
+                }
+                bytes
+            }
+            Content::Bytes(bytes) => bytes,
+        };
+        let start = address.wrapping_sub(base) as usize;
+        image[start..start + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    let entry = symbols.get("_start").copied().unwrap_or(base);
+    Ok(Program { base, image, entry, symbols })
+}
+
+// Code gets padded with nops so execution can fall through an .align
+fn padding_bytes(section: Section, padding: u32) -> Vec<u8> {
+    if section == Section::Text && padding.is_multiple_of(4) {
+        let nop = InstructionBuilder.alui(0, F3_ADDI, 0, 0);
+        (0..padding / 4).flat_map(|_| nop.to_le_bytes()).collect()
+    } else {
+        vec![0; padding as usize]
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::asm::*;
+    use crate::cpu::CPU;
+    use crate::cpu::register::*;
+
+    fn words(program: &Program) -> Vec<u32> {
+        program.image.chunks(4).map(|chunk| {
+            let mut bytes = [0u8; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(bytes)
+        }).collect()
+    }
+
+    #[test]
+    fn test_assemble_sample() {
+        let program = assemble(include_str!("../sample-code/simple-add.s"), 0x4).unwrap();
+        assert_eq!(words(&program), vec![0x00100413, 0x00240493]);
+        assert_eq!(program.entry, 0x4);
+
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.run(program.entry);
+        assert_eq!(cpu.registers.get_register(REG_S0), 1);
+        assert_eq!(cpu.registers.get_register(REG_S1), 3);
+    }
+
+    #[test]
+    fn test_assemble_labels_and_data() {
+        let source = "
+            .equ COUNT, 3
+            .text
+            .globl _start
+            nop
+        _start:
+            la a0, values      # two instructions
+            li a1, COUNT
+            j done
+            .data
+        values: .word 1, 2, values
+            .half 0x1234
+            .byte 'x'
+            .align 2
+        message: .asciz \"hi\"
+            .bss
+        buffer: .zero 8
+            .text
+        done: ebreak
+        ";
+        let program = assemble(source, 0x100).unwrap();
+        assert_eq!(program.entry, 0x104);
+        assert_eq!(program.symbols["done"], 0x114);
+        // .data starts right after .text
+        assert_eq!(program.symbols["values"], 0x118);
+        assert_eq!(program.symbols["message"], 0x128);
+        assert_eq!(program.symbols["buffer"], 0x12C);
+        assert_eq!(program.image.len(), 0x134 - 0x100);
+
+        let words = words(&program);
+        assert_eq!(words[1], 0x00000537); // lui a0, 0
+        assert_eq!(words[2], 0x11850513); // addi a0, a0, 0x118
+        assert_eq!(words[3], 0x00300593); // li a1, 3
+        assert_eq!(words[4], 0x0040006F); // j +4
+        assert_eq!(&words[6..9], &[1, 2, 0x118]);
+        assert_eq!(words[9], 0x00781234);
+        assert_eq!(&program.image[0x28..0x2B], b"hi\0");
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let source = "
+            li t0, 5
+        loop:
+            addi t1, t1, 3
+            addi t0, t0, -1
+            bnez t0, loop
+            li a0, -42
+            li a1, 0x7ffffff0
+            li s0, -0x12345
+            j skip
+            li a0, 1
+        skip:
+            slti s1, a0, 0
+            sw a0, -4(sp)
+            lb s2, -4(sp)
+            ebreak
+        ";
+        let program = assemble(source, 0x100).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.registers.set_register(REG_SP, 0x1000);
+        cpu.run(program.entry);
+        assert_eq!(cpu.registers.get_register(REG_T0), 0);
+        assert_eq!(cpu.registers.get_register(REG_T1), 15, "The loop ran five times");
+        assert_eq!(cpu.registers.get_register(REG_A0), -42i32 as u32);
+        assert_eq!(cpu.registers.get_register(REG_A1), 0x7ffffff0);
+        assert_eq!(cpu.registers.get_register(REG_S0), -0x12345i32 as u32);
+        assert_eq!(cpu.registers.get_register(REG_S1), 1, "a0 is negative");
+        assert_eq!(cpu.registers.get_register(REG_S2), -42i32 as u32);
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Neither the branch nor j links");
+    }
+
+    #[test]
+    fn test_assemble_align_code() {
+        let program = assemble("ebreak\n.align 3\nloop: j loop", 0).unwrap();
+        assert_eq!(program.symbols["loop"], 8);
+        assert_eq!(words(&program), vec![0x00100073, 0x00000013, 0x0000006F]);
+    }
+
+    #[test]
+    fn test_assemble_forward_li() {
+        // li with a label can't be sized in the first pass, so it always takes two instructions
+        let program = assemble("li a0, end\nend:", 0).unwrap();
+        assert_eq!(program.symbols["end"], 8);
+        assert_eq!(words(&program), vec![0x00000537, 0x00850513]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = assemble("nop\n  addi a0, a0\n", 0).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.to_string(), "line 2: 'addi' takes 3 operands, got 2");
+        assert_eq!(assemble("a: nop\na: nop", 0).unwrap_err().line, 2);
+        assert_eq!(assemble("j nowhere", 0).unwrap_err().message, "Undefined symbol 'nowhere'");
+        assert!(assemble(".frobnicate", 0).is_err());
+        assert!(assemble(".align 1.5", 0).is_err());
+    }
+
+    #[test]
+    fn test_assemble_end_of_address_space() {
+        let program = assemble("nop\nnop\nnop\nnop", 0xfffffff0).unwrap();
+        assert_eq!(program.image.len(), 16, "Ending right at the top is fine");
+        assert_eq!(assemble("nop\nnop\nnop\nnop\nnop", 0xfffffff0).unwrap_err().line, 5);
+        // Caught before the bytes are allocated
+        assert_eq!(assemble(".zero 0x80000000\n.zero 0x80000000", 0).unwrap_err().line, 2);
+        assert_eq!(assemble(".zero 0xffffffff", 0x10).unwrap_err().message, "Program runs past the end of the address space");
+        // Each section fits by itself, not one after the other
+        assert!(assemble(".zero 0x80000000\n.data\n.zero 0x80000000", 0x100).is_err());
+    }
+}
@@ -0,0 +1,702 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Turns one instruction (or pseudo-instruction) into machine words.
+// The encoder runs twice: the first pass only needs the size, so labels
+// that aren't known yet evaluate to 0 and range checks are skipped.
+
+use std::collections::HashMap;
+use crate::cpu::instruction::builder::InstructionBuilder;
+use crate::cpu::opcodes::*;
+use crate::cpu::register::*;
+
+pub struct Encoder<'a> {
+    pub constants: &'a HashMap<String, u32>, // .equ / .set
+    pub labels: Option<&'a HashMap<String, u32>>, // None during the first pass
+    pub pc: u32,
+}
+
+impl Encoder<'_> {
+    fn resolve(&self, name: &str) -> Result<u32, String> {
+        if let Some(value) = self.constants.get(name) {
+            return Ok(*value);
+        }
+        match self.labels {
+            Some(labels) => labels.get(name).copied().ok_or_else(|| format!("Undefined symbol '{}'", name)),
+            None => Ok(0),
+        }
+    }
+
+    pub fn eval(&self, expr: &str) -> Result<i64, String> {
+        let mut parser = Expression { chars: expr.trim().chars().collect(), pos: 0, encoder: self };
+        let value = parser.sum()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.chars.len() {
+            return Err(format!("Unexpected '{}' in expression '{}'", parser.chars[parser.pos], expr));
+        }
+        Ok(value)
+    }
+
+    // Evaluates without touching labels, for things that have to be known during the first pass
+    pub fn eval_constant(&self, expr: &str) -> Result<i64, String> {
+        Encoder { constants: self.constants, labels: Some(&HashMap::new()), pc: self.pc }.eval(expr)
+    }
+
+    fn is_final(&self) -> bool {
+        self.labels.is_some()
+    }
+
+    fn immediate(&self, expr: &str, bits: u32) -> Result<u32, String> {
+        let value = self.eval(expr)?;
+        // 12 bit immediates are sign extended, so like GNU as only -2048..2047 fit. lui takes
+        // its 20 bits as written, 0xFFFFF included.
+        let min = -(1i64 << (bits - 1));
+        let max = match bits {
+            12 => (1i64 << (bits - 1)) - 1,
+            _ => (1i64 << bits) - 1,
+        };
+        if self.is_final() && (value < min || value > max) {
+            return Err(format!("Immediate {} does not fit in {} bits", value, bits));
+        }
+        Ok(value as u32 & ((1u32 << bits) - 1))
+    }
+
+    fn shamt(&self, expr: &str) -> Result<u32, String> {
+        let value = self.eval(expr)?;
+        if !(0..32).contains(&value) {
+            return Err(format!("Shift amount {} out of range", value));
+        }
+        Ok(value as u32)
+    }
+
+    // PC relative offset to a label or an absolute address
+    fn offset(&self, expr: &str, bits: u32) -> Result<u32, String> {
+        let offset = self.eval(expr)? - self.pc as i64;
+        if self.is_final() {
+            if offset < -(1i64 << (bits - 1)) || offset >= (1i64 << (bits - 1)) {
+                return Err(format!("Target {} is out of range", expr));
+            }
+            if offset & 1 != 0 {
+                return Err(format!("Target {} is not aligned", expr));
+            }
+        }
+        Ok(offset as u32)
+    }
+
+    // Memory operand: "offset(reg)", "(reg)" or "%lo(symbol)(reg)"
+    fn memory(&self, operand: &str) -> Result<(u32, u8), String> {
+        let open = operand.rfind('(').filter(|_| operand.ends_with(')'))
+            .ok_or_else(|| format!("Expected offset(register), got '{}'", operand))?;
+        let register = register(&operand[open + 1..operand.len() - 1])?;
+        let offset = operand[..open].trim();
+        let offset = if offset.is_empty() { 0 } else { self.immediate(offset, 12)? };
+        Ok((offset, register))
+    }
+
+    fn csr(&self, operand: &str) -> Result<u32, String> {
+        match csr_number(operand) {
+            Some(number) => Ok(number),
+            None => self.immediate(operand, 12),
+        }
+    }
+
+    // Number of bytes the instruction assembles to. Always matches what encode produces.
+    pub fn size(&self, mnemonic: &str, operands: &[String]) -> Result<u32, String> {
+        Ok(self.encode(mnemonic, operands)?.len() as u32 * 4)
+    }
+
+    pub fn encode(&self, mnemonic: &str, operands: &[String]) -> Result<Vec<u32>, String> {
+        let op = |index: usize| -> Result<&str, String> {
+            operands.get(index).map(|operand| operand.as_str())
+                .ok_or_else(|| format!("'{}' is missing operand {}", mnemonic, index + 1))
+        };
+        let reg = |index: usize| register(op(index)?);
+        let expect = |count: usize| -> Result<(), String> {
+            if operands.len() != count {
+                return Err(format!("'{}' takes {} operands, got {}", mnemonic, count, operands.len()));
+            }
+            Ok(())
+        };
+        let builder = InstructionBuilder;
+
+        let word = match mnemonic {
+            // Base instructions
+            "lui" | "auipc" => {
+                expect(2)?;
+                let opcode = if mnemonic == "lui" { OP_LUI } else { OP_AUIPC };
+                builder.u_type(opcode, self.immediate(op(1)?, 20)?, reg(0)?)
+            }
+            "jal" if operands.len() == 1 => builder.j_type(self.offset(op(0)?, 21)?, REG_RA),
+            "jal" => {
+                expect(2)?;
+                builder.j_type(self.offset(op(1)?, 21)?, reg(0)?)
+            }
+            "jalr" => match operands.len() {
+                1 => builder.i_type(OP_JALR, 0, 0, reg(0)?, REG_RA),
+                2 => {
+                    let (offset, rs1) = self.memory(op(1)?)?;
+                    builder.i_type(OP_JALR, offset, 0, rs1, reg(0)?)
+                }
+                _ => {
+                    expect(3)?;
+                    builder.i_type(OP_JALR, self.immediate(op(2)?, 12)?, 0, reg(1)?, reg(0)?)
+                }
+            },
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+                expect(3)?;
+                builder.branch(self.offset(op(2)?, 13)?, branch_funct3(mnemonic), reg(1)?, reg(0)?)
+            }
+            "lb" | "lh" | "lw" | "lbu" | "lhu" => {
+                expect(2)?;
+                let (offset, rs1) = self.memory(op(1)?)?;
+                builder.i_type(OP_LOAD, offset, load_funct3(mnemonic), rs1, reg(0)?)
+            }
+            "sb" | "sh" | "sw" => {
+                expect(2)?;
+                let (offset, rs1) = self.memory(op(1)?)?;
+                let funct3 = match mnemonic { "sb" => F3_SB, "sh" => F3_SH, _ => F3_SW };
+                builder.store(offset, funct3, reg(0)?, rs1)
+            }
+            "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" => {
+                expect(3)?;
+                let funct3 = match mnemonic {
+                    "addi" => F3_ADDI,
+                    "slti" => F3_SLTI,
+                    "sltiu" => F3_SLTIU,
+                    "xori" => F3_XORI,
+                    "ori" => F3_ORI,
+                    _ => F3_ANDI,
+                };
+                builder.alui(self.immediate(op(2)?, 12)?, funct3, reg(1)?, reg(0)?)
+            }
+            "slli" | "srli" | "srai" => {
+                expect(3)?;
+                let (funct3, funct7) = match mnemonic {
+                    "slli" => (F3_SLLI, 0),
+                    "srli" => (F3_SRLI_SRAI, F7_SRL),
+                    _ => (F3_SRLI_SRAI, F7_SRA),
+                };
+                builder.alui((funct7 as u32) << 5 | self.shamt(op(2)?)?, funct3, reg(1)?, reg(0)?)
+            }
+            "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and"
+            | "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => {
+                expect(3)?;
+                let (funct7, funct3) = alu_functs(mnemonic);
+                builder.alu(funct7, funct3, reg(2)?, reg(1)?, reg(0)?)
+            }
+            "fence" => {
+                let (pred, succ) = match operands.len() {
+                    0 => (0xF, 0xF),
+                    _ => {
+                        expect(2)?;
+                        (fence_set(op(0)?)?, fence_set(op(1)?)?)
+                    }
+                };
+                builder.i_type(OP_FENCE, pred << 4 | succ, F3_FENCE, 0, 0)
+            }
+            "fence.i" => builder.i_type(OP_FENCE, 0, F3_FENCE_I, 0, 0),
+            "ecall" => builder.system(F12_ECALL, F3_ECALL_EBREAK, 0, 0),
+            "ebreak" => builder.system(F12_EBREAK, F3_ECALL_EBREAK, 0, 0),
+            "csrrw" | "csrrs" | "csrrc" => {
+                expect(3)?;
+                builder.system(self.csr(op(1)?)?, csr_funct3(mnemonic), reg(2)?, reg(0)?)
+            }
+            "csrrwi" | "csrrsi" | "csrrci" => {
+                expect(3)?;
+                builder.system(self.csr(op(1)?)?, csr_funct3(mnemonic), self.uimm5(op(2)?)?, reg(0)?)
+            }
+
+            // Pseudo-instructions
+            "nop" => builder.alui(0, F3_ADDI, REG_ZERO, REG_ZERO),
+            "li" => {
+                expect(2)?;
+                return self.load_immediate(op(1)?, reg(0)?);
+            }
+            "la" | "lla" => {
+                expect(2)?;
+                let rd = reg(0)?;
+                let address = self.eval(op(1)?)? as u32;
+                return Ok(vec![
+                    builder.lui(hi(address), rd),
+                    builder.alui(lo(address), F3_ADDI, rd, rd),
+                ]);
+            }
+            "mv" => {
+                expect(2)?;
+                builder.alui(0, F3_ADDI, reg(1)?, reg(0)?)
+            }
+            "not" => {
+                expect(2)?;
+                builder.alui(0xFFF, F3_XORI, reg(1)?, reg(0)?)
+            }
+            "neg" => {
+                expect(2)?;
+                builder.alu(F7_SUB, F3_ADD_SUB, reg(1)?, REG_ZERO, reg(0)?)
+            }
+            "seqz" => {
+                expect(2)?;
+                builder.alui(1, F3_SLTIU, reg(1)?, reg(0)?)
+            }
+            "snez" => {
+                expect(2)?;
+                builder.alu(0, F3_SLTU, reg(1)?, REG_ZERO, reg(0)?)
+            }
+            "sltz" => {
+                expect(2)?;
+                builder.alu(0, F3_SLT, REG_ZERO, reg(1)?, reg(0)?)
+            }
+            "sgtz" => {
+                expect(2)?;
+                builder.alu(0, F3_SLT, reg(1)?, REG_ZERO, reg(0)?)
+            }
+            "beqz" | "bnez" | "bltz" | "bgez" => {
+                expect(2)?;
+                let funct3 = branch_funct3(&mnemonic[..3]);
+                builder.branch(self.offset(op(1)?, 13)?, funct3, REG_ZERO, reg(0)?)
+            }
+            "blez" | "bgtz" => {
+                // blez rs -> bge zero, rs and bgtz rs -> blt zero, rs
+                expect(2)?;
+                let funct3 = if mnemonic == "blez" { F3_BGE } else { F3_BLT };
+                builder.branch(self.offset(op(1)?, 13)?, funct3, reg(0)?, REG_ZERO)
+            }
+            "bgt" | "ble" | "bgtu" | "bleu" => {
+                // Same as blt, bge, bltu, bgeu with the operands swapped
+                expect(3)?;
+                let funct3 = match mnemonic {
+                    "bgt" => F3_BLT,
+                    "ble" => F3_BGE,
+                    "bgtu" => F3_BLTU,
+                    _ => F3_BGEU,
+                };
+                builder.branch(self.offset(op(2)?, 13)?, funct3, reg(0)?, reg(1)?)
+            }
+            "j" | "tail" => {
+                expect(1)?;
+                builder.j_type(self.offset(op(0)?, 21)?, REG_ZERO)
+            }
+            // A plain jal reaches +-1MiB, which covers the whole of memory
+            "call" => {
+                expect(1)?;
+                builder.j_type(self.offset(op(0)?, 21)?, REG_RA)
+            }
+            "jr" => {
+                expect(1)?;
+                builder.i_type(OP_JALR, 0, 0, reg(0)?, REG_ZERO)
+            }
+            "ret" => {
+                expect(0)?;
+                builder.i_type(OP_JALR, 0, 0, REG_RA, REG_ZERO)
+            }
+            "csrr" => {
+                expect(2)?;
+                builder.system(self.csr(op(1)?)?, F3_CSRRS, REG_ZERO, reg(0)?)
+            }
+            "csrw" | "csrs" | "csrc" => {
+                expect(2)?;
+                let funct3 = csr_funct3(&format!("csrr{}", &mnemonic[3..]));
+                builder.system(self.csr(op(0)?)?, funct3, reg(1)?, REG_ZERO)
+            }
+            "csrwi" | "csrsi" | "csrci" => {
+                expect(2)?;
+                let funct3 = csr_funct3(&format!("csrr{}", &mnemonic[3..]));
+                builder.system(self.csr(op(0)?)?, funct3, self.uimm5(op(1)?)?, REG_ZERO)
+            }
+            _ => return Err(format!("Unknown instruction '{}'", mnemonic)),
+        };
+        Ok(vec![word])
+    }
+
+    fn uimm5(&self, expr: &str) -> Result<u8, String> {
+        let value = self.eval(expr)?;
+        if !(0..32).contains(&value) {
+            return Err(format!("Immediate {} does not fit in 5 bits", value));
+        }
+        Ok(value as u8)
+    }
+
+    // li picks the shortest sequence for constants. Anything that depends on a label
+    // isn't known during the first pass, so it always gets lui + addi.
+    fn load_immediate(&self, expr: &str, rd: u8) -> Result<Vec<u32>, String> {
+        let builder = InstructionBuilder;
+        if let Ok(value) = self.eval_constant(expr) {
+            if !(-(1i64 << 31)..(1i64 << 32)).contains(&value) {
+                return Err(format!("Immediate {} does not fit in 32 bits", value));
+            }
+            let value = value as u32;
+            if (value as i32) >= -2048 && (value as i32) < 2048 {
+                return Ok(vec![builder.alui(value, F3_ADDI, REG_ZERO, rd)]);
+            }
+            if lo(value) == 0 {
+                return Ok(vec![builder.lui(hi(value), rd)]);
+            }
+        }
+        let value = self.eval(expr)? as u32;
+        Ok(vec![
+            builder.lui(hi(value), rd),
+            builder.alui(lo(value), F3_ADDI, rd, rd),
+        ])
+    }
+}
+
+// Upper 20 bits, rounded so that adding the sign extended lower 12 bits gives the value back
+pub fn hi(value: u32) -> u32 {
+    (value.wrapping_add(0x800) >> 12) & 0xFFFFF
+}
+
+pub fn lo(value: u32) -> u32 {
+    value & 0xFFF
+}
+
+pub fn register(name: &str) -> Result<u8, String> {
+    let name = name.trim();
+    if let Some(number) = name.strip_prefix('x').and_then(|number| number.parse::<u8>().ok()) {
+        if number < 32 {
+            return Ok(number);
+        }
+    }
+    if name == "fp" {
+        return Ok(REG_S0);
+    }
+    REG_ALIASES.iter().position(|alias| *alias == name)
+        .map(|number| number as u8)
+        .ok_or_else(|| format!("Unknown register '{}'", name))
+}
+
+fn branch_funct3(mnemonic: &str) -> u8 {
+    match mnemonic {
+        "beq" => F3_BEQ,
+        "bne" => F3_BNE,
+        "blt" => F3_BLT,
+        "bge" => F3_BGE,
+        "bltu" => F3_BLTU,
+        _ => F3_BGEU,
+    }
+}
+
+fn load_funct3(mnemonic: &str) -> u8 {
+    match mnemonic {
+        "lb" => F3_LB,
+        "lh" => F3_LH,
+        "lw" => F3_LW,
+        "lbu" => F3_LBU,
+        _ => F3_LHU,
+    }
+}
+
+fn alu_functs(mnemonic: &str) -> (u8, u8) {
+    match mnemonic {
+        "add" => (F7_ADD, F3_ADD_SUB),
+        "sub" => (F7_SUB, F3_ADD_SUB),
+        "sll" => (0, F3_SLL),
+        "slt" => (0, F3_SLT),
+        "sltu" => (0, F3_SLTU),
+        "xor" => (0, F3_XOR),
+        "srl" => (F7_SRL, F3_SRL_SLA),
+        "sra" => (F7_SRA, F3_SRL_SLA),
+        "or" => (0, F3_OR),
+        "and" => (0, F3_AND),
+        "mul" => (F7_M_EXTENSION, F3_MUL),
+        "mulh" => (F7_M_EXTENSION, F3_MULH),
+        "mulhsu" => (F7_M_EXTENSION, F3_MULHSU),
+        "mulhu" => (F7_M_EXTENSION, F3_MULHU),
+        "div" => (F7_M_EXTENSION, F3_DIV),
+        "divu" => (F7_M_EXTENSION, F3_DIVU),
+        "rem" => (F7_M_EXTENSION, F3_REM),
+        _ => (F7_M_EXTENSION, F3_REMU),
+    }
+}
+
+fn csr_funct3(mnemonic: &str) -> u8 {
+    match mnemonic {
+        "csrrw" => F3_CSRRW,
+        "csrrs" => F3_CSRRS,
+        "csrrc" => F3_CSRRC,
+        "csrrwi" => F3_CSRRWI,
+        "csrrsi" => F3_CSRRSI,
+        _ => F3_CSRRCI,
+    }
+}
+
+// Names for the CSRs programs commonly touch
+pub const CSR_NAMES: [(&str, u32); 20] = [
+    ("cycle", 0xC00),
+    ("time", 0xC01),
+    ("instret", 0xC02),
+    ("cycleh", 0xC80),
+    ("timeh", 0xC81),
+    ("instreth", 0xC82),
+    ("mstatus", 0x300),
+    ("misa", 0x301),
+    ("mie", 0x304),
+    ("mtvec", 0x305),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("mip", 0x344),
+    ("mcycle", 0xB00),
+    ("minstret", 0xB02),
+    ("mvendorid", 0xF11),
+    ("marchid", 0xF12),
+    ("mhartid", 0xF14),
+];
+
+fn csr_number(name: &str) -> Option<u32> {
+    CSR_NAMES.iter().find(|(csr, _)| *csr == name).map(|(_, number)| *number)
+}
+
+// "iorw" style fence operand
+fn fence_set(operand: &str) -> Result<u32, String> {
+    let mut set = 0;
+    for c in operand.chars() {
+        set |= match c {
+            'i' => 0x8,
+            'o' => 0x4,
+            'r' => 0x2,
+            'w' => 0x1,
+            _ => return Err(format!("Invalid fence operand '{}'", operand)),
+        };
+    }
+    Ok(set)
+}
+
+// Expressions: numbers, 'c', symbols, %hi(), %lo(), unary -, ~ and binary +, -
+struct Expression<'a, 'b> {
+    chars: Vec<char>,
+    pos: usize,
+    encoder: &'a Encoder<'b>,
+}
+
+impl Expression<'_, '_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.term()?;
+        loop {
+            self.skip_whitespace();
+            match self.chars.get(self.pos) {
+                Some('+') => {
+                    self.pos += 1;
+                    value = value.wrapping_add(self.term()?);
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    value = value.wrapping_sub(self.term()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.chars.get(self.pos) {
+            Some('-') => {
+                self.pos += 1;
+                Ok(self.term()?.wrapping_neg())
+            }
+            Some('~') => {
+                self.pos += 1;
+                Ok(!self.term()?)
+            }
+            Some('(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                self.close()?;
+                Ok(value)
+            }
+            Some('%') => {
+                let name: String = self.take_while(|c| c.is_ascii_alphanumeric() || c == '%');
+                self.skip_whitespace();
+                if self.chars.get(self.pos) != Some(&'(') {
+                    return Err(format!("Expected '(' after {}", name));
+                }
+                self.pos += 1;
+                let value = self.sum()? as u32;
+                self.close()?;
+                match name.as_str() {
+                    "%hi" => Ok(hi(value) as i64),
+                    // Sign extended, so that it can be used as an immediate directly
+                    "%lo" => Ok(((lo(value) << 20) as i32 >> 20) as i64),
+                    _ => Err(format!("Unknown relocation {}", name)),
+                }
+            }
+            Some('\'') => {
+                let end = (self.pos + 1..self.chars.len()).find(|i| self.chars[*i] == '\'' && self.chars[*i - 1] != '\\')
+                    .ok_or("Unterminated character literal")?;
+                let text: String = self.chars[self.pos + 1..end].iter().collect();
+                self.pos = end + 1;
+                let bytes = super::parser::unescape(&text)?;
+                if bytes.len() != 1 {
+                    return Err(format!("Invalid character literal '{}'", text));
+                }
+                Ok(bytes[0] as i64)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let text: String = self.take_while(|c| c.is_ascii_alphanumeric());
+                parse_number(&text)
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' || *c == '.' => {
+                let name: String = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+                Ok(self.encoder.resolve(&name)? as i64)
+            }
+            _ => Err(format!("Expected a value at '{}'", self.chars[start..].iter().collect::<String>())),
+        }
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) != Some(&')') {
+            return Err("Expected ')'".to_string());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| predicate(*c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+fn parse_number(text: &str) -> Result<i64, String> {
+    let lower = text.to_lowercase();
+    let result = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        lower.parse::<i64>()
+    };
+    result.map_err(|_| format!("Invalid number '{}'", text))
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::asm::encoder::*;
+
+    fn encode_at(pc: u32, mnemonic: &str, operands: &[&str], labels: &[(&str, u32)]) -> Result<Vec<u32>, String> {
+        let constants = HashMap::new();
+        let labels: HashMap<String, u32> = labels.iter().map(|(name, value)| (name.to_string(), *value)).collect();
+        let operands: Vec<String> = operands.iter().map(|operand| operand.to_string()).collect();
+        Encoder { constants: &constants, labels: Some(&labels), pc }.encode(mnemonic, &operands)
+    }
+
+    fn encode(mnemonic: &str, operands: &[&str]) -> u32 {
+        let words = encode_at(0, mnemonic, operands, &[]).unwrap();
+        assert_eq!(words.len(), 1);
+        words[0]
+    }
+
+    #[test]
+    fn test_encode_base() {
+        // Expected values as produced by GNU as
+        assert_eq!(encode("addi", &["s0", "zero", "1"]), 0x00100413);
+        assert_eq!(encode("addi", &["a0", "a0", "-1"]), 0xFFF50513);
+        assert_eq!(encode("add", &["a0", "a1", "a2"]), 0x00C58533);
+        assert_eq!(encode("sub", &["x5", "x6", "x7"]), 0x407302B3);
+        assert_eq!(encode("lui", &["a0", "0x12345"]), 0x12345537);
+        assert_eq!(encode("lw", &["a0", "8(sp)"]), 0x00812503);
+        assert_eq!(encode("lbu", &["t0", "(a1)"]), 0x0005C283);
+        assert_eq!(encode("jalr", &["ra", "0(t0)"]), 0x000280E7);
+        assert_eq!(encode("srai", &["a0", "a0", "3"]), 0x40355513);
+        assert_eq!(encode("slli", &["x0", "x0", "0x1f"]), 0x01F01013);
+        assert_eq!(encode("ecall", &[]), 0x00000073);
+        assert_eq!(encode("ebreak", &[]), 0x00100073);
+        assert_eq!(encode("fence", &[]), 0x0FF0000F);
+        assert_eq!(encode("csrrs", &["a0", "mhartid", "zero"]), 0xF1402573);
+    }
+
+    #[test]
+    fn test_encode_store() {
+        assert_eq!(encode("sw", &["a1", "8(a0)"]), InstructionBuilder.store(8, F3_SW, REG_A1, REG_A0));
+        assert_eq!(encode("sb", &["zero", "-1(sp)"]), InstructionBuilder.store(0xFFF, F3_SB, REG_ZERO, REG_SP));
+    }
+
+    #[test]
+    fn test_encode_m_extension() {
+        assert_eq!(encode("mul", &["a0", "a1", "a2"]), InstructionBuilder.alu(F7_M_EXTENSION, F3_MUL, 12, 11, 10));
+        assert_eq!(encode("remu", &["a0", "a1", "a2"]), InstructionBuilder.alu(F7_M_EXTENSION, F3_REMU, 12, 11, 10));
+    }
+
+    #[test]
+    fn test_encode_pc_relative() {
+        // Forward and backward jumps and branches
+        assert_eq!(encode_at(0x100, "jal", &["ra", "target"], &[("target", 0x110)]).unwrap(), vec![0x010000EF]);
+        assert_eq!(encode_at(0x100, "j", &["target"], &[("target", 0xF8)]).unwrap(), vec![0xFF9FF06F]);
+        assert_eq!(encode_at(0x100, "beq", &["a0", "a1", "target"], &[("target", 0x108)]).unwrap(), vec![0x00B50463]);
+        assert_eq!(encode_at(0x100, "bnez", &["a0", "target"], &[("target", 0xFC)]).unwrap(), vec![0xFE051EE3]);
+        assert!(encode_at(0, "beq", &["a0", "a1", "target"], &[("target", 0x2000)]).is_err());
+        assert!(encode_at(0, "beq", &["a0", "a1", "target"], &[("target", 0x3)]).is_err());
+        assert!(encode_at(0, "j", &["nowhere"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_encode_pseudo() {
+        assert_eq!(encode("nop", &[]), 0x00000013);
+        assert_eq!(encode("mv", &["a0", "a1"]), 0x00058513);
+        assert_eq!(encode("not", &["a0", "a1"]), 0xFFF5C513);
+        assert_eq!(encode("neg", &["a0", "a1"]), 0x40B00533);
+        assert_eq!(encode("seqz", &["a0", "a1"]), 0x0015B513);
+        assert_eq!(encode("snez", &["a0", "a1"]), 0x00B03533);
+        assert_eq!(encode("ret", &[]), 0x00008067);
+        assert_eq!(encode("jr", &["t0"]), 0x00028067);
+        assert_eq!(encode("csrr", &["a0", "cycle"]), 0xC0002573);
+    }
+
+    #[test]
+    fn test_encode_li() {
+        assert_eq!(encode_at(0, "li", &["a0", "42"], &[]).unwrap(), vec![0x02A00513]);
+        assert_eq!(encode_at(0, "li", &["a0", "-1"], &[]).unwrap(), vec![0xFFF00513]);
+        assert_eq!(encode_at(0, "li", &["a0", "0x12345000"], &[]).unwrap(), vec![0x12345537]);
+        assert_eq!(encode_at(0, "li", &["a0", "0x12345678"], &[]).unwrap(), vec![0x12345537, 0x67850513]);
+        // Lower half is negative, so the upper half gets rounded up
+        assert_eq!(encode_at(0, "li", &["a0", "0xDEADBEEF"], &[]).unwrap(), vec![0xDEADC537, 0xEEF50513]);
+        assert!(encode_at(0, "li", &["a0", "0x100000000"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_encode_la() {
+        assert_eq!(encode_at(0, "la", &["a0", "msg"], &[("msg", 0x12345FFF)]).unwrap(), vec![0x12346537, 0xFFF50513]);
+        assert_eq!(encode_at(0, "lw", &["a0", "%lo(msg)(a0)"], &[("msg", 0x800)]).unwrap(), vec![0x80052503]);
+        assert_eq!(encode_at(0, "lui", &["a0", "%hi(msg)"], &[("msg", 0x800)]).unwrap(), vec![0x00001537]);
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        assert!(encode_at(0, "addi", &["a0", "a0"], &[]).is_err());
+        assert!(encode_at(0, "addi", &["a0", "a0", "4096"], &[]).is_err());
+        // Would sign extend to -1096, GNU as rejects them too
+        assert!(encode_at(0, "addi", &["a0", "a0", "3000"], &[]).is_err());
+        assert!(encode_at(0, "lw", &["a0", "3000(a1)"], &[]).is_err());
+        assert!(encode_at(0, "addi", &["a0", "a0", "0xFFF"], &[]).is_err());
+        assert!(encode_at(0, "addi", &["a0", "a0", "-2049"], &[]).is_err());
+        assert_eq!(encode("addi", &["a0", "a0", "2047"]), InstructionBuilder.alui(0x7FF, F3_ADDI, REG_A0, REG_A0));
+        assert_eq!(encode("lui", &["a0", "0xFFFFF"]), 0xFFFFF537);
+        assert!(encode_at(0, "addi", &["a0", "x32", "1"], &[]).is_err());
+        assert!(encode_at(0, "slli", &["a0", "a0", "32"], &[]).is_err());
+        assert!(encode_at(0, "frobnicate", &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_eval() {
+        let constants = HashMap::from([("SIZE".to_string(), 16)]);
+        let labels = HashMap::from([("buffer".to_string(), 0x100)]);
+        let encoder = Encoder { constants: &constants, labels: Some(&labels), pc: 0 };
+        assert_eq!(encoder.eval("buffer + SIZE - 4").unwrap(), 0x10C);
+        assert_eq!(encoder.eval("-(2 + 3)").unwrap(), -5);
+        assert_eq!(encoder.eval("'A' + 0b10").unwrap(), 67);
+        assert_eq!(encoder.eval("'\\n'").unwrap(), 10);
+        assert!(encoder.eval("2 +").is_err());
+        assert!(encoder.eval_constant("buffer").is_err());
+    }
+}
@@ -0,0 +1,220 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Splits GNU style assembly source into labels, directives and instructions.
+// Nothing is evaluated here, operands are kept as text for the encoder.
+
+#[derive(Debug, PartialEq)]
+pub enum Statement {
+    Label(String),
+    Directive(String, Vec<String>),
+    Instruction(String, Vec<String>),
+}
+
+// Parses one source line. A line can hold several labels and several statements separated by ';'.
+pub fn parse_line(line: &str) -> Result<Vec<Statement>, String> {
+    let mut statements = Vec::new();
+    for part in split_outside_quotes(strip_comment(line), ';') {
+        let mut rest = part.trim();
+        // Labels come first: "loop: addi a0, a0, -1"
+        while let Some(colon) = label_end(rest) {
+            let name = &rest[..colon];
+            if !is_symbol(name) {
+                return Err(format!("Invalid label name '{}'", name));
+            }
+            statements.push(Statement::Label(name.to_string()));
+            rest = rest[colon + 1..].trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+        let (name, operands) = match rest.find(char::is_whitespace) {
+            Some(space) => (&rest[..space], rest[space..].trim()),
+            None => (rest, ""),
+        };
+        let operands: Vec<String> = if operands.is_empty() {
+            Vec::new()
+        } else {
+            split_outside_quotes(operands, ',').iter().map(|operand| operand.trim().to_string()).collect()
+        };
+        let name = name.to_lowercase();
+        if name.starts_with('.') {
+            statements.push(Statement::Directive(name, operands));
+        } else {
+            statements.push(Statement::Instruction(name, operands));
+        }
+    }
+    Ok(statements)
+}
+
+pub fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' || first == '.' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+// Position of the ':' ending a leading label, if the text starts with one
+fn label_end(text: &str) -> Option<usize> {
+    let colon = text.find(':')?;
+    let name = &text[..colon];
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || matches!(c, '\'' | '"' | ',' | '(')) {
+        return None;
+    }
+    Some(colon)
+}
+
+// Everything after '#' or "//" is a comment, unless it's inside a string or character literal
+fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(_), b'\\') => i += 1,
+            (Some(q), c) if c == q => quote = None,
+            (None, b'"') | (None, b'\'') => quote = Some(bytes[i]),
+            (None, b'#') => return &line[..i],
+            (None, b'/') if bytes.get(i + 1) == Some(&b'/') => return &line[..i],
+            _ => {}
+        }
+        i += 1;
+    }
+    line
+}
+
+fn split_outside_quotes(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, c) if c == separator => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+// Decodes a "quoted string" with C style escapes
+pub fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text.strip_prefix('"').and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(|| format!("Expected a quoted string, got '{}'", text))?;
+    unescape(inner)
+}
+
+pub fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('\'') => bytes.push(b'\''),
+            Some('x') => {
+                let mut value = 0u32;
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+                    value = value * 16 + digit;
+                    chars.next();
+                }
+                bytes.push(value as u8);
+            }
+            Some(digit @ '0'..='7') => {
+                // Up to three octal digits, "\0" being the common case
+                let mut value = digit.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            Some(other) => return Err(format!("Unknown escape sequence '\\{}'", other)),
+            None => return Err("Unterminated escape sequence".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::asm::parser::*;
+
+    fn strings(operands: &[&str]) -> Vec<String> {
+        operands.iter().map(|operand| operand.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_instruction() {
+        assert_eq!(parse_line("  addi s0, zero, 1 # comment").unwrap(), vec![
+            Statement::Instruction("addi".to_string(), strings(&["s0", "zero", "1"])),
+        ]);
+        assert_eq!(parse_line("lw a0, 8(sp)").unwrap(), vec![
+            Statement::Instruction("lw".to_string(), strings(&["a0", "8(sp)"])),
+        ]);
+        assert_eq!(parse_line("ret").unwrap(), vec![
+            Statement::Instruction("ret".to_string(), vec![]),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        assert_eq!(parse_line("_start: loop:  j loop // forever").unwrap(), vec![
+            Statement::Label("_start".to_string()),
+            Statement::Label("loop".to_string()),
+            Statement::Instruction("j".to_string(), strings(&["loop"])),
+        ]);
+        assert!(parse_line("1abc: nop").is_err());
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        assert_eq!(parse_line(".ascii \"a, b # c\\n\"").unwrap(), vec![
+            Statement::Directive(".ascii".to_string(), strings(&["\"a, b # c\\n\""])),
+        ]);
+        assert_eq!(parse_line(".word 1, 2, label").unwrap(), vec![
+            Statement::Directive(".word".to_string(), strings(&["1", "2", "label"])),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_separator() {
+        assert_eq!(parse_line("nop; nop").unwrap().len(), 2);
+        assert_eq!(parse_line("li a0, ';'").unwrap(), vec![
+            Statement::Instruction("li".to_string(), strings(&["a0", "';'"])),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_string() {
+        assert_eq!(parse_string("\"hi\\n\\0\\x41\\101\"").unwrap(), b"hi\n\0AA".to_vec());
+        assert!(parse_string("hi").is_err());
+        assert!(parse_string("\"\\q\"").is_err());
+    }
+}
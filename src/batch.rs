@@ -0,0 +1,177 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Headless runs for CI: the process exits with the guest's status and can dump the
+// registers and chosen memory ranges for a test script to check.
+// The status is what the guest reported through semihosting, HTIF or a syscon device,
+// or a0 if it halted without reporting one. A run that's cut short or traps exits with
+// EXIT_LIMIT or EXIT_TRAP instead, VM errors like a missing image with 1. A status that
+// doesn't fit the 8 bits the OS keeps becomes EXIT_OVERFLOW, so 256 can't pass for success.
+
+use std::ops::Range;
+use serde::Serialize;
+use serde::ser::SerializeMap;
+use crate::cpu::CPU;
+use crate::cpu::register::{REG_A0, REG_ALIASES};
+use crate::machine::StopReason;
+
+// Same as timeout(1) uses
+pub const EXIT_LIMIT: i32 = 124;
+pub const EXIT_TRAP: i32 = 125;
+pub const EXIT_OVERFLOW: i32 = 255;
+
+pub fn exit_status(stop: &StopReason, cpu: &CPU) -> i32 {
+    match stop {
+        StopReason::Halted(Some(exit_code)) => status(*exit_code),
+        StopReason::Halted(None) => status(cpu.registers.get_register(REG_A0)),
+        StopReason::Trap(_) => EXIT_TRAP,
+        _ => EXIT_LIMIT,
+    }
+}
+
+fn status(code: u32) -> i32 {
+    match code {
+        0..=0xff => code as i32,
+        _ => EXIT_OVERFLOW,
+    }
+}
+
+// Short name for why the run stopped, the dump's "stop" field
+fn stop_kind(stop: &StopReason, timed_out: bool) -> &'static str {
+    match stop {
+        StopReason::Halted(Some(_)) => "exited",
+        StopReason::Halted(None) => "halted",
+        StopReason::Trap(_) => "trap",
+        StopReason::BudgetExhausted => "limit",
+        StopReason::Paused if timed_out => "timeout",
+        _ => "stopped",
+    }
+}
+
+#[derive(Serialize)]
+struct Dump {
+    stop: &'static str,
+    message: String,
+    exit_status: i32,
+    pc: u32,
+    instret: u64,
+    registers: Registers,
+    memory: Vec<MemoryDump>,
+}
+
+// By ABI name, in register order
+struct Registers([u32; 32]);
+
+#[derive(Serialize)]
+struct MemoryDump {
+    address: u32,
+    bytes: String, // Hex, two digits per byte
+}
+
+impl Serialize for Registers {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(32))?;
+        for (name, value) in REG_ALIASES.iter().zip(self.0) {
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
+}
+
+// Bytes of a RAM or ROM range, devices aren't read since that has side effects
+fn read_range(cpu: &CPU, range: &Range<u64>) -> Result<Vec<u8>, String> {
+    if range.start >= range.end || range.end > 1 << 32 {
+        return Err(format!("0x{:x}..0x{:x} isn't a range of addresses", range.start, range.end));
+    }
+    (range.start..range.end).map(|address| match cpu.memory.contains(address as u32) {
+        true => Ok(cpu.memory.get_u8(address as u32)),
+        false => Err(format!("Can't dump 0x{:x}..0x{:x}, 0x{:08x} isn't in RAM or ROM", range.start, range.end, address)),
+    }).collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn json(cpu: &CPU, stop: &StopReason, timed_out: bool, ranges: &[Range<u64>]) -> Result<String, String> {
+    let mut memory = Vec::new();
+    for range in ranges {
+        memory.push(MemoryDump { address: range.start as u32, bytes: hex(&read_range(cpu, range)?) });
+    }
+    let dump = Dump {
+        stop: stop_kind(stop, timed_out),
+        message: stop.to_string(),
+        exit_status: exit_status(stop, cpu),
+        pc: cpu.get_pc(),
+        instret: cpu.instret(),
+        registers: Registers(cpu.registers.registers),
+        memory,
+    };
+    serde_json::to_string_pretty(&dump).map(|text| text + "\n").map_err(|error| error.to_string())
+}
+
+// Same contents as the JSON, one register per line and memory like a hex dump
+pub fn text(cpu: &CPU, stop: &StopReason, timed_out: bool, ranges: &[Range<u64>]) -> Result<String, String> {
+    let mut text = format!("stop {}\nexit_status {}\npc 0x{:08x}\ninstret {}\n",
+        stop_kind(stop, timed_out), exit_status(stop, cpu), cpu.get_pc(), cpu.instret());
+    for (name, value) in REG_ALIASES.iter().zip(cpu.registers.registers) {
+        text.push_str(&format!("{} 0x{:08x}\n", name, value));
+    }
+    for range in ranges {
+        let bytes = read_range(cpu, range)?;
+        for (index, line) in bytes.chunks(16).enumerate() {
+            let bytes: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            text.push_str(&format!("{:08x}: {}\n", range.start as usize + index * 16, bytes.join(" ")));
+        }
+    }
+    Ok(text)
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::batch::*;
+    use crate::cpu::Trap;
+    use crate::machine::Machine;
+
+    fn run(source: &str) -> (Machine, StopReason) {
+        let program = crate::asm::assemble(source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        let mut machine = Machine::new(cpu);
+        let stop = machine.run();
+        (machine, stop)
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let (machine, stop) = run("li a0, 42\nebreak");
+        assert_eq!(exit_status(&stop, &machine.cpu), 42);
+        assert_eq!(exit_status(&StopReason::Halted(Some(3)), &machine.cpu), 3);
+        assert_eq!(exit_status(&StopReason::Halted(Some(256)), &machine.cpu), EXIT_OVERFLOW);
+        assert_eq!(exit_status(&StopReason::Halted(Some(0xffff_ffff)), &machine.cpu), EXIT_OVERFLOW);
+        assert_eq!(exit_status(&StopReason::Trap(Trap::IllegalInstruction(0)), &machine.cpu), EXIT_TRAP);
+        assert_eq!(exit_status(&StopReason::BudgetExhausted, &machine.cpu), EXIT_LIMIT);
+    }
+
+    #[test]
+    fn test_dump() {
+        let (machine, stop) = run("li a0, 7\nli a1, 0x100\nsw a0, 0(a1)\nebreak");
+        let dump: serde_json::Value = serde_json::from_str(&json(&machine.cpu, &stop, false, &[0x100..0x106, 0x4..0x8]).unwrap()).unwrap();
+        assert_eq!(dump["stop"], "halted");
+        assert_eq!(dump["exit_status"], 7);
+        assert_eq!(dump["registers"]["a1"], 0x100);
+        assert_eq!(dump["instret"], 3);
+        assert_eq!(dump["memory"][0]["address"], 0x100);
+        assert_eq!(dump["memory"][0]["bytes"], "070000000000");
+        assert_eq!(dump["memory"][1]["bytes"], "13057000", "li a0, 7");
+
+        let dump = text(&machine.cpu, &stop, false, &[0x4..0x8, 0x100..0x112]).unwrap();
+        assert!(dump.starts_with("stop halted\nexit_status 7\n"));
+        assert!(dump.contains("\na0 0x00000007\n"));
+        assert!(dump.ends_with("00000100: 07 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n00000110: 00 00\n"));
+
+        assert!(json(&machine.cpu, &stop, false, &[0x4..0x8, 0x100..0x100]).is_err());
+        assert!(text(&machine.cpu, &stop, false, &[0x4..0x8, 0x1ffffe..0x200004]).err().unwrap().contains("0x00200000 isn't in RAM"));
+    }
+}
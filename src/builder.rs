@@ -0,0 +1,262 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Putting a machine together from code, for test harnesses and tools that embed the VM:
+//     let mut machine = MachineBuilder::new()
+//         .ram(0x80000000, 1 << 20)
+//         .device("uart0", 0x10000000, UART_SIZE, None, Box::new(Uart::new()))
+//         .image(0x80000000, program)
+//         .build()?;
+//     assert_eq!(machine.run(), StopReason::Halted(Some(0)));
+// Without ram() the machine gets the 2 MB at address 0 that CPU::new has. The reset
+// vector is entry() if given, otherwise the entry point of the first ELF, otherwise where
+// the first image went. Machine configuration files are built through here too.
+
+use std::path::PathBuf;
+use crate::cpu::{CPU, Isa, MEMSIZE, PAGE_OFFSET_BITS};
+use crate::cpu::htif::Htif;
+use crate::cpu::memory::Memory;
+use crate::cpu::memory::bus::Device;
+use crate::cpu::semihosting::Semihosting;
+use crate::elf::Elf;
+use crate::machine::Machine;
+
+struct RegionSpec {
+    name: String,
+    base: u32,
+    size: usize,
+    read_only: bool,
+}
+
+struct DeviceSpec {
+    name: String,
+    base: u32,
+    size: u32,
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+enum ImageSpec {
+    Raw(u32, Vec<u8>),
+    Elf(Elf),
+}
+
+pub struct MachineBuilder {
+    ram: (u32, usize),
+    page_offset_bits: usize,
+    regions: Vec<RegionSpec>,
+    devices: Vec<DeviceSpec>,
+    isa: Isa,
+    htif: Option<(u32, Option<u32>)>,
+    semihosting: Option<(PathBuf, String)>,
+    images: Vec<ImageSpec>,
+    entry: Option<u32>,
+    budget: Option<u64>,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        Self {
+            ram: (0, MEMSIZE),
+            page_offset_bits: PAGE_OFFSET_BITS,
+            regions: Vec::new(),
+            devices: Vec::new(),
+            isa: Isa::RV32IM,
+            htif: None,
+            semihosting: None,
+            images: Vec::new(),
+            entry: None,
+            budget: None,
+        }
+    }
+
+    // Main RAM, the memory the GUI shows
+    pub fn ram(mut self, base: u32, size: usize) -> Self {
+        self.ram = (base, size);
+        self
+    }
+
+    // A power of two, 256 bytes unless set. Regions start and end on page boundaries.
+    pub fn page_size(mut self, size: usize) -> Self {
+        self.page_offset_bits = size.trailing_zeros() as usize;
+        self
+    }
+
+    // More RAM besides main RAM
+    pub fn region(mut self, name: &str, base: u32, size: usize) -> Self {
+        self.regions.push(RegionSpec { name: name.to_string(), base, size, read_only: false });
+        self
+    }
+
+    // Images can be loaded into ROM, the guest can't write it
+    pub fn rom(mut self, name: &str, base: u32, size: usize) -> Self {
+        self.regions.push(RegionSpec { name: name.to_string(), base, size, read_only: true });
+        self
+    }
+
+    // A memory mapped device at base..base + size
+    pub fn device(mut self, name: &str, base: u32, size: u32, irq: Option<u32>, device: Box<dyn Device>) -> Self {
+        self.devices.push(DeviceSpec { name: name.to_string(), base, size, irq, device });
+        self
+    }
+
+    pub fn isa(mut self, isa: Isa) -> Self {
+        self.isa = isa;
+        self
+    }
+
+    // HTIF at these addresses; ELFs that define tohost get it anyway
+    pub fn htif(mut self, tohost: u32, fromhost: Option<u32>) -> Self {
+        self.htif = Some((tohost, fromhost));
+        self
+    }
+
+    // Semihosting with file access confined to root
+    pub fn semihosting(mut self, root: impl Into<PathBuf>, cmdline: &str) -> Self {
+        self.semihosting = Some((root.into(), cmdline.to_string()));
+        self
+    }
+
+    // Raw bytes at address, in RAM or ROM
+    pub fn image(mut self, address: u32, bytes: Vec<u8>) -> Self {
+        self.images.push(ImageSpec::Raw(address, bytes));
+        self
+    }
+
+    pub fn elf(mut self, elf: Elf) -> Self {
+        self.images.push(ImageSpec::Elf(elf));
+        self
+    }
+
+    pub fn entry(mut self, pc: u32) -> Self {
+        self.entry = Some(pc);
+        self
+    }
+
+    // Machine::run gives up after this many instructions
+    pub fn budget(mut self, instructions: u64) -> Self {
+        self.budget = Some(instructions);
+        self
+    }
+
+    pub fn build(self) -> Result<Machine, String> {
+        let budget = self.budget;
+        let mut machine = Machine::new(self.build_cpu()?);
+        machine.set_budget(budget);
+        Ok(machine)
+    }
+
+    // The CPU on its own, with everything loaded and pc at the reset vector
+    pub fn build_cpu(self) -> Result<CPU, String> {
+        let page_size = 1usize << self.page_offset_bits;
+        let (base, size) = self.ram;
+        let mut ranges = vec![("main RAM".to_string(), base as u64, size as u64)];
+        for region in &self.regions {
+            ranges.push((region.name.clone(), region.base as u64, region.size as u64));
+        }
+        for (name, base, size) in &ranges {
+            if *size == 0 || size % page_size as u64 != 0 || base % page_size as u64 != 0 {
+                return Err(format!("{} has to start and end on a {} byte page boundary", name, page_size));
+            }
+        }
+        for device in &self.devices {
+            ranges.push((device.name.clone(), device.base as u64, device.size as u64));
+        }
+        ranges.sort_by_key(|range| range.1);
+        for (name, base, size) in &ranges {
+            if base + size > 1 << 32 {
+                return Err(format!("{} at 0x{:08x} goes past the end of the address space", name, base));
+            }
+        }
+        for pair in ranges.windows(2) {
+            if pair[0].1 + pair[0].2 > pair[1].1 {
+                return Err(format!("{} at 0x{:08x} overlaps {} at 0x{:08x}", pair[0].0, pair[0].1, pair[1].0, pair[1].1));
+            }
+        }
+
+        let mut memory = Memory::with_base(base, size, self.page_offset_bits);
+        for region in &self.regions {
+            memory.bus.add_region(&region.name, region.base, region.size, self.page_offset_bits, region.read_only);
+        }
+        for device in self.devices {
+            memory.bus.add_device(&device.name, device.base, device.size, device.irq, device.device);
+        }
+        let mut cpu = CPU::with_memory(memory);
+        cpu.set_isa(self.isa);
+        if let Some((tohost, fromhost)) = self.htif {
+            cpu.enable_htif(Htif::new(tohost, fromhost));
+        }
+        if let Some((root, cmdline)) = self.semihosting {
+            cpu.enable_semihosting(Semihosting::new(root, &cmdline));
+        }
+
+        let fits = |cpu: &CPU, address: u32, len: usize| {
+            let end = address as u64 + len.max(1) as u64 - 1;
+            match cpu.memory.contains(address) && end <= u32::MAX as u64 && cpu.memory.contains(end as u32) {
+                true => Ok(()),
+                false => Err(format!("Image at 0x{:08x}..0x{:08x} isn't in RAM or ROM", address, end + 1)),
+            }
+        };
+        let mut start = None;
+        for image in &self.images {
+            match image {
+                ImageSpec::Raw(address, bytes) => {
+                    fits(&cpu, *address, bytes.len())?;
+                    cpu.load_image(*address, bytes);
+                    start.get_or_insert(*address);
+                }
+                ImageSpec::Elf(elf) => {
+                    for segment in &elf.segments {
+                        fits(&cpu, segment.address, segment.mem_size.max(segment.data.len() as u32) as usize)?;
+                    }
+                    cpu.load_elf(elf);
+                    start.get_or_insert(elf.entry);
+                }
+            }
+        }
+        cpu.set_pc(self.entry.or(start).unwrap_or(base));
+        Ok(cpu)
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::builder::*;
+    use crate::cpu::syscon::{Syscon, SYSCON_SIZE};
+    use crate::machine::StopReason;
+
+    #[test]
+    fn test_build() {
+        let program = crate::asm::assemble("li a0, 5\nlui t0, 0x100\nli t1, 0x5555\nsw t1, 0(t0)", 0x80000000).unwrap();
+        let mut machine = MachineBuilder::new()
+            .ram(0x80000000, 0x10000)
+            .rom("boot", 0x1000, 0x1000)
+            .device("finisher", 0x100000, SYSCON_SIZE, Some(1), Box::new(Syscon::new()))
+            .image(program.base, program.image)
+            .image(0x1000, vec![0x13, 0, 0, 0])
+            .build()
+            .unwrap();
+        assert_eq!(machine.cpu.get_pc(), 0x80000000, "The first image is the entry point");
+        assert_eq!(machine.cpu.memory.get_u32(0x1000), 0x13);
+        assert_eq!(machine.run(), StopReason::Halted(Some(0)));
+        assert_eq!(machine.cpu.devices(), ["syscon"]);
+
+        let mut machine = MachineBuilder::new().image(0x4, vec![0x6f, 0, 0, 0]).entry(0x4).budget(3).build().unwrap();
+        assert_eq!(machine.run(), StopReason::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = |builder: MachineBuilder| builder.build().err().unwrap();
+        assert!(error(MachineBuilder::new().rom("boot", 0x1000, 0x100)).contains("overlaps boot at 0x00001000"));
+        assert!(error(MachineBuilder::new().ram(0x10, 0x1000)).contains("page boundary"));
+        assert!(error(MachineBuilder::new().image(0x1ffffc, vec![0; 8])).contains("isn't in RAM or ROM"));
+        assert!(error(MachineBuilder::new().ram(0xffff0000, 0x20000)).contains("past the end"));
+    }
+}
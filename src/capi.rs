@@ -0,0 +1,310 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// C ABI for using the VM from C and C++, as a co-simulation model for instance. The
+// declarations and the rules for calling them are in include/tiny_vm.h; every function
+// here takes pointers the caller vouches for, as the header describes.
+//
+// Functions that can fail return TVM_OK or TVM_ERROR, and tvm_last_error() says what went
+// wrong. Devices the embedder registers get called back for every load and store in
+// their window, on the thread that runs the machine.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use crate::builder::MachineBuilder;
+use crate::cpu::memory::bus::Device;
+use crate::elf::Elf;
+use crate::machine::{Machine, StopReason};
+
+pub const TVM_OK: c_int = 0;
+pub const TVM_ERROR: c_int = -1;
+
+// What tvm_step and tvm_run return
+pub const TVM_STOP_STEPPED: c_int = 0;
+pub const TVM_STOP_BREAKPOINT: c_int = 1;
+pub const TVM_STOP_WATCHPOINT: c_int = 2;
+pub const TVM_STOP_TRAP: c_int = 3;
+pub const TVM_STOP_HALTED: c_int = 4;
+pub const TVM_STOP_EXITED: c_int = 5;
+pub const TVM_STOP_BUDGET: c_int = 6;
+pub const TVM_STOP_PAUSED: c_int = 7;
+
+pub const TVM_REG_PC: u32 = 32;
+
+pub type TvmLoadFn = extern "C" fn(user: *mut c_void, offset: u32, size: u32) -> u32;
+pub type TvmStoreFn = extern "C" fn(user: *mut c_void, offset: u32, size: u32, value: u32);
+
+pub struct TvmMachine {
+    machine: Machine,
+    error: CString,
+}
+
+// A device whose registers live on the other side of the C ABI
+struct CallbackDevice {
+    load: TvmLoadFn,
+    store: TvmStoreFn,
+    user: *mut c_void,
+}
+
+// The embedder promises the callbacks can be called from whichever thread runs the machine
+unsafe impl Send for CallbackDevice {}
+
+impl Device for CallbackDevice {
+    fn kind(&self) -> &'static str {
+        "external"
+    }
+
+    fn load(&mut self, offset: u32, size: u32) -> u32 {
+        (self.load)(self.user, offset, size)
+    }
+
+    fn store(&mut self, offset: u32, size: u32, value: u32, silent: bool) {
+        // Running a stretch again for reverse execution mustn't repeat side effects
+        if !silent {
+            (self.store)(self.user, offset, size, value);
+        }
+    }
+}
+
+impl TvmMachine {
+    fn fail(&mut self, error: impl Into<String>) -> c_int {
+        self.error = CString::new(error.into().replace('\0', " ")).unwrap_or_default();
+        TVM_ERROR
+    }
+}
+
+fn stop_code(stop: StopReason) -> c_int {
+    match stop {
+        StopReason::Stepped => TVM_STOP_STEPPED,
+        StopReason::Breakpoint(_) => TVM_STOP_BREAKPOINT,
+        StopReason::Watchpoint(_) => TVM_STOP_WATCHPOINT,
+        StopReason::Trap(_) => TVM_STOP_TRAP,
+        StopReason::Halted(Some(_)) => TVM_STOP_EXITED,
+        StopReason::Halted(None) => TVM_STOP_HALTED,
+        StopReason::BudgetExhausted => TVM_STOP_BUDGET,
+        StopReason::Paused | StopReason::HistoryStart => TVM_STOP_PAUSED,
+    }
+}
+
+unsafe fn slice<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 { &[] } else { std::slice::from_raw_parts(data, len) }
+}
+
+#[no_mangle]
+pub extern "C" fn tvm_create(ram_base: u32, ram_size: u32) -> *mut TvmMachine {
+    match MachineBuilder::new().ram(ram_base, ram_size as usize).entry(ram_base).build() {
+        Ok(machine) => Box::into_raw(Box::new(TvmMachine { machine, error: CString::default() })),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_destroy(machine: *mut TvmMachine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_last_error(machine: *const TvmMachine) -> *const c_char {
+    (*machine).error.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_load_image(machine: *mut TvmMachine, address: u32, data: *const u8, len: usize) -> c_int {
+    let machine = &mut *machine;
+    let bytes = slice(data, len);
+    let memory = &mut machine.machine.cpu.memory;
+    let end = address as u64 + len.max(1) as u64 - 1;
+    if !memory.contains(address) || end > u32::MAX as u64 || !memory.contains(end as u32) {
+        return machine.fail(format!("Image at 0x{:08x}..0x{:08x} isn't in memory", address, end + 1));
+    }
+    memory.load_image(address, &bytes.to_vec());
+    TVM_OK
+}
+
+// Loads the segments and sets pc to the entry point
+#[no_mangle]
+pub unsafe extern "C" fn tvm_load_elf(machine: *mut TvmMachine, data: *const u8, len: usize) -> c_int {
+    let machine = &mut *machine;
+    let elf = match Elf::parse(slice(data, len)) {
+        Ok(elf) => elf,
+        Err(error) => return machine.fail(error),
+    };
+    let memory = &machine.machine.cpu.memory;
+    for segment in &elf.segments {
+        let end = segment.address as u64 + segment.mem_size.max(segment.data.len() as u32).max(1) as u64 - 1;
+        if !memory.contains(segment.address) || end > u32::MAX as u64 || !memory.contains(end as u32) {
+            return machine.fail(format!("Segment at 0x{:08x} isn't in memory", segment.address));
+        }
+    }
+    machine.machine.cpu.load_elf(&elf);
+    machine.machine.cpu.set_pc(elf.entry);
+    TVM_OK
+}
+
+// A device at base..base + size. load and store get the offset into the window and
+// the access size in bytes, user is passed through.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_add_device(machine: *mut TvmMachine, name: *const c_char, base: u32, size: u32,
+    load: TvmLoadFn, store: TvmStoreFn, user: *mut c_void) -> c_int {
+    let machine = &mut *machine;
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    let bus = &machine.machine.cpu.memory.bus;
+    let end = base as u64 + size as u64;
+    let taken = size == 0 || end > 1 << 32
+        || (0..size).step_by(4).any(|offset| machine.machine.cpu.memory.contains(base + offset))
+        || bus.devices.iter().any(|device| (base as u64) < device.base as u64 + device.size as u64 && end > device.base as u64);
+    if taken {
+        return machine.fail(format!("Device {} at 0x{:08x} overlaps memory or another device", name, base));
+    }
+    machine.machine.cpu.memory.bus.add_device(&name, base, size, None, Box::new(CallbackDevice { load, store, user }));
+    TVM_OK
+}
+
+// Runs at most count instructions
+#[no_mangle]
+pub unsafe extern "C" fn tvm_step(machine: *mut TvmMachine, count: u64) -> c_int {
+    stop_code((*machine).machine.step(count))
+}
+
+// Runs until something stops the machine
+#[no_mangle]
+pub unsafe extern "C" fn tvm_run(machine: *mut TvmMachine) -> c_int {
+    stop_code((*machine).machine.run())
+}
+
+// Stores the guest's exit status and returns TVM_OK if it reported one
+#[no_mangle]
+pub unsafe extern "C" fn tvm_exit_code(machine: *const TvmMachine, exit_code: *mut u32) -> c_int {
+    match (*machine).machine.cpu.exit_code() {
+        Some(code) => {
+            *exit_code = code;
+            TVM_OK
+        }
+        None => TVM_ERROR,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_instret(machine: *const TvmMachine) -> u64 {
+    (*machine).machine.retired()
+}
+
+// Registers 0 to 31, or TVM_REG_PC
+#[no_mangle]
+pub unsafe extern "C" fn tvm_get_register(machine: *const TvmMachine, register: u32) -> u32 {
+    let cpu = &(*machine).machine.cpu;
+    match register {
+        TVM_REG_PC => cpu.get_pc(),
+        0..=31 => cpu.registers.get_register(register as u8),
+        _ => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_set_register(machine: *mut TvmMachine, register: u32, value: u32) -> c_int {
+    let machine = &mut *machine;
+    match register {
+        TVM_REG_PC => machine.machine.cpu.set_pc(value),
+        0..=31 => machine.machine.cpu.registers.set_register(register as u8, value),
+        _ => return machine.fail(format!("No register {}", register)),
+    }
+    TVM_OK
+}
+
+// RAM and ROM only, devices aren't read behind the guest's back. Watchpoints and the
+// access log don't see it either.
+#[no_mangle]
+pub unsafe extern "C" fn tvm_read_memory(machine: *mut TvmMachine, address: u32, data: *mut u8, len: usize) -> c_int {
+    let machine = &mut *machine;
+    let out = if len == 0 { &mut [] } else { std::slice::from_raw_parts_mut(data, len) };
+    for (offset, byte) in out.iter_mut().enumerate() {
+        let Some(address) = address.checked_add(offset as u32).filter(|address| machine.machine.cpu.memory.contains(*address)) else {
+            return machine.fail(format!("0x{:08x} isn't in memory", address as u64 + offset as u64));
+        };
+        *byte = machine.machine.cpu.memory.peek_u8(address);
+    }
+    TVM_OK
+}
+
+// Writes RAM, and ROM too like loading an image does
+#[no_mangle]
+pub unsafe extern "C" fn tvm_write_memory(machine: *mut TvmMachine, address: u32, data: *const u8, len: usize) -> c_int {
+    tvm_load_image(machine, address, data, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_add_breakpoint(machine: *mut TvmMachine, address: u32) {
+    (*machine).machine.add_breakpoint(address);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tvm_remove_breakpoint(machine: *mut TvmMachine, address: u32) {
+    (*machine).machine.remove_breakpoint(address);
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::capi::*;
+    use crate::cpu::memory::watch::{WatchKind, Watchpoint};
+
+    // Keeps what the guest stored, reads back a counter
+    struct Model {
+        stored: Vec<(u32, u32, u32)>,
+        loads: u32,
+    }
+
+    extern "C" fn model_load(user: *mut c_void, _offset: u32, _size: u32) -> u32 {
+        let model = unsafe { &mut *(user as *mut Model) };
+        model.loads += 1;
+        model.loads * 10
+    }
+
+    extern "C" fn model_store(user: *mut c_void, offset: u32, size: u32, value: u32) {
+        let model = unsafe { &mut *(user as *mut Model) };
+        model.stored.push((offset, size, value));
+    }
+
+    #[test]
+    fn test_c_api() {
+        let program = crate::asm::assemble("lui t0, 0x40000\nlw a0, 4(t0)\nsh a0, 8(t0)\nebreak", 0x80000000).unwrap();
+        let mut model = Model { stored: Vec::new(), loads: 0 };
+        unsafe {
+            assert!(tvm_create(0x80000000, 100).is_null(), "Not a whole number of pages");
+            let machine = tvm_create(0x80000000, 0x10000);
+            assert_eq!(tvm_load_image(machine, 0x80000000, program.image.as_ptr(), program.image.len()), TVM_OK);
+            assert_eq!(tvm_load_image(machine, 0x8000fffe, program.image.as_ptr(), 4), TVM_ERROR);
+            assert!(CStr::from_ptr(tvm_last_error(machine)).to_str().unwrap().contains("isn't in memory"));
+
+            let name = CString::new("model").unwrap();
+            let user = &mut model as *mut Model as *mut c_void;
+            assert_eq!(tvm_add_device(machine, name.as_ptr(), 0x40000000, 0x100, model_load, model_store, user), TVM_OK);
+            assert_eq!(tvm_add_device(machine, name.as_ptr(), 0x80000000, 0x100, model_load, model_store, user), TVM_ERROR);
+
+            assert_eq!(tvm_get_register(machine, TVM_REG_PC), 0x80000000);
+            assert_eq!(tvm_step(machine, 2), TVM_STOP_STEPPED);
+            assert_eq!(tvm_get_register(machine, 10), 10);
+            assert_eq!(tvm_set_register(machine, 10, 0x1234), TVM_OK);
+            assert_eq!(tvm_run(machine), TVM_STOP_HALTED);
+            assert_eq!(tvm_instret(machine), 3);
+            let mut exit_code = 0;
+            assert_eq!(tvm_exit_code(machine, &mut exit_code), TVM_ERROR);
+
+            let mut word = [0u8; 4];
+            assert_eq!(tvm_read_memory(machine, 0x80000000, word.as_mut_ptr(), 4), TVM_OK);
+            assert_eq!(u32::from_le_bytes(word), 0x400002B7);
+            assert_eq!(tvm_write_memory(machine, 0x80000100, [1, 2].as_ptr(), 2), TVM_OK);
+            assert_eq!(tvm_read_memory(machine, 0x800000ff, word.as_mut_ptr(), 4), TVM_OK);
+            assert_eq!(word, [0, 1, 2, 0]);
+            let watchpoint = Watchpoint { kind: WatchKind::Access, address: 0x80000100, length: 2, size: None, value: None };
+            (*machine).machine.add_watchpoint(watchpoint);
+            assert_eq!(tvm_read_memory(machine, 0x80000100, word.as_mut_ptr(), 2), TVM_OK);
+            assert_eq!((*machine).machine.cpu.memory.take_watch_hit(), None, "Reading memory from the host isn't a guest access");
+            assert_eq!(tvm_read_memory(machine, 0x8000fffe, word.as_mut_ptr(), 4), TVM_ERROR);
+            tvm_destroy(machine);
+        }
+        assert_eq!(model.loads, 1);
+        assert_eq!(model.stored, [(8, 2, 0x1234)]);
+    }
+}
@@ -0,0 +1,247 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Command line. `tiny-vm <image> [options]` is short for `tiny-vm run <image> [options]`,
+// the other subcommands look at an image without running it.
+// Numbers can be decimal or hex, sizes take K, M and G suffixes and durations s, ms and m,
+// see parse.rs.
+
+use std::ops::Range;
+use std::time::Duration;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tiny_vm::cpu::Isa;
+use tiny_vm::parse::{parse_address, parse_duration, parse_number, parse_range, parse_size};
+
+pub(crate) const SUBCOMMANDS: [&str; 5] = ["run", "disasm", "asm", "inspect", "help"];
+
+#[derive(Parser)]
+#[command(name = "tiny-vm", version, about = "RISC-V RV32IM virtual machine")]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Run an image, in the GUI unless --no-gui is given
+    Run(Box<RunArgs>),
+    /// Print an objdump style listing of an image
+    Disasm(ImageArgs),
+    /// Assemble GNU style RV32IM source into a raw image
+    Asm(AsmArgs),
+    /// Print what an image contains without running it
+    Inspect(ImageArgs),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum Format {
+    /// State files and ELFs by their magic, assembly by the .s extension, raw otherwise
+    Auto,
+    Elf,
+    Asm,
+    Raw,
+    State,
+    /// A machine configuration, the .toml extension picks it automatically
+    Config,
+}
+
+#[derive(Args)]
+pub(crate) struct ImageArgs {
+    /// ELF, assembly source, raw binary, machine state file or machine configuration
+    pub(crate) image: String,
+    #[command(flatten)]
+    pub(crate) load: LoadArgs,
+}
+
+#[derive(Args, Clone)]
+pub(crate) struct LoadArgs {
+    #[arg(long, value_enum, default_value_t = Format::Auto)]
+    pub(crate) format: Format,
+    /// Where raw images and assembly are loaded; memory starts at the page this is in
+    #[arg(long, value_parser = parse_address, default_value = "0x4")]
+    pub(crate) load_address: u32,
+}
+
+#[derive(Args)]
+pub(crate) struct RunArgs {
+    /// ELF, assembly source, raw binary or machine state file; loaded after the boot
+    /// images of a machine configuration
+    #[arg(required_unless_present = "config")]
+    pub(crate) image: Option<String>,
+    #[command(flatten)]
+    pub(crate) load: LoadArgs,
+    /// Machine configuration describing memory, devices and boot images
+    #[arg(long, value_name = "FILE")]
+    pub(crate) config: Option<String>,
+    /// Print the memory map of the machine before running it
+    #[arg(long)]
+    pub(crate) print_map: bool,
+    /// Start here instead of at the entry point of the image
+    #[arg(long, value_parser = parse_address)]
+    pub(crate) entry: Option<u32>,
+    /// Size of memory
+    #[arg(long, value_parser = parse_size, default_value = "2M", conflicts_with = "config")]
+    pub(crate) memory: usize,
+    /// ISA to execute, rv32im or rv32i; rv32im unless the machine configuration says otherwise
+    #[arg(long, value_parser = Isa::parse)]
+    pub(crate) isa: Option<Isa>,
+    /// Run without opening the GUI and exit with the guest's status
+    #[arg(long, alias = "headless")]
+    pub(crate) no_gui: bool,
+    /// Open the GUI before anything runs, to step through the program
+    #[arg(long, conflicts_with = "no_gui")]
+    pub(crate) pause: bool,
+    /// Stop after this many instructions
+    #[arg(long, value_parser = parse_number)]
+    pub(crate) max_instructions: Option<u64>,
+    /// Stop after this much wall-clock time, like 10s or 500ms
+    #[arg(long, value_parser = parse_duration)]
+    pub(crate) timeout: Option<Duration>,
+    /// Wait for GDB on host:port or unix:/path before running
+    #[arg(long, value_name = "ADDRESS")]
+    pub(crate) gdb: Option<String>,
+    /// Control the machine from a monitor console on stdio, host:port or unix:/path
+    #[arg(long, value_name = "ADDRESS", conflicts_with_all = ["gdb", "timeout"])]
+    pub(crate) monitor: Option<String>,
+    /// Leave the guest stopped until `cont` from the monitor
+    #[arg(short = 'S', long, requires = "monitor")]
+    pub(crate) stopped: bool,
+    /// Write registers, memory and devices to this file when the run stops
+    #[arg(long, value_name = "FILE")]
+    pub(crate) save_state: Option<String>,
+    #[command(flatten)]
+    pub(crate) trace: TraceArgs,
+    #[command(flatten)]
+    pub(crate) dump: DumpArgs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub(crate) enum DumpFormat {
+    Json,
+    /// One register per line, memory as a hex dump
+    Hex,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Dumps")]
+pub(crate) struct DumpArgs {
+    /// Write registers and memory here when the run stops, - for stdout
+    #[arg(long, value_name = "FILE")]
+    pub(crate) dump: Option<String>,
+    #[arg(long, value_enum, default_value_t = DumpFormat::Json, requires = "dump")]
+    pub(crate) dump_format: DumpFormat,
+    /// Memory to include, like 0x80000000..0x80000100; can be repeated
+    #[arg(long, value_parser = parse_range, requires = "dump")]
+    pub(crate) dump_memory: Vec<Range<u64>>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Tracing")]
+pub(crate) struct TraceArgs {
+    /// Write a commit log in spike's --log-commits format
+    #[arg(long, value_name = "FILE")]
+    pub(crate) log_commits: Option<String>,
+    /// Only log instructions in this pc range, like 0x100..0x200
+    #[arg(long, value_parser = parse_range, requires = "log_commits")]
+    pub(crate) log_pc: Option<Range<u64>>,
+    /// Only log the n-th to m-th instruction, like 1000..2000
+    #[arg(long, value_parser = parse_range, requires = "log_commits")]
+    pub(crate) log_window: Option<Range<u64>>,
+    /// Log console input and clock readings for replaying the run
+    #[arg(long, value_name = "FILE")]
+    pub(crate) record_inputs: Option<String>,
+    /// Feed console input and clock readings back from a recording
+    #[arg(long, value_name = "FILE", conflicts_with = "record_inputs")]
+    pub(crate) replay_inputs: Option<String>,
+    /// Write call stacks in the folded format and print the hottest code
+    #[arg(long, value_name = "FILE")]
+    pub(crate) profile: Option<String>,
+    /// Functions and instructions the profile prints
+    #[arg(long, value_name = "N", default_value_t = 20, requires = "profile")]
+    pub(crate) profile_top: usize,
+    /// Write line and branch coverage in lcov format, needs an ELF with debug info
+    #[arg(long, value_name = "FILE")]
+    pub(crate) coverage: Option<String>,
+    /// Print the instruction mix when the run stops
+    #[arg(long)]
+    pub(crate) stats: bool,
+}
+
+#[derive(Args)]
+pub(crate) struct AsmArgs {
+    /// Assembly source
+    pub(crate) source: String,
+    /// Raw image to write, the source with a .bin extension by default
+    #[arg(short, long)]
+    pub(crate) output: Option<String>,
+    /// Address the code is assembled for
+    #[arg(long, value_parser = parse_address, default_value = "0x4")]
+    pub(crate) base: u32,
+}
+
+// Arguments with `run` put in front when no subcommand is given, so plain
+// `tiny-vm program.elf --gdb localhost:1234` keeps working
+pub(crate) fn with_default_command(mut args: Vec<String>) -> Vec<String> {
+    let explicit = args.get(1).is_none_or(|arg| {
+        SUBCOMMANDS.contains(&arg.as_str()) || ["-h", "--help", "-V", "--version"].contains(&arg.as_str())
+    });
+    if !explicit {
+        args.insert(1, "run".to_string());
+    }
+    args
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cli::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        let args = with_default_command(args.iter().map(|arg| arg.to_string()).collect());
+        Cli::try_parse_from(args)
+    }
+
+    #[test]
+    fn test_default_command() {
+        let Command::Run(run) = parse(&["tiny-vm", "program.elf", "--no-gui", "--memory", "64K"]).unwrap().command else {
+            panic!("Should default to run");
+        };
+        assert_eq!(run.image.as_deref(), Some("program.elf"));
+        assert!(run.no_gui);
+        assert_eq!(run.memory, 64 * 1024);
+        assert_eq!(run.isa, None);
+        assert_eq!(run.trace.profile_top, 20);
+        let Command::Run(run) = parse(&["tiny-vm", "a.s", "--dump", "-", "--dump-memory", "0..4", "--dump-memory", "0x100..0x200"]).unwrap().command else {
+            panic!("Should default to run");
+        };
+        assert_eq!(run.dump.dump_format, DumpFormat::Json);
+        assert_eq!(run.dump.dump_memory, [0..4, 0x100..0x200]);
+        assert!(matches!(parse(&["tiny-vm", "--headless", "a.s"]).unwrap().command, Command::Run(run) if run.no_gui));
+        assert!(matches!(parse(&["tiny-vm", "disasm", "a.s"]).unwrap().command, Command::Disasm(_)));
+        assert!(matches!(parse(&["tiny-vm", "asm", "a.s", "-o", "a.bin"]).unwrap().command, Command::Asm(asm) if asm.output.as_deref() == Some("a.bin")));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse(&["tiny-vm"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--memory", "100"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--isa", "rv32imac"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--no-gui", "--pause"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--log-pc", "0..4"]).is_err(), "--log-pc needs --log-commits");
+        assert!(parse(&["tiny-vm", "a.s", "--record-inputs", "a", "--replay-inputs", "b"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--entry", "0x100000000"]).is_err());
+        assert!(parse(&["tiny-vm", "run", "--no-gui"]).is_err(), "Needs an image or a configuration");
+        assert!(parse(&["tiny-vm", "run", "--config", "virt.toml"]).is_ok());
+        assert!(parse(&["tiny-vm", "a.s", "--config", "virt.toml", "--memory", "1M"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--dump-memory", "0..4"]).is_err(), "--dump-memory needs --dump");
+        assert!(parse(&["tiny-vm", "a.s", "-S"]).is_err(), "-S needs --monitor");
+        assert!(parse(&["tiny-vm", "a.s", "--monitor", "stdio", "--gdb", "localhost:1234"]).is_err());
+        assert!(parse(&["tiny-vm", "a.s", "--monitor", "stdio", "-S", "--no-gui"]).is_ok());
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(Isa::parse("RV32I"), Ok(Isa { m: false }));
+        assert!(Isa::parse("rv64im").is_err());
+        assert!(Isa::parse("rv32imm").is_err());
+    }
+}
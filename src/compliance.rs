@@ -0,0 +1,154 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Runner for signature based test suites like the official RISC-V architectural tests
+// (riscv-arch-test). Every test ELF halts through HTIF and leaves its results in memory
+// between the begin_signature and end_signature symbols. That region gets dumped one word
+// per line and compared against the reference signature, which riscv-arch-test gets from
+// the Sail model.
+//
+// Each <name>.elf sits next to its <name>.reference_output. tests/isa_tests.rs runs the
+// suite generated in this repository, tests/isa-tests; tests/arch_test.rs runs
+// riscv-arch-test from RISCV_ARCH_TEST_DIR, e.g. a riscof work directory.
+// See tests/isa-tests/README.md.
+
+use std::path::{Path, PathBuf};
+use crate::cpu::CPU;
+use crate::elf::Elf;
+use crate::machine::{Machine, StopReason};
+
+// Every test finishes in well under a million instructions. Anything longer is stuck in a loop.
+const INSTRUCTION_LIMIT: u64 = 10_000_000;
+
+// Dumps the signature region in the standard format: one 32-bit word per line, lowercase hex
+pub fn dump_signature(cpu: &CPU, begin: u32, end: u32) -> String {
+    let mut signature = String::new();
+    for address in (begin..end).step_by(4) {
+        signature.push_str(&format!("{:08x}\n", cpu.memory.peek_u32(address)));
+    }
+    signature
+}
+
+// Runs a single test ELF and returns its signature
+pub fn run_test(image: &[u8]) -> Result<String, String> {
+    let elf = Elf::parse(image)?;
+    let begin = elf.symbol("begin_signature").ok_or("Missing begin_signature symbol")?;
+    let end = elf.symbol("end_signature").ok_or("Missing end_signature symbol")?;
+
+    let mut cpu = CPU::with_ram_base(elf.lowest_address());
+    cpu.load_elf(&elf);
+    cpu.set_pc(elf.entry);
+
+    let mut machine = Machine::new(cpu);
+    machine.set_budget(Some(INSTRUCTION_LIMIT));
+    match machine.run() {
+        StopReason::Halted(_) => Ok(dump_signature(&machine.cpu, begin, end)),
+        StopReason::BudgetExhausted => Err(format!("Did not halt within {} instructions", INSTRUCTION_LIMIT)),
+        stop => Err(format!("Stopped at pc 0x{:08x}: {}", machine.cpu.get_pc(), stop)),
+    }
+}
+
+// Compares line by line, so trailing whitespace and case don't matter
+pub fn compare_signature(signature: &str, reference: &str) -> Result<(), String> {
+    let signature: Vec<String> = signature.lines().map(|line| line.trim().to_lowercase()).collect();
+    let reference: Vec<String> = reference.lines().map(|line| line.trim().to_lowercase()).filter(|line| !line.is_empty()).collect();
+    if signature.len() != reference.len() {
+        return Err(format!("Signature has {} words, reference has {}", signature.len(), reference.len()));
+    }
+    for (i, (word, expected)) in signature.iter().zip(reference.iter()).enumerate() {
+        if word != expected {
+            return Err(format!("Word {} (offset 0x{:x}) is {}, expected {}", i, i * 4, word, expected));
+        }
+    }
+    Ok(())
+}
+
+// Runs every test below dir, printing PASS or FAIL for each. Returns how many ran, or
+// what failed. A directory without any tests fails too, so a missing suite can't pass.
+pub fn run_suite(dir: &Path) -> Result<usize, String> {
+    let tests = find_tests(dir);
+    if tests.is_empty() {
+        return Err(format!("No tests found in {}", dir.display()));
+    }
+    let mut failures = Vec::new();
+    for test in &tests {
+        let name = test.strip_prefix(dir).unwrap_or(test).display().to_string();
+        let result = std::fs::read_to_string(test.with_extension("reference_output"))
+            .map_err(|_| "missing reference_output".to_string())
+            .and_then(|reference| {
+                let image = std::fs::read(test).map_err(|error| error.to_string())?;
+                compare_signature(&run_test(&image)?, &reference)
+            });
+        match result {
+            Ok(()) => println!("PASS {}", name),
+            Err(error) => {
+                println!("FAIL {}: {}", name, error);
+                failures.push(format!("{}: {}", name, error));
+            }
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(tests.len()),
+        false => Err(format!("{} of {} tests failed:\n{}", failures.len(), tests.len(), failures.join("\n"))),
+    }
+}
+
+// Every test ELF below dir, sorted so the output is stable
+pub fn find_tests(dir: &Path) -> Vec<PathBuf> {
+    let mut tests = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return tests,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            tests.extend(find_tests(&path));
+        } else if path.extension().is_some_and(|extension| extension == "elf") {
+            tests.push(path);
+        }
+    }
+    tests.sort();
+    tests
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::compliance::*;
+    use crate::cpu::instruction::builder::InstructionBuilder;
+    use crate::cpu::opcodes::*;
+    use crate::cpu::register::*;
+    use crate::elf::tests::build_elf;
+
+    // A hand built "test" that stores two words into its signature and halts through tohost
+    #[test]
+    fn test_run_test_signature() {
+        let code: Vec<u8> = [
+            InstructionBuilder.alui(0x123, F3_ADDI, REG_ZERO, REG_S0),
+            InstructionBuilder.store(0x100, F3_SW, REG_S0, REG_ZERO),
+            InstructionBuilder.store(0x104, F3_SW, REG_S0, REG_ZERO),
+            InstructionBuilder.alui(1, F3_ADDI, REG_ZERO, REG_S1),
+            InstructionBuilder.store(0x80, F3_SW, REG_S1, REG_ZERO),
+            InstructionBuilder.store(0x84, F3_SW, REG_ZERO, REG_ZERO),
+        ].iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let symbols = [("tohost", 0x80), ("begin_signature", 0x100), ("end_signature", 0x10C)];
+        let image = build_elf(0x10, 0x10, &code, &symbols);
+
+        let signature = run_test(&image).unwrap();
+        assert_eq!(signature, "00000123\n00000123\n00000000\n");
+        assert!(compare_signature(&signature, "00000123\n00000123\n00000000\n").is_ok());
+        assert!(compare_signature(&signature, "00000123\n00000124\n00000000\n").is_err());
+        assert!(compare_signature(&signature, "00000123\n").is_err());
+    }
+
+    #[test]
+    fn test_run_test_errors() {
+        let image = build_elf(0x10, 0x10, &[0; 4], &[]);
+        assert!(run_test(&image).is_err(), "Missing signature symbols should be reported");
+
+        // An unknown opcode traps
+        let symbols = [("begin_signature", 0x100), ("end_signature", 0x104)];
+        let image = build_elf(0x10, 0x10, &[0x7F, 0, 0, 0], &symbols);
+        assert_eq!(run_test(&image).unwrap_err(), "Stopped at pc 0x00000010: Illegal instruction 0x0000007f");
+    }
+}
@@ -0,0 +1,420 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Machine configurations: a TOML description of the address space, instead of the 2 MB of
+// RAM every run gets otherwise.
+//     isa = "rv32im"
+//     reset_vector = 0x1000
+//
+//     [[memory]]
+//     name = "boot"
+//     kind = "rom"
+//     base = 0x1000
+//     size = "64K"
+//
+//     [[memory]]
+//     name = "ram"
+//     kind = "ram"
+//     base = 0x80000000
+//     size = "16M"
+//
+//     [[device]]
+//     kind = "uart"
+//     base = 0x10000000
+//     irq = 10
+//
+//     [[boot]]
+//     file = "firmware.elf"
+// The first RAM region is main RAM, the one the GUI shows. Devices are uart, syscon (the
+// test finisher), htif (tohost/fromhost addresses instead of ELF symbols) and semihosting.
+// Boot images are loaded in order, ROM included; the reset vector defaults to the entry
+// point of the first one. There's one hart and no interrupt controller, so IRQs are
+// checked and shown in the memory map but nothing delivers them yet.
+
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::parse::parse_size;
+use crate::builder::MachineBuilder;
+use crate::cpu::{CPU, Isa, new_device};
+use crate::cpu::syscon::SYSCON_SIZE;
+use crate::cpu::uart::UART_SIZE;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    #[serde(default = "default_harts")]
+    pub harts: u32,
+    #[serde(default = "default_isa")]
+    pub isa: String,
+    #[serde(default = "default_page_size")]
+    pub page_size: Size,
+    pub reset_vector: Option<u32>,
+    #[serde(default)]
+    pub memory: Vec<MemoryConfig>,
+    #[serde(default)]
+    pub device: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub boot: Vec<BootConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryConfig {
+    pub name: String,
+    pub kind: MemoryKind,
+    pub base: u32,
+    pub size: Size,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryKind {
+    Ram,
+    Rom,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: Option<String>, // The kind, numbered if there are several
+    pub kind: String,
+    pub base: Option<u32>,
+    pub size: Option<Size>,
+    pub irq: Option<u32>,
+    pub tohost: Option<u32>,
+    pub fromhost: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootConfig {
+    pub file: String,
+    pub address: Option<u32>, // For raw images and assembly
+    pub format: Option<String>,
+}
+
+// 4096, or "4K"
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+fn default_harts() -> u32 {
+    1
+}
+
+fn default_isa() -> String {
+    "rv32im".to_string()
+}
+
+fn default_page_size() -> Size {
+    Size::Bytes(1 << crate::cpu::PAGE_OFFSET_BITS)
+}
+
+impl Size {
+    fn bytes(&self) -> Result<u64, String> {
+        match self {
+            Size::Bytes(bytes) => Ok(*bytes),
+            Size::Text(text) => parse_size(text).map(|size| size as u64),
+        }
+    }
+}
+
+// One line of the memory map
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapEntry {
+    pub name: String,
+    pub kind: String,
+    pub base: u32,
+    pub size: u64,
+    pub irq: Option<u32>,
+}
+
+// What validating a configuration comes down to
+pub struct Layout {
+    pub isa: Isa,
+    pub page_offset_bits: usize,
+    pub map: Vec<MapEntry>, // Sorted by address
+    pub main_ram: usize, // Index into map
+    htif: Option<(u32, Option<u32>)>,
+    semihosting: bool,
+}
+
+impl MachineConfig {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|error| error.to_string())
+    }
+
+    pub fn read(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| format!("Can't read {}: {}", path, error))?;
+        Self::parse(&text).map_err(|error| format!("{}: {}", path, error))
+    }
+
+    pub fn layout(&self) -> Result<Layout, String> {
+        if self.harts != 1 {
+            return Err(format!("{} harts asked for, the VM only runs one", self.harts));
+        }
+        let isa = Isa::parse(&self.isa)?;
+        let page_size = self.page_size.bytes()?;
+        if !page_size.is_power_of_two() || !(4..=1 << 16).contains(&page_size) {
+            return Err(format!("Page size {} isn't a power of two from 4 bytes to 64K", page_size));
+        }
+
+        let mut map = Vec::new();
+        for memory in &self.memory {
+            let size = memory.size.bytes()?;
+            if size == 0 || !size.is_multiple_of(page_size) || !(memory.base as u64).is_multiple_of(page_size) {
+                return Err(format!("Memory {} has to start and end on a {} byte page boundary", memory.name, page_size));
+            }
+            let kind = match memory.kind {
+                MemoryKind::Ram => "ram",
+                MemoryKind::Rom => "rom",
+            };
+            map.push(MapEntry { name: memory.name.clone(), kind: kind.to_string(), base: memory.base, size, irq: None });
+        }
+        let main_name = self.memory.iter().find(|memory| memory.kind == MemoryKind::Ram)
+            .map(|memory| memory.name.clone())
+            .ok_or("The machine needs at least one RAM region")?;
+
+        let mut htif = None;
+        let mut semihosting = false;
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for device in &self.device {
+            let count = counts.entry(device.kind.as_str()).or_insert(0);
+            let name = device.name.clone().unwrap_or_else(|| format!("{}{}", device.kind, count));
+            *count += 1;
+            let window = match device.kind.as_str() {
+                "uart" => Some(UART_SIZE),
+                "syscon" => Some(SYSCON_SIZE),
+                "htif" => {
+                    let tohost = device.tohost.ok_or_else(|| format!("HTIF device {} needs a tohost address", name))?;
+                    if htif.replace((tohost, device.fromhost)).is_some() {
+                        return Err("Only one HTIF device is supported".to_string());
+                    }
+                    None
+                }
+                "semihosting" => {
+                    semihosting = true;
+                    None
+                }
+                kind => return Err(format!("Unknown device kind '{}' for {}, expected uart, syscon, htif or semihosting", kind, name)),
+            };
+            match (window, device.base) {
+                (Some(window), Some(base)) => {
+                    let size = match &device.size {
+                        Some(size) => size.bytes()?,
+                        None => window as u64,
+                    };
+                    map.push(MapEntry { name, kind: device.kind.clone(), base, size, irq: device.irq });
+                }
+                (Some(_), None) => return Err(format!("Device {} needs a base address", name)),
+                (None, _) if device.base.is_some() || device.irq.is_some() => {
+                    return Err(format!("Device {} isn't memory mapped, it takes no base or irq", name));
+                }
+                (None, _) => {}
+            }
+        }
+
+        for entry in &map {
+            if entry.base as u64 + entry.size > 1 << 32 {
+                return Err(format!("{} at 0x{:08x} goes past the end of the address space", entry.name, entry.base));
+            }
+        }
+        map.sort_by_key(|entry| entry.base);
+        for pair in map.windows(2) {
+            if pair[0].base as u64 + pair[0].size > pair[1].base as u64 {
+                return Err(format!("{} at 0x{:08x}..0x{:08x} overlaps {} at 0x{:08x}",
+                    pair[0].name, pair[0].base, pair[0].base as u64 + pair[0].size, pair[1].name, pair[1].base));
+            }
+        }
+        let mut irqs: HashMap<u32, &str> = HashMap::new();
+        for entry in &map {
+            if let Some(irq) = entry.irq {
+                if let Some(other) = irqs.insert(irq, &entry.name) {
+                    return Err(format!("IRQ {} is used by both {} and {}", irq, other, entry.name));
+                }
+            }
+        }
+        let memory = |address: u32| map.iter().any(|entry| (entry.kind == "ram" || entry.kind == "rom")
+            && (address as u64).wrapping_sub(entry.base as u64) < entry.size);
+        for (what, address) in [("Reset vector", self.reset_vector), ("HTIF tohost", htif.map(|htif| htif.0)), ("HTIF fromhost", htif.and_then(|htif| htif.1))] {
+            if let Some(address) = address.filter(|address| !memory(*address)) {
+                return Err(format!("{} 0x{:08x} isn't in RAM or ROM", what, address));
+            }
+        }
+
+        let main_ram = map.iter().position(|entry| entry.name == main_name).unwrap();
+        Ok(Layout { isa, page_offset_bits: page_size.trailing_zeros() as usize, map, main_ram, htif, semihosting })
+    }
+}
+
+impl Layout {
+    // A CPU with the memory and devices, and nothing loaded yet
+    pub fn build(&self, cmdline: &str) -> CPU {
+        self.builder(cmdline).build_cpu().expect("The layout was validated")
+    }
+
+    pub fn builder(&self, cmdline: &str) -> MachineBuilder {
+        let main = &self.map[self.main_ram];
+        let mut builder = MachineBuilder::new()
+            .ram(main.base, main.size as usize)
+            .page_size(1 << self.page_offset_bits)
+            .isa(self.isa);
+        for (index, entry) in self.map.iter().enumerate() {
+            builder = match entry.kind.as_str() {
+                _ if index == self.main_ram => builder,
+                "ram" => builder.region(&entry.name, entry.base, entry.size as usize),
+                "rom" => builder.rom(&entry.name, entry.base, entry.size as usize),
+                kind => builder.device(&entry.name, entry.base, entry.size as u32, entry.irq, new_device(kind).unwrap()),
+            };
+        }
+        if let Some((tohost, fromhost)) = self.htif {
+            builder = builder.htif(tohost, fromhost);
+        }
+        if self.semihosting {
+            builder = builder.semihosting(".", cmdline);
+        }
+        builder
+    }
+
+    pub fn main_ram(&self) -> &MapEntry {
+        &self.map[self.main_ram]
+    }
+
+    // One line per region or device, in address order
+    pub fn memory_map(&self) -> String {
+        let mut text = String::new();
+        for (index, entry) in self.map.iter().enumerate() {
+            let mut line = format!("0x{:08x}-0x{:08x}  {:<7} {:>9}  {}", entry.base, entry.base as u64 + entry.size - 1, entry.kind, human_size(entry.size), entry.name);
+            if index == self.main_ram {
+                line.push_str(" (main RAM)");
+            }
+            if let Some(irq) = entry.irq {
+                line.push_str(&format!(", irq {}", irq));
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+fn human_size(size: u64) -> String {
+    match size {
+        _ if size >= 1 << 30 && size.is_multiple_of(1 << 30) => format!("{} GiB", size >> 30),
+        _ if size >= 1 << 20 && size.is_multiple_of(1 << 20) => format!("{} MiB", size >> 20),
+        _ if size >= 1 << 10 && size.is_multiple_of(1 << 10) => format!("{} KiB", size >> 10),
+        _ => format!("{} B", size),
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::cpu::memory::Fault;
+    use crate::machine::{Machine, StopReason};
+
+    const VIRT: &str = r#"
+        isa = "rv32im"
+        reset_vector = 0x1000
+
+        [[memory]]
+        name = "boot"
+        kind = "rom"
+        base = 0x1000
+        size = "4K"
+
+        [[memory]]
+        name = "ram"
+        kind = "ram"
+        base = 0x80000000
+        size = 0x10000
+
+        [[device]]
+        kind = "uart"
+        base = 0x10000000
+        irq = 10
+
+        [[device]]
+        kind = "syscon"
+        base = 0x100000
+
+        [[device]]
+        kind = "htif"
+        tohost = 0x80001000
+    "#;
+
+    fn layout(text: &str) -> Result<Layout, String> {
+        MachineConfig::parse(text)?.layout()
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let layout = layout(VIRT).unwrap();
+        assert_eq!(layout.memory_map(), "\
+            0x00001000-0x00001fff  rom         4 KiB  boot\n\
+            0x00100000-0x00100fff  syscon      4 KiB  syscon0\n\
+            0x10000000-0x100000ff  uart        256 B  uart0, irq 10\n\
+            0x80000000-0x8000ffff  ram        64 KiB  ram (main RAM)\n");
+        assert_eq!(layout.main_ram().base, 0x80000000);
+    }
+
+    #[test]
+    fn test_build() {
+        let mut cpu = layout(VIRT).unwrap().build("");
+        assert_eq!(cpu.devices(), ["htif", "syscon", "uart"]);
+        cpu.memory.load_image(0x1000, &vec![0x13, 0, 0, 0]);
+        assert_eq!(cpu.memory.get_u32(0x1000), 0x13, "Boot images go into ROM");
+        assert!(cpu.memory.contains(0x1000));
+        assert!(!cpu.memory.contains(0x10000000), "Devices can't be executed from");
+        assert_eq!(cpu.memory.get_u8(0x10000005) & 0x20, 0x20, "The UART is always ready to send");
+        cpu.memory.set_u32(0x80000010, 7);
+        assert_eq!(cpu.memory.get_u32(0x80000010), 7);
+
+        // The ROM has to stay unchanged
+        assert_eq!(cpu.memory.store(0x1000, 4, 0), Err(Fault(0x1000)));
+        assert_eq!(cpu.memory.get_u32(0x1000), 0x13);
+    }
+
+    #[test]
+    fn test_run() {
+        // Prints through the UART, then fails with status 3 through the test finisher
+        let program = crate::asm::assemble("lui t0, 0x10000\nli t1, 0x41\nsb t1, 0(t0)\nlui t0, 0x100\nli t1, 0x33333\nsw t1, 0(t0)\nebreak", 0x80000000).unwrap();
+        let mut cpu = layout(VIRT).unwrap().build("");
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        let mut machine = Machine::new(cpu);
+        assert_eq!(machine.run(), StopReason::Halted(Some(3)));
+        assert_eq!(machine.retired(), 7);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let ram = "[[memory]]\nname = \"ram\"\nkind = \"ram\"\nbase = 0\nsize = \"64K\"\n";
+        for (text, error) in [
+            ("", "at least one RAM region"),
+            ("harts = 2\n", "only runs one"),
+            ("isa = \"rv32imac\"\n", "Unsupported extension"),
+            ("page_size = 100\n", "power of two"),
+            ("[[memory]]\nname = \"rom\"\nkind = \"rom\"\nbase = 0x8000\nsize = \"4K\"\n", "ram at 0x00000000..0x00010000 overlaps rom"),
+            ("[[memory]]\nname = \"odd\"\nkind = \"ram\"\nbase = 0x10010\nsize = 256\n", "page boundary"),
+            ("[[device]]\nkind = \"uart\"\nbase = 0xfff0\n", "ram at 0x00000000..0x00010000 overlaps uart0 at 0x0000fff0"),
+            ("[[device]]\nkind = \"uart\"\n", "needs a base address"),
+            ("[[device]]\nkind = \"gpu\"\nbase = 0x20000\n", "Unknown device kind 'gpu'"),
+            ("[[device]]\nkind = \"uart\"\nbase = 0x20000\nirq = 1\n[[device]]\nkind = \"uart\"\nbase = 0x30000\nirq = 1\n", "IRQ 1 is used by both uart0 and uart1"),
+            ("[[device]]\nkind = \"htif\"\ntohost = 0x20000\n", "HTIF tohost 0x00020000 isn't in RAM"),
+            ("reset_vector = 0x20000\n", "Reset vector"),
+            ("colour = \"blue\"\n", "unknown field"),
+        ] {
+            let text = match text.starts_with('[') {
+                true => format!("{}{}", ram, text),
+                false if text.is_empty() => String::new(),
+                false => format!("{}{}", text, ram),
+            };
+            let result = layout(&text);
+            assert!(result.as_ref().err().is_some_and(|message| message.contains(error)), "{:?} should fail with {}, got {:?}", text, error, result.err());
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Guest code coverage: how often every pc retired, and for every conditional branch how
+// often it was taken and not taken. Taken means the next pc isn't the one after the
+// branch, so it doesn't matter how the branch computed where it went.
+// The DWARF line table of the ELF maps it all to source lines, written as lcov tracefiles
+// that genhtml, the usual editor plugins and coverage services all read:
+//     SF:src/main.c
+//     DA:12,3
+//     BRDA:14,0,0,1
+//     BRDA:14,0,1,2
+//     end_of_record
+// A line counts as often as its most executed instruction. Each branch instruction is a
+// block of two lcov branches, taken first, "-" for both if it never ran.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use gimli::{EndianSlice, LittleEndian};
+use crate::cpu::opcodes::OP_BRANCH;
+
+pub struct Coverage {
+    counts: HashMap<u32, u64>, // By pc
+    branches: HashMap<u32, (u64, u64)>, // Taken and not taken, by pc of the branch
+}
+
+// Code from address up to end came from line of file
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineRange {
+    pub address: u32,
+    pub end: u32,
+    pub file: String,
+    pub line: u32,
+}
+
+pub struct LineTable {
+    ranges: Vec<LineRange>,
+}
+
+impl LineTable {
+    // Reads .debug_line through the compilation units in .debug_info, which name the files
+    pub fn from_dwarf(sections: &HashMap<String, Vec<u8>>) -> Result<Self, String> {
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let data = sections.get(id.name()).map(Vec::as_slice).unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        }).map_err(|error| error.to_string())?;
+        let mut ranges = Vec::new();
+        let mut headers = dwarf.units();
+        while let Some(header) = headers.next().map_err(|error| error.to_string())? {
+            let unit = dwarf.unit(header).map_err(|error| error.to_string())?;
+            read_unit(&dwarf, &unit, &mut ranges).map_err(|error| error.to_string())?;
+        }
+        if ranges.is_empty() {
+            return Err("No DWARF line table, build with -g".to_string());
+        }
+        ranges.sort_by_key(|range| range.address);
+        Ok(Self { ranges })
+    }
+
+    pub fn ranges(&self) -> &[LineRange] {
+        &self.ranges
+    }
+}
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+fn read_unit(dwarf: &gimli::Dwarf<Slice>, unit: &gimli::Unit<Slice>, ranges: &mut Vec<LineRange>) -> gimli::Result<()> {
+    let Some(program) = unit.line_program.clone() else {
+        return Ok(());
+    };
+    let comp_dir = unit.comp_dir.map(|dir| dir.to_string_lossy().into_owned());
+    let mut rows = program.rows();
+    // The row before, which covers everything up to the current one
+    let mut previous: Option<(u32, String, u32)> = None;
+    while let Some((header, row)) = rows.next_row()? {
+        let address = row.address() as u32;
+        if let Some((start, file, line)) = previous.take() {
+            if address > start {
+                ranges.push(LineRange { address: start, end: address, file, line });
+            }
+        }
+        if row.end_sequence() {
+            continue;
+        }
+        let Some(line) = row.line() else {
+            continue; // Code that doesn't belong to any line
+        };
+        let Some(entry) = header.file(row.file_index()) else {
+            continue;
+        };
+        let mut path = dwarf.attr_string(unit, entry.path_name())?.to_string_lossy().into_owned();
+        if !path.starts_with('/') {
+            if let Some(dir) = entry.directory(header) {
+                let dir = dwarf.attr_string(unit, dir)?.to_string_lossy().into_owned();
+                path = join(&dir, &path);
+            }
+        }
+        if let (false, Some(comp_dir)) = (path.starts_with('/'), &comp_dir) {
+            path = join(comp_dir, &path);
+        }
+        previous = Some((address, path, line.get() as u32));
+    }
+    Ok(())
+}
+
+fn join(dir: &str, path: &str) -> String {
+    if dir.is_empty() || dir == "." {
+        return path.to_string();
+    }
+    format!("{}/{}", dir.trim_end_matches('/'), path)
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self { counts: HashMap::new(), branches: HashMap::new() }
+    }
+
+    // Called for every retired instruction with the pc it ran at and the pc after it
+    pub fn record(&mut self, pc: u32, instruction: u32, next_pc: u32) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        if (instruction & 0x7F) as u8 == OP_BRANCH {
+            let branch = self.branches.entry(pc).or_insert((0, 0));
+            if next_pc == pc.wrapping_add(4) {
+                branch.1 += 1;
+            } else {
+                branch.0 += 1;
+            }
+        }
+    }
+
+    pub fn count(&self, pc: u32) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    // Taken and not taken
+    pub fn branch(&self, pc: u32) -> (u64, u64) {
+        self.branches.get(&pc).copied().unwrap_or((0, 0))
+    }
+
+    // One record per source file. image reads instruction words to find the branches,
+    // None where there is no memory.
+    pub fn write_lcov(&self, out: &mut impl Write, lines: &LineTable, image: impl Fn(u32) -> Option<u32>) -> std::io::Result<()> {
+        // Hits and branches of every line, sorted by file and line
+        let mut files: BTreeMap<&str, BTreeMap<u32, (u64, Vec<u32>)>> = BTreeMap::new();
+        for range in lines.ranges() {
+            let line = files.entry(&range.file).or_default().entry(range.line).or_insert((0, Vec::new()));
+            for pc in (range.address..range.end).step_by(4) {
+                line.0 = line.0.max(self.count(pc));
+                if image(pc).is_some_and(|word| (word & 0x7F) as u8 == OP_BRANCH) {
+                    line.1.push(pc);
+                }
+            }
+        }
+
+        writeln!(out, "TN:")?;
+        for (file, lines) in files {
+            writeln!(out, "SF:{}", file)?;
+            let (mut found, mut hit) = (0, 0);
+            for (number, (_, branches)) in &lines {
+                for (block, pc) in branches.iter().enumerate() {
+                    let (taken, not_taken) = self.branch(*pc);
+                    let ran = self.count(*pc) > 0;
+                    for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                        match ran {
+                            true => writeln!(out, "BRDA:{},{},{},{}", number, block, branch, count)?,
+                            false => writeln!(out, "BRDA:{},{},{},-", number, block, branch)?,
+                        }
+                        found += 1;
+                        hit += (count > 0) as u32;
+                    }
+                }
+            }
+            writeln!(out, "BRF:{}", found)?;
+            writeln!(out, "BRH:{}", hit)?;
+            for (number, (count, _)) in &lines {
+                writeln!(out, "DA:{},{}", number, count)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|(count, _)| *count > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::coverage::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::elf::Elf;
+    use crate::elf::tests::build_elf_with_sections;
+    use crate::machine::{Machine, StopReason};
+    use gimli::write::{Address, DwarfUnit, EndianVec, LineProgram, LineString, Sections};
+    use gimli::{Encoding, Format, LineEncoding};
+
+    const SOURCE: &str = "li a0, 1\nbeqz a0, skip\nli a1, 2\n\nskip: ebreak";
+
+    // Debug sections saying the code at address came from the given lines of /src/main.s, one instruction each
+    fn dwarf(address: u32, lines: &[u64]) -> Vec<(&'static str, Vec<u8>)> {
+        let encoding = Encoding { format: Format::Dwarf32, version: 5, address_size: 4 };
+        let mut dwarf = DwarfUnit::new(encoding);
+        let mut program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::String(b"/src".to_vec()),
+            None,
+            LineString::String(b"main.s".to_vec()),
+            None,
+        );
+        let directory = program.default_directory();
+        let file = program.add_file(LineString::String(b"main.s".to_vec()), directory, None);
+        program.begin_sequence(Some(Address::Constant(address as u64)));
+        for (index, line) in lines.iter().enumerate() {
+            program.row().file = file;
+            program.row().line = *line;
+            program.row().address_offset = index as u64 * 4;
+            program.generate_row();
+        }
+        program.end_sequence(lines.len() as u64 * 4);
+        dwarf.unit.line_program = program;
+
+        let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+        dwarf.write(&mut sections).unwrap();
+        let mut debug = Vec::new();
+        sections.for_each(|id, data| -> Result<(), ()> {
+            if !data.slice().is_empty() {
+                debug.push((id.name(), data.slice().to_vec()));
+            }
+            Ok(())
+        }).unwrap();
+        debug
+    }
+
+    fn line_table(address: u32, lines: &[u64]) -> LineTable {
+        LineTable::from_dwarf(&dwarf(address, lines).into_iter().map(|(name, data)| (name.to_string(), data)).collect()).unwrap()
+    }
+
+    fn lcov(coverage: &Coverage, lines: &LineTable, image: impl Fn(u32) -> Option<u32>) -> String {
+        let mut out = Vec::new();
+        coverage.write_lcov(&mut out, lines, image).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_line_table() {
+        let lines = line_table(0x100, &[1, 2, 2, 5]);
+        assert_eq!(lines.ranges().len(), 4);
+        assert_eq!(lines.ranges()[0], LineRange { address: 0x100, end: 0x104, file: "/src/main.s".to_string(), line: 1 });
+        assert_eq!(lines.ranges()[3], LineRange { address: 0x10C, end: 0x110, file: "/src/main.s".to_string(), line: 5 });
+        assert!(LineTable::from_dwarf(&HashMap::new()).err().unwrap().contains("-g"));
+    }
+
+    #[test]
+    fn test_branches() {
+        const BEQ: u32 = 0x00050463; // beq a0, zero, 8
+        let mut coverage = Coverage::new();
+        coverage.record(0x100, BEQ, 0x104);
+        coverage.record(0x100, BEQ, 0x108);
+        coverage.record(0x100, BEQ, 0x108);
+        coverage.record(0x104, BEQ, 0x108); // Falls through
+        assert_eq!(coverage.branch(0x100), (2, 1));
+        assert_eq!(coverage.branch(0x104), (0, 1));
+        assert_eq!(coverage.count(0x100), 3);
+
+        let lines = line_table(0x100, &[1, 2, 3]);
+        let text = lcov(&coverage, &lines, |pc| Some(if pc < 0x10C { BEQ } else { 0x13 }));
+        assert_eq!(text, "TN:\nSF:/src/main.s\n\
+            BRDA:1,0,0,2\nBRDA:1,0,1,1\nBRDA:2,0,0,0\nBRDA:2,0,1,1\nBRDA:3,0,0,-\nBRDA:3,0,1,-\nBRF:6\nBRH:3\n\
+            DA:1,3\nDA:2,1\nDA:3,0\nLF:3\nLH:2\nend_of_record\n");
+    }
+
+    #[test]
+    fn test_elf() {
+        let program = assemble(SOURCE, 0x4).unwrap();
+        let image = build_elf_with_sections(0x4, 0x4, &program.image, &[], &dwarf(0x4, &[1, 2, 3, 5]));
+        let elf = Elf::parse(&image).unwrap();
+        let lines = LineTable::from_dwarf(&elf.debug_sections).unwrap();
+
+        let mut cpu = CPU::new();
+        cpu.load_elf(&elf);
+        cpu.set_pc(elf.entry);
+        let mut machine = Machine::new(cpu);
+        machine.set_coverage(Some(Coverage::new()));
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        let memory = &machine.cpu.memory;
+        let text = lcov(machine.coverage().unwrap(), &lines, |pc| Some(memory.peek_u32(pc)));
+        // The ebreak that halts doesn't retire
+        assert_eq!(text, "TN:\nSF:/src/main.s\nBRDA:2,0,0,0\nBRDA:2,0,1,1\nBRF:2\nBRH:1\n\
+            DA:1,1\nDA:2,1\nDA:3,1\nDA:5,0\nLF:4\nLH:3\nend_of_record\n");
+    }
+}
@@ -1,56 +1,406 @@
 // RISC-V Tiny VM - Ivi Ballou / Amechania
 
-mod register;
-mod opcodes;
-mod memory;
-mod instruction;
+pub mod register;
+pub mod opcodes;
+pub mod decode;
+pub mod memory;
+pub mod instruction;
+pub mod semihosting;
+pub mod htif;
+pub mod replay;
+pub mod state;
+pub mod syscon;
+pub mod uart;
+#[cfg(feature = "jit")]
+pub mod jit;
 
+use std::fmt;
+use std::sync::Arc;
 use crate::cpu::register::*;
+use crate::cpu::decode::{Block, Decoded, Op, BLOCK_LIMIT};
 use crate::cpu::memory::Memory;
+use crate::cpu::memory::mmu::Page;
+use crate::cpu::semihosting::Semihosting;
+use crate::cpu::htif::Htif;
+use crate::cpu::replay::Inputs;
+use crate::cpu::opcodes::OP_STORE;
+use crate::cpu::memory::bus::Device;
+use crate::cpu::syscon::Syscon;
+use crate::cpu::uart::Uart;
+use crate::elf::Elf;
 const MEMSIZE_MB: usize = 2;
-const MEMSIZE: usize = MEMSIZE_MB*1024*1024; // 2MB
+pub const MEMSIZE: usize = MEMSIZE_MB*1024*1024; // 2MB
+pub const PAGE_OFFSET_BITS: usize = 8;
+
+// Why the CPU stopped, when it wasn't the program's own doing
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trap {
+    IllegalInstruction(u32), // The instruction word
+    FetchFault(u32), // pc points outside of memory
+    Fault(u32), // The instruction at this pc accessed memory that doesn't exist
+    ReplayDiverged(u64), // A replayed run asked for an input the recording doesn't have at this instruction count
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::IllegalInstruction(instruction) => write!(f, "Illegal instruction 0x{:08x}", instruction),
+            Trap::FetchFault(address) => write!(f, "Instruction fetch outside of memory at 0x{:08x}", address),
+            Trap::Fault(pc) => write!(f, "Memory access outside of memory at pc 0x{:08x}", pc),
+            Trap::ReplayDiverged(instret) => write!(f, "Replay diverged from the recording at instruction {}", instret),
+        }
+    }
+}
+
+// The CPU at an earlier point of the same run, for going back to it. Device state like
+// open semihosting files isn't part of it, the host side can't be rewound. The exit
+// statuses semihosting and HTIF keep are, or going back past an exit would exit again.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub instret: u64,
+    pc: u32,
+    registers: [u32; 32],
+    pages: Vec<Arc<Page>>,
+    exit_code: Option<u32>,
+    semihosting_exit_code: Option<u32>,
+    htif_exit_code: Option<u32>,
+}
+
+// Memory mapped devices by the kind machine configurations and state files call them
+pub fn new_device(kind: &str) -> Option<Box<dyn Device>> {
+    match kind {
+        "uart" => Some(Box::new(Uart::new())),
+        "syscon" => Some(Box::new(Syscon::new())),
+        _ => None,
+    }
+}
+
+// Extensions on top of RV32I the CPU executes. Everything else is an illegal instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Isa {
+    pub m: bool,
+}
+
+impl Isa {
+    pub const RV32IM: Isa = Isa { m: true };
+
+    // ISA strings the way compilers take them, "rv32im" or "rv32i"
+    pub fn parse(text: &str) -> Result<Isa, String> {
+        let lower = text.to_ascii_lowercase();
+        let Some(extensions) = lower.strip_prefix("rv32i") else {
+            return Err(format!("Unsupported ISA '{}', the VM runs rv32i with optional m", text));
+        };
+        let mut isa = Isa { m: false };
+        for extension in extensions.chars() {
+            match extension {
+                'm' if !isa.m => isa.m = true,
+                _ => return Err(format!("Unsupported extension '{}' in ISA '{}', the VM runs rv32i with optional m", extension, text)),
+            }
+        }
+        Ok(isa)
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv32i{}", if self.m { "m" } else { "" })
+    }
+}
 
 pub struct CPU {
     pc: u32,
-    pub(crate) registers: Register,
-    pub(crate) memory: Memory,
+    pub registers: Register,
+    pub memory: Memory,
     instruction: u32,
     opcode: u8,
+    semihosting: Option<Semihosting>, // ebreak just halts the CPU unless this is set
+    htif: Option<Htif>,
+    isa: Isa,
+    pub inputs: Inputs, // Console input and clocks for the devices, possibly recorded or replayed
+    instret: u64,
+    exit_code: Option<u32>,
+    trap: Option<Trap>,
+    decode_cache: bool, // Reuse decoded instructions from main RAM, off only to compare against
+    #[cfg(feature = "jit")]
+    jit: jit::Jit, // Native code for hot blocks, see jit.rs
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[allow(dead_code)]
 impl CPU {
     pub fn new() -> Self {
+        Self::with_memory(Memory::new(MEMSIZE, PAGE_OFFSET_BITS))
+    }
+
+    // Used for images that are linked somewhere other than address 0
+    pub fn with_ram_base(base: u32) -> Self {
+        Self::with_ram(base, MEMSIZE)
+    }
+
+    // size bytes of memory starting at base, a whole number of pages
+    pub fn with_ram(base: u32, size: usize) -> Self {
+        Self::with_memory(Memory::with_base(base, size, PAGE_OFFSET_BITS))
+    }
+
+    pub fn with_memory(memory: Memory) -> Self {
         Self {
         pc: 4,
             registers: Register::new(),
-            memory: Memory::new(MEMSIZE, 8),
+            memory,
             instruction: 0,
             opcode: 0,
+            semihosting: None,
+            htif: None,
+            isa: Isa::RV32IM,
+            inputs: Inputs::live(),
+            instret: 0,
+            exit_code: None,
+            trap: None,
+            decode_cache: true,
+            #[cfg(feature = "jit")]
+            jit: jit::Jit::default(),
         }
     }
+
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    // Exit status reported by the guest, if it reported one before halting
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
     
-    fn get_pc(&self) -> u32 {
+    // Set when the last step stopped on something the CPU couldn't execute
+    pub fn trap(&self) -> Option<&Trap> {
+        self.trap.as_ref()
+    }
+
+    // Number of instructions retired, the clock replayed inputs are lined up against
+    pub fn instret(&self) -> u64 {
+        self.instret
+    }
+
+    // Devices that are hooked up, for describing the machine
+    pub fn devices(&self) -> Vec<&'static str> {
+        let mut devices = Vec::new();
+        if self.htif.is_some() {
+            devices.push("htif");
+        }
+        if self.semihosting.is_some() {
+            devices.push("semihosting");
+        }
+        devices.extend(self.memory.bus.devices.iter().map(|device| device.kind()));
+        devices
+    }
+
+    pub fn isa(&self) -> Isa {
+        self.isa
+    }
+
+    // Instructions decoded for the old ISA might not be legal anymore
+    pub fn set_isa(&mut self, isa: Isa) {
+        self.isa = isa;
+        self.memory.flush_blocks();
+    }
+
+    pub fn set_decode_cache(&mut self, enable: bool) {
+        self.decode_cache = enable;
+        self.memory.flush_blocks();
+    }
+
+    pub fn set_inputs(&mut self, inputs: Inputs) {
+        self.inputs = inputs;
+    }
+
+    // Pages that didn't change since previous are shared with it
+    pub fn snapshot(&mut self, previous: Option<&Snapshot>) -> Snapshot {
+        Snapshot {
+            instret: self.instret,
+            pc: self.pc,
+            registers: self.registers.registers,
+            pages: self.memory.snapshot(previous.map(|previous| previous.pages.as_slice())),
+            exit_code: self.exit_code,
+            semihosting_exit_code: self.semihosting.as_ref().and_then(|semihosting| semihosting.exit_code()),
+            htif_exit_code: self.htif.as_ref().and_then(|htif| htif.exit_code()),
+        }
+    }
+
+    // Only valid for snapshots of this run: memory keeps track of changes since the last one
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.instret = snapshot.instret;
+        self.pc = snapshot.pc;
+        self.registers.registers = snapshot.registers;
+        self.memory.restore(&snapshot.pages);
+        self.exit_code = snapshot.exit_code;
+        if let Some(semihosting) = self.semihosting.as_mut() {
+            semihosting.set_exit_code(snapshot.semihosting_exit_code);
+        }
+        if let Some(htif) = self.htif.as_mut() {
+            htif.set_exit_code(snapshot.htif_exit_code);
+        }
+        self.trap = None;
+        self.inputs.rewind(snapshot.instret);
+    }
+
+    pub fn get_pc(&self) -> u32 {
         self.pc
     }
 
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
     fn fetch_inst(&mut self) {
-        self.instruction = self.memory.get_u32(self.pc);
+        self.instruction = self.memory.peek_u32(self.pc);
         self.opcode = (self.instruction & 0x7F) as u8;
     }
 
-    pub(crate) fn load_image(&mut self, offset: u32, program: &Vec<u8>) {
+    // The block starting at pc, decoded now unless it ran before. None if pc isn't in memory.
+    fn fetch_block(&mut self) -> Option<Block> {
+        if let Some(block) = self.memory.block(self.pc) {
+            return Some(block.clone());
+        }
+        if !self.memory.contains(self.pc) {
+            return None;
+        }
+        // Outside of main RAM the block is just this instruction, and it isn't kept
+        let room = self.memory.block_room(self.pc).min(BLOCK_LIMIT);
+        let mut block = vec![Decoded::new(self.memory.peek_u32(self.pc), self.isa)];
+        while block.len() < room && !block[block.len() - 1].op.ends_block() {
+            let address = self.pc + 4 * block.len() as u32;
+            block.push(Decoded::new(self.memory.peek_u32(address), self.isa));
+        }
+        let block: Block = block.into();
+        if room > 0 {
+            self.memory.cache_block(self.pc, block.clone());
+        }
+        Some(block)
+    }
+
+    pub fn enable_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    pub fn load_image(&mut self, offset: u32, program: &Vec<u8>) {
         self.memory.load_image(offset, program);
     }
 
-    pub(crate) fn run(&mut self, start: u32) {
+    // Loads every segment of the ELF and hooks up HTIF if the program defines tohost
+    pub fn load_elf(&mut self, elf: &Elf) {
+        for segment in &elf.segments {
+            self.memory.load_image(segment.address, &segment.data);
+            let bss = segment.mem_size.saturating_sub(segment.data.len() as u32);
+            self.memory.load_image(segment.address + segment.data.len() as u32, &vec![0; bss as usize]);
+        }
+        if let Some(tohost) = elf.symbol("tohost") {
+            self.enable_htif(Htif::new(tohost, elf.symbol("fromhost")));
+        }
+    }
+
+    pub fn run(&mut self, start: u32) {
         self.pc = start;
-        loop {
-            self.fetch_inst();
-            if self.exec_inst() {
-                return;
+        while !self.step() {}
+    }
+
+    // Executes a single instruction. Returns true if the CPU halted.
+    pub fn step(&mut self) -> bool {
+        self.trap = None;
+        let decoded = match self.decode_cache {
+            true => match self.memory.block(self.pc) {
+                Some(block) => Some(block[0]),
+                None => self.fetch_block().map(|block| block[0]),
+            },
+            false => self.memory.contains(self.pc).then(|| Decoded::new(self.memory.peek_u32(self.pc), self.isa)),
+        };
+        let Some(decoded) = decoded else {
+            self.trap = Some(Trap::FetchFault(self.pc));
+            return true;
+        };
+        self.step_decoded(&decoded)
+    }
+
+    // Executes the rest of the basic block at pc, at most limit instructions, with nothing
+    // in between. Returns true if the CPU halted; instret says how many instructions retired.
+    pub fn step_block(&mut self, limit: u64) -> bool {
+        if !self.decode_cache {
+            return limit > 0 && self.step();
+        }
+        self.trap = None;
+        let start = self.pc;
+        let Some(block) = self.fetch_block() else {
+            self.trap = Some(Trap::FetchFault(self.pc));
+            return true;
+        };
+        // Native code for as much of the block as the JIT compiled, the interpreter goes on
+        // from there unless that left the block
+        #[cfg(feature = "jit")]
+        let compiled = self.run_compiled(start, &block, limit);
+        #[cfg(not(feature = "jit"))]
+        let compiled = 0;
+        if compiled > 0 && (self.pc != start.wrapping_add(4 * compiled as u32) || !self.memory.has_blocks(start)) {
+            return false;
+        }
+        for decoded in block.iter().take(limit.min(BLOCK_LIMIT as u64) as usize).skip(compiled) {
+            if self.step_decoded(decoded) {
+                return true;
+            }
+            // A store to the block's own page drops it, the rest of it might not be there anymore
+            if decoded.op == Op::Store && !self.memory.has_blocks(start) {
+                break;
             }
         }
+        false
+    }
+
+    fn step_decoded(&mut self, decoded: &Decoded) -> bool {
+        self.instruction = decoded.instruction;
+        self.opcode = decoded.opcode;
+        self.inputs.instret = self.instret;
+        self.memory.bus.silent = self.inputs.silent;
+        let halted = self.execute(decoded);
+        if let Some(instret) = self.inputs.diverged() {
+            self.trap = Some(Trap::ReplayDiverged(instret));
+            return true;
+        }
+        if halted {
+            return true;
+        }
+        self.instret += 1;
+        let exited = self.opcode == OP_STORE && (self.poll_htif() || self.poll_devices());
+        if let Some(instret) = self.inputs.diverged() {
+            self.trap = Some(Trap::ReplayDiverged(instret));
+            return true;
+        }
+        exited
+    }
+
+    // Hands the store that just retired to HTIF. Returns true if the guest asked to exit.
+    fn poll_htif(&mut self) -> bool {
+        let address = self.store_address();
+        if let Some(htif) = self.htif.as_mut() {
+            if htif.store(address, &mut self.memory, &mut self.inputs) {
+                self.exit_code = htif.exit_code();
+                return true;
+            }
+        }
+        false
+    }
+
+    // Devices like the test finisher end the run on a store too
+    fn poll_devices(&mut self) -> bool {
+        match self.memory.bus.take_exit_code() {
+            Some(exit_code) => {
+                self.exit_code = Some(exit_code);
+                true
+            }
+            None => false,
+        }
     }
 }
 
@@ -60,6 +410,8 @@ impl CPU {
 mod tests {
     use crate::cpu::*;
     use crate::cpu::opcodes::*;
+    use crate::cpu::instruction::builder::InstructionBuilder;
+    use crate::cpu::semihosting::{SEMIHOSTING_POST, SEMIHOSTING_PRE};
 
     #[test]
     fn test_fetch() {
@@ -75,5 +427,138 @@ mod tests {
         assert_eq!(cpu.instruction, instruction);
         assert_eq!(cpu.opcode, OP_JAL);
     }
-}
 
+    #[test]
+    fn test_htif_exit() {
+        let mut cpu = CPU::new();
+        cpu.enable_htif(Htif::new(0x100, None));
+        cpu.registers.set_register(REG_S1, 3 << 1 | 1);
+        cpu.memory.set_u32(0x10, InstructionBuilder.store(0x100, F3_SW, REG_S1, REG_ZERO));
+        cpu.memory.set_u32(0x14, InstructionBuilder.store(0x104, F3_SW, REG_ZERO, REG_ZERO));
+        cpu.memory.set_u32(0x18, InstructionBuilder.alui(1, F3_ADDI, REG_ZERO, REG_S0));
+        cpu.run(0x10);
+
+        assert_eq!(cpu.exit_code(), Some(3));
+        assert_eq!(cpu.pc, 0x18, "CPU should stop right after the store to tohost!");
+        assert_eq!(cpu.registers.get_register(REG_S0), 0);
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let code = [InstructionBuilder.alui(1, F3_ADDI, REG_ZERO, REG_S0).to_le_bytes(), [0; 4]].concat();
+        let image = crate::elf::tests::build_elf(0x80000000, 0x80000000, &code, &[("tohost", 0x80000100)]);
+        let elf = Elf::parse(&image).unwrap();
+        let mut cpu = CPU::with_ram_base(elf.lowest_address());
+        cpu.load_elf(&elf);
+        cpu.run(elf.entry);
+
+        assert_eq!(cpu.registers.get_register(REG_S0), 1);
+        assert!(cpu.htif.is_some());
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // Runs 0x4 once, stores a different instruction over it and runs it again
+        let mut cpu = CPU::new();
+        cpu.registers.set_register(REG_T0, InstructionBuilder.alui(2, F3_ADDI, REG_ZERO, REG_A0));
+        cpu.memory.set_u32(0x4, InstructionBuilder.alui(1, F3_ADDI, REG_ZERO, REG_A0));
+        cpu.memory.set_u32(0x8, InstructionBuilder.branch(0x10, F3_BNE, REG_ZERO, REG_S1));
+        cpu.memory.set_u32(0xC, InstructionBuilder.store(0x4, F3_SW, REG_T0, REG_ZERO));
+        cpu.memory.set_u32(0x10, InstructionBuilder.alui(1, F3_ADDI, REG_ZERO, REG_S1));
+        cpu.memory.set_u32(0x14, InstructionBuilder.jal(-0x10i32 as u32, REG_ZERO));
+        cpu.run(0x4);
+        assert_eq!(cpu.registers.get_register(REG_A0), 2, "The stale decoded instruction ran");
+        assert_eq!(cpu.pc, 0x18);
+
+        let program = crate::asm::assemble("fence\nfence.i\nli a0, 3\nebreak", 0x4).unwrap();
+        cpu.load_image(program.base, &program.image);
+        cpu.run(program.entry);
+        assert_eq!((cpu.trap(), cpu.registers.get_register(REG_A0)), (None, 3));
+    }
+
+    #[test]
+    fn test_step_block() {
+        let program = crate::asm::assemble("li a0, 1\nli a1, 2\nbeq zero, zero, skip\nli a0, 9\nskip: li a2, 3\nebreak", 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        assert!(!cpu.step_block(100));
+        assert_eq!((cpu.instret(), cpu.pc), (3, 0x14), "The block ends with the branch");
+        assert!(!cpu.step_block(1));
+        assert!(cpu.step_block(100));
+        assert_eq!((cpu.instret(), cpu.pc, cpu.registers.get_register(REG_A0)), (4, 0x18, 1));
+
+        // The store replaces the next instruction of its own block
+        let patch = crate::asm::assemble("li a0, 7", 0).unwrap().image;
+        let program = crate::asm::assemble("sw t0, 8(zero)\nli a0, 1\nebreak", 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        cpu.registers.set_register(REG_T0, u32::from_le_bytes(patch[..4].try_into().unwrap()));
+        while !cpu.step_block(100) {}
+        assert_eq!(cpu.registers.get_register(REG_A0), 7);
+    }
+
+    #[test]
+    fn test_trap() {
+        let mut cpu = CPU::new();
+        cpu.memory.set_u32(0x10, 0x0000007F);
+        cpu.run(0x10);
+        assert_eq!(cpu.trap(), Some(&Trap::IllegalInstruction(0x7F)));
+        assert_eq!(cpu.pc, 0x10, "PC should stay on the illegal instruction!");
+
+        cpu.set_pc(MEMSIZE as u32);
+        assert!(cpu.step());
+        assert_eq!(cpu.trap(), Some(&Trap::FetchFault(MEMSIZE as u32)));
+    }
+
+    // Reads SYS_CLOCK into s0 and SYS_TIME into a0 through semihosting
+    fn clock_reader(inputs: Inputs) -> CPU {
+        let call = format!(".word 0x{:08x}\nebreak\n.word 0x{:08x}\n", SEMIHOSTING_PRE, SEMIHOSTING_POST);
+        let source = format!("li a0, 0x10\n{}mv s0, a0\nli a0, 0x11\n{}ebreak", call, call);
+        let program = crate::asm::assemble(&source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.enable_semihosting(Semihosting::new(".", ""));
+        cpu.set_inputs(inputs);
+        cpu.run(program.entry);
+        cpu
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let path = std::env::temp_dir().join(format!("tiny-vm-record-replay-{}.log", std::process::id()));
+        let cpu = clock_reader(Inputs::record_to(path.to_str().unwrap()).unwrap());
+        drop(cpu); // Flushes the log
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let instrets: Vec<&str> = log.lines().skip(1).map(|line| line.split(' ').next().unwrap()).collect();
+        assert_eq!(instrets, ["2", "7"], "Both clock readings should be logged with their instruction count");
+
+        let replayed = format!("{} clock 1234\n{} clock 5678\n", instrets[0], instrets[1]);
+        let cpu = clock_reader(Inputs::replay(&replayed).unwrap());
+        assert_eq!(cpu.trap(), None);
+        assert_eq!(cpu.registers.get_register(REG_S0), 1234);
+        assert_eq!(cpu.registers.get_register(REG_A0), 5678);
+        assert_eq!(cpu.instret(), 9, "The final ebreak doesn't retire");
+
+        let cpu = clock_reader(Inputs::replay("2 clock 1234\n8 clock 5678\n").unwrap());
+        assert_eq!(cpu.trap(), Some(&Trap::ReplayDiverged(7)));
+        assert_eq!(cpu.registers.get_register(REG_S0), 1234);
+    }
+
+    #[test]
+    fn test_isa() {
+        let program = crate::asm::assemble("li a0, 6\nli a1, 7\nmul a1, a0, a1\nebreak", 0x4).unwrap();
+        for (isa, product) in [(Isa::RV32IM, Some(42)), (Isa::parse("rv32i").unwrap(), None)] {
+            let mut cpu = CPU::new();
+            cpu.set_isa(isa);
+            cpu.load_image(program.base, &program.image);
+            cpu.run(program.entry);
+            match product {
+                Some(product) => assert_eq!(cpu.registers.get_register(REG_A1), product),
+                None => assert!(matches!(cpu.trap(), Some(Trap::IllegalInstruction(_))), "{} shouldn't run mul", isa),
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Instructions taken apart once, so running the same code again skips fetching and
+// decoding it. The fields are pulled out the way the handlers in instruction.rs use
+// them, immediates included, and each instruction carries a pointer to its handler.
+//
+// Main RAM keeps basic blocks of decoded instructions, keyed by the address they start
+// at, for every page code ran from until something writes to that page or the guest
+// executes fence.i. A block is a straight run of instructions that ends with the first
+// one that can jump, branch, halt or trap, at the end of the page or after BLOCK_LIMIT
+// instructions. Loads and stores that fault end it too, by setting the trap.
+
+use std::sync::Arc;
+use crate::cpu::{Isa, CPU};
+use crate::cpu::instruction::handler;
+use crate::cpu::opcodes::*;
+
+pub const BLOCK_LIMIT: usize = 64;
+
+// Executes a decoded instruction, true if the CPU should halt
+pub type Handler = fn(&mut CPU, &Decoded) -> bool;
+
+pub type Block = Arc<[Decoded]>;
+
+// What executes the instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Lui,
+    Auipc,
+    Jal,
+    Jalr,
+    Branch,
+    Load,
+    Store,
+    Alui,
+    Alu,
+    Fence,
+    System,
+    Halt, // The zero instruction
+    Illegal,
+}
+
+impl Op {
+    // Whether a block ends with this instruction
+    pub fn ends_block(self) -> bool {
+        !matches!(self, Op::Lui | Op::Auipc | Op::Load | Op::Store | Op::Alui | Op::Alu)
+    }
+}
+
+// The lowest bits of value as a two's complement number
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    (((value << (32 - bits)) as i32) >> (32 - bits)) as u32
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decoded {
+    pub instruction: u32,
+    pub op: Op,
+    pub opcode: u8,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub funct3: u8,
+    pub funct7: u8,
+    pub imm: u32, // Already put back together for the instruction's format and sign extended
+    pub handler: Handler,
+}
+
+impl Decoded {
+    pub fn new(instruction: u32, isa: Isa) -> Self {
+        let opcode = (instruction & 0x7F) as u8;
+        let funct7 = ((instruction >> 25) & 0x7F) as u8;
+        let funct3 = ((instruction >> 12) & 0x7) as u8;
+        let imm_i = ((instruction as i32) >> 20) as u32; // Sign extended
+        let (op, imm) = match opcode {
+            // The 20-bit immediate goes in the upper bits, the lower 12 are zero
+            OP_LUI => (Op::Lui, instruction & 0xFFFFF000),
+            OP_AUIPC => (Op::Auipc, instruction & 0xFFFFF000),
+            OP_JAL => {
+                let imm_20 = ((instruction >> 31) & 0x1) << 20; // Bit 20
+                let imm_10_1 = ((instruction >> 21) & 0x3FF) << 1; // Bits 10:1
+                let imm_11 = ((instruction >> 20) & 0x1) << 11; // Bit 11
+                let imm_19_12 = ((instruction >> 12) & 0xFF) << 12; // Bits 19:12
+                (Op::Jal, sign_extend(imm_20 | imm_19_12 | imm_11 | imm_10_1, 21))
+            }
+            OP_JALR if funct3 == 0 => (Op::Jalr, imm_i),
+            OP_BRANCH => {
+                let imm_12 = ((instruction >> 31) & 0x1) << 12;
+                let imm_11 = ((instruction >> 7) & 0x1) << 11;
+                let imm_10_5 = ((instruction >> 25) & 0x3F) << 5;
+                let imm_4_1 = ((instruction >> 8) & 0xF) << 1;
+                (Op::Branch, sign_extend(imm_12 | imm_11 | imm_10_5 | imm_4_1, 13))
+            }
+            OP_LOAD => (Op::Load, imm_i),
+            OP_STORE => (Op::Store, (((instruction as i32) >> 25) << 5) as u32 | (instruction >> 7 & 0x1F)),
+            // Shifts only take a 5 bit amount, the bits above it pick the kind of shift
+            OP_ALUI if funct3 == F3_SLLI && funct7 != 0 => (Op::Illegal, 0),
+            OP_ALUI if funct3 == F3_SRLI_SRAI && funct7 != F7_SRL && funct7 != F7_SRA => (Op::Illegal, 0),
+            OP_ALUI => (Op::Alui, imm_i),
+            OP_ALU if funct7 == F7_M_EXTENSION && !isa.m => (Op::Illegal, 0),
+            OP_ALU => (Op::Alu, 0),
+            OP_FENCE if matches!(funct3, F3_FENCE | F3_FENCE_I) => (Op::Fence, 0),
+            OP_E_C => (Op::System, instruction >> 20),
+            0x0 => (Op::Halt, 0),
+            _ => (Op::Illegal, 0),
+        };
+        Self {
+            instruction,
+            op,
+            opcode,
+            rd: ((instruction >> 7) & 0x1F) as u8,
+            rs1: ((instruction >> 15) & 0x1F) as u8,
+            rs2: ((instruction >> 20) & 0x1F) as u8,
+            funct3,
+            funct7,
+            imm,
+            handler: handler(op),
+        }
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::decode::*;
+    use crate::cpu::instruction::builder::InstructionBuilder;
+    use crate::cpu::register::*;
+
+    #[test]
+    fn test_decode() {
+        let decoded = Decoded::new(InstructionBuilder.store(0x123, F3_SH, REG_S1, REG_S0), Isa::RV32IM);
+        assert_eq!((decoded.op, decoded.funct3, decoded.rs1, decoded.rs2, decoded.imm), (Op::Store, F3_SH, REG_S0, REG_S1, 0x123));
+        let decoded = Decoded::new(InstructionBuilder.branch(0x40, F3_BNE, REG_S2, REG_S1), Isa::RV32IM);
+        assert_eq!((decoded.op, decoded.imm), (Op::Branch, 0x40));
+        let decoded = Decoded::new(InstructionBuilder.branch(-8i32 as u32, F3_BNE, REG_S2, REG_S1), Isa::RV32IM);
+        assert_eq!(decoded.imm, -8i32 as u32, "Backward branch");
+        assert_eq!(Decoded::new(InstructionBuilder.jal(-0x100i32 as u32, REG_RA), Isa::RV32IM).imm, -0x100i32 as u32);
+        assert_eq!(Decoded::new(InstructionBuilder.alui(0xFFF, F3_ADDI, REG_ZERO, REG_S0), Isa::RV32IM).imm, u32::MAX);
+        assert_eq!(Decoded::new(InstructionBuilder.store(0x800, F3_SW, REG_S1, REG_S0), Isa::RV32IM).imm, 0xFFFFF800);
+        assert_eq!(Decoded::new(InstructionBuilder.alui(1 << 5 | 3, F3_SLLI, REG_S0, REG_S0), Isa::RV32IM).op, Op::Illegal);
+
+        let mul = InstructionBuilder.alu(F7_M_EXTENSION, F3_MUL, REG_S0, REG_S1, REG_S0);
+        assert_eq!(Decoded::new(mul, Isa::RV32IM).op, Op::Alu);
+        assert_eq!(Decoded::new(mul, Isa::parse("rv32i").unwrap()).op, Op::Illegal);
+        assert_eq!(Decoded::new(0x0000100F, Isa::RV32IM).op, Op::Fence, "fence.i");
+        assert_eq!(Decoded::new(0, Isa::RV32IM).op, Op::Halt);
+    }
+}
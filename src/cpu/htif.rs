@@ -0,0 +1,303 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// HTIF, the host-target interface used by spike and the riscv-tests.
+// The guest writes a 64-bit command to the `tohost` symbol:
+//     bits 63:56 device, bits 55:48 command, bits 47:0 payload
+// Device 0 is the syscall proxy. If bit 0 of the payload is set the guest exits
+// with status payload >> 1, otherwise the payload points to eight u64 holding
+// a syscall number and its arguments.
+// Device 1 is the console: command 1 writes a character, command 0 reads one.
+// The host answers by clearing `tohost` and writing a response to `fromhost`.
+
+use std::io::Write;
+use crate::cpu::memory::{Fault, Memory};
+use crate::cpu::replay::Inputs;
+use crate::cpu::state::{StateReader, StateWriter};
+
+const DEVICE_SYSCALL: u8 = 0;
+const DEVICE_CONSOLE: u8 = 1;
+
+const CONSOLE_GETCHAR: u8 = 0;
+const CONSOLE_PUTCHAR: u8 = 1;
+
+// Syscall numbers follow riscv-pk / Linux
+const SYS_CLOSE: u64 = 57;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+
+const ENOSYS: i64 = 38;
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+
+pub struct Htif {
+    tohost: u32,
+    fromhost: Option<u32>, // Programs that never read input don't define it
+    exit_code: Option<u32>,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            exit_code: None,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    pub fn set_exit_code(&mut self, exit_code: Option<u32>) {
+        self.exit_code = exit_code;
+    }
+
+    pub fn save(&self, out: &mut StateWriter) {
+        out.put_u32(self.tohost);
+        out.put_option(self.fromhost);
+        out.put_option(self.exit_code);
+    }
+
+    pub fn load(input: &mut StateReader) -> Result<Self, String> {
+        Ok(Self {
+            tohost: input.get_u32()?,
+            fromhost: input.get_option()?,
+            exit_code: input.get_option()?,
+        })
+    }
+
+    // Whether store has to see a store to address. Compiled code leaves those to the interpreter.
+    pub fn intercepts(&self, address: u32) -> bool {
+        self.tohost.checked_add(4).is_none_or(|high| high == address)
+    }
+
+    // Called for every retired store. RV32 guests write tohost as two words, low word first,
+    // so a command is only picked up once its high word was written.
+    // Returns true if the guest asked to exit.
+    pub fn store(&mut self, address: u32, memory: &mut Memory, inputs: &mut Inputs) -> bool {
+        if self.tohost.checked_add(4) != Some(address) {
+            return false;
+        }
+        // A tohost that can't be read back and cleared isn't a command
+        let Ok(command) = read_u64(memory, self.tohost) else {
+            return false;
+        };
+        if command == 0 || write_u64(memory, self.tohost, 0).is_err() {
+            return false;
+        }
+
+        let device = (command >> 56) as u8;
+        let cmd = (command >> 48) as u8;
+        let payload = command & 0xFFFF_FFFF_FFFF;
+
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) => {
+                if payload & 1 == 1 {
+                    self.exit_code = Some((payload >> 1) as u32);
+                    return true;
+                }
+                self.syscall(payload as u32, memory, inputs);
+                self.respond(memory, device, cmd, 1);
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                if !inputs.silent {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&[payload as u8]).and_then(|_| stdout.flush());
+                }
+                self.respond(memory, device, cmd, 0);
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => {
+                let mut byte = [0u8];
+                let value = match inputs.read(&mut byte) {
+                    Ok(1) => byte[0] as u64,
+                    _ => 0xFFFF_FFFF_FFFF, // -1 truncated to the payload
+                };
+                self.respond(memory, device, cmd, value);
+            }
+            _ => {}
+        }
+        self.exit_code.is_some()
+    }
+
+    fn respond(&self, memory: &mut Memory, device: u8, cmd: u8, payload: u64) {
+        // Without a fromhost in memory the guest just doesn't get an answer
+        if let Some(fromhost) = self.fromhost {
+            let _ = write_u64(memory, fromhost, (device as u64) << 56 | (cmd as u64) << 48 | payload);
+        }
+    }
+
+    // magic_mem[0] holds the syscall number, the arguments follow. The result replaces the number.
+    // Pointers to memory that isn't there get -EFAULT, the store to tohost already retired.
+    fn syscall(&mut self, magic_mem: u32, memory: &mut Memory, inputs: &mut Inputs) {
+        let result = self.run_syscall(magic_mem, memory, inputs).unwrap_or(-EFAULT);
+        let _ = write_u64(memory, magic_mem, result as u64);
+    }
+
+    fn run_syscall(&mut self, magic_mem: u32, memory: &mut Memory, inputs: &mut Inputs) -> Result<i64, Fault> {
+        let number = read_u64(memory, magic_mem)?;
+        let args = (1..4).map(|i| read_u64(memory, magic_mem.wrapping_add(i * 8))).collect::<Result<Vec<u64>, Fault>>()?;
+
+        Ok(match number {
+            SYS_WRITE => {
+                let bytes = (0..args[2] as u32).map(|i| memory.load((args[1] as u32).wrapping_add(i), 1).map(|byte| byte as u8)).collect::<Result<Vec<u8>, Fault>>()?;
+                let result = match args[0] {
+                    1 | 2 if inputs.silent => Ok(()), // Already shown the first time around
+                    1 => std::io::stdout().write_all(&bytes).and_then(|_| std::io::stdout().flush()),
+                    2 => std::io::stderr().write_all(&bytes),
+                    _ => Err(std::io::ErrorKind::InvalidInput.into()),
+                };
+                match result {
+                    Ok(_) => bytes.len() as i64,
+                    Err(_) => -EBADF,
+                }
+            }
+            SYS_READ if args[0] == 0 => {
+                // No more than fits in the buffer's region
+                let room = memory.room(args[1] as u32).min(args[2]);
+                if room == 0 && args[2] > 0 {
+                    return Err(Fault(args[1] as u32));
+                }
+                let mut buffer = vec![0u8; room as usize];
+                match inputs.read(&mut buffer) {
+                    Ok(read) => {
+                        for (i, byte) in buffer[..read].iter().enumerate() {
+                            memory.store((args[1] as u32).wrapping_add(i as u32), 1, *byte as u32)?;
+                        }
+                        read as i64
+                    }
+                    Err(_) => -EBADF,
+                }
+            }
+            SYS_READ => -EBADF,
+            SYS_CLOSE => 0,
+            SYS_EXIT => {
+                self.exit_code = Some(args[0] as u32);
+                0
+            }
+            _ => -ENOSYS,
+        })
+    }
+}
+
+fn read_u64(memory: &Memory, address: u32) -> Result<u64, Fault> {
+    Ok((memory.load(address.wrapping_add(4), 4)? as u64) << 32 | memory.load(address, 4)? as u64)
+}
+
+fn write_u64(memory: &mut Memory, address: u32, value: u64) -> Result<(), Fault> {
+    memory.store(address, 4, value as u32)?;
+    memory.store(address.wrapping_add(4), 4, (value >> 32) as u32)
+}
+
+///// TESTS /////
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use crate::cpu::htif::*;
+
+    const TOHOST: u32 = 0x100;
+    const FROMHOST: u32 = 0x140;
+
+    fn write_tohost(htif: &mut Htif, memory: &mut Memory, value: u64) -> bool {
+        // Same order the riscv-tests use: low word, then high word
+        memory.set_u32(TOHOST, value as u32);
+        assert!(!htif.store(TOHOST, memory, &mut Inputs::live()));
+        memory.set_u32(TOHOST + 4, (value >> 32) as u32);
+        htif.store(TOHOST + 4, memory, &mut Inputs::live())
+    }
+
+    #[test]
+    fn test_exit_pass() {
+        let mut memory = Memory::new(1024, 8);
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST));
+        assert!(write_tohost(&mut htif, &mut memory, 1));
+        assert_eq!(htif.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn test_exit_fail() {
+        let mut memory = Memory::new(1024, 8);
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST));
+        // riscv-tests report the failing test number as (TESTNUM << 1) | 1
+        assert!(write_tohost(&mut htif, &mut memory, 7 << 1 | 1));
+        assert_eq!(htif.exit_code(), Some(7));
+    }
+
+    #[test]
+    fn test_syscall_proxy() {
+        let mut memory = Memory::new(1024, 8);
+        let mut htif = Htif::new(TOHOST, Some(FROMHOST));
+        write_u64(&mut memory, 0x200, SYS_WRITE).unwrap();
+        write_u64(&mut memory, 0x208, 1).unwrap();
+        write_u64(&mut memory, 0x210, 0x300).unwrap();
+        write_u64(&mut memory, 0x218, 3).unwrap();
+        memory.set_u32(0x300, 0x0A6B6F); // "ok\n"
+
+        assert!(!write_tohost(&mut htif, &mut memory, 0x200));
+        assert_eq!(read_u64(&memory, 0x200).unwrap(), 3, "write should return the number of bytes written");
+        assert_eq!(read_u64(&memory, TOHOST).unwrap(), 0, "tohost should be cleared");
+        assert_eq!(read_u64(&memory, FROMHOST).unwrap(), 1, "fromhost should be acknowledged");
+
+        write_u64(&mut memory, 0x200, SYS_EXIT).unwrap();
+        write_u64(&mut memory, 0x208, 5).unwrap();
+        assert!(write_tohost(&mut htif, &mut memory, 0x200));
+        assert_eq!(htif.exit_code(), Some(5));
+    }
+
+    #[test]
+    fn test_unknown_syscall() {
+        let mut memory = Memory::new(1024, 8);
+        let mut htif = Htif::new(TOHOST, None);
+        write_u64(&mut memory, 0x200, 1234).unwrap();
+        assert!(!write_tohost(&mut htif, &mut memory, 0x200));
+        assert_eq!(read_u64(&memory, 0x200).unwrap() as i64, -ENOSYS);
+
+        // Writing a buffer that isn't there
+        write_u64(&mut memory, 0x200, SYS_WRITE).unwrap();
+        write_u64(&mut memory, 0x208, 1).unwrap();
+        write_u64(&mut memory, 0x210, 0x10000).unwrap();
+        write_u64(&mut memory, 0x218, 3).unwrap();
+        assert!(!write_tohost(&mut htif, &mut memory, 0x200));
+        assert_eq!(read_u64(&memory, 0x200).unwrap() as i64, -EFAULT);
+    }
+
+    #[test]
+    fn test_addresses_wrap() {
+        let mut memory = Memory::with_base(0xffff_f000, 4096, 8);
+        // A tohost without room for its high word never sees a command
+        let mut htif = Htif::new(0xffff_fffc, None);
+        memory.set_u32(0xffff_fffc, 1);
+        assert!(!htif.store(0xffff_fffc, &mut memory, &mut Inputs::live()));
+        assert!(!htif.store(0, &mut memory, &mut Inputs::live()));
+
+        // The arguments of a syscall at the top of memory lie at 0, which isn't there
+        let mut htif = Htif::new(0xffff_f000, None);
+        write_u64(&mut memory, 0xffff_fff8, SYS_WRITE).unwrap();
+        write_u64(&mut memory, 0xffff_f000, 0xffff_fff8).unwrap();
+        assert!(!htif.store(0xffff_f004, &mut memory, &mut Inputs::live()));
+        assert_eq!(read_u64(&memory, 0xffff_fff8).unwrap() as i64, -EFAULT);
+    }
+
+    #[test]
+    fn test_read_capped() {
+        let mut memory = Memory::new(1024, 8);
+        let mut htif = Htif::new(TOHOST, None);
+        // A read into the last 2 bytes of memory that asks for far more
+        write_u64(&mut memory, 0x200, SYS_READ).unwrap();
+        write_u64(&mut memory, 0x208, 0).unwrap();
+        write_u64(&mut memory, 0x210, 0x3fe).unwrap();
+        write_u64(&mut memory, 0x218, u64::MAX).unwrap();
+        let mut inputs = Inputs::replay("0 input 6869").unwrap();
+        memory.set_u32(TOHOST, 0x200);
+        memory.set_u32(TOHOST + 4, 0);
+        assert!(!htif.store(TOHOST + 4, &mut memory, &mut inputs));
+        assert_eq!(read_u64(&memory, 0x200).unwrap(), 2);
+        assert_eq!(memory.get_u16(0x3fe), u16::from_le_bytes(*b"hi"));
+
+        // A buffer that isn't there
+        write_u64(&mut memory, 0x200, SYS_READ).unwrap();
+        write_u64(&mut memory, 0x210, 0x10000).unwrap();
+        assert!(!write_tohost(&mut htif, &mut memory, 0x200));
+        assert_eq!(read_u64(&memory, 0x200).unwrap() as i64, -EFAULT);
+    }
+}
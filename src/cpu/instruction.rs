@@ -1,200 +1,141 @@
-mod builder;
+pub mod builder;
+#[cfg(test)]
 mod tests;
 
 use std::num::Wrapping;
 use crate::cpu::*;
+use crate::cpu::decode::{Decoded, Handler, Op};
 use crate::cpu::opcodes::*;
 use crate::cpu::register::*;
+use crate::cpu::semihosting::{SEMIHOSTING_POST, SEMIHOSTING_PRE};
 
 #[allow(dead_code)]
 impl CPU {
 
-    fn inst_lui(&mut self) {
-        let rd = ((self.instruction >> 7) & 0x1F) as u8;
+    fn inst_lui(&mut self, decoded: &Decoded) {
         // LUI is a special case, it's an immediate, not an offset
         // The LUI instruction stores the 20-bit immediate
         // in the 20 most significant bits of the destination register.
         // The 12 least significant bits are set to zero.
-        let imm = self.instruction & 0xFFFFF000;
-        self.registers.set_register(rd, imm);
-        self.pc += 4;
+        self.registers.set_register(decoded.rd, decoded.imm);
+        self.pc = self.pc.wrapping_add(4);
     }
-    
-    fn inst_jal(&mut self) {
-        let rd = ((self.instruction >> 7) as u8) & 0x1F;
 
-        // Immediate is split into parts, reconstruct it correctly
-        let imm_20 = ((self.instruction >> 31) & 0x1) << 20; // Bit 20
-        let imm_10_1 = ((self.instruction >> 21) & 0x3FF) << 1; // Bits 10:1
-        let imm_11 = ((self.instruction >> 20) & 0x1) << 11; // Bit 11
-        let imm_19_12 = ((self.instruction >> 12) & 0xFF) << 12; // Bits 19:12
-
-        // Combine and sign-extend the immediate
-        let imm = imm_20 | imm_19_12 | imm_11 | imm_10_1;
-
-        self.registers.set_register(rd, self.pc + 4);
+    fn inst_auipc(&mut self, decoded: &Decoded) {
+        // Same immediate as LUI, added to the address of the instruction
+        self.registers.set_register(decoded.rd, self.pc.wrapping_add(decoded.imm));
+        self.pc = self.pc.wrapping_add(4);
+    }
 
-        self.pc = imm;
+    fn inst_jal(&mut self, decoded: &Decoded) {
+        self.registers.set_register(decoded.rd, self.pc.wrapping_add(4));
+        self.pc = self.pc.wrapping_add(decoded.imm);
     }
 
-    fn inst_jalr(&mut self) {
-        let rd = ((self.instruction >> 7) as u8) & 0x1F;
-        let rs1 = ((self.instruction >> 15) as u8) & 0x1F;
-        let imm = (self.instruction >> 20) & 0xFFFFF;
-        self.registers.set_register(rd, self.pc + 4);
-        self.pc = self.pc + imm + self.registers.get_register(rs1);
+    fn inst_jalr(&mut self, decoded: &Decoded) {
+        // rs1 has to be read before rd is written, they can be the same register
+        let target = self.registers.get_register(decoded.rs1).wrapping_add(decoded.imm) & !1;
+        self.registers.set_register(decoded.rd, self.pc.wrapping_add(4));
+        self.pc = target;
     }
 
-    fn inst_load(&mut self) {
-        let rd = ((self.instruction >> 7) & 0x1F) as u8; // Bits 7:2
-        let funct3 = ((self.instruction >> 12) & 0x7) as u8; // Bits 14:12
-        let rs1 = ((self.instruction >> 15) & 0x1F) as u8; // Bits 15:11
-        let imm = (self.instruction >> 20) & 0xFFF; // Bits 31:20
+    fn inst_load(&mut self, decoded: &Decoded) {
+        let Decoded { rd, rs1, imm, .. } = *decoded;
+        let address = self.registers.get_register(rs1).wrapping_add(imm);
 
-        match funct3 {
-            F3_LW => {
-                self.registers.set_register(rd, self.memory.get_u32(self.registers.get_register(rs1) + imm));
-            }
-            F3_LH => {
-                self.registers.set_register(rd, self.memory.get_u16(self.registers.get_register(rs1) + imm + 2) as u32);
-            }
-            F3_LHU => {
-                self.registers.set_register(rd, self.memory.get_u16(self.registers.get_register(rs1) + imm) as u32); // TODO: Verify
-            }
-            F3_LB => {
-                self.registers.set_register(rd, self.memory.get_u8(self.registers.get_register(rs1) + imm + 3) as u32);
-            }
+        // Signed loads sign extend, the U variants zero extend
+        let value = match decoded.funct3 {
+            F3_LW => self.memory.load(address, 4),
+            F3_LH => self.memory.load(address, 2).map(|value| value as i16 as u32),
+            F3_LHU => self.memory.load(address, 2),
+            F3_LB => self.memory.load(address, 1).map(|value| value as i8 as u32),
+            F3_LBU => self.memory.load(address, 1),
             _ => {
-                panic!("Invalid load instruction");
+                return self.illegal_instruction();
             }
-        }
-        self.pc += 4;
+        };
+        let Ok(value) = value else {
+            return self.fault();
+        };
+        self.registers.set_register(rd, value);
+        self.pc = self.pc.wrapping_add(4);
     }
 
-    // TODO: Double check endianness
-    fn inst_store(&mut self) {
-        let funct3 = (self.instruction >> 12) as u8 & 0x7;
-        let rs1 = (self.instruction >> 15) as u8 & 0x1F;
-        let rs2 = (self.instruction >> 20) as u8 & 0x1F;
-        let imm_11_5 = self.instruction >> 25 & 0x7F;
-        let imm_4_0 = self.instruction >> 7 & 0x1F;
-        let imm = imm_11_5 << 5 | imm_4_0;
+    fn inst_store(&mut self, decoded: &Decoded) {
+        let Decoded { rs1, rs2, imm, .. } = *decoded;
+        let address = self.registers.get_register(rs1).wrapping_add(imm);
+        let value = self.registers.get_register(rs2);
 
-        match funct3 {
-            F3_SW => {
-                self.memory.set_u32(self.registers.get_register(rs1) + imm, self.registers.get_register(rs2));
-            }
-            F3_SH => {
-                self.memory.set_u16(self.registers.get_register(rs1) + imm, self.registers.get_register(rs2) as u16);
-            }
-            F3_SB => {
-                self.memory.set_u8(self.registers.get_register(rs1) + imm, self.registers.get_register(rs2) as u8);
-            }
+        let stored = match decoded.funct3 {
+            F3_SW => self.memory.store(address, 4, value),
+            F3_SH => self.memory.store(address, 2, value & 0xFFFF),
+            F3_SB => self.memory.store(address, 1, value & 0xFF),
             _ => {
-                panic!("Invalid store instruction");
+                return self.illegal_instruction();
             }
+        };
+        if stored.is_err() {
+            return self.fault();
         }
-
-        self.pc += 4;
+        self.pc = self.pc.wrapping_add(4);
     }
 
-    fn inst_branch(&mut self) {
-        let rs1 = ((self.instruction >> 15) as u8) & 0x1F;
-        let rs2 = ((self.instruction >> 20) as u8) & 0x1F;
-        let funct3 = ((self.instruction >> 12) as u8) & 0x7;
-        let imm_12 = ((self.instruction >> 31) & 0x1) << 12;
-        let imm_11 = ((self.instruction >> 7) & 0x1) << 11;
-        let imm_10_5 = ((self.instruction >> 25) & 0x7F) << 5;
-        let imm_4_1 = ((self.instruction >> 8) & 0xF) << 1;
-        let imm = imm_12 | imm_11 | imm_10_5 | imm_4_1;
+    // Address the current store instruction writes to
+    pub fn store_address(&self) -> u32 {
+        let rs1 = (self.instruction >> 15) as u8 & 0x1F;
+        let imm_11_5 = ((self.instruction as i32) >> 25) as u32;
+        let imm_4_0 = self.instruction >> 7 & 0x1F;
+        self.registers.get_register(rs1).wrapping_add(imm_11_5 << 5 | imm_4_0)
+    }
 
-        let condition:bool;
+    fn inst_branch(&mut self, decoded: &Decoded) {
+        let Decoded { rs1, rs2, imm, .. } = *decoded;
+        let rs1_value = self.registers.get_register(rs1);
+        let rs2_value = self.registers.get_register(rs2);
 
-        match funct3 {
-            F3_BEQ => {
-                condition = self.registers.get_register(rs1) == self.registers.get_register(rs2);
-            }
-            F3_BNE => {
-                condition = self.registers.get_register(rs1) != self.registers.get_register(rs2);
-            }
-            F3_BLT => {
-                condition = self.registers.get_register(rs1) < self.registers.get_register(rs2);
-            }
-            F3_BGE => {
-                condition = self.registers.get_register(rs1) >= self.registers.get_register(rs2);
-            }
-            F3_BLTU => {
-                condition = self.registers.get_register(rs1) < self.registers.get_register(rs2);
-            }
-            F3_BGEU => {
-                condition = self.registers.get_register(rs1) >= self.registers.get_register(rs2);
-            }
+        let condition = match decoded.funct3 {
+            F3_BEQ => rs1_value == rs2_value,
+            F3_BNE => rs1_value != rs2_value,
+            F3_BLT => (rs1_value as i32) < (rs2_value as i32),
+            F3_BGE => (rs1_value as i32) >= (rs2_value as i32),
+            F3_BLTU => rs1_value < rs2_value,
+            F3_BGEU => rs1_value >= rs2_value,
             _ => {
-                panic!("Invalid branch instruction");
+                return self.illegal_instruction();
             }
-        }
-        if condition {
-            self.registers.set_register(REG_RA, self.pc + 4);
-            self.pc = self.pc + imm;
-        } else {
-            self.pc += 4;
-        }
+        };
+        self.pc = match condition {
+            true => self.pc.wrapping_add(imm),
+            false => self.pc.wrapping_add(4),
+        };
     }
 
-    fn inst_alui(&mut self) {
-        let rd = ((self.instruction >> 7) & 0x1F) as u8;
-        let funct3 = ((self.instruction >> 12) & 0x7) as u8;
-        let rs1 = ((self.instruction >> 15) & 0x1F) as u8;
-        let imm = (self.instruction >> 20) & 0xFFF;
+    fn inst_alui(&mut self, decoded: &Decoded) {
+        let Decoded { rd, funct3, funct7, rs1, imm, .. } = *decoded;
 
         let rs1_value = self.registers.get_register(rs1);
-        let result:u32;
-        match funct3 {
-            F3_ADDI => result = imm + rs1_value,
-            F3_SLTI => {
-                let mut imm_extended = imm as i32;
-                // We need to sign extend the immediate
-                if (imm & 0x800) != 0 {  // MSB is not set
-                    imm_extended = imm_extended | (0xFFFF_F000u32 as i32);
-                }
-                result = ((rs1_value as i32) < imm_extended) as u32;
-            }
-            F3_SLTIU => {
-                let mut imm_extended = imm;
-                // We need to sign extend the immediate
-                if (imm & 0x800) != 0 {  // MSB is not set
-                    imm_extended = imm_extended | 0xFFFF_F000;
-                }
-                result = (rs1_value < imm_extended) as u32;
-            }
-            F3_XORI => result = rs1_value ^ imm,
-            F3_ORI => result = rs1_value | imm,
-            F3_ANDI => result = rs1_value & imm,
-            F3_SLLI => result = rs1_value << imm,
-            F3_SRLI_SRAI => {
-                let slai_bit = ((self.instruction >> 30) as u8) & 0x1;
-                if slai_bit == 0 { // SLRI
-                    result = rs1_value >> imm;
-                }
-                else { // SLAI
-                    // Here, we need to rotate, instead of shifting and zero filling
-                    result = rs1_value.rotate_right(imm);
-                }
-            }
+        let shamt = imm & 0x1F;
+        // The immediate is already sign extended
+        let result = match funct3 {
+            F3_ADDI => rs1_value.wrapping_add(imm),
+            F3_SLTI => ((rs1_value as i32) < (imm as i32)) as u32,
+            F3_SLTIU => (rs1_value < imm) as u32,
+            F3_XORI => rs1_value ^ imm,
+            F3_ORI => rs1_value | imm,
+            F3_ANDI => rs1_value & imm,
+            F3_SLLI => rs1_value << shamt,
+            F3_SRLI_SRAI if funct7 == F7_SRA => ((rs1_value as i32) >> shamt) as u32,
+            F3_SRLI_SRAI => rs1_value >> shamt,
             _ => {
-                panic!("Invalid alui instruction - funct3");
+                return self.illegal_instruction();
             }
-        }
+        };
         self.registers.set_register(rd, result);
-        self.pc += 4;
+        self.pc = self.pc.wrapping_add(4);
     }
 
-    fn inst_alu(&mut self) {
-        let rd = ((self.instruction >> 7) & 0x1F) as u8;
-        let funct3 = ((self.instruction >> 12) & 0x7) as u8;
-        let rs1 = ((self.instruction >> 15) & 0x1F) as u8;
-        let rs2 = ((self.instruction >> 20) & 0x1F) as u8;
-        let funct7 = ((self.instruction >> 25) & 0x7F) as u8;
+    fn inst_alu(&mut self, decoded: &Decoded) {
+        let Decoded { rd, funct3, rs1, rs2, funct7, .. } = *decoded;
         let funct73:u16 = ((funct7 as u16) << 3) | funct3 as u16;
 
         let rs1_value = self.registers.get_register(rs1);
@@ -202,14 +143,14 @@ impl CPU {
         let result:u32;
 
         match funct73 {
-            F73_ADD => result = rs1_value + rs2_value,
+            F73_ADD => result = rs1_value.wrapping_add(rs2_value),
             F73_SUB => result = (Wrapping(rs1_value) - Wrapping(rs2_value)).0,
             F73_SLL => result = rs1_value << (rs2_value & 0x1F),
             F73_SLT => result = ( (rs1_value as i32) < (rs2_value as i32) ) as u32,
             F73_SLTU => result = ( rs1_value < rs2_value ) as u32,
             F73_XOR => result = rs1_value ^ rs2_value,
             F73_SRL => result = rs1_value >> (rs2_value & 0x1F),
-            F73_SRA => result = rs1_value.rotate_right(rs2_value & 0x1F), // Only lower 5 bits of rs2 are used
+            F73_SRA => result = ((rs1_value as i32) >> (rs2_value & 0x1F)) as u32, // Only lower 5 bits of rs2 are used
             F73_OR => result = rs1_value | rs2_value,
             F73_AND => result = rs1_value & rs2_value,
             F73_MUL => result = (rs1_value as i32).wrapping_mul(rs2_value as i32) as u32, // Rust does not like multiplication overflows
@@ -229,7 +170,8 @@ impl CPU {
                 if rs2_value == 0 {
                     result = 0xFFFFFFFF;
                 } else {
-                    result = (rs1_value as i32 / rs2_value as i32) as u32;
+                    // i32::MIN / -1 overflows, the result is i32::MIN
+                    result = (rs1_value as i32).wrapping_div(rs2_value as i32) as u32;
                 }
             },
             F73_DIVU => {
@@ -256,29 +198,103 @@ impl CPU {
                 }
             },
             _ => {
-                panic!("Invalid alu instruction - funct3");
+                return self.illegal_instruction();
             }
         }
 
         self.registers.set_register(rd, result);
-        self.pc += 4;
+        self.pc = self.pc.wrapping_add(4);
     }
 
-    pub(crate) fn exec_inst(&mut self) -> bool {
-        match self.opcode {
-            OP_LUI => self.inst_lui(),
-            OP_JAL => self.inst_jal(),
-            OP_JALR => self.inst_jalr(),
-            OP_BRANCH => self.inst_branch(),
-            OP_LOAD => self.inst_load(),
-            OP_STORE => self.inst_store(),
-            OP_ALUI => self.inst_alui(),
-            OP_ALU => self.inst_alu(),
-            //OP_FENCE => self.inst_fence(),
-            OP_E_C => return true,
-            0x0 => return true,
-            _ => panic!("Invalid opcode: 0b{:0>8b}", self.opcode),
+    // Both are no-ops for a single hart without caches, except that fence.i drops the
+    // decoded instructions. Stores already do for their page, this is for good measure.
+    fn inst_fence(&mut self, decoded: &Decoded) {
+        if decoded.funct3 == F3_FENCE_I {
+            self.memory.flush_blocks();
         }
-        false
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    // Returns true if the CPU should halt
+    fn inst_system(&mut self, decoded: &Decoded) -> bool {
+        if decoded.funct3 == F3_ECALL_EBREAK && decoded.imm == F12_EBREAK && self.is_semihosting_call() {
+            let op = self.registers.get_register(REG_A0);
+            let param = self.registers.get_register(REG_A1);
+            if let Some(semihosting) = self.semihosting.as_mut() {
+                // A parameter block or buffer nothing is mapped at faults like a load would
+                let Ok(result) = semihosting.call(op, param, &mut self.memory, &mut self.inputs) else {
+                    self.fault();
+                    return true;
+                };
+                if let Some(exit_code) = semihosting.exit_code() {
+                    self.exit_code = Some(exit_code);
+                    return true;
+                }
+                self.registers.set_register(REG_A0, result);
+                self.pc = self.pc.wrapping_add(4);
+                return false;
+            }
+        }
+        // Everything else still stops the machine
+        true
+    }
+
+    // The ebreak has to be surrounded by the semihosting entry and exit markers
+    fn is_semihosting_call(&self) -> bool {
+        self.semihosting.is_some()
+            && self.pc >= 4
+            && self.memory.peek_u32(self.pc - 4) == SEMIHOSTING_PRE
+            && self.memory.peek_u32(self.pc.wrapping_add(4)) == SEMIHOSTING_POST
+    }
+
+    // Decodes and executes the current instruction. Returns true if the CPU should halt.
+    pub fn exec_inst(&mut self) -> bool {
+        let decoded = Decoded::new(self.instruction, self.isa);
+        self.execute(&decoded)
+    }
+
+    pub fn execute(&mut self, decoded: &Decoded) -> bool {
+        (decoded.handler)(self, decoded)
+    }
+
+    // Stops the CPU instead of executing an encoding it doesn't know
+    fn illegal_instruction(&mut self) {
+        self.trap = Some(Trap::IllegalInstruction(self.instruction));
+    }
+
+    // Stops the CPU on an access nothing answered. The instruction doesn't retire, so pc
+    // and the registers stay as they were.
+    fn fault(&mut self) {
+        self.trap = Some(Trap::Fault(self.pc));
+    }
+}
+
+// The handler decoded instructions call through, so running them doesn't go through a match
+pub fn handler(op: Op) -> Handler {
+    macro_rules! handler {
+        ($inst:ident) => {
+            |cpu: &mut CPU, decoded: &Decoded| {
+                cpu.$inst(decoded);
+                cpu.trap.is_some()
+            }
+        };
+    }
+    match op {
+        Op::Lui => handler!(inst_lui),
+        Op::Auipc => handler!(inst_auipc),
+        Op::Jal => handler!(inst_jal),
+        Op::Jalr => handler!(inst_jalr),
+        Op::Branch => handler!(inst_branch),
+        Op::Load => handler!(inst_load),
+        Op::Store => handler!(inst_store),
+        Op::Alui => handler!(inst_alui),
+        Op::Alu => handler!(inst_alu),
+        Op::Fence => handler!(inst_fence),
+        Op::System => |cpu, decoded| cpu.inst_system(decoded),
+        Op::Halt => |_, _| true,
+        Op::Illegal => |cpu, _| {
+            cpu.illegal_instruction();
+            true
+        },
     }
-}
\ No newline at end of file
+}
@@ -14,25 +14,13 @@ impl InstructionBuilder {
         | OP_LUI as u32
     }
     
-    pub fn jal(&self, address: u32, rd: u8) -> u32 {
-        let imm_encoded = ((address & 0x80000) >> 20)        // Bit 20
-            | ((address & 0xFF000) >> 12)                   // Bits 19:12
-            | ((address & 0x800) << 9)                      // Bit 11
-            | ((address & 0x7FE) << 20);                    // Bits 10:1
-        imm_encoded
-        | ((rd as u32) << 7)
-        | OP_JAL as u32
+    // Both take the offset, from pc for jal and from rs1 for jalr
+    pub fn jal(&self, offset: u32, rd: u8) -> u32 {
+        self.j_type(offset, rd)
     }
 
     pub fn jalr(&self, offset: u32, rs1: u8, rd: u8) -> u32 {
-        let imm_encoded = ((offset & 0x80000) >> 20)     // Bit 20
-            | ((offset & 0xFF000) >> 12)   // Bits 19:12
-            | ((offset & 0x800) << 9)      // Bit 11
-            | ((offset & 0x7FE) << 20);    // Bits 10:1
-        imm_encoded // We don't offset imm here, as it already is offset
-        | (rs1 as u32) << 15
-        | (rd as u32) << 7
-        | OP_JALR as u32
+        self.i_type(OP_JALR, offset, 0, rs1, rd)
     }
 
     pub fn load(&self, address: u32, funct3: u8, rd: u8) -> u32 {
@@ -86,4 +74,43 @@ impl InstructionBuilder {
         | (rd as u32) << 7
         | OP_ALU as u32
     }
-}
\ No newline at end of file
+
+    pub fn system(&self, imm: u32, funct3: u8, rs1: u8, rd: u8) -> u32 {
+        (imm & 0xFFF) << 20
+        | (rs1 as u32) << 15
+        | (funct3 as u32) << 12
+        | (rd as u32) << 7
+        | OP_E_C as u32
+    }
+
+    // Generic formats, for instructions that don't have a helper above
+
+    pub fn i_type(&self, opcode: u8, imm: u32, funct3: u8, rs1: u8, rd: u8) -> u32 {
+        (imm & 0xFFF) << 20
+        | (rs1 as u32) << 15
+        | (funct3 as u32) << 12
+        | (rd as u32) << 7
+        | opcode as u32
+    }
+
+    pub fn u_type(&self, opcode: u8, imm: u32, rd: u8) -> u32 {
+        (imm & 0xFFFFF) << 12
+        | (rd as u32) << 7
+        | opcode as u32
+    }
+
+    // JAL with a PC relative offset
+    pub fn j_type(&self, offset: u32, rd: u8) -> u32 {
+        let imm_20 = (offset >> 20) & 0x1;
+        let imm_10_1 = (offset >> 1) & 0x3FF;
+        let imm_11 = (offset >> 11) & 0x1;
+        let imm_19_12 = (offset >> 12) & 0xFF;
+
+        imm_20 << 31
+        | imm_10_1 << 21
+        | imm_11 << 20
+        | imm_19_12 << 12
+        | (rd as u32) << 7
+        | OP_JAL as u32
+    }
+}
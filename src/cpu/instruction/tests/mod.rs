@@ -8,4 +8,5 @@ mod test_alui;
 mod test_alu_base;
 mod test_alu_mul;
 mod test_alu_div;
-mod test_alu_rem;
\ No newline at end of file
+mod test_alu_rem;
+mod test_system;
\ No newline at end of file
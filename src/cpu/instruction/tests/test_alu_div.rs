@@ -13,7 +13,7 @@ fn test_div() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 1;
@@ -35,7 +35,7 @@ fn test_div_non_perfect() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 1;
@@ -57,7 +57,7 @@ fn test_div_negative_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFF0;
@@ -79,7 +79,7 @@ fn test_div_negative_divisor() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFFF;
@@ -101,7 +101,7 @@ fn test_div_negative_double() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x2;
@@ -123,7 +123,7 @@ fn test_div_complex() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x4;
@@ -145,7 +145,7 @@ fn test_div_zero_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0;
@@ -167,7 +167,7 @@ fn test_div_zero_divisor() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIV, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFFF;
@@ -189,7 +189,7 @@ fn test_divu() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIVU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x1;
@@ -211,7 +211,7 @@ fn test_divu_zero_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIVU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -233,7 +233,7 @@ fn test_divu_zero_divisor() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_DIVU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFFF;
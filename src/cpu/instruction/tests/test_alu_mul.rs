@@ -14,7 +14,7 @@ fn test_mul()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MUL, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x100;
@@ -37,7 +37,7 @@ fn test_mul_double_negative()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MUL, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x2;
@@ -60,7 +60,7 @@ fn test_mul_single_negative()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MUL, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = -4i32 as u32;
@@ -83,7 +83,7 @@ fn test_mul_overflow()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MUL, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -105,7 +105,7 @@ fn test_mulh() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULH, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -128,7 +128,7 @@ fn test_mulh_double_negative()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULH, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -151,7 +151,7 @@ fn test_mulh_single_negative()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULH, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFFF;
@@ -174,7 +174,7 @@ fn test_mulh_overflow_to_higher()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULH, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x1;
@@ -197,7 +197,7 @@ fn test_mulh_overflow()
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULH, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -219,7 +219,7 @@ fn test_mulhsu() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHSU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -241,7 +241,7 @@ fn test_mulhsu_negative_small_int() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHSU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xF21F494C;
@@ -263,7 +263,7 @@ fn test_mulhsu_negative_big_int() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHSU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xD245ECB3;
@@ -285,7 +285,7 @@ fn test_mulhsu_positive_big_int() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHSU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x2DBA134C;
@@ -307,7 +307,7 @@ fn test_mulhu() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -329,7 +329,7 @@ fn test_mulhu_high_ls() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x01A2B7F0;
@@ -351,7 +351,7 @@ fn test_mulhu_ls_high() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x01A2B7F0;
@@ -373,7 +373,7 @@ fn test_mulhu_high_high() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xDE0B6B3A;
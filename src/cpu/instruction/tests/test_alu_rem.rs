@@ -13,7 +13,7 @@ fn test_rem() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -35,7 +35,7 @@ fn test_rem_zero_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -57,7 +57,7 @@ fn test_rem_zero_divisor() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x10;
@@ -79,7 +79,7 @@ fn test_rem_negative_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFFA;
@@ -101,7 +101,7 @@ fn test_rem_negative_divisor() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x1;
@@ -123,7 +123,7 @@ fn test_rem_negative_double() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0xFFFFFFF7;
@@ -145,7 +145,7 @@ fn test_rem_overflow() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REM, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -167,7 +167,7 @@ fn test_remu() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REMU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -189,7 +189,7 @@ fn test_remu_zero_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REMU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x0;
@@ -211,7 +211,7 @@ fn test_remu_zero_divisor() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REMU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x10;
@@ -233,7 +233,7 @@ fn test_remu_large_dividend() {
     cpu.instruction = InstructionBuilder.alu(F7_M_EXTENSION, F3_REMU, REG_S0, REG_S1, REG_S0);
 
     // Execute load
-    cpu.inst_alu();
+    cpu.exec_inst();
 
     // Verify results
     let expected:u32 = 0x9;
@@ -16,7 +16,7 @@ mod test_alui {
     fn test_addi() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_ADDI, 0x420, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x840;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -29,7 +29,7 @@ mod test_alui {
     fn test_slti_yes() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLTI, 0x419, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 1;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -42,7 +42,7 @@ mod test_alui {
     fn test_slti_no_eq() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLTI, 0x420, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -55,7 +55,7 @@ mod test_alui {
     fn test_slti_no_gt() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLTI, 0x421, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -68,7 +68,7 @@ mod test_alui {
     fn test_sltiu_yes() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLTIU, 0x419, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 1;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -81,7 +81,7 @@ mod test_alui {
     fn test_sltiu_no_eq() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLTIU, 0x420, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -94,7 +94,7 @@ mod test_alui {
     fn test_sltiu_no_gt() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLTIU, 0x421, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -107,7 +107,7 @@ mod test_alui {
     fn test_xori() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_XORI, 0x400, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x20;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -120,7 +120,7 @@ mod test_alui {
     fn test_ori() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_ORI, 0x400, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x420;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -133,7 +133,7 @@ mod test_alui {
     fn test_andi() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_ANDI, 0x400, REG_S0, 0x420);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x400;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -146,7 +146,7 @@ mod test_alui {
     fn test_slli() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLLI, 0x400, REG_S0, 0x1);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x800;
         assert_eq!(cpu.registers.get_register(REG_S0), 0x800,
             "\nexpected: 0x{:0>8x},\n\
@@ -159,7 +159,7 @@ mod test_alui {
     fn test_slli_overflow() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SLLI, 0x80_00_00_00, REG_S0, 0x1);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x0;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
            "\nexpected: 0x{:0>8x},\n\
@@ -172,7 +172,7 @@ mod test_alui {
     fn test_srli() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SRLI_SRAI, 0x401, REG_S0, 0x1);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x200;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
             "\nexpected: 0x{:0>8x},\n\
@@ -185,7 +185,7 @@ mod test_alui {
     fn test_srli_underflow() {
         let mut cpu = CPU::new();
         prep_alui_inst(&mut cpu, F3_SRLI_SRAI, 0x1, REG_S0, 0x1);
-        cpu.inst_alui();
+        cpu.exec_inst();
         let expected = 0x0;
         assert_eq!(cpu.registers.get_register(REG_S0), 0x0,
                "\nSRLI should NOT underflow to: 0x{:0>8x},\n\
@@ -200,22 +200,22 @@ mod test_alui {
         prep_alui_inst(&mut cpu, F3_SRLI_SRAI, 0x400, REG_S0, 0x1);
         // Set the SRAI bit (bit 30)
         cpu.instruction = cpu.instruction | (0x1 << 30);
-        cpu.inst_alui();
+        cpu.exec_inst();
         assert_eq!(cpu.registers.get_register(REG_S0), 0x200);
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
 
     #[test]
-    fn test_srai_overflow() {
+    fn test_srai_negative() {
         let mut cpu = CPU::new();
-        prep_alui_inst(&mut cpu, F3_SRLI_SRAI, 0x0000_03b1, REG_S0, 0x4);
+        prep_alui_inst(&mut cpu, F3_SRLI_SRAI, 0x8000_03b1, REG_S0, 0x4);
         // Set the SRAI bit (bit 30)
         cpu.instruction = cpu.instruction | (0x1 << 30);
-        cpu.inst_alui();
+        cpu.exec_inst();
 
-        let expected = 0x1000_003b;
+        let expected = 0xF800_003B;
         assert_eq!(cpu.registers.get_register(REG_S0), expected,
-            "\nSRAI should overflow to: 0x{:0>8x},\n\
+            "\nSRAI should sign extend to: 0x{:0>8x},\n\
             but instead returned:    0x{:0>8x}",
             expected, cpu.registers.get_register(REG_S0));
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
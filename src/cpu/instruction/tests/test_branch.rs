@@ -18,16 +18,16 @@ mod test_branch {
     fn test_beq_yes() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BEQ, 0x420, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x118, "PC was not updated correctly!");
-        assert_eq!(cpu.registers.get_register(REG_RA), 0x14);
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Branches don't link");
     }
 
     #[test]
     fn test_beq_no() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BEQ, 0x420, 0x421);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
 
@@ -35,16 +35,16 @@ mod test_branch {
     fn test_bne_yes() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BNE, 0x420, 0x421);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x118, "PC was not updated correctly!");
-        assert_eq!(cpu.registers.get_register(REG_RA), 0x14);
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Branches don't link");
     }
 
     #[test]
     fn test_bne_no() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BNE, 0x420, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
 
@@ -52,16 +52,16 @@ mod test_branch {
     fn test_blt_yes() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BLT, 0x41F, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x118, "PC was not updated correctly!");
-        assert_eq!(cpu.registers.get_register(REG_RA), 0x14);
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Branches don't link");
     }
 
     #[test]
     fn test_blt_no() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BLT, 0x420, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
 
@@ -69,25 +69,50 @@ mod test_branch {
     fn test_bge_yes_gt() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BGE, 0x422, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x118, "PC was not updated correctly!");
-        assert_eq!(cpu.registers.get_register(REG_RA), 0x14);
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Branches don't link");
     }
 
     #[test]
     fn test_bge_yes_eq() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BGE, 0x420, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x118, "PC was not updated correctly!");
-        assert_eq!(cpu.registers.get_register(REG_RA), 0x14);
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Branches don't link");
+    }
+
+    #[test]
+    fn test_blt_signed() {
+        let mut cpu = CPU::new();
+        prep_branch_inst(&mut cpu, F3_BLT, 0xFFFF_FFFF, 0x1);
+        cpu.exec_inst();
+        assert_eq!(cpu.pc, 0x118, "-1 is less than 1");
+    }
+
+    #[test]
+    fn test_bltu_unsigned() {
+        let mut cpu = CPU::new();
+        prep_branch_inst(&mut cpu, F3_BLTU, 0xFFFF_FFFF, 0x1);
+        cpu.exec_inst();
+        assert_eq!(cpu.pc, 0x14, "0xFFFFFFFF is not less than 1");
+    }
+
+    #[test]
+    fn test_branch_backward() {
+        let mut cpu = CPU::new();
+        prep_branch_inst(&mut cpu, F3_BEQ, 0x420, 0x420);
+        cpu.instruction = InstructionBuilder.branch(-0x10i32 as u32, F3_BEQ, REG_S2, REG_S1);
+        cpu.exec_inst();
+        assert_eq!(cpu.pc, 0x0, "PC was not updated correctly!");
     }
 
     #[test]
     fn test_bge_no() {
         let mut cpu = CPU::new();
         prep_branch_inst(&mut cpu, F3_BGE, 0x419, 0x420);
-        cpu.inst_branch();
+        cpu.exec_inst();
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
 }
\ No newline at end of file
@@ -15,10 +15,21 @@ mod test_jal {
         cpu.opcode = OP_JAL;
 
         // Execute JAL
-        cpu.inst_jal();
+        cpu.exec_inst();
 
         // Verify results
         assert_eq!(cpu.registers.get_register(REG_S0), 0x14); // Return address
-        assert_eq!(cpu.get_pc(), 0x8);             // New PC (0x10 + 8)
+        assert_eq!(cpu.get_pc(), 0x18);            // New PC (0x10 + 8)
+    }
+
+    #[test]
+    fn test_jal_backward() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0x10;
+        cpu.instruction = InstructionBuilder.jal(-8i32 as u32, REG_S0);
+        cpu.opcode = OP_JAL;
+        cpu.exec_inst();
+        assert_eq!(cpu.registers.get_register(REG_S0), 0x14);
+        assert_eq!(cpu.get_pc(), 0x8);             // New PC (0x10 - 8)
     }
 }
\ No newline at end of file
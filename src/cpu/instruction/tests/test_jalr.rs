@@ -14,10 +14,23 @@ mod test_jalr {
         cpu.instruction = InstructionBuilder.jalr(8,REG_S1, REG_S0);
 
         // Execute JALR
-        cpu.inst_jalr();
+        cpu.exec_inst();
 
         // Verify results
         assert_eq!(cpu.registers.get_register(REG_S0), 0x14); // Return address
-        assert_eq!(cpu.get_pc(), 0x28);             // New PC (0x10 + 8)
+        assert_eq!(cpu.get_pc(), 0x18);             // New PC (s1 + 8)
+    }
+
+    #[test]
+    fn test_jalr_same_register() {
+        let mut cpu = CPU::new();
+
+        // rs1 is read before rd is written, and the lowest bit is cleared
+        cpu.pc = 0x10;
+        cpu.registers.set_register(REG_S0, 0x41);
+        cpu.instruction = InstructionBuilder.jalr(-0x20i32 as u32, REG_S0, REG_S0);
+        cpu.exec_inst();
+        assert_eq!(cpu.registers.get_register(REG_S0), 0x14);
+        assert_eq!(cpu.get_pc(), 0x20);
     }
 }
\ No newline at end of file
@@ -15,7 +15,7 @@ mod test_load {
         cpu.instruction = InstructionBuilder.load(address, F3_LW, REG_S0);
 
         // Execute load
-        cpu.inst_load();
+        cpu.exec_inst();
 
         // Verify results
         // word at address is 0b11001100_11001100_00110011_00110011
@@ -37,13 +37,13 @@ mod test_load {
         cpu.instruction = InstructionBuilder.load(address, F3_LH, REG_S0);
 
         // Execute load
-        cpu.inst_load();
+        cpu.exec_inst();
 
         // Verify results (half word at address is 0b11001100_11001100)
-        assert_eq!(cpu.registers.get_register(REG_S0), 0xCC33
+        assert_eq!(cpu.registers.get_register(REG_S0), 0xFFFFCC33
             , "Loaded value was not correct!\
-            \nExpected: 0xCC33,\
-            \nGot:      0x{:0>4x}",
+            \nExpected: 0xFFFFCC33,\
+            \nGot:      0x{:0>8x}",
             cpu.registers.get_register(REG_S0));
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
@@ -58,14 +58,30 @@ mod test_load {
         cpu.instruction = InstructionBuilder.load(address, F3_LB, REG_S0);
 
         // Execute load
-        cpu.inst_load();
+        cpu.exec_inst();
 
-        // Verify results (byte at address is 0b11001100)
-        assert_eq!(cpu.registers.get_register(REG_S0), 0xCC
+        // Verify results (byte at address is 0b00110011)
+        assert_eq!(cpu.registers.get_register(REG_S0), 0x33
             , "Loaded value was not correct!\
             \nExpected: 0x33,\
             \nGot:      0x{:0>2x}",
             cpu.registers.get_register(REG_S0));
         assert_eq!(cpu.pc, 0x14, "PC was not updated correctly!");
     }
+
+    #[test]
+    fn test_load_byte_signed() {
+        let mut cpu = CPU::new();
+        cpu.memory.set_u32(0x50, 0xCC33CC33);
+        cpu.pc = 0x10;
+        cpu.opcode = OP_LOAD;
+
+        // Byte at 0x51 is 0b11001100, LB sign extends it and LBU doesn't
+        cpu.instruction = InstructionBuilder.load(0x51, F3_LB, REG_S0);
+        cpu.exec_inst();
+        assert_eq!(cpu.registers.get_register(REG_S0), 0xFFFFFFCC);
+        cpu.instruction = InstructionBuilder.load(0x51, F3_LBU, REG_S0);
+        cpu.exec_inst();
+        assert_eq!(cpu.registers.get_register(REG_S0), 0xCC);
+    }
 }
\ No newline at end of file
@@ -12,7 +12,7 @@ mod test_lui {
         cpu.opcode = OP_LUI;
 
         // Execute LUI
-        cpu.inst_lui();
+        cpu.exec_inst();
 
         // Verify results
         assert_eq!(cpu.registers.get_register(REG_S0), 0x420000,
@@ -18,7 +18,7 @@ mod test_store {
         cpu.instruction = InstructionBuilder.store(0x550, F3_SW, REG_S1, REG_S0);
 
         // Execute load
-        cpu.inst_store();
+        cpu.exec_inst();
 
         // Verify results
         // word at 0x55A is 0b11001100_11001100_00110011_00110011
@@ -47,7 +47,7 @@ mod test_store {
         cpu.instruction = InstructionBuilder.store(0x554, F3_SH, REG_S1, REG_S0);
 
         // Execute load
-        cpu.inst_store();
+        cpu.exec_inst();
 
         // Verify results (half word at 0x55E is 0b11001100_11001100)
         assert_eq!(cpu.memory.get_u16(0x55E), 0xCC33
@@ -71,7 +71,7 @@ mod test_store {
         cpu.instruction = InstructionBuilder.store(0x558, F3_SB, REG_S1, REG_S0);
 
         // Execute load
-        cpu.inst_store();
+        cpu.exec_inst();
 
         // Verify results (byte at 0x562 is 0b11001100)
         assert_eq!(cpu.memory.get_u8(0x562), 0x33
@@ -0,0 +1,56 @@
+use crate::cpu::CPU;
+use crate::cpu::instruction::builder::InstructionBuilder;
+use crate::cpu::opcodes::*;
+use crate::cpu::register::*;
+use crate::cpu::semihosting::*;
+
+// Places the semihosting sequence at 0x10 and a halting zero word right behind it
+fn prep_semihosting_call(cpu: &mut CPU, op: u32, param: u32) {
+    cpu.enable_semihosting(Semihosting::new(".", ""));
+    cpu.memory.set_u32(0x10, SEMIHOSTING_PRE);
+    cpu.memory.set_u32(0x14, InstructionBuilder.system(F12_EBREAK, F3_ECALL_EBREAK, 0, 0));
+    cpu.memory.set_u32(0x18, SEMIHOSTING_POST);
+    cpu.memory.set_u32(0x1C, 0);
+    cpu.registers.set_register(REG_A0, op);
+    cpu.registers.set_register(REG_A1, param);
+}
+
+#[test]
+fn test_ebreak_halts() {
+    let mut cpu = CPU::new();
+    cpu.memory.set_u32(0x10, InstructionBuilder.system(F12_EBREAK, F3_ECALL_EBREAK, 0, 0));
+    cpu.run(0x10);
+    assert_eq!(cpu.pc, 0x10, "PC should stay on the ebreak!");
+    assert_eq!(cpu.exit_code(), None);
+}
+
+#[test]
+fn test_semihosting_disabled() {
+    let mut cpu = CPU::new();
+    prep_semihosting_call(&mut cpu, SYS_TICKFREQ, 0);
+    cpu.semihosting = None;
+    cpu.run(0x10);
+    assert_eq!(cpu.pc, 0x14, "PC should stay on the ebreak!");
+    assert_eq!(cpu.registers.get_register(REG_A0), SYS_TICKFREQ);
+}
+
+#[test]
+fn test_semihosting_call() {
+    let mut cpu = CPU::new();
+    prep_semihosting_call(&mut cpu, SYS_TICKFREQ, 0);
+    cpu.run(0x10);
+    assert_eq!(cpu.registers.get_register(REG_A0), 1_000_000);
+    assert_eq!(cpu.pc, 0x1C, "Execution should continue after the sequence!");
+    assert_eq!(cpu.exit_code(), None);
+}
+
+#[test]
+fn test_semihosting_exit() {
+    let mut cpu = CPU::new();
+    prep_semihosting_call(&mut cpu, SYS_EXIT_EXTENDED, 0x100);
+    cpu.memory.set_u32(0x100, ADP_STOPPED_APPLICATION_EXIT);
+    cpu.memory.set_u32(0x104, 3);
+    cpu.run(0x10);
+    assert_eq!(cpu.exit_code(), Some(3));
+    assert_eq!(cpu.pc, 0x14, "PC should stay on the ebreak!");
+}
@@ -0,0 +1,593 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Hot basic blocks compiled to native code with Cranelift, behind the jit feature.
+//
+// A block from the decode cache gets compiled once it ran HOT_RUNS times. The compiled
+// function keeps the guest registers it touches in host registers and works on a Context
+// with a copy of the guest's; loads and stores call into main RAM directly. It covers the
+// instructions from the start of the block up to the first one it can't do, the
+// interpreter runs the rest of the block from there.
+//
+// Results are the interpreter's, so anything that goes differently leaves compiled code
+// before the instruction did anything:
+//  - loads and stores outside of main RAM, across pages or while watchpoints or the access
+//    log are on, and stores HTIF has to see
+//  - division, its corner cases stay in one place
+// A store to a page with decoded code leaves right after it, the block might be gone.
+// Compiled code belongs to the Block it was made from, so when a write drops the block
+// the next one at that address starts counting again.
+
+use std::collections::HashMap;
+use std::mem::offset_of;
+use std::sync::Arc;
+use cranelift_codegen::ir::{types, AbiParam, FuncRef, InstBuilder, MemFlags, Value};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use crate::cpu::CPU;
+use crate::cpu::decode::{Block, Decoded, Op};
+use crate::cpu::opcodes::*;
+
+// Runs before a block gets compiled
+const HOT_RUNS: u32 = 16;
+// Compiled blocks kept before all of them are thrown away, code that keeps rewriting
+// itself would grow the code memory forever otherwise
+const BLOCKS_LIMIT: usize = 1 << 16;
+
+// What compiled code works on
+#[repr(C)]
+struct Context {
+    registers: [u32; 32],
+    pc: u32,
+}
+
+// Takes the context and the CPU, returns how many instructions retired
+type Function = unsafe extern "C" fn(*mut Context, *mut CPU) -> u32;
+
+#[derive(Clone, Copy)]
+struct Code {
+    function: Function,
+    len: usize, // Instructions compiled, the most it retires
+}
+
+enum State {
+    Counting(u32),
+    Compiled(Code),
+    Interpreted, // Starts with something compiled code can't do
+}
+
+struct Entry {
+    block: Block, // Keeps the block alive, so a new one at the address is a different Arc
+    state: State,
+}
+
+pub struct Jit {
+    enabled: bool,
+    module: Option<JITModule>, // Made when the first block gets hot
+    failed: bool, // Cranelift doesn't support the host
+    blocks: HashMap<u32, Entry>,
+    compiled: usize,
+}
+
+// The module only holds code and data that compiled functions use, from whichever thread
+// runs the CPU
+unsafe impl Send for Jit {}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self { enabled: true, module: None, failed: false, blocks: HashMap::new(), compiled: 0 }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+impl Jit {
+    // Throws away all compiled code
+    fn reset(&mut self) {
+        self.blocks.clear();
+        self.compiled = 0;
+        if let Some(module) = self.module.take() {
+            // Nothing points into the module anymore
+            unsafe { module.free_memory() };
+        }
+    }
+
+    // Compiled code for the block at start, once it's hot
+    fn code(&mut self, start: u32, block: &Block) -> Option<Code> {
+        if !self.enabled || self.failed {
+            return None;
+        }
+        let entry = self.blocks.entry(start).or_insert_with(|| Entry { block: block.clone(), state: State::Counting(0) });
+        if !Arc::ptr_eq(&entry.block, block) {
+            *entry = Entry { block: block.clone(), state: State::Counting(0) };
+        }
+        match entry.state {
+            State::Compiled(code) => return Some(code),
+            State::Interpreted => return None,
+            State::Counting(runs) if runs + 1 < HOT_RUNS => {
+                entry.state = State::Counting(runs + 1);
+                return None;
+            }
+            State::Counting(_) => {}
+        }
+
+        if self.compiled >= BLOCKS_LIMIT {
+            self.reset();
+            self.blocks.insert(start, Entry { block: block.clone(), state: State::Counting(0) });
+        }
+        if self.module.is_none() {
+            match new_module() {
+                Ok(module) => self.module = Some(module),
+                Err(_) => {
+                    self.failed = true;
+                    return None;
+                }
+            }
+        }
+        let state = match compile(self.module.as_mut().unwrap(), start, block) {
+            Ok(Some(code)) => {
+                self.compiled += 1;
+                State::Compiled(code)
+            }
+            Ok(None) | Err(_) => State::Interpreted,
+        };
+        let entry = self.blocks.get_mut(&start).unwrap();
+        entry.state = state;
+        match entry.state {
+            State::Compiled(code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl CPU {
+    // Compiled code for hot blocks is on by default with the jit feature
+    pub fn set_jit(&mut self, enable: bool) {
+        self.jit.enabled = enable;
+        self.jit.reset();
+    }
+
+    // Runs the compiled code of the block at start if it has any and it doesn't retire
+    // more than limit instructions. Returns how many instructions retired.
+    pub(super) fn run_compiled(&mut self, start: u32, block: &Block, limit: u64) -> usize {
+        let Some(code) = self.jit.code(start, block) else {
+            return 0;
+        };
+        if code.len as u64 > limit {
+            return 0;
+        }
+        let mut context = Context { registers: self.registers.registers, pc: start };
+        let retired = unsafe { (code.function)(&mut context, self) } as usize;
+        self.registers.registers = context.registers;
+        self.pc = context.pc;
+        self.instret += retired as u64;
+        retired
+    }
+}
+
+// Compiled loads: the value, or above u32::MAX if the interpreter has to do it
+extern "C" fn load(cpu: *mut CPU, address: u32, size: u32) -> u64 {
+    let cpu = unsafe { &*cpu };
+    cpu.memory.load_ram(address, size).map_or(u64::MAX, |value| value as u64)
+}
+
+// Compiled stores: 0 when stored, 1 if the interpreter has to do it, 2 when stored to a
+// page with decoded code
+extern "C" fn store(cpu: *mut CPU, address: u32, size: u32, value: u32) -> u32 {
+    let cpu = unsafe { &mut *cpu };
+    if cpu.htif.as_ref().is_some_and(|htif| htif.intercepts(address)) {
+        return 1;
+    }
+    match cpu.memory.store_ram(address, size, value) {
+        Some(false) => 0,
+        None => 1,
+        Some(true) => 2,
+    }
+}
+
+fn new_module() -> Result<JITModule, String> {
+    let mut builder = JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names()).map_err(|e| e.to_string())?;
+    builder.symbol("tvm_jit_load", load as *const u8);
+    builder.symbol("tvm_jit_store", store as *const u8);
+    Ok(JITModule::new(builder))
+}
+
+// Whether compiled code does the instruction the way the interpreter would
+fn supported(decoded: &Decoded) -> bool {
+    match decoded.op {
+        Op::Lui | Op::Auipc | Op::Jal | Op::Jalr | Op::Branch | Op::Alui => true,
+        Op::Load => matches!(decoded.funct3, F3_LW | F3_LH | F3_LHU | F3_LB | F3_LBU),
+        Op::Store => matches!(decoded.funct3, F3_SW | F3_SH | F3_SB),
+        Op::Alu => matches!(funct73(decoded), F73_ADD | F73_SUB | F73_SLL | F73_SLT | F73_SLTU | F73_XOR | F73_SRL | F73_SRA
+            | F73_OR | F73_AND | F73_MUL | F73_MULH | F73_MULHSU | F73_MULHU),
+        Op::Fence | Op::System | Op::Halt | Op::Illegal => false,
+    }
+}
+
+fn funct73(decoded: &Decoded) -> u16 {
+    ((decoded.funct7 as u16) << 3) | decoded.funct3 as u16
+}
+
+// Compiles as much of the block as it can, None if that's nothing
+fn compile(module: &mut JITModule, start: u32, block: &[Decoded]) -> Result<Option<Code>, String> {
+    let len = block.iter().take_while(|decoded| supported(decoded)).count();
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let pointer = module.target_config().pointer_type();
+    let mut load_signature = module.make_signature();
+    load_signature.params.extend([AbiParam::new(pointer), AbiParam::new(types::I32), AbiParam::new(types::I32)]);
+    load_signature.returns.push(AbiParam::new(types::I64));
+    let mut store_signature = module.make_signature();
+    store_signature.params.extend([AbiParam::new(pointer), AbiParam::new(types::I32), AbiParam::new(types::I32), AbiParam::new(types::I32)]);
+    store_signature.returns.push(AbiParam::new(types::I32));
+    let load_id = module.declare_function("tvm_jit_load", Linkage::Import, &load_signature).map_err(|e| e.to_string())?;
+    let store_id = module.declare_function("tvm_jit_store", Linkage::Import, &store_signature).map_err(|e| e.to_string())?;
+
+    let mut context = module.make_context();
+    context.func.signature.params.extend([AbiParam::new(pointer), AbiParam::new(pointer)]);
+    context.func.signature.returns.push(AbiParam::new(types::I32));
+    let mut function_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut context.func, &mut function_context);
+    let load = module.declare_func_in_func(load_id, builder.func);
+    let store = module.declare_func_in_func(store_id, builder.func);
+
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    builder.seal_block(entry);
+    let params = builder.block_params(entry);
+    let (guest, cpu) = (params[0], params[1]);
+    for register in 0..32 {
+        builder.declare_var(Variable::from_u32(register as u32), types::I32);
+    }
+
+    let mut translator = Translator { builder, guest, cpu, load, store, loaded: 0, dirty: 0 };
+    for (i, decoded) in block[..len].iter().enumerate() {
+        translator.instruction(decoded, start.wrapping_add(4 * i as u32), i as u32);
+    }
+    // Fell off the end of what got compiled, unless that was a jump or branch
+    if !block[len - 1].op.ends_block() {
+        let next = translator.constant(start.wrapping_add(4 * len as u32));
+        translator.exit(next, len as u32);
+    }
+    translator.builder.finalize();
+
+    let id = module.declare_anonymous_function(&context.func.signature).map_err(|e| e.to_string())?;
+    module.define_function(id, &mut context).map_err(|e| e.to_string())?;
+    module.clear_context(&mut context);
+    module.finalize_definitions().map_err(|e| e.to_string())?;
+    let function = unsafe { std::mem::transmute::<*const u8, Function>(module.get_finalized_function(id)) };
+    Ok(Some(Code { function, len }))
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    guest: Value, // *mut Context
+    cpu: Value,
+    load: FuncRef,
+    store: FuncRef,
+    loaded: u32, // Guest registers in variables so far, a bit each
+    dirty: u32, // The ones written, those go back to the context on the way out
+}
+
+impl Translator<'_> {
+    fn constant(&mut self, value: u32) -> Value {
+        self.builder.ins().iconst(types::I32, value as i64)
+    }
+
+    fn read(&mut self, register: u8) -> Value {
+        if register == 0 {
+            return self.constant(0);
+        }
+        let variable = Variable::from_u32(register as u32);
+        if self.loaded & 1 << register == 0 {
+            let offset = offset_of!(Context, registers) + 4 * register as usize;
+            let value = self.builder.ins().load(types::I32, MemFlags::trusted(), self.guest, offset as i32);
+            self.builder.def_var(variable, value);
+            self.loaded |= 1 << register;
+        }
+        self.builder.use_var(variable)
+    }
+
+    fn write(&mut self, register: u8, value: Value) {
+        if register != 0 {
+            self.builder.def_var(Variable::from_u32(register as u32), value);
+            self.loaded |= 1 << register;
+            self.dirty |= 1 << register;
+        }
+    }
+
+    // Writes the registers back and returns
+    fn exit(&mut self, pc: Value, retired: u32) {
+        for register in (1..32).filter(|register| self.dirty & 1 << register != 0) {
+            let value = self.builder.use_var(Variable::from_u32(register as u32));
+            let offset = offset_of!(Context, registers) + 4 * register;
+            self.builder.ins().store(MemFlags::trusted(), value, self.guest, offset as i32);
+        }
+        self.builder.ins().store(MemFlags::trusted(), pc, self.guest, offset_of!(Context, pc) as i32);
+        let retired = self.constant(retired);
+        self.builder.ins().return_(&[retired]);
+    }
+
+    // Leaves for the interpreter at pc if condition holds, with retired instructions done
+    fn exit_if(&mut self, condition: Value, pc: u32, retired: u32) {
+        let (exit, next) = (self.builder.create_block(), self.builder.create_block());
+        self.builder.ins().brif(condition, exit, &[], next, &[]);
+        self.builder.switch_to_block(exit);
+        self.builder.seal_block(exit);
+        let pc = self.constant(pc);
+        self.exit(pc, retired);
+        self.builder.switch_to_block(next);
+        self.builder.seal_block(next);
+    }
+
+    fn compare(&mut self, condition: IntCC, a: Value, b: Value) -> Value {
+        let result = self.builder.ins().icmp(condition, a, b);
+        self.builder.ins().uextend(types::I32, result)
+    }
+
+    // The instruction at pc, index instructions into the block
+    fn instruction(&mut self, decoded: &Decoded, pc: u32, index: u32) {
+        let Decoded { rd, rs1, rs2, funct3, funct7, imm, .. } = *decoded;
+        let next = pc.wrapping_add(4);
+        match decoded.op {
+            Op::Lui => {
+                let value = self.constant(imm);
+                self.write(rd, value);
+            }
+            Op::Auipc => {
+                let value = self.constant(pc.wrapping_add(imm));
+                self.write(rd, value);
+            }
+            Op::Jal => {
+                let link = self.constant(next);
+                self.write(rd, link);
+                let target = self.constant(pc.wrapping_add(imm));
+                self.exit(target, index + 1);
+            }
+            Op::Jalr => {
+                // rs1 is read before rd is written
+                let base = self.read(rs1);
+                let imm = self.constant(imm);
+                let target = self.builder.ins().iadd(base, imm);
+                let mask = self.constant(!1);
+                let target = self.builder.ins().band(target, mask);
+                let link = self.constant(next);
+                self.write(rd, link);
+                self.exit(target, index + 1);
+            }
+            Op::Branch => {
+                let (a, b) = (self.read(rs1), self.read(rs2));
+                let condition = match funct3 {
+                    F3_BEQ => IntCC::Equal,
+                    F3_BNE => IntCC::NotEqual,
+                    F3_BLT => IntCC::SignedLessThan,
+                    F3_BGE => IntCC::SignedGreaterThanOrEqual,
+                    F3_BLTU => IntCC::UnsignedLessThan,
+                    _ => IntCC::UnsignedGreaterThanOrEqual,
+                };
+                let taken = self.builder.ins().icmp(condition, a, b);
+                let (taken_block, next_block) = (self.builder.create_block(), self.builder.create_block());
+                self.builder.ins().brif(taken, taken_block, &[], next_block, &[]);
+                self.builder.switch_to_block(taken_block);
+                self.builder.seal_block(taken_block);
+                let target = self.constant(pc.wrapping_add(imm));
+                self.exit(target, index + 1);
+                self.builder.switch_to_block(next_block);
+                self.builder.seal_block(next_block);
+                let next = self.constant(next);
+                self.exit(next, index + 1);
+            }
+            Op::Load => {
+                let size = match funct3 {
+                    F3_LW => 4,
+                    F3_LH | F3_LHU => 2,
+                    _ => 1, // LB, LBU
+                };
+                let base = self.read(rs1);
+                let imm = self.constant(imm);
+                let address = self.builder.ins().iadd(base, imm);
+                let size = self.constant(size);
+                let call = self.builder.ins().call(self.load, &[self.cpu, address, size]);
+                let result = self.builder.inst_results(call)[0];
+                let missed = self.builder.ins().ushr_imm(result, 32);
+                self.exit_if(missed, pc, index);
+                let value = match funct3 {
+                    F3_LH => {
+                        let half = self.builder.ins().ireduce(types::I16, result);
+                        self.builder.ins().sextend(types::I32, half)
+                    }
+                    F3_LB => {
+                        let byte = self.builder.ins().ireduce(types::I8, result);
+                        self.builder.ins().sextend(types::I32, byte)
+                    }
+                    _ => self.builder.ins().ireduce(types::I32, result),
+                };
+                self.write(rd, value);
+            }
+            Op::Store => {
+                let size = match funct3 {
+                    F3_SW => 4,
+                    F3_SH => 2,
+                    _ => 1, // SB
+                };
+                let base = self.read(rs1);
+                let imm = self.constant(imm);
+                let address = self.builder.ins().iadd(base, imm);
+                let value = self.read(rs2);
+                let size = self.constant(size);
+                let call = self.builder.ins().call(self.store, &[self.cpu, address, size, value]);
+                let result = self.builder.inst_results(call)[0];
+                let missed = self.builder.ins().icmp_imm(IntCC::Equal, result, 1);
+                self.exit_if(missed, pc, index);
+                let code_page = self.builder.ins().icmp_imm(IntCC::Equal, result, 2);
+                self.exit_if(code_page, next, index + 1);
+            }
+            Op::Alui => {
+                let a = self.read(rs1);
+                let shamt = (imm & 0x1F) as i64;
+                let value = match funct3 {
+                    F3_ADDI => {
+                        let imm = self.constant(imm);
+                        self.builder.ins().iadd(a, imm)
+                    }
+                    F3_SLTI | F3_SLTIU => {
+                        let imm = self.constant(imm);
+                        let condition = if funct3 == F3_SLTI { IntCC::SignedLessThan } else { IntCC::UnsignedLessThan };
+                        self.compare(condition, a, imm)
+                    }
+                    F3_XORI | F3_ORI | F3_ANDI => {
+                        let imm = self.constant(imm);
+                        match funct3 {
+                            F3_XORI => self.builder.ins().bxor(a, imm),
+                            F3_ORI => self.builder.ins().bor(a, imm),
+                            _ => self.builder.ins().band(a, imm),
+                        }
+                    }
+                    F3_SLLI => self.builder.ins().ishl_imm(a, shamt),
+                    _ if funct7 == F7_SRA => self.builder.ins().sshr_imm(a, shamt),
+                    _ => self.builder.ins().ushr_imm(a, shamt),
+                };
+                self.write(rd, value);
+            }
+            Op::Alu => {
+                let (a, b) = (self.read(rs1), self.read(rs2));
+                // Cranelift shifts by the lower 5 bits like the interpreter
+                let value = match funct73(decoded) {
+                    F73_ADD => self.builder.ins().iadd(a, b),
+                    F73_SUB => self.builder.ins().isub(a, b),
+                    F73_SLL => self.builder.ins().ishl(a, b),
+                    F73_SLT => self.compare(IntCC::SignedLessThan, a, b),
+                    F73_SLTU => self.compare(IntCC::UnsignedLessThan, a, b),
+                    F73_XOR => self.builder.ins().bxor(a, b),
+                    F73_SRL => self.builder.ins().ushr(a, b),
+                    F73_SRA => self.builder.ins().sshr(a, b),
+                    F73_OR => self.builder.ins().bor(a, b),
+                    F73_AND => self.builder.ins().band(a, b),
+                    F73_MUL => self.builder.ins().imul(a, b),
+                    F73_MULH => self.builder.ins().smulhi(a, b),
+                    F73_MULHU => self.builder.ins().umulhi(a, b),
+                    _ => { // MULHSU
+                        let a = self.builder.ins().sextend(types::I64, a);
+                        let b = self.builder.ins().uextend(types::I64, b);
+                        let product = self.builder.ins().imul(a, b);
+                        let high = self.builder.ins().ushr_imm(product, 32);
+                        self.builder.ins().ireduce(types::I32, high)
+                    }
+                };
+                self.write(rd, value);
+            }
+            Op::Fence | Op::System | Op::Halt | Op::Illegal => unreachable!("Not compiled"),
+        }
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::jit::*;
+    use crate::asm::assemble;
+    use crate::cpu::register::*;
+
+    // Runs the program at 0x100 until it halts, true if it faulted
+    fn run(cpu: &mut CPU) -> bool {
+        cpu.set_pc(0x100);
+        while !cpu.step_block(u64::MAX) {}
+        cpu.trap().is_some()
+    }
+
+    // Runs the program until it's hot, then once more after setup, with and without the
+    // JIT. Both have to end up the same. Returns the one with the JIT.
+    fn compare(source: &str, setup: impl Fn(&mut CPU)) -> CPU {
+        let program = assemble(source, 0x100).unwrap();
+        let cpus = [false, true].map(|jit| {
+            let mut cpu = CPU::new();
+            cpu.load_image(program.base, &program.image);
+            cpu.set_jit(jit);
+            cpu.registers.set_register(REG_S0, 1);
+            cpu.registers.set_register(REG_S2, 0x400);
+            cpu.registers.set_register(REG_T2, 0x404);
+            for _ in 0..HOT_RUNS {
+                assert!(!run(&mut cpu));
+            }
+            setup(&mut cpu);
+            let faulted = run(&mut cpu);
+            (cpu, faulted)
+        });
+        let [(interpreted, interpreted_faulted), (compiled, compiled_faulted)] = cpus;
+        assert_eq!(interpreted_faulted, compiled_faulted);
+        assert_eq!(interpreted.registers.registers, compiled.registers.registers);
+        assert_eq!((interpreted.get_pc(), interpreted.instret()), (compiled.get_pc(), compiled.instret()));
+        assert!(interpreted.memory.get_memory() == compiled.memory.get_memory());
+        compiled
+    }
+
+    fn compiled_len(cpu: &CPU) -> Option<usize> {
+        match cpu.jit.blocks.get(&0x100)?.state {
+            State::Compiled(code) => Some(code.len),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_jit() {
+        let cpu = compare("
+            addi s1, s1, 3
+            add s1, s1, s0
+            sub t0, s0, s1
+            slli t1, s1, 4
+            srai t2, t0, 3
+            sra t3, t0, s1
+            slt t4, t0, s1
+            sltiu t5, s1, -1
+            mul a0, t0, s1
+            mulh a1, t0, s1
+            mulhsu t6, t0, s1
+            sw t0, 0(s2)
+            sh s1, 4(s2)
+            lw s3, 0(s2)
+            lh a0, 0(s2)
+            lb a1, 0(s2)
+            lbu t1, 1(s2)
+            lhu t2, 2(s2)
+            addi sp, sp, -1
+            srli t4, sp, 7
+            auipc t3, 1
+            blt sp, s1, end
+            xori a0, a0, 0x7ff
+        end:
+            ebreak
+        ", |_| {});
+        assert_eq!(compiled_len(&cpu), Some(22));
+        assert_eq!(cpu.registers.get_register(REG_RA), 0, "Branches don't link");
+        assert_eq!(cpu.registers.get_register(REG_SP), -(HOT_RUNS as i32 + 1) as u32, "Negative, so blt is taken");
+        assert_eq!(cpu.registers.get_register(REG_S1), 4 * (HOT_RUNS + 1));
+    }
+
+    const SOURCE: &str = "add s1, s1, s0\nlw t0, 0(s2)\nsw t0, 0(t2)\naddi t1, t1, 1\nebreak";
+
+    #[test]
+    fn test_fallback() {
+        // A load from unmapped memory leaves it to the interpreter
+        let cpu = compare(SOURCE, |cpu| cpu.registers.set_register(REG_S2, 0x8000_0000));
+        assert_eq!(compiled_len(&cpu), Some(4));
+        assert_eq!(cpu.get_pc(), 0x104, "Faulted at the load");
+    }
+
+    #[test]
+    fn test_self_modifying() {
+        // The store replaces the addi after it with a nop, compiled code has to notice
+        let cpu = compare(SOURCE, |cpu| {
+            cpu.memory.set_u32(0x400, 0x00000013); // nop
+            cpu.registers.set_register(REG_T2, 0x10C);
+        });
+        assert_eq!(cpu.registers.get_register(REG_T1), HOT_RUNS, "The addi didn't run again");
+    }
+}
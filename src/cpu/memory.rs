@@ -1,9 +1,43 @@
+use std::cell::{Cell, RefCell, RefMut};
+use std::fmt;
+use std::sync::Arc;
+use crate::cpu::decode::Block;
+use crate::cpu::memory::bus::Bus;
 use crate::cpu::memory::mmu::{MMU, Page};
+use crate::cpu::memory::watch::{WatchHit, Watchpoint};
 
+pub mod bus;
 pub mod mmu;
+pub mod watch;
 
-pub(crate) struct Memory {
-    mmu: MMU,
+pub struct Memory {
+    mmu: MMU, // Main RAM
+    pub bus: Bus, // Whatever else a machine configuration maps
+    // Every access is checked against these, but only while there are any
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>, // Reads only borrow memory, so the hit goes in a Cell
+    accesses: Option<RefCell<Vec<Access>>>, // Guest accesses for instrumentation plugins, None when nobody asked
+    observed: bool, // Watchpoints or the access log, so the hot path checks one flag
+}
+
+// A guest access nothing answers: nothing is mapped at the address, it's a store to ROM,
+// or it's split across two pages
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Fault(pub u32);
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Access to unmapped address 0x{:08x}", self.0)
+    }
+}
+
+// A guest load or store, value is what was read or written
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Access {
+    pub address: u32,
+    pub size: u32,
+    pub value: u32,
+    pub write: bool,
 }
 
 impl Memory {
@@ -11,46 +45,242 @@ impl Memory {
         let mmu = MMU::new(memsize, page_offset_bits);
         Self {
             mmu,
+            bus: Bus::default(),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            accesses: None,
+            observed: false,
         }
     }
 
-    pub(crate) fn get_memory(&self) -> &Vec<Page> {
+    // Same as new, but the first byte of memory is mapped at base instead of 0
+    pub fn with_base(base: u32, memsize: usize, page_offset_bits: usize) -> Self {
+        let mmu = MMU::with_base(base, memsize, page_offset_bits);
+        Self {
+            mmu,
+            bus: Bus::default(),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            accesses: None,
+            observed: false,
+        }
+    }
+
+    pub fn get_memory(&self) -> &Vec<Page> {
         self.mmu.get_memory()
     }
 
+    // Address the first page is mapped at
+    pub fn base(&self) -> u32 {
+        self.mmu.base()
+    }
+
+    // Overwrites a whole page, bytes has to be exactly a page long
+    pub fn set_page(&mut self, index: usize, bytes: &[u8]) {
+        self.mmu.set_page(index, bytes);
+    }
+
+    // True for RAM and ROM, not for devices
+    pub fn contains(&self, address: u32) -> bool {
+        self.mmu.contains(address) || self.bus.contains(address)
+    }
+
+    // Bytes from address to the end of the RAM or ROM region it's in, 0 if it's in neither
+    pub fn room(&self, address: u32) -> u64 {
+        match self.mmu.contains(address) {
+            true => self.mmu.room(address),
+            false => self.bus.room(address),
+        }
+    }
+
+    // Main RAM first, then the other regions
+    pub fn snapshot(&mut self, previous: Option<&[Arc<Page>]>) -> Vec<Arc<Page>> {
+        let count = self.mmu.get_memory().len();
+        let mut pages = self.mmu.snapshot(previous.map(|previous| &previous[..count]));
+        pages.extend(self.bus.snapshot(previous.map(|previous| &previous[count..])));
+        pages
+    }
+
+    pub fn restore(&mut self, pages: &[Arc<Page>]) {
+        let count = self.mmu.get_memory().len();
+        self.mmu.restore(&pages[..count]);
+        self.bus.restore(&pages[count..]);
+    }
+
+    // A guest load of 1, 2 or 4 bytes. Main RAM first, then whatever the bus maps there.
+    pub fn load(&self, address: u32, size: u32) -> Result<u32, Fault> {
+        let value = match self.mmu.fits(address, size) {
+            true => self.mmu.read(address, size),
+            false if self.mmu.contains(address) => return Err(Fault(address)),
+            false => self.bus.load(address, size)?,
+        };
+        if self.observed {
+            self.observe(address, size, value, false);
+        }
+        Ok(value)
+    }
+
+    // A guest store, nothing is written if it faults
+    pub fn store(&mut self, address: u32, size: u32, value: u32) -> Result<(), Fault> {
+        match self.mmu.fits(address, size) {
+            true => self.mmu.write(address, size, value),
+            false if self.mmu.contains(address) => return Err(Fault(address)),
+            false => self.bus.store(address, size, value)?,
+        }
+        if self.observed {
+            self.observe(address, size, value, true);
+        }
+        Ok(())
+    }
+
+    // The accessors below are for the host side, which only uses addresses it knows are
+    // mapped. Guest accesses go through load and store.
+
     // Sets a byte in memory using MMU
     pub fn set_u8(&mut self, address: u32, value: u8) {
-        self.mmu.set_u8(address, value);
+        self.store(address, 1, value as u32).unwrap_or_else(|fault| panic!("{}", fault));
     }
 
     // Gets a byte from memory using MMU
     pub fn get_u8(&self, address: u32) -> u8 {
-        self.mmu.get_u8(address)
+        self.load(address, 1).unwrap_or_else(|fault| panic!("{}", fault)) as u8
     }
 
     // Splits a half word into 2 bytes and stores them in memory using MMU
     pub fn set_u16(&mut self, address: u32, value: u16) {
-        self.mmu.set_u16(address, value);
+        self.store(address, 2, value as u32).unwrap_or_else(|fault| panic!("{}", fault));
     }
 
     // Gets a half word from memory using MMU, as two bytes, combines and returns it as u16
     pub fn get_u16(&self, address: u32) -> u16 {
-        self.mmu.get_u16(address)
+        self.load(address, 2).unwrap_or_else(|fault| panic!("{}", fault)) as u16
     }
 
     // Splits a word into 4 bytes and stores them in memory using MMU
     pub fn set_u32(&mut self, address: u32, value: u32) {
-        self.mmu.set_u32(address, value);
+        self.store(address, 4, value).unwrap_or_else(|fault| panic!("{}", fault));
     }
 
     // Gets a word from memory using MMU, as four bytes, combines and returns it as u32
     pub fn get_u32(&self, address: u32) -> u32 {
-        self.mmu.get_u32(address)
+        self.load(address, 4).unwrap_or_else(|fault| panic!("{}", fault))
+    }
+
+    // Basic blocks decoded from main RAM, see decode.rs. Writing a page drops its own.
+    pub fn block(&self, address: u32) -> Option<&Block> {
+        self.mmu.block(address)
+    }
+
+    pub fn block_room(&self, address: u32) -> usize {
+        self.mmu.block_room(address)
+    }
+
+    pub fn cache_block(&mut self, address: u32, block: Block) {
+        self.mmu.cache_block(address, block);
+    }
+
+    pub fn has_blocks(&self, address: u32) -> bool {
+        self.mmu.has_blocks(address)
+    }
+
+    pub fn flush_blocks(&mut self) {
+        self.mmu.flush_blocks();
+    }
+
+    // Loads and stores of compiled code, see jit.rs. None when the interpreter has to make
+    // the access instead: outside of main RAM, across a page, or while anything observes them.
+    pub fn load_ram(&self, address: u32, size: u32) -> Option<u32> {
+        if self.observed || !self.mmu.fits(address, size) {
+            return None;
+        }
+        Some(self.mmu.read(address, size))
+    }
+
+    // Some(true) if the store dropped decoded blocks of its page
+    pub fn store_ram(&mut self, address: u32, size: u32, value: u32) -> Option<bool> {
+        if self.observed || !self.mmu.fits(address, size) {
+            return None;
+        }
+        let had_blocks = self.mmu.has_blocks(address);
+        self.mmu.write(address, size, value);
+        Some(had_blocks)
+    }
+
+    // Reads a word without triggering watchpoints, for instruction fetch and debugger views.
+    // Bytes outside of RAM and ROM read as 0.
+    pub fn peek_u32(&self, address: u32) -> u32 {
+        if self.mmu.fits(address, 4) {
+            return self.mmu.get_u32(address);
+        }
+        (0..4).rev().fold(0, |word, offset| word << 8 | self.peek_u8(address.wrapping_add(offset)) as u32)
+    }
+
+    // A byte the same way
+    pub fn peek_u8(&self, address: u32) -> u8 {
+        match self.mmu.contains(address) {
+            true => self.mmu.get_u8(address),
+            false => self.bus.peek(address),
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+        self.observed = true;
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if let Some(index) = self.watchpoints.iter().position(|existing| *existing == watchpoint) {
+            self.watchpoints.remove(index);
+        }
+        self.observed = !self.watchpoints.is_empty() || self.accesses.is_some();
+    }
+
+    // Starts or stops recording every guest load and store
+    pub fn log_accesses(&mut self, enable: bool) {
+        self.accesses = enable.then(|| RefCell::new(self.accesses.take().map(RefCell::into_inner).unwrap_or_default()));
+        self.observed = !self.watchpoints.is_empty() || self.accesses.is_some();
+    }
+
+    // Accesses since they were last drained, None unless logging
+    pub fn accesses(&self) -> Option<RefMut<'_, Vec<Access>>> {
+        self.accesses.as_ref().map(RefCell::borrow_mut)
+    }
+
+    fn observe(&self, address: u32, size: u32, value: u32, write: bool) {
+        if let Some(accesses) = &self.accesses {
+            accesses.borrow_mut().push(Access { address, size, value, write });
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(address, size, value, write);
+        }
     }
 
+    // The first access that hit a watchpoint since the last call
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn watch(&self, address: u32, size: u32, value: u32, write: bool) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        if let Some(watchpoint) = self.watchpoints.iter().find(|watchpoint| watchpoint.matches(address, size, value, write)) {
+            self.watch_hit.set(Some(WatchHit { watchpoint: *watchpoint, address, size, value, write }));
+        }
+    }
+
+    // Writes ROM too
     pub fn load_image(&mut self, offset: u32, image: &Vec<u8>) {
         for (i, byte) in image.iter().enumerate() {
-            self.set_u8(offset + i as u32, *byte);
+            let address = offset + i as u32;
+            match self.mmu.contains(address) {
+                true => self.mmu.set_u8(address, *byte),
+                false => self.bus.load_u8(address, *byte),
+            }
         }
     }
 }
@@ -61,6 +291,7 @@ impl Memory {
 #[allow(non_snake_case)]
 mod tests {
     use crate::cpu::memory::{*};
+    use crate::cpu::memory::watch::WatchKind;
 
     #[test]
     fn test_set_get_u8() {
@@ -82,4 +313,63 @@ mod tests {
         memory.set_u32(10, 0xFFFFFFFF);
         assert_eq!(memory.get_u32(10), 0xFFFFFFFF);
     }
+
+    #[test]
+    fn test_with_base() {
+        let mut memory = Memory::with_base(0x80000000, 1024, 8);
+        memory.set_u32(0x80000010, 0xCAFEBABE);
+        assert_eq!(memory.get_u32(0x80000010), 0xCAFEBABE);
+        assert_eq!(memory.get_memory()[0].get_u32(0x10), 0xCAFEBABE);
+    }
+
+    #[test]
+    fn test_contains() {
+        let memory = Memory::with_base(0x80000000, 1024, 8);
+        assert!(memory.contains(0x80000000));
+        assert!(memory.contains(0x800003FF));
+        assert!(!memory.contains(0x80000400));
+        assert!(!memory.contains(0x7FFFFFFF));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut memory = Memory::new(1024, 8);
+        memory.set_u32(0x10, 1);
+        let first = memory.snapshot(None);
+        memory.set_u32(0x210, 2);
+        let second = memory.snapshot(Some(&first));
+        assert!(Arc::ptr_eq(&first[0], &second[0]), "Unchanged pages should be shared");
+        assert!(!Arc::ptr_eq(&first[2], &second[2]));
+
+        memory.set_u32(0x10, 3);
+        memory.restore(&first);
+        assert_eq!(memory.get_u32(0x10), 1);
+        assert_eq!(memory.get_u32(0x210), 0);
+        memory.restore(&second);
+        assert_eq!(memory.get_u32(0x210), 2);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut memory = Memory::new(1024, 8);
+        let watchpoint = Watchpoint::parse(WatchKind::Write, "*0x100 == 0xdeadbeef").unwrap();
+        memory.add_watchpoint(watchpoint);
+        memory.set_u32(0x100, 1);
+        assert_eq!(memory.take_watch_hit(), None, "Only the watched value should trigger");
+        memory.set_u32(0x100, 0xDEADBEEF);
+        let hit = memory.take_watch_hit().unwrap();
+        assert_eq!((hit.address, hit.size, hit.value, hit.write), (0x100, 4, 0xDEADBEEF, true));
+        assert_eq!(memory.take_watch_hit(), None);
+
+        memory.add_watchpoint(Watchpoint::new(WatchKind::Read, 0x202, 2));
+        memory.get_u8(0x201);
+        assert_eq!(memory.peek_u32(0x200), 0);
+        assert_eq!(memory.take_watch_hit(), None, "Peeking doesn't count as an access");
+        memory.get_u16(0x201);
+        assert_eq!(memory.take_watch_hit().unwrap().address, 0x201);
+
+        memory.remove_watchpoint(watchpoint);
+        memory.set_u32(0x100, 0xDEADBEEF);
+        assert_eq!(memory.take_watch_hit(), None);
+    }
 }
\ No newline at end of file
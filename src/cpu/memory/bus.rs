@@ -0,0 +1,180 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Everything in the address space besides main RAM: more RAM, ROM and memory mapped
+// devices, as a machine configuration describes them. Main RAM stays in Memory itself
+// so the common case doesn't have to look anything up.
+// An access nothing is mapped at is a Fault, which the CPU turns into a trap. So is a
+// write to ROM; loading images ignores that.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use crate::cpu::memory::Fault;
+use crate::cpu::memory::mmu::{MMU, Page};
+
+// A memory mapped device. Accesses come with the offset into the device's window and
+// a size of 1, 2 or 4 bytes.
+pub trait Device: Send {
+    // Kind of device, the name machine configurations and state files use
+    fn kind(&self) -> &'static str;
+
+    fn load(&mut self, offset: u32, size: u32) -> u32;
+
+    // silent is set while reverse execution runs a stretch again, output already went out
+    fn store(&mut self, offset: u32, size: u32, value: u32, silent: bool);
+
+    // Register names and values for the monitor, read without side effects
+    fn registers(&self) -> Vec<(&'static str, u32)> {
+        Vec::new()
+    }
+
+    // Exit status once the guest asked the device to end the run, handed out only once
+    fn take_exit_code(&mut self) -> Option<u32> {
+        None
+    }
+
+    // Register contents and whatever else a state file has to carry, in the device's own
+    // format. Devices without state keep the default, an empty one.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct Region {
+    pub name: String,
+    pub read_only: bool,
+    pub mmu: MMU,
+}
+
+pub struct MappedDevice {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+    pub irq: Option<u32>, // There's no interrupt controller yet, so this is only described
+    device: RefCell<Box<dyn Device>>, // Loads have side effects but only borrow memory
+}
+
+#[derive(Default)]
+pub struct Bus {
+    pub regions: Vec<Region>,
+    pub devices: Vec<MappedDevice>,
+    pub silent: bool,
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        self.mmu.get_memory().iter().map(|page| page.get_page().len() as u64).sum()
+    }
+}
+
+impl MappedDevice {
+    pub fn kind(&self) -> &'static str {
+        self.device.borrow().kind()
+    }
+
+    pub fn registers(&self) -> Vec<(&'static str, u32)> {
+        self.device.borrow().registers()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.device.borrow().save_state()
+    }
+}
+
+impl Bus {
+    pub fn add_region(&mut self, name: &str, base: u32, size: usize, page_offset_bits: usize, read_only: bool) {
+        self.regions.push(Region { name: name.to_string(), read_only, mmu: MMU::with_base(base, size, page_offset_bits) });
+    }
+
+    pub fn add_device(&mut self, name: &str, base: u32, size: u32, irq: Option<u32>, device: Box<dyn Device>) {
+        self.devices.push(MappedDevice { name: name.to_string(), base, size, irq, device: RefCell::new(device) });
+    }
+
+    fn region(&self, address: u32) -> Option<&Region> {
+        self.regions.iter().find(|region| region.mmu.contains(address))
+    }
+
+    fn device(&self, address: u32) -> Option<&MappedDevice> {
+        self.devices.iter().find(|device| address.wrapping_sub(device.base) < device.size)
+    }
+
+    // Only RAM and ROM, devices can't be executed or peeked at
+    pub fn contains(&self, address: u32) -> bool {
+        self.region(address).is_some()
+    }
+
+    // Like in main RAM, an access split across two pages faults
+    pub fn load(&self, address: u32, size: u32) -> Result<u32, Fault> {
+        if let Some(region) = self.region(address) {
+            return region.mmu.fits(address, size).then(|| region.mmu.read(address, size)).ok_or(Fault(address));
+        }
+        match self.device(address) {
+            Some(device) => Ok(device.device.borrow_mut().load(address - device.base, size)),
+            None => Err(Fault(address)),
+        }
+    }
+
+    pub fn store(&mut self, address: u32, size: u32, value: u32) -> Result<(), Fault> {
+        if let Some(region) = self.regions.iter_mut().find(|region| region.mmu.contains(address)) {
+            if region.read_only || !region.mmu.fits(address, size) {
+                return Err(Fault(address));
+            }
+            region.mmu.write(address, size, value);
+            return Ok(());
+        }
+        let silent = self.silent;
+        match self.device(address) {
+            Some(device) => {
+                device.device.borrow_mut().store(address - device.base, size, value, silent);
+                Ok(())
+            }
+            None => Err(Fault(address)),
+        }
+    }
+
+    // Reads a byte of RAM or ROM without going near devices, 0 anywhere else
+    pub fn peek(&self, address: u32) -> u8 {
+        self.region(address).map_or(0, |region| region.mmu.get_u8(address))
+    }
+
+    pub fn room(&self, address: u32) -> u64 {
+        self.region(address).map_or(0, |region| region.mmu.room(address))
+    }
+
+    // For loading images, ROM included
+    pub fn load_u8(&mut self, address: u32, value: u8) {
+        match self.regions.iter_mut().find(|region| region.mmu.contains(address)) {
+            Some(region) => region.mmu.set_u8(address, value),
+            None => panic!("Image byte at unmapped address 0x{:08x}", address),
+        }
+    }
+
+    // The first exit status a device was asked for since the last call
+    pub fn take_exit_code(&mut self) -> Option<u32> {
+        self.devices.iter_mut().find_map(|device| device.device.get_mut().take_exit_code())
+    }
+
+    // Pages of every region one after the other, see MMU::snapshot
+    pub fn snapshot(&mut self, previous: Option<&[Arc<Page>]>) -> Vec<Arc<Page>> {
+        let mut pages = Vec::new();
+        for region in &mut self.regions {
+            let start = pages.len();
+            let count = region.mmu.get_memory().len();
+            pages.extend(region.mmu.snapshot(previous.map(|previous| &previous[start..start + count])));
+        }
+        pages
+    }
+
+    pub fn restore(&mut self, pages: &[Arc<Page>]) {
+        let mut start = 0;
+        for region in &mut self.regions {
+            let count = region.mmu.get_memory().len();
+            region.mmu.restore(&pages[start..start + count]);
+            start += count;
+        }
+    }
+}
+
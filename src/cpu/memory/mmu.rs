@@ -1,22 +1,63 @@
+use std::sync::Arc;
+use crate::cpu::decode::Block;
 pub use crate::cpu::memory::mmu::page::Page;
 
 mod page;
 
-pub(crate) struct MMU {
+pub struct MMU {
     page_table: Vec<Page>,
+    base: u32, // Address the first page is mapped at
     page_offset_bits: usize, // Number of lower bits in the global address used for page offset
     page_mask: usize, // We calculate the mask once :3
     num_pages: usize,
+    dirty: Vec<bool>, // Pages written since the last snapshot
+    blocks: Vec<Option<Box<[Option<Block>]>>>, // Decoded blocks of each page, by the word they start at
 }
 
 impl MMU {
-    pub(crate) fn get_memory(&self) -> &Vec<Page> {
+    pub fn get_memory(&self) -> &Vec<Page> {
         &self.page_table
     }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn set_page(&mut self, index: usize, bytes: &[u8]) {
+        self.dirty[index] = true;
+        self.blocks[index] = None;
+        self.page_table[index].set_page(bytes);
+    }
+
+    // Copies of the pages for a snapshot. Pages nobody wrote to since the previous
+    // snapshot are shared with it instead of copied.
+    pub fn snapshot(&mut self, previous: Option<&[Arc<Page>]>) -> Vec<Arc<Page>> {
+        let pages = self.page_table.iter().enumerate().map(|(i, page)| match previous {
+            Some(previous) if !self.dirty[i] => previous[i].clone(),
+            _ => Arc::new(page.clone()),
+        }).collect();
+        self.dirty.fill(false);
+        pages
+    }
+
+    // Puts the pages of a snapshot back. Dirty flags are relative to that snapshot afterwards.
+    pub fn restore(&mut self, pages: &[Arc<Page>]) {
+        for (index, (page, saved)) in self.page_table.iter_mut().zip(pages).enumerate() {
+            if *page != **saved {
+                *page = (**saved).clone();
+                self.blocks[index] = None;
+            }
+        }
+        self.dirty.fill(false);
+    }
 }
 
 impl MMU {
-    pub(crate) fn new(memsize: usize, page_offset_bits: usize) -> Self {
+    pub fn new(memsize: usize, page_offset_bits: usize) -> Self {
+        Self::with_base(0, memsize, page_offset_bits)
+    }
+
+    pub fn with_base(base: u32, memsize: usize, page_offset_bits: usize) -> Self {
         let page_size = 1 << page_offset_bits;
         let num_pages = memsize / page_size;
         let mut page_table: Vec<Page> = Vec::with_capacity(num_pages);
@@ -26,45 +67,130 @@ impl MMU {
 
         Self {
             page_table,
+            base,
             page_offset_bits,
             page_mask: page_size - 1,
             num_pages,
+            dirty: vec![false; num_pages],
+            blocks: vec![None; num_pages],
         }
     }
-    
-    pub(crate) fn set_u8(&mut self, address: u32, value: u8) {
+
+    // Splits a global address into page index and page offset
+    fn locate(&self, address: u32) -> (usize, u32) {
+        let address = address.wrapping_sub(self.base);
         let page_index = (address >> self.page_offset_bits) as usize;
         let page_offset = address as usize & self.page_mask;
-        self.page_table[page_index].set_u8(page_offset as u32, value);
+        (page_index, page_offset as u32)
     }
     
-    pub(crate) fn get_u8(&self, address: u32) -> u8 {
-        let page_index = (address >> self.page_offset_bits) as usize;
-        let page_offset = address as usize & self.page_mask;
-        self.page_table[page_index].get_u8(page_offset as u32)
+    // True if address falls inside mapped memory
+    pub fn contains(&self, address: u32) -> bool {
+        self.locate(address).0 < self.num_pages
+    }
+
+    pub fn set_u8(&mut self, address: u32, value: u8) {
+        let (page_index, page_offset) = self.locate(address);
+        self.dirty[page_index] = true;
+        self.blocks[page_index] = None;
+        self.page_table[page_index].set_u8(page_offset, value);
     }
     
-    pub(crate) fn set_u16(&mut self, address: u32, value: u16) {
-        let page_index = (address >> self.page_offset_bits) as usize;
-        let page_offset = address as usize & self.page_mask;
-        self.page_table[page_index].set_u16(page_offset as u32, value);
+    pub fn get_u8(&self, address: u32) -> u8 {
+        let (page_index, page_offset) = self.locate(address);
+        self.page_table[page_index].get_u8(page_offset)
     }
     
-    pub(crate) fn get_u16(&self, address: u32) -> u16 {
-        let page_index = (address >> self.page_offset_bits) as usize;
-        let page_offset = address as usize & self.page_mask;
-        self.page_table[page_index].get_u16(page_offset as u32)
+    pub fn set_u16(&mut self, address: u32, value: u16) {
+        let (page_index, page_offset) = self.locate(address);
+        self.dirty[page_index] = true;
+        self.blocks[page_index] = None;
+        self.page_table[page_index].set_u16(page_offset, value);
     }
     
-    pub(crate) fn set_u32(&mut self, address: u32, value: u32) {
-        let page_index = (address >> self.page_offset_bits) as usize;
-        let page_offset = address as usize & self.page_mask;
-        self.page_table[page_index].set_u32(page_offset as u32, value);
+    pub fn get_u16(&self, address: u32) -> u16 {
+        let (page_index, page_offset) = self.locate(address);
+        self.page_table[page_index].get_u16(page_offset)
     }
     
-    pub(crate) fn get_u32(&self, address: u32) -> u32 {
-        let page_index = (address >> self.page_offset_bits) as usize;
-        let page_offset = address as usize & self.page_mask;
-        self.page_table[page_index].get_u32(page_offset as u32)
+    pub fn set_u32(&mut self, address: u32, value: u32) {
+        let (page_index, page_offset) = self.locate(address);
+        self.dirty[page_index] = true;
+        self.blocks[page_index] = None;
+        self.page_table[page_index].set_u32(page_offset, value);
+    }
+    
+    pub fn get_u32(&self, address: u32) -> u32 {
+        let (page_index, page_offset) = self.locate(address);
+        self.page_table[page_index].get_u32(page_offset)
+    }
+
+    // Accesses of 1, 2 or 4 bytes, which have to fit in one page
+    pub fn read(&self, address: u32, size: u32) -> u32 {
+        match size {
+            1 => self.get_u8(address) as u32,
+            2 => self.get_u16(address) as u32,
+            _ => self.get_u32(address),
+        }
+    }
+
+    pub fn write(&mut self, address: u32, size: u32, value: u32) {
+        match size {
+            1 => self.set_u8(address, value as u8),
+            2 => self.set_u16(address, value as u16),
+            _ => self.set_u32(address, value),
+        }
+    }
+
+    // Whether size bytes at address are all in one page of main RAM
+    pub fn fits(&self, address: u32, size: u32) -> bool {
+        let (page_index, page_offset) = self.locate(address);
+        page_index < self.num_pages && page_offset as usize + size as usize <= self.page_mask + 1
+    }
+
+    // The block decoded from a word aligned address, if its page wasn't written since
+    pub fn block(&self, address: u32) -> Option<&Block> {
+        let (page_index, page_offset) = self.locate(address);
+        match address & 3 == 0 && page_index < self.num_pages {
+            true => self.blocks[page_index].as_ref()?[page_offset as usize >> 2].as_ref(),
+            false => None,
+        }
+    }
+
+    // Bytes mapped from address to the end of memory, 0 outside of it
+    pub fn room(&self, address: u32) -> u64 {
+        match self.contains(address) {
+            true => ((self.num_pages as u64) << self.page_offset_bits) - address.wrapping_sub(self.base) as u64,
+            false => 0,
+        }
     }
-}
\ No newline at end of file
+
+    // Words from address to the end of its page, how long a block starting there can get.
+    // 0 for unaligned addresses, those aren't cached.
+    pub fn block_room(&self, address: u32) -> usize {
+        let (page_index, page_offset) = self.locate(address);
+        match address & 3 == 0 && page_index < self.num_pages {
+            true => (self.page_mask + 1 - page_offset as usize) >> 2,
+            false => 0,
+        }
+    }
+
+    pub fn cache_block(&mut self, address: u32, block: Block) {
+        let (page_index, page_offset) = self.locate(address);
+        if address & 3 == 0 && page_index < self.num_pages {
+            let words = (self.page_mask + 1) >> 2;
+            let page = self.blocks[page_index].get_or_insert_with(|| vec![None; words].into_boxed_slice());
+            page[page_offset as usize >> 2] = Some(block);
+        }
+    }
+
+    // False once the page address is in was written to
+    pub fn has_blocks(&self, address: u32) -> bool {
+        let (page_index, _) = self.locate(address);
+        page_index < self.num_pages && self.blocks[page_index].is_some()
+    }
+
+    pub fn flush_blocks(&mut self) {
+        self.blocks.fill(None);
+    }
+}
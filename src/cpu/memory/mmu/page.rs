@@ -1,3 +1,4 @@
+#[derive(Clone, PartialEq)]
 pub struct Page {
     page: Box<[u8]>,
 }
@@ -12,6 +13,10 @@ impl Page {
         &self.page
     }
 
+    pub fn set_page(&mut self, bytes: &[u8]) {
+        self.page.copy_from_slice(bytes);
+    }
+
     // Sets a byte in page
     pub fn set_u8(&mut self, offset: u32, value: u8) {
         self.page[offset as usize] = value;
@@ -0,0 +1,115 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Watchpoints checked by Memory on every guest access.
+// Written as "0x8000", "0x8000..0x8010" or with a value condition,
+// "*0x8000 == 0xdeadbeef", which only fires when the value read or written matches.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access, // Either
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub address: u32,
+    pub length: u32,
+    pub size: Option<u32>, // Only accesses of this many bytes, any size if None
+    pub value: Option<u32>, // Only accesses of this value
+}
+
+// The access that triggered a watchpoint
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub address: u32,
+    pub size: u32,
+    pub value: u32,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    pub fn new(kind: WatchKind, address: u32, length: u32) -> Self {
+        Self { kind, address, length, size: None, value: None }
+    }
+
+    pub fn parse(kind: WatchKind, text: &str) -> Result<Self, String> {
+        let (range, value) = match text.split_once("==") {
+            Some((range, value)) => (range, Some(parse_number(value)?)),
+            None => (text, None),
+        };
+        let range = range.trim();
+        let range = range.strip_prefix('*').unwrap_or(range);
+        let (address, length) = match range.split_once("..") {
+            Some((start, end)) => {
+                let (start, end) = (parse_number(start)?, parse_number(end)?);
+                if end <= start {
+                    return Err(format!("Empty watch range '{}'", range));
+                }
+                (start, end - start)
+            }
+            // A value condition compares a whole word
+            None => (parse_number(range)?, if value.is_some() { 4 } else { 1 }),
+        };
+        Ok(Self { kind, address, length, size: None, value })
+    }
+
+    pub fn matches(&self, address: u32, size: u32, value: u32, write: bool) -> bool {
+        let kind = match self.kind {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        };
+        kind && address < self.address.wrapping_add(self.length)
+            && self.address < address.wrapping_add(size)
+            && self.size.is_none_or(|watched| watched == size)
+            && self.value.is_none_or(|watched| watched == value)
+    }
+}
+
+fn parse_number(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    let result = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    };
+    result.map_err(|_| format!("Invalid number '{}'", text))
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::memory::watch::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Watchpoint::parse(WatchKind::Write, "0x8000").unwrap(), Watchpoint::new(WatchKind::Write, 0x8000, 1));
+        assert_eq!(Watchpoint::parse(WatchKind::Read, "0x8000..0x8010").unwrap(), Watchpoint::new(WatchKind::Read, 0x8000, 0x10));
+        let watchpoint = Watchpoint::parse(WatchKind::Write, "*0x8000 == 0xdeadbeef").unwrap();
+        assert_eq!(watchpoint.address, 0x8000);
+        assert_eq!(watchpoint.length, 4);
+        assert_eq!(watchpoint.value, Some(0xDEADBEEF));
+        assert!(Watchpoint::parse(WatchKind::Write, "0x10..0x10").is_err());
+        assert!(Watchpoint::parse(WatchKind::Write, "*foo").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut watchpoint = Watchpoint::new(WatchKind::Write, 0x100, 4);
+        assert!(watchpoint.matches(0x102, 1, 0, true));
+        assert!(watchpoint.matches(0xFE, 4, 0, true), "Overlapping accesses should match");
+        assert!(!watchpoint.matches(0x104, 4, 0, true));
+        assert!(!watchpoint.matches(0x100, 4, 0, false), "Reads shouldn't match a write watchpoint");
+
+        watchpoint.size = Some(2);
+        assert!(!watchpoint.matches(0x100, 4, 0, true));
+        assert!(watchpoint.matches(0x100, 2, 0, true));
+
+        watchpoint.size = None;
+        watchpoint.value = Some(0xDEADBEEF);
+        assert!(!watchpoint.matches(0x100, 4, 0, true));
+        assert!(watchpoint.matches(0x100, 4, 0xDEADBEEF, true));
+    }
+}
@@ -12,14 +12,58 @@ pub const REG_T1:u8 = 6;
 pub const REG_T2:u8 = 7;
 pub const REG_S0:u8 = 8;
 pub const REG_S1:u8 = 9;
+pub const REG_A0:u8 = 10;
+pub const REG_A1:u8 = 11;
 pub const REG_S2:u8 = 18;
 
-pub(crate) struct Register {
-    pub(crate) registers: [u32; 32],
+// ABI names, indexed by register number
+pub const REG_ALIASES: [&str; 32] = [
+    "zero",
+    "ra",
+    "sp",
+    "gp",
+    "tp",
+    "t0",
+    "t1",
+    "t2",
+    "s0",
+    "s1",
+    "a0",
+    "a1",
+    "a2",
+    "a3",
+    "a4",
+    "a5",
+    "a6",
+    "a7",
+    "s2",
+    "s3",
+    "s4",
+    "s5",
+    "s6",
+    "s7",
+    "s8",
+    "s9",
+    "s10",
+    "s11",
+    "t3",
+    "t4",
+    "t5",
+    "t6"
+];
+
+pub struct Register {
+    pub registers: [u32; 32],
+}
+
+impl Default for Register {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Register {
-    pub(crate) fn new() -> Register {
+    pub fn new() -> Register {
         Register {
             registers: [0; 32],
         }
@@ -0,0 +1,241 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Record and replay of everything that makes a run non-deterministic. The devices get
+// console input and clock readings through Inputs, which either passes them through,
+// passes them through and logs them, or plays a log back instead of asking the host.
+// Each entry is tagged with the number of instructions retired when it happened:
+//     # tiny-vm inputs
+//     1042 clock 17
+//     2210 input 68690a
+//     2301 input error
+// A replayed run has to ask for the same inputs at the same instruction counts. If it
+// doesn't, it stops with a trap instead of silently running into different behaviour.
+// Live runs keep what they read too, so reverse execution can run a stretch again
+// with the same inputs.
+// There are no interrupts yet. Once there are, their arrival points belong in here too.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+const HEADER: &str = "# tiny-vm inputs";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Input(Option<Vec<u8>>), // Bytes read from the console, None if the read failed
+    Clock(u64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub instret: u64,
+    pub event: Event,
+}
+
+enum Mode {
+    Live,
+    Record(Box<dyn Write + Send>),
+    Replay, // Everything comes from entries, asking for more is a divergence
+}
+
+pub struct Inputs {
+    mode: Mode,
+    entries: Vec<Entry>, // The replayed log, or everything read so far, for running a stretch again
+    next: usize, // Entry the next input has to match
+    pub instret: u64, // Kept up to date by the CPU before it calls a device
+    pub silent: bool, // Set while a stretch runs again, so devices don't repeat their output
+    diverged: Option<u64>,
+}
+
+impl Inputs {
+    pub fn live() -> Self {
+        Self::with_mode(Mode::Live, Vec::new())
+    }
+
+    pub fn record(mut output: Box<dyn Write + Send>) -> std::io::Result<Self> {
+        writeln!(output, "{}", HEADER)?;
+        Ok(Self::with_mode(Mode::Record(output), Vec::new()))
+    }
+
+    pub fn record_to(path: &str) -> std::io::Result<Self> {
+        Self::record(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn replay(log: &str) -> Result<Self, String> {
+        let entries = log.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| parse_entry(line).ok_or_else(|| format!("Line {}: invalid entry '{}'", number + 1, line)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::with_mode(Mode::Replay, entries))
+    }
+
+    fn with_mode(mode: Mode, entries: Vec<Entry>) -> Self {
+        Self { mode, entries, next: 0, instret: 0, silent: false, diverged: None }
+    }
+
+    // Instruction count at which a replay asked for something the log doesn't have
+    pub fn diverged(&self) -> Option<u64> {
+        self.diverged
+    }
+
+    // Goes back to the given instruction count: the inputs from there on are handed out again
+    pub fn rewind(&mut self, instret: u64) {
+        self.next = self.entries.partition_point(|entry| entry.instret < instret);
+        self.diverged = None;
+    }
+
+    // Reads console input into buffer, like Read::read on stdin
+    pub fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.logged(|event| matches!(event, Event::Input(_))) {
+            Some(Some(Event::Input(Some(bytes)))) if bytes.len() <= buffer.len() => {
+                buffer[..bytes.len()].copy_from_slice(&bytes);
+                return Ok(bytes.len());
+            }
+            Some(Some(Event::Input(None))) => return Err(std::io::ErrorKind::Other.into()),
+            Some(_) => {
+                self.diverged = Some(self.instret);
+                return Ok(0);
+            }
+            None => {}
+        }
+        let result = std::io::stdin().read(buffer);
+        let bytes = result.as_ref().ok().map(|read| buffer[..*read].to_vec());
+        self.log(Event::Input(bytes));
+        result
+    }
+
+    // A clock reading. now is only called if the reading isn't replayed.
+    pub fn clock(&mut self, now: impl FnOnce() -> u64) -> u64 {
+        match self.logged(|event| matches!(event, Event::Clock(_))) {
+            Some(Some(Event::Clock(value))) => return value,
+            Some(_) => {
+                self.diverged = Some(self.instret);
+                return 0;
+            }
+            None => {}
+        }
+        let value = now();
+        self.log(Event::Clock(value));
+        value
+    }
+
+    fn log(&mut self, event: Event) {
+        let entry = Entry { instret: self.instret, event };
+        if let Mode::Record(output) = &mut self.mode {
+            // Flushed right away so the log survives the guest crashing the VM
+            let _ = writeln!(output, "{}", format_entry(&entry)).and_then(|_| output.flush());
+        }
+        self.entries.push(entry);
+        self.next = self.entries.len();
+    }
+
+    // What the log has for the input being asked for: None to ask the host,
+    // Some(None) if the log has something else at this point
+    fn logged(&mut self, kind: impl Fn(&Event) -> bool) -> Option<Option<Event>> {
+        let replay = matches!(self.mode, Mode::Replay);
+        if self.next == self.entries.len() && !replay {
+            return None;
+        }
+        match self.entries.get(self.next).filter(|entry| entry.instret == self.instret && kind(&entry.event)) {
+            Some(entry) => {
+                self.next += 1;
+                Some(Some(entry.event.clone()))
+            }
+            None if replay => Some(None),
+            // A live run that went differently the second time, say because a debugger
+            // changed a register. What it read later on never happened now.
+            None => {
+                self.entries.truncate(self.next);
+                None
+            }
+        }
+    }
+}
+
+fn format_entry(entry: &Entry) -> String {
+    match &entry.event {
+        Event::Input(Some(bytes)) => {
+            let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{} input {}", entry.instret, hex)
+        }
+        Event::Input(None) => format!("{} input error", entry.instret),
+        Event::Clock(value) => format!("{} clock {}", entry.instret, value),
+    }
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split_whitespace();
+    let instret = fields.next()?.parse().ok()?;
+    let event = match (fields.next()?, fields.next().unwrap_or("")) {
+        ("input", "error") => Event::Input(None),
+        ("input", hex) if hex.len() % 2 == 0 => {
+            let bytes = (0..hex.len()).step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<_>>>()?;
+            Event::Input(Some(bytes))
+        }
+        ("clock", value) => Event::Clock(value.parse().ok()?),
+        _ => return None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(Entry { instret, event })
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::replay::*;
+
+    #[test]
+    fn test_parse() {
+        let entry = |instret, event| Entry { instret, event };
+        for (line, expected) in [
+            ("1042 clock 17", entry(1042, Event::Clock(17))),
+            ("7 input 68690a", entry(7, Event::Input(Some(b"hi\n".to_vec())))),
+            ("7 input", entry(7, Event::Input(Some(Vec::new())))),
+            ("9 input error", entry(9, Event::Input(None))),
+        ] {
+            assert_eq!(parse_entry(line), Some(expected.clone()), "{}", line);
+            assert_eq!(parse_entry(&format_entry(&expected)), Some(expected));
+        }
+        for line in ["clock 17", "1 clock", "1 input 6", "1 input zz", "1 tick 3", "1 clock 2 3"] {
+            assert_eq!(parse_entry(line), None, "{}", line);
+        }
+        assert!(Inputs::replay("# tiny-vm inputs\n1 clock 2\nbogus\n").is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut inputs = Inputs::replay("# tiny-vm inputs\n3 clock 42\n5 input 6869\n").unwrap();
+        inputs.instret = 3;
+        assert_eq!(inputs.clock(|| panic!("A replayed clock shouldn't read the host's")), 42);
+        inputs.instret = 5;
+        let mut buffer = [0u8; 4];
+        assert_eq!(inputs.read(&mut buffer).unwrap(), 2);
+        assert_eq!(&buffer[..2], b"hi");
+        assert_eq!(inputs.diverged(), None);
+
+        inputs.instret = 6;
+        inputs.clock(|| 0);
+        assert_eq!(inputs.diverged(), Some(6), "Running past the end of the log should diverge");
+
+        inputs.rewind(4);
+        inputs.instret = 5;
+        assert_eq!(inputs.read(&mut buffer).unwrap(), 2, "Rewinding should hand out the input again");
+        assert_eq!(inputs.diverged(), None);
+    }
+
+    #[test]
+    fn test_rewind_live() {
+        let mut inputs = Inputs::live();
+        inputs.instret = 3;
+        assert_eq!(inputs.clock(|| 42), 42);
+        inputs.instret = 8;
+        assert_eq!(inputs.clock(|| 43), 43);
+        inputs.rewind(5);
+        assert_eq!(inputs.clock(|| panic!("A rewound clock shouldn't read the host's")), 43);
+        assert_eq!(inputs.clock(|| 44), 44, "Past the end of what was read, the host is asked again");
+    }
+}
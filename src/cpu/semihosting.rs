@@ -0,0 +1,567 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// RISC-V semihosting. A semihosting call is an ebreak wrapped in a magic sequence:
+//     slli x0, x0, 0x1f
+//     ebreak
+//     srai x0, x0, 7
+// The operation number is passed in a0, a pointer to the parameter block in a1,
+// and the result is returned in a0. The operations are the ones from the ARM spec.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::cpu::memory::{Fault, Memory};
+use crate::cpu::replay::Inputs;
+use crate::cpu::state::{StateReader, StateWriter};
+
+pub const SEMIHOSTING_PRE: u32 = 0x01F01013; // slli x0, x0, 0x1f
+pub const SEMIHOSTING_POST: u32 = 0x40705013; // srai x0, x0, 7
+
+// Operation numbers
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_READC: u32 = 0x07;
+pub const SYS_ISERROR: u32 = 0x08;
+pub const SYS_ISTTY: u32 = 0x09;
+pub const SYS_SEEK: u32 = 0x0A;
+pub const SYS_FLEN: u32 = 0x0C;
+pub const SYS_TMPNAM: u32 = 0x0D;
+pub const SYS_REMOVE: u32 = 0x0E;
+pub const SYS_RENAME: u32 = 0x0F;
+pub const SYS_CLOCK: u32 = 0x10;
+pub const SYS_TIME: u32 = 0x11;
+pub const SYS_SYSTEM: u32 = 0x12;
+pub const SYS_ERRNO: u32 = 0x13;
+pub const SYS_GET_CMDLINE: u32 = 0x15;
+pub const SYS_HEAPINFO: u32 = 0x16;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+pub const SYS_ELAPSED: u32 = 0x30;
+pub const SYS_TICKFREQ: u32 = 0x31;
+
+// Reason code for a normal exit. Every other reason is reported as a failure.
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// Special file name used by the C libraries for the console
+const CONSOLE: &str = ":tt";
+
+// errno values we hand back to the guest when the host doesn't give us one
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EINVAL: u32 = 22;
+
+const ERROR: u32 = u32::MAX; // -1
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    root: PathBuf, // Every file the guest touches lives under this directory
+    cmdline: String,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    errno: u32,
+    start: Instant,
+    exit_code: Option<u32>,
+}
+
+impl Semihosting {
+    pub fn new(root: impl Into<PathBuf>, cmdline: &str) -> Self {
+        Self {
+            root: root.into(),
+            cmdline: cmdline.to_string(),
+            handles: HashMap::new(),
+            next_handle: 1,
+            errno: 0,
+            start: Instant::now(),
+            exit_code: None,
+        }
+    }
+
+    // Open files aren't saved, only which handles are the console. Neither is the root:
+    // a state file from somewhere else mustn't decide what the guest can get at.
+    pub fn save(&self, out: &mut StateWriter) {
+        out.put_bytes(self.cmdline.as_bytes());
+        out.put_u64(self.start.elapsed().as_micros() as u64);
+        out.put_u32(self.next_handle);
+        out.put_u32(self.errno);
+        out.put_option(self.exit_code);
+        let mut consoles: Vec<(u32, u8)> = self.handles.iter().filter_map(|(number, handle)| match handle {
+            Handle::Stdin => Some((*number, 0)),
+            Handle::Stdout => Some((*number, 1)),
+            Handle::Stderr => Some((*number, 2)),
+            Handle::File(_) => None,
+        }).collect();
+        consoles.sort();
+        out.put_u32(consoles.len() as u32);
+        for (number, kind) in consoles {
+            out.put_u32(number);
+            out.put_u8(kind);
+        }
+    }
+
+    pub fn load(input: &mut StateReader, root: impl Into<PathBuf>) -> Result<Self, String> {
+        let mut semihosting = Self::new(root, &input.get_string()?);
+        let elapsed = Duration::from_micros(input.get_u64()?);
+        // Clocks carry on from where they were
+        semihosting.start = Instant::now().checked_sub(elapsed).unwrap_or(semihosting.start);
+        semihosting.next_handle = input.get_u32()?;
+        semihosting.errno = input.get_u32()?;
+        semihosting.exit_code = input.get_option()?;
+        for _ in 0..input.get_u32()? {
+            let number = input.get_u32()?;
+            let handle = match input.get_u8()? {
+                0 => Handle::Stdin,
+                1 => Handle::Stdout,
+                2 => Handle::Stderr,
+                kind => return Err(format!("Invalid semihosting handle kind {}", kind)),
+            };
+            semihosting.handles.insert(number, handle);
+        }
+        Ok(semihosting)
+    }
+
+    // Directory the guest's file access is confined to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Set once the guest called SYS_EXIT or SYS_EXIT_EXTENDED
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    // For going back to before the guest exited, see CPU::restore
+    pub fn set_exit_code(&mut self, exit_code: Option<u32>) {
+        self.exit_code = exit_code;
+    }
+
+    // Executes a single semihosting operation and returns the value for a0, or the fault
+    // if the guest passed a pointer to memory that isn't there
+    pub fn call(&mut self, op: u32, param: u32, memory: &mut Memory, inputs: &mut Inputs) -> Result<u32, Fault> {
+        Ok(match op {
+            SYS_OPEN => {
+                let name = read_string(memory, memory.load(param, 4)?, memory.load(param.wrapping_add(8), 4)?)?;
+                self.open(&name, memory.load(param.wrapping_add(4), 4)?)
+            }
+            SYS_CLOSE => {
+                match self.handles.remove(&memory.load(param, 4)?) {
+                    Some(_) => 0,
+                    None => self.fail(EBADF),
+                }
+            }
+            SYS_WRITEC => {
+                if !inputs.silent {
+                    self.console_write(&[memory.load(param, 1)? as u8]);
+                }
+                0
+            }
+            SYS_WRITE0 => {
+                let mut bytes = Vec::new();
+                let mut address = param;
+                loop {
+                    let byte = memory.load(address, 1)? as u8;
+                    if byte == 0 {
+                        break;
+                    }
+                    bytes.push(byte);
+                    address = address.wrapping_add(1);
+                }
+                if !inputs.silent {
+                    self.console_write(&bytes);
+                }
+                0
+            }
+            SYS_WRITE => {
+                let handle = memory.load(param, 4)?;
+                let len = memory.load(param.wrapping_add(8), 4)?;
+                let bytes = read_bytes(memory, memory.load(param.wrapping_add(4), 4)?, len)?;
+                // Returns the number of bytes that were *not* written
+                match self.write(handle, &bytes, inputs.silent) {
+                    Some(written) => len - written,
+                    None => len,
+                }
+            }
+            SYS_READ => {
+                let handle = memory.load(param, 4)?;
+                let buffer = memory.load(param.wrapping_add(4), 4)?;
+                let len = memory.load(param.wrapping_add(8), 4)?;
+                // No more than fits in the buffer's region, so a huge len doesn't allocate
+                // gigabytes on the host
+                let room = memory.room(buffer).min(len as u64) as u32;
+                if room == 0 && len > 0 {
+                    return Err(Fault(buffer));
+                }
+                // Returns the number of bytes that were *not* read
+                match self.read(handle, room, inputs) {
+                    Some(bytes) => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            memory.store(buffer.wrapping_add(i as u32), 1, *byte as u32)?;
+                        }
+                        len - bytes.len() as u32
+                    }
+                    None => len,
+                }
+            }
+            SYS_READC => {
+                let mut byte = [0u8];
+                match inputs.read(&mut byte) {
+                    Ok(1) => byte[0] as u32,
+                    _ => ERROR,
+                }
+            }
+            SYS_ISERROR => ((memory.load(param, 4)? as i32) < 0) as u32,
+            SYS_ISTTY => {
+                match self.handles.get(&memory.load(param, 4)?) {
+                    Some(Handle::File(_)) => 0,
+                    Some(_) => 1,
+                    None => self.fail(EBADF),
+                }
+            }
+            SYS_SEEK => {
+                let position = memory.load(param.wrapping_add(4), 4)? as u64;
+                match self.handles.get_mut(&memory.load(param, 4)?) {
+                    Some(Handle::File(file)) => match file.seek(SeekFrom::Start(position)) {
+                        Ok(_) => 0,
+                        Err(error) => self.io_fail(error),
+                    },
+                    _ => self.fail(EBADF),
+                }
+            }
+            SYS_FLEN => {
+                match self.handles.get(&memory.load(param, 4)?) {
+                    Some(Handle::File(file)) => match file.metadata() {
+                        Ok(metadata) => metadata.len() as u32,
+                        Err(error) => self.io_fail(error),
+                    },
+                    _ => self.fail(EBADF),
+                }
+            }
+            SYS_TMPNAM => {
+                let buffer = memory.load(param, 4)?;
+                let name = format!("tmp{:03}", memory.load(param.wrapping_add(4), 4)? & 0xFF);
+                if name.len() as u32 >= memory.load(param.wrapping_add(8), 4)? {
+                    return Ok(ERROR);
+                }
+                write_string(memory, buffer, &name)?;
+                0
+            }
+            SYS_REMOVE => {
+                let name = read_string(memory, memory.load(param, 4)?, memory.load(param.wrapping_add(4), 4)?)?;
+                match self.resolve(&name) {
+                    Some(path) => match std::fs::remove_file(path) {
+                        Ok(_) => 0,
+                        Err(error) => self.io_fail(error),
+                    },
+                    None => self.fail(EACCES),
+                }
+            }
+            SYS_RENAME => {
+                let old = read_string(memory, memory.load(param, 4)?, memory.load(param.wrapping_add(4), 4)?)?;
+                let new = read_string(memory, memory.load(param.wrapping_add(8), 4)?, memory.load(param.wrapping_add(12), 4)?)?;
+                match (self.resolve(&old), self.resolve(&new)) {
+                    (Some(old), Some(new)) => match std::fs::rename(old, new) {
+                        Ok(_) => 0,
+                        Err(error) => self.io_fail(error),
+                    },
+                    _ => self.fail(EACCES),
+                }
+            }
+            SYS_CLOCK => inputs.clock(|| (self.start.elapsed().as_millis() / 10) as u64) as u32, // Centiseconds
+            SYS_TIME => inputs.clock(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)) as u32,
+            SYS_SYSTEM => self.fail(EACCES), // Running host commands would escape the sandbox
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => {
+                let buffer = memory.load(param, 4)?;
+                let len = memory.load(param.wrapping_add(4), 4)?;
+                // The buffer needs to fit the terminating zero as well
+                if self.cmdline.len() as u32 >= len {
+                    return Ok(ERROR);
+                }
+                write_string(memory, buffer, &self.cmdline)?;
+                memory.store(param.wrapping_add(4), 4, self.cmdline.len() as u32)?;
+                0
+            }
+            SYS_HEAPINFO => {
+                // Zeroes tell the C library to fall back to the addresses from its linker script
+                let block = memory.load(param, 4)?;
+                for i in 0..4 {
+                    memory.store(block.wrapping_add(i * 4), 4, 0)?;
+                }
+                0
+            }
+            SYS_EXIT => {
+                // On RV32 the reason code is passed directly instead of through a parameter block
+                self.exit_code = Some(if param == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 });
+                0
+            }
+            SYS_EXIT_EXTENDED => {
+                let reason = memory.load(param, 4)?;
+                self.exit_code = Some(if reason == ADP_STOPPED_APPLICATION_EXIT { memory.load(param.wrapping_add(4), 4)? } else { 1 });
+                0
+            }
+            SYS_ELAPSED => {
+                let ticks = inputs.clock(|| self.start.elapsed().as_micros() as u64);
+                memory.store(param, 4, ticks as u32)?;
+                memory.store(param.wrapping_add(4), 4, (ticks >> 32) as u32)?;
+                0
+            }
+            SYS_TICKFREQ => 1_000_000, // SYS_ELAPSED counts microseconds
+            _ => self.fail(EINVAL),
+        })
+    }
+
+    fn fail(&mut self, errno: u32) -> u32 {
+        self.errno = errno;
+        ERROR
+    }
+
+    fn io_fail(&mut self, error: std::io::Error) -> u32 {
+        self.fail(error.raw_os_error().map(|errno| errno as u32).unwrap_or(EINVAL))
+    }
+
+    // Maps a guest path into the sandbox. Absolute paths and paths escaping the root are refused.
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        Some(self.root.join(path))
+    }
+
+    fn open(&mut self, name: &str, mode: u32) -> u32 {
+        // Modes follow fopen: r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+        let handle = if name == CONSOLE {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                8..=11 => Handle::Stderr,
+                _ => return self.fail(EINVAL),
+            }
+        } else {
+            let path = match self.resolve(name) {
+                Some(path) => path,
+                None => return self.fail(EACCES),
+            };
+            let mut options = OpenOptions::new();
+            match mode {
+                0 | 1 => options.read(true),
+                2 | 3 => options.read(true).write(true),
+                4 | 5 => options.write(true).create(true).truncate(true),
+                6 | 7 => options.read(true).write(true).create(true).truncate(true),
+                8 | 9 => options.append(true).create(true),
+                10 | 11 => options.read(true).append(true).create(true),
+                _ => return self.fail(EINVAL),
+            };
+            match options.open(path) {
+                Ok(file) => Handle::File(file),
+                Err(error) => return self.io_fail(error),
+            }
+        };
+        let number = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(number, handle);
+        number
+    }
+
+    // Console output is dropped when silent, it was already shown the first time around
+    fn write(&mut self, handle: u32, bytes: &[u8], silent: bool) -> Option<u32> {
+        let result = match self.handles.get_mut(&handle) {
+            Some(Handle::Stdout | Handle::Stderr) if silent => Ok(bytes.len()),
+            Some(Handle::Stdout) => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(bytes).and_then(|_| stdout.flush()).map(|_| bytes.len())
+            }
+            Some(Handle::Stderr) => std::io::stderr().write_all(bytes).map(|_| bytes.len()),
+            Some(Handle::File(file)) => file.write(bytes),
+            _ => {
+                self.fail(EBADF);
+                return None;
+            }
+        };
+        match result {
+            Ok(written) => Some(written as u32),
+            Err(error) => {
+                self.io_fail(error);
+                None
+            }
+        }
+    }
+
+    fn read(&mut self, handle: u32, len: u32, inputs: &mut Inputs) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; len as usize];
+        let result = match self.handles.get_mut(&handle) {
+            Some(Handle::Stdin) => inputs.read(&mut buffer),
+            Some(Handle::File(file)) => file.read(&mut buffer),
+            _ => {
+                self.fail(EBADF);
+                return None;
+            }
+        };
+        match result {
+            Ok(read) => {
+                buffer.truncate(read);
+                Some(buffer)
+            }
+            Err(error) => {
+                self.io_fail(error);
+                None
+            }
+        }
+    }
+
+    fn console_write(&mut self, bytes: &[u8]) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+    }
+}
+
+fn read_bytes(memory: &Memory, address: u32, len: u32) -> Result<Vec<u8>, Fault> {
+    (0..len).map(|i| memory.load(address.wrapping_add(i), 1).map(|byte| byte as u8)).collect()
+}
+
+fn read_string(memory: &Memory, address: u32, len: u32) -> Result<String, Fault> {
+    Ok(String::from_utf8_lossy(&read_bytes(memory, address, len)?).into_owned())
+}
+
+// Writes a zero terminated string into guest memory
+fn write_string(memory: &mut Memory, address: u32, string: &str) -> Result<(), Fault> {
+    for (i, byte) in string.bytes().enumerate() {
+        memory.store(address.wrapping_add(i as u32), 1, byte as u32)?;
+    }
+    memory.store(address.wrapping_add(string.len() as u32), 1, 0)
+}
+
+///// TESTS /////
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod tests {
+    use crate::cpu::memory::Memory;
+    use crate::cpu::semihosting::*;
+
+    fn sandbox(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tiny-vm-semihosting-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    // Lays out a parameter block at 0x100 and a string at 0x200
+    fn prep_params(memory: &mut Memory, params: &[u32], string: &str) {
+        for (i, param) in params.iter().enumerate() {
+            memory.set_u32(0x100 + i as u32 * 4, *param);
+        }
+        write_string(memory, 0x200, string).unwrap();
+    }
+
+    #[test]
+    fn test_open_write_read() {
+        let root = sandbox("rw");
+        let mut semihosting = Semihosting::new(&root, "");
+        let mut memory = Memory::new(4096, 8);
+
+        prep_params(&mut memory, &[0x200, 4, 8], "test.txt"); // "w"
+        let handle = semihosting.call(SYS_OPEN, 0x100, &mut memory, &mut Inputs::live()).unwrap();
+        assert_ne!(handle, ERROR);
+
+        prep_params(&mut memory, &[handle, 0x200, 5], "hello");
+        assert_eq!(semihosting.call(SYS_WRITE, 0x100, &mut memory, &mut Inputs::live()).unwrap(), 0);
+        prep_params(&mut memory, &[handle], "");
+        assert_eq!(semihosting.call(SYS_CLOSE, 0x100, &mut memory, &mut Inputs::live()).unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(root.join("test.txt")).unwrap(), "hello");
+
+        prep_params(&mut memory, &[0x200, 0, 8], "test.txt"); // "r"
+        let handle = semihosting.call(SYS_OPEN, 0x100, &mut memory, &mut Inputs::live()).unwrap();
+        prep_params(&mut memory, &[handle], "");
+        assert_eq!(semihosting.call(SYS_FLEN, 0x100, &mut memory, &mut Inputs::live()).unwrap(), 5);
+        prep_params(&mut memory, &[handle, 0x300, 8], "");
+        // 3 of the requested 8 bytes could not be read
+        assert_eq!(semihosting.call(SYS_READ, 0x100, &mut memory, &mut Inputs::live()).unwrap(), 3);
+        assert_eq!(read_string(&memory, 0x300, 5).unwrap(), "hello");
+
+        // Only as much as fits before the end of memory is read
+        prep_params(&mut memory, &[0x200, 0, 8], "test.txt");
+        let handle = semihosting.call(SYS_OPEN, 0x100, &mut memory, &mut Inputs::live()).unwrap();
+        prep_params(&mut memory, &[handle, 0xffe, 0xffff_ffff], "");
+        assert_eq!(semihosting.call(SYS_READ, 0x100, &mut memory, &mut Inputs::live()).unwrap(), 0xffff_fffd);
+        assert_eq!(memory.get_u16(0xffe), u16::from_le_bytes(*b"he"));
+        prep_params(&mut memory, &[handle, 0x10000, 8], "");
+        assert_eq!(semihosting.call(SYS_READ, 0x100, &mut memory, &mut Inputs::live()), Err(Fault(0x10000)));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_open_outside_sandbox() {
+        let root = sandbox("escape");
+        let mut semihosting = Semihosting::new(&root, "");
+        let mut memory = Memory::new(4096, 8);
+
+        prep_params(&mut memory, &[0x200, 0, 11], "../test.txt");
+        assert_eq!(semihosting.call(SYS_OPEN, 0x100, &mut memory, &mut Inputs::live()).unwrap(), ERROR);
+        assert_eq!(semihosting.call(SYS_ERRNO, 0, &mut memory, &mut Inputs::live()).unwrap(), EACCES);
+
+        prep_params(&mut memory, &[0x200, 0, 9], "/etc/motd");
+        assert_eq!(semihosting.call(SYS_OPEN, 0x100, &mut memory, &mut Inputs::live()).unwrap(), ERROR);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_get_cmdline() {
+        let mut semihosting = Semihosting::new(".", "prog --verbose");
+        let mut memory = Memory::new(4096, 8);
+
+        prep_params(&mut memory, &[0x300, 64], "");
+        assert_eq!(semihosting.call(SYS_GET_CMDLINE, 0x100, &mut memory, &mut Inputs::live()).unwrap(), 0);
+        assert_eq!(memory.get_u32(0x104), 14);
+        assert_eq!(read_string(&memory, 0x300, 14).unwrap(), "prog --verbose");
+
+        // Too small to fit the terminating zero
+        prep_params(&mut memory, &[0x300, 14], "");
+        assert_eq!(semihosting.call(SYS_GET_CMDLINE, 0x100, &mut memory, &mut Inputs::live()).unwrap(), ERROR);
+
+        // A buffer outside of memory faults
+        prep_params(&mut memory, &[0x10000, 64], "");
+        assert_eq!(semihosting.call(SYS_GET_CMDLINE, 0x100, &mut memory, &mut Inputs::live()), Err(Fault(0x10000)));
+    }
+
+    #[test]
+    fn test_parameters_wrap() {
+        // The length lies past the top of the address space, at 0
+        let root = sandbox("wrap");
+        let mut semihosting = Semihosting::new(&root, "");
+        let mut memory = Memory::with_base(0xffff_f000, 4096, 8);
+        memory.set_u32(0xffff_fff8, 1);
+        memory.set_u32(0xffff_fffc, 0xffff_f000);
+        assert_eq!(semihosting.call(SYS_READ, 0xffff_fff8, &mut memory, &mut Inputs::live()), Err(Fault(0)));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut memory = Memory::new(4096, 8);
+
+        let mut semihosting = Semihosting::new(".", "");
+        semihosting.call(SYS_EXIT, ADP_STOPPED_APPLICATION_EXIT, &mut memory, &mut Inputs::live()).unwrap();
+        assert_eq!(semihosting.exit_code(), Some(0));
+
+        let mut semihosting = Semihosting::new(".", "");
+        semihosting.call(SYS_EXIT, 0x20023, &mut memory, &mut Inputs::live()).unwrap(); // ADP_Stopped_RunTimeErrorUnknown
+        assert_eq!(semihosting.exit_code(), Some(1));
+
+        let mut semihosting = Semihosting::new(".", "");
+        prep_params(&mut memory, &[ADP_STOPPED_APPLICATION_EXIT, 42], "");
+        semihosting.call(SYS_EXIT_EXTENDED, 0x100, &mut memory, &mut Inputs::live()).unwrap();
+        assert_eq!(semihosting.exit_code(), Some(42));
+    }
+}
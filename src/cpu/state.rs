@@ -0,0 +1,373 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Machine state files: everything needed to carry on with a run later, or somewhere else.
+// Little endian throughout:
+//     "TVMSTATE", u32 version
+//     pc, x0..x31, u64 instret, optional exit code, ISA string
+//     memory base, page size, page count, then the pages: a 0 byte and a u32 count for a
+//     run of pages that are all zeros, a 1 byte followed by the bytes for any other page
+//     u32 count of other memory regions, each a name, base, ROM flag byte, page count and pages
+//     u32 count of devices, each a name, kind, base, size, optional IRQ and the device's
+//     own state as a u32 length prefixed blob, see Device::save_state
+//     optional HTIF state, optional semihosting state
+// Optional values are a byte saying whether the value follows, strings are u32 length
+// prefixed. There are no CSRs yet; once there are, they get a new version of the format.
+// Version 1 files, from before ISA strings, regions and devices, still load, and so do
+// version 2 files, which stored the semihosting root. The root is never taken from the
+// file: the host passes it to load_state, like it does for a fresh run.
+// Open semihosting files can't be carried over, a restored guest finds them closed.
+// Devices from version 2 and 3 files, which didn't have their state, come back reset.
+
+use std::path::PathBuf;
+use crate::cpu::{CPU, Isa, new_device};
+use crate::cpu::htif::Htif;
+use crate::cpu::memory::Memory;
+use crate::cpu::memory::mmu::Page;
+use crate::cpu::semihosting::Semihosting;
+
+const MAGIC: &[u8; 8] = b"TVMSTATE";
+const VERSION: u32 = 4;
+
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+
+pub fn is_state(image: &[u8]) -> bool {
+    image.starts_with(MAGIC)
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn put_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_option(&mut self, value: Option<u32>) {
+        self.put_u8(value.is_some() as u8);
+        if let Some(value) = value {
+            self.put_u32(value);
+        }
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn put_pages(&mut self, pages: &[Page]) {
+        let mut zeros = 0;
+        for page in pages {
+            let bytes = page.get_page();
+            if bytes.iter().all(|byte| *byte == 0) {
+                zeros += 1;
+                continue;
+            }
+            if zeros > 0 {
+                self.put_u8(PAGE_ZERO);
+                self.put_u32(zeros);
+                zeros = 0;
+            }
+            self.put_u8(PAGE_DATA);
+            self.bytes.extend_from_slice(bytes);
+        }
+        if zeros > 0 {
+            self.put_u8(PAGE_ZERO);
+            self.put_u32(zeros);
+        }
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.offset.checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| format!("State file ends early at offset 0x{:x}", self.offset))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn get_option(&mut self) -> Result<Option<u32>, String> {
+        Ok(match self.get_u8()? {
+            0 => None,
+            _ => Some(self.get_u32()?),
+        })
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn get_string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.get_bytes()?.to_vec()).map_err(|_| "Invalid string in state file".to_string())
+    }
+
+    // Hands every page that isn't all zeros to set_page
+    fn get_pages(&mut self, page_count: usize, page_size: usize, mut set_page: impl FnMut(usize, &[u8])) -> Result<(), String> {
+        let mut page = 0;
+        while page < page_count {
+            match self.get_u8()? {
+                PAGE_ZERO => page += self.get_u32()? as usize,
+                PAGE_DATA => {
+                    set_page(page, self.take(page_size)?);
+                    page += 1;
+                }
+                tag => return Err(format!("Invalid tag {} for page {}", tag, page)),
+            }
+        }
+        if page != page_count {
+            return Err(format!("Run of zero pages goes past the last page, {}", page_count));
+        }
+        Ok(())
+    }
+}
+
+impl CPU {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = StateWriter { bytes: MAGIC.to_vec() };
+        out.put_u32(VERSION);
+        out.put_u32(self.pc);
+        for register in self.registers.registers {
+            out.put_u32(register);
+        }
+        out.put_u64(self.instret);
+        out.put_option(self.exit_code);
+
+        out.put_bytes(self.isa.to_string().as_bytes());
+
+        let pages = self.memory.get_memory();
+        let page_size = pages.first().map_or(0, |page| page.get_page().len());
+        out.put_u32(self.memory.base());
+        out.put_u32(page_size as u32);
+        out.put_u32(pages.len() as u32);
+        out.put_pages(pages);
+
+        let bus = &self.memory.bus;
+        out.put_u32(bus.regions.len() as u32);
+        for region in &bus.regions {
+            out.put_bytes(region.name.as_bytes());
+            out.put_u32(region.mmu.base());
+            out.put_u8(region.read_only as u8);
+            out.put_u32(region.mmu.get_memory().len() as u32);
+            out.put_pages(region.mmu.get_memory());
+        }
+        out.put_u32(bus.devices.len() as u32);
+        for device in &bus.devices {
+            out.put_bytes(device.name.as_bytes());
+            out.put_bytes(device.kind().as_bytes());
+            out.put_u32(device.base);
+            out.put_u32(device.size);
+            out.put_option(device.irq);
+            out.put_bytes(&device.save_state());
+        }
+
+        out.put_u8(self.htif.is_some() as u8);
+        if let Some(htif) = &self.htif {
+            htif.save(&mut out);
+        }
+        out.put_u8(self.semihosting.is_some() as u8);
+        if let Some(semihosting) = &self.semihosting {
+            semihosting.save(&mut out);
+        }
+        out.bytes
+    }
+
+    pub fn load_state(bytes: &[u8], root: impl Into<PathBuf>) -> Result<CPU, String> {
+        if !is_state(bytes) {
+            return Err("Not a tiny-vm state file".to_string());
+        }
+        let mut input = StateReader { bytes, offset: MAGIC.len() };
+        let version = input.get_u32()?;
+        if version == 0 || version > VERSION {
+            return Err(format!("Unsupported state file version {}, expected {} or older", version, VERSION));
+        }
+        let pc = input.get_u32()?;
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = input.get_u32()?;
+        }
+        let instret = input.get_u64()?;
+        let exit_code = input.get_option()?;
+        let isa = match version {
+            1 => Isa::RV32IM,
+            _ => Isa::parse(&input.get_string()?)?,
+        };
+
+        let base = input.get_u32()?;
+        let page_size = input.get_u32()? as usize;
+        let page_count = input.get_u32()? as usize;
+        if !page_size.is_power_of_two() || page_size.checked_mul(page_count).is_none_or(|size| size > u32::MAX as usize + 1) {
+            return Err(format!("Invalid memory layout: {} pages of {} bytes", page_count, page_size));
+        }
+        let page_offset_bits = page_size.trailing_zeros() as usize;
+        let mut memory = Memory::with_base(base, page_size * page_count, page_offset_bits);
+        input.get_pages(page_count, page_size, |page, bytes| memory.set_page(page, bytes))?;
+
+        if version >= 2 {
+            for _ in 0..input.get_u32()? {
+                let name = input.get_string()?;
+                let base = input.get_u32()?;
+                let read_only = input.get_u8()? != 0;
+                let page_count = input.get_u32()? as usize;
+                if page_size.checked_mul(page_count).is_none_or(|size| size > u32::MAX as usize + 1) {
+                    return Err(format!("Invalid size for region {}: {} pages", name, page_count));
+                }
+                memory.bus.add_region(&name, base, page_size * page_count, page_offset_bits, read_only);
+                let region = memory.bus.regions.last_mut().unwrap();
+                input.get_pages(page_count, page_size, |page, bytes| region.mmu.set_page(page, bytes))?;
+            }
+            for _ in 0..input.get_u32()? {
+                let name = input.get_string()?;
+                let kind = input.get_string()?;
+                let (base, size, irq) = (input.get_u32()?, input.get_u32()?, input.get_option()?);
+                let mut device = new_device(&kind).ok_or_else(|| format!("Unknown device kind '{}' for {}", kind, name))?;
+                if version >= 4 {
+                    device.load_state(input.get_bytes()?).map_err(|error| format!("{}: {}", name, error))?;
+                }
+                memory.bus.add_device(&name, base, size, irq, device);
+            }
+        }
+
+        let mut cpu = CPU::with_memory(memory);
+        cpu.pc = pc;
+        cpu.registers.registers = registers;
+        cpu.instret = instret;
+        cpu.exit_code = exit_code;
+        cpu.isa = isa;
+        if input.get_u8()? != 0 {
+            cpu.htif = Some(Htif::load(&mut input)?);
+        }
+        if input.get_u8()? != 0 {
+            if version < 3 {
+                input.get_bytes()?;
+            }
+            cpu.semihosting = Some(Semihosting::load(&mut input, root)?);
+        }
+        Ok(cpu)
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::state::*;
+    use crate::cpu::register::*;
+    use crate::machine::{Machine, StopReason};
+    use std::path::Path;
+
+    #[test]
+    fn test_save_load() {
+        let program = crate::asm::assemble("li a0, 7\nlui t0, 0x80000\nsw a0, 0x100(t0)\nli a1, 1\nebreak", 0x4).unwrap();
+        let mut cpu = CPU::with_ram_base(0x80000000);
+        cpu.load_image(0x80000000, &program.image);
+        cpu.enable_htif(Htif::new(0x80001000, Some(0x80001008)));
+        cpu.set_pc(0x80000000);
+        let mut machine = Machine::new(cpu);
+        assert_eq!(machine.step(1), StopReason::Stepped);
+
+        let state = machine.cpu.save_state();
+        assert!(is_state(&state));
+        assert!(state.len() < 0x1000, "Zero pages should be stored compactly, got {} bytes", state.len());
+
+        let mut restored = Machine::new(CPU::load_state(&state, ".").unwrap());
+        assert_eq!(restored.cpu.get_pc(), 0x80000004);
+        assert_eq!(restored.cpu.registers.get_register(REG_A0), 7);
+        assert_eq!(restored.cpu.instret(), 1);
+        assert_eq!(restored.cpu.save_state(), state, "Saving again should give the same file");
+
+        // Both carry on the same way
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        assert_eq!(restored.run(), StopReason::Halted(None));
+        assert_eq!(restored.cpu.save_state(), machine.cpu.save_state());
+        assert_eq!(restored.cpu.memory.get_u32(0x80000100), 7);
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut cpu = CPU::new();
+        cpu.enable_semihosting(Semihosting::new(".", "test --flag"));
+        cpu.enable_htif(Htif::new(0x1000, None));
+        let cpu = CPU::load_state(&cpu.save_state(), ".").unwrap();
+        assert!(cpu.semihosting.is_some());
+        assert!(cpu.htif.is_some());
+        assert!(CPU::load_state(&CPU::new().save_state(), ".").unwrap().semihosting.is_none());
+    }
+
+    #[test]
+    fn test_semihosting_root() {
+        let mut cpu = CPU::new();
+        cpu.enable_semihosting(Semihosting::new("/tmp/tiny-vm-sandbox", "prog"));
+        let state = cpu.save_state();
+        assert!(!state.windows(20).any(|bytes| bytes == b"/tmp/tiny-vm-sandbox"), "The root shouldn't be saved");
+        let restored = CPU::load_state(&state, "sandbox").unwrap();
+        assert_eq!(restored.semihosting.as_ref().unwrap().root(), Path::new("sandbox"));
+
+        // Version 2 files have the root in front of the semihosting state, it's skipped
+        let mut record = StateWriter { bytes: Vec::new() };
+        cpu.semihosting.as_ref().unwrap().save(&mut record);
+        let at = state.len() - record.bytes.len();
+        let mut old = StateWriter { bytes: state[..at].to_vec() };
+        old.bytes[8] = 2;
+        old.put_bytes(b"/");
+        old.bytes.extend_from_slice(&state[at..]);
+        let restored = CPU::load_state(&old.bytes, "sandbox").unwrap();
+        assert_eq!(restored.semihosting.as_ref().unwrap().root(), Path::new("sandbox"));
+        assert_eq!(restored.get_pc(), cpu.get_pc());
+    }
+
+    #[test]
+    fn test_bus() {
+        let mut memory = Memory::with_base(0x80000000, 0x1000, 8);
+        memory.bus.add_region("boot", 0x1000, 0x200, 8, true);
+        memory.bus.add_device("finisher", 0x100000, crate::cpu::syscon::SYSCON_SIZE, Some(4), new_device("syscon").unwrap());
+        memory.bus.add_device("uart", 0x200000, crate::cpu::uart::UART_SIZE, None, new_device("uart").unwrap());
+        let mut cpu = CPU::with_memory(memory);
+        cpu.load_image(0x1104, &vec![1, 2, 3, 4]);
+        cpu.memory.store(0x200003, 1, 0x83).unwrap(); // LCR
+
+        let cpu = CPU::load_state(&cpu.save_state(), ".").unwrap();
+        let region = &cpu.memory.bus.regions[0];
+        assert_eq!((region.name.as_str(), region.mmu.base(), region.size(), region.read_only), ("boot", 0x1000, 0x200, true));
+        assert_eq!(cpu.memory.get_u32(0x1104), 0x04030201);
+        let device = &cpu.memory.bus.devices[0];
+        assert_eq!((device.name.as_str(), device.kind(), device.base, device.irq), ("finisher", "syscon", 0x100000, Some(4)));
+        assert_eq!(cpu.memory.bus.devices[1].registers()[3], ("lcr", 0x83), "Device registers should be restored");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let state = CPU::new().save_state();
+        assert!(CPU::load_state(b"\x7fELF", ".").is_err());
+        assert!(CPU::load_state(&state[..state.len() - 1], ".").err().unwrap().contains("ends early"));
+        let mut newer = state.clone();
+        newer[8] = 5;
+        assert!(CPU::load_state(&newer, ".").err().unwrap().contains("version 5"));
+    }
+}
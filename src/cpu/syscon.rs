@@ -0,0 +1,73 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// SiFive's test finisher, "sifive,test0" in QEMU's virt machine, that bare-metal test
+// suites use to end a run. A word written to it:
+//     0x5555            pass, exit status 0
+//     code << 16 | 0x3333   fail with exit status code
+// Anything else, the reset command 0x7777 included, is ignored.
+
+use crate::cpu::memory::bus::Device;
+
+pub const SYSCON_SIZE: u32 = 0x1000;
+
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_FAIL: u32 = 0x3333;
+
+pub struct Syscon {
+    exit_code: Option<u32>,
+}
+
+impl Default for Syscon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Syscon {
+    pub fn new() -> Self {
+        Self { exit_code: None }
+    }
+}
+
+impl Device for Syscon {
+    fn kind(&self) -> &'static str {
+        "syscon"
+    }
+
+    fn load(&mut self, _offset: u32, _size: u32) -> u32 {
+        0
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32, _silent: bool) {
+        if offset != 0 {
+            return;
+        }
+        match value & 0xFFFF {
+            FINISHER_PASS => self.exit_code = Some(0),
+            FINISHER_FAIL => self.exit_code = Some(value >> 16),
+            _ => {}
+        }
+    }
+
+    fn take_exit_code(&mut self) -> Option<u32> {
+        self.exit_code.take()
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::syscon::*;
+
+    #[test]
+    fn test_finisher() {
+        let mut syscon = Syscon::new();
+        syscon.store(0, 4, 0x7777, false);
+        assert_eq!(syscon.take_exit_code(), None);
+        syscon.store(0, 4, 3 << 16 | FINISHER_FAIL, false);
+        assert_eq!(syscon.take_exit_code(), Some(3));
+        assert_eq!(syscon.take_exit_code(), None, "The CPU keeps the status once it took it");
+        syscon.store(0, 4, FINISHER_PASS, false);
+        assert_eq!(syscon.take_exit_code(), Some(0));
+    }
+}
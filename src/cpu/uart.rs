@@ -0,0 +1,102 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// The transmit side of a 16550 UART, the serial port most RISC-V boards and QEMU's virt
+// machine have. Bytes written to THR go to stdout and LSR always reports the transmitter
+// as empty, which is all polled console output needs. Nothing is ever received yet, since
+// reading the console would need interrupts or a non-blocking stdin to be any use.
+// Registers are a byte apart; the others accept writes and read back as written.
+
+use std::io::Write;
+use crate::cpu::memory::bus::Device;
+
+pub const UART_SIZE: u32 = 0x100;
+
+const REG_THR: u32 = 0; // Transmit holding register on writes, receive buffer on reads
+const REG_LSR: u32 = 5; // Line status
+const LSR_THR_EMPTY: u32 = 0x20;
+const LSR_TX_IDLE: u32 = 0x40;
+const REGISTER_NAMES: [&str; 8] = ["thr", "ier", "fcr", "lcr", "mcr", "lsr", "msr", "scr"];
+
+pub struct Uart {
+    registers: [u8; 8],
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self { registers: [0; 8] }
+    }
+
+    fn register(&self, offset: u32) -> u32 {
+        match offset {
+            REG_THR => 0,
+            REG_LSR => LSR_THR_EMPTY | LSR_TX_IDLE,
+            _ => self.registers.get(offset as usize).copied().unwrap_or(0) as u32,
+        }
+    }
+}
+
+impl Device for Uart {
+    fn kind(&self) -> &'static str {
+        "uart"
+    }
+
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        self.register(offset)
+    }
+
+    fn registers(&self) -> Vec<(&'static str, u32)> {
+        REGISTER_NAMES.iter().enumerate().map(|(offset, name)| (*name, self.register(offset as u32))).collect()
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32, silent: bool) {
+        match offset {
+            REG_THR if !silent => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(&[value as u8]).and_then(|_| stdout.flush());
+            }
+            REG_THR | REG_LSR => {}
+            _ => {
+                if let Some(register) = self.registers.get_mut(offset as usize) {
+                    *register = value as u8;
+                }
+            }
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.registers.to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        self.registers = state.try_into().map_err(|_| format!("Invalid UART state, {} bytes", state.len()))?;
+        Ok(())
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::cpu::uart::*;
+
+    #[test]
+    fn test_registers() {
+        let mut uart = Uart::new();
+        assert_eq!(uart.load(REG_LSR, 1) & LSR_THR_EMPTY, LSR_THR_EMPTY);
+        uart.store(3, 1, 0x83, false); // LCR
+        assert_eq!(uart.load(3, 1), 0x83);
+        assert_eq!(uart.load(0x80, 1), 0, "Past the registers reads as 0");
+        assert_eq!(uart.registers()[3], ("lcr", 0x83));
+        assert_eq!(uart.registers()[5], ("lsr", 0x60));
+
+        let mut restored = Uart::new();
+        restored.load_state(&uart.save_state()).unwrap();
+        assert_eq!(restored.load(3, 1), 0x83);
+        assert!(restored.load_state(&[0; 3]).is_err());
+    }
+}
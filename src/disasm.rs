@@ -0,0 +1,338 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Turns instruction words back into assembly, the way objdump prints them:
+// ABI register names, pseudo-instructions where one applies and branch/jump
+// targets as absolute addresses. Everything printed reassembles to the same word.
+
+use std::collections::HashMap;
+use crate::asm::encoder::CSR_NAMES;
+use crate::cpu::opcodes::*;
+use crate::cpu::register::*;
+
+// Disassembles a single instruction located at pc
+pub fn disassemble(word: u32, pc: u32) -> String {
+    let opcode = (word & 0x7F) as u8;
+    let rd = ((word >> 7) & 0x1F) as u8;
+    let funct3 = ((word >> 12) & 0x7) as u8;
+    let rs1 = ((word >> 15) & 0x1F) as u8;
+    let rs2 = ((word >> 20) & 0x1F) as u8;
+    let funct7 = (word >> 25) as u8;
+    let imm_i = (word as i32) >> 20;
+
+    let text = match opcode {
+        OP_LUI => Some(format!("lui {}, 0x{:x}", reg(rd), word >> 12)),
+        OP_AUIPC => Some(format!("auipc {}, 0x{:x}", reg(rd), word >> 12)),
+        OP_JAL => {
+            let target = jump_target(word, pc);
+            match rd {
+                REG_ZERO => Some(format!("j 0x{:x}", target)),
+                REG_RA => Some(format!("jal 0x{:x}", target)),
+                _ => Some(format!("jal {}, 0x{:x}", reg(rd), target)),
+            }
+        }
+        OP_JALR if funct3 == 0 => match (rd, rs1, imm_i) {
+            (REG_ZERO, REG_RA, 0) => Some("ret".to_string()),
+            (REG_ZERO, _, 0) => Some(format!("jr {}", reg(rs1))),
+            (REG_RA, _, 0) => Some(format!("jalr {}", reg(rs1))),
+            _ => Some(format!("jalr {}, {}({})", reg(rd), imm_i, reg(rs1))),
+        },
+        OP_BRANCH => branch(funct3, rs1, rs2, branch_target(word, pc)),
+        OP_LOAD => {
+            let name = match funct3 {
+                F3_LB => Some("lb"),
+                F3_LH => Some("lh"),
+                F3_LW => Some("lw"),
+                F3_LBU => Some("lbu"),
+                F3_LHU => Some("lhu"),
+                _ => None,
+            };
+            name.map(|name| format!("{} {}, {}({})", name, reg(rd), imm_i, reg(rs1)))
+        }
+        OP_STORE => {
+            let name = match funct3 {
+                F3_SB => Some("sb"),
+                F3_SH => Some("sh"),
+                F3_SW => Some("sw"),
+                _ => None,
+            };
+            let imm = ((word as i32) >> 25) << 5 | ((word >> 7) & 0x1F) as i32;
+            name.map(|name| format!("{} {}, {}({})", name, reg(rs2), imm, reg(rs1)))
+        }
+        OP_ALUI => alui(funct3, rd, rs1, imm_i),
+        OP_ALU => alu(funct7, funct3, rd, rs1, rs2),
+        OP_FENCE => match funct3 {
+            F3_FENCE if word >> 28 == 0 && rd == 0 && rs1 == 0 => {
+                let (pred, succ) = ((word >> 24) & 0xF, (word >> 20) & 0xF);
+                if pred == 0xF && succ == 0xF {
+                    Some("fence".to_string())
+                } else if pred != 0 && succ != 0 {
+                    Some(format!("fence {}, {}", fence_set(pred), fence_set(succ)))
+                } else {
+                    None
+                }
+            }
+            F3_FENCE_I if word >> 15 == 0 && rd == 0 => Some("fence.i".to_string()),
+            _ => None,
+        },
+        OP_E_C => system(word, funct3, rd, rs1),
+        _ => None,
+    };
+    // Anything we can't decode is shown as data, which still reassembles to the same word
+    text.unwrap_or_else(|| format!(".word 0x{:08x}", word))
+}
+
+// Target address of a jump or branch at pc, None for every other instruction
+pub fn target(word: u32, pc: u32) -> Option<u32> {
+    match (word & 0x7F) as u8 {
+        OP_JAL => Some(jump_target(word, pc)),
+        OP_BRANCH if !matches!(((word >> 12) & 0x7) as u8, 2 | 3) => Some(branch_target(word, pc)),
+        _ => None,
+    }
+}
+
+// objdump style listing of an image loaded at base. Symbols label the lines they point to
+// and the targets of jumps and branches.
+pub fn listing(base: u32, image: &[u8], symbols: &HashMap<String, u32>) -> String {
+    let mut names: HashMap<u32, &str> = HashMap::new();
+    for (name, address) in symbols {
+        // Several names for one address: keep the same one every time
+        let entry = names.entry(*address).or_insert(name);
+        if name.as_str() < *entry {
+            *entry = name;
+        }
+    }
+
+    let mut text = String::new();
+    for (i, chunk) in image.chunks(4).enumerate() {
+        let address = base + i as u32 * 4;
+        let mut bytes = [0u8; 4];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let word = u32::from_le_bytes(bytes);
+
+        if let Some(name) = names.get(&address) {
+            text.push_str(&format!("\n{:08x} <{}>:\n", address, name));
+        }
+        text.push_str(&format!("{:8x}:\t{:08x}\t{}", address, word, disassemble(word, address)));
+        if let Some(name) = target(word, address).and_then(|target| names.get(&target)) {
+            text.push_str(&format!(" <{}>", name));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn reg(register: u8) -> &'static str {
+    REG_ALIASES[register as usize]
+}
+
+fn jump_target(word: u32, pc: u32) -> u32 {
+    let imm_20 = ((word >> 31) & 0x1) << 20;
+    let imm_10_1 = ((word >> 21) & 0x3FF) << 1;
+    let imm_11 = ((word >> 20) & 0x1) << 11;
+    let imm_19_12 = ((word >> 12) & 0xFF) << 12;
+    let offset = ((imm_20 | imm_19_12 | imm_11 | imm_10_1) << 11) as i32 >> 11;
+    pc.wrapping_add(offset as u32)
+}
+
+fn branch_target(word: u32, pc: u32) -> u32 {
+    let imm_12 = ((word >> 31) & 0x1) << 12;
+    let imm_10_5 = ((word >> 25) & 0x3F) << 5;
+    let imm_4_1 = ((word >> 8) & 0xF) << 1;
+    let imm_11 = ((word >> 7) & 0x1) << 11;
+    let offset = ((imm_12 | imm_11 | imm_10_5 | imm_4_1) << 19) as i32 >> 19;
+    pc.wrapping_add(offset as u32)
+}
+
+fn branch(funct3: u8, rs1: u8, rs2: u8, target: u32) -> Option<String> {
+    let name = match funct3 {
+        F3_BEQ => "beq",
+        F3_BNE => "bne",
+        F3_BLT => "blt",
+        F3_BGE => "bge",
+        F3_BLTU => "bltu",
+        F3_BGEU => "bgeu",
+        _ => return None,
+    };
+    let text = match (funct3, rs1, rs2) {
+        (F3_BEQ | F3_BNE | F3_BLT | F3_BGE, _, REG_ZERO) => format!("{}z {}, 0x{:x}", name, reg(rs1), target),
+        (F3_BLT, REG_ZERO, _) => format!("bgtz {}, 0x{:x}", reg(rs2), target),
+        (F3_BGE, REG_ZERO, _) => format!("blez {}, 0x{:x}", reg(rs2), target),
+        _ => format!("{} {}, {}, 0x{:x}", name, reg(rs1), reg(rs2), target),
+    };
+    Some(text)
+}
+
+fn alui(funct3: u8, rd: u8, rs1: u8, imm: i32) -> Option<String> {
+    let shamt = imm & 0x1F;
+    let text = match funct3 {
+        F3_ADDI => match (rd, rs1, imm) {
+            (REG_ZERO, REG_ZERO, 0) => "nop".to_string(),
+            (_, REG_ZERO, _) => format!("li {}, {}", reg(rd), imm),
+            (_, _, 0) => format!("mv {}, {}", reg(rd), reg(rs1)),
+            _ => format!("addi {}, {}, {}", reg(rd), reg(rs1), imm),
+        },
+        F3_SLTIU if imm == 1 => format!("seqz {}, {}", reg(rd), reg(rs1)),
+        F3_XORI if imm == -1 => format!("not {}, {}", reg(rd), reg(rs1)),
+        F3_SLTI => format!("slti {}, {}, {}", reg(rd), reg(rs1), imm),
+        F3_SLTIU => format!("sltiu {}, {}, {}", reg(rd), reg(rs1), imm),
+        F3_XORI => format!("xori {}, {}, {}", reg(rd), reg(rs1), imm),
+        F3_ORI => format!("ori {}, {}, {}", reg(rd), reg(rs1), imm),
+        F3_ANDI => format!("andi {}, {}, {}", reg(rd), reg(rs1), imm),
+        F3_SLLI if imm >> 5 == 0 => format!("slli {}, {}, {}", reg(rd), reg(rs1), shamt),
+        F3_SRLI_SRAI if imm >> 5 == F7_SRL as i32 => format!("srli {}, {}, {}", reg(rd), reg(rs1), shamt),
+        F3_SRLI_SRAI if imm >> 5 == F7_SRA as i32 => format!("srai {}, {}, {}", reg(rd), reg(rs1), shamt),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn alu(funct7: u8, funct3: u8, rd: u8, rs1: u8, rs2: u8) -> Option<String> {
+    let name = match (funct7, funct3) {
+        (F7_SUB, F3_ADD_SUB) if rs1 == REG_ZERO => return Some(format!("neg {}, {}", reg(rd), reg(rs2))),
+        (0, F3_SLTU) if rs1 == REG_ZERO => return Some(format!("snez {}, {}", reg(rd), reg(rs2))),
+        (0, F3_SLT) if rs2 == REG_ZERO => return Some(format!("sltz {}, {}", reg(rd), reg(rs1))),
+        (0, F3_SLT) if rs1 == REG_ZERO => return Some(format!("sgtz {}, {}", reg(rd), reg(rs2))),
+        (F7_ADD, F3_ADD_SUB) => "add",
+        (F7_SUB, F3_ADD_SUB) => "sub",
+        (0, F3_SLL) => "sll",
+        (0, F3_SLT) => "slt",
+        (0, F3_SLTU) => "sltu",
+        (0, F3_XOR) => "xor",
+        (F7_SRL, F3_SRL_SLA) => "srl",
+        (F7_SRA, F3_SRL_SLA) => "sra",
+        (0, F3_OR) => "or",
+        (0, F3_AND) => "and",
+        (F7_M_EXTENSION, F3_MUL) => "mul",
+        (F7_M_EXTENSION, F3_MULH) => "mulh",
+        (F7_M_EXTENSION, F3_MULHSU) => "mulhsu",
+        (F7_M_EXTENSION, F3_MULHU) => "mulhu",
+        (F7_M_EXTENSION, F3_DIV) => "div",
+        (F7_M_EXTENSION, F3_DIVU) => "divu",
+        (F7_M_EXTENSION, F3_REM) => "rem",
+        (F7_M_EXTENSION, F3_REMU) => "remu",
+        _ => return None,
+    };
+    Some(format!("{} {}, {}, {}", name, reg(rd), reg(rs1), reg(rs2)))
+}
+
+fn system(word: u32, funct3: u8, rd: u8, rs1: u8) -> Option<String> {
+    let csr = word >> 20;
+    let csr_name = CSR_NAMES.iter().find(|(_, number)| *number == csr)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("0x{:x}", csr));
+    let text = match funct3 {
+        F3_ECALL_EBREAK if rd == 0 && rs1 == 0 => match csr {
+            F12_ECALL => "ecall".to_string(),
+            F12_EBREAK => "ebreak".to_string(),
+            _ => return None,
+        },
+        F3_CSRRS if rs1 == REG_ZERO => format!("csrr {}, {}", reg(rd), csr_name),
+        F3_CSRRW | F3_CSRRS | F3_CSRRC if rd == REG_ZERO => {
+            let name = match funct3 { F3_CSRRW => "csrw", F3_CSRRS => "csrs", _ => "csrc" };
+            format!("{} {}, {}", name, csr_name, reg(rs1))
+        }
+        F3_CSRRWI | F3_CSRRSI | F3_CSRRCI if rd == REG_ZERO => {
+            let name = match funct3 { F3_CSRRWI => "csrwi", F3_CSRRSI => "csrsi", _ => "csrci" };
+            format!("{} {}, {}", name, csr_name, rs1)
+        }
+        F3_CSRRW => format!("csrrw {}, {}, {}", reg(rd), csr_name, reg(rs1)),
+        F3_CSRRS => format!("csrrs {}, {}, {}", reg(rd), csr_name, reg(rs1)),
+        F3_CSRRC => format!("csrrc {}, {}, {}", reg(rd), csr_name, reg(rs1)),
+        F3_CSRRWI => format!("csrrwi {}, {}, {}", reg(rd), csr_name, rs1),
+        F3_CSRRSI => format!("csrrsi {}, {}, {}", reg(rd), csr_name, rs1),
+        F3_CSRRCI => format!("csrrci {}, {}, {}", reg(rd), csr_name, rs1),
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn fence_set(set: u32) -> String {
+    ['i', 'o', 'r', 'w'].iter().enumerate()
+        .filter(|(i, _)| set & (0x8 >> i) != 0)
+        .map(|(_, c)| *c)
+        .collect()
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::disasm::*;
+    use crate::asm::assemble;
+    use crate::cpu::instruction::builder::InstructionBuilder;
+
+    #[test]
+    fn test_disassemble_base() {
+        assert_eq!(disassemble(0x00100413, 0), "li s0, 1");
+        assert_eq!(disassemble(0xFFF50513, 0), "addi a0, a0, -1");
+        assert_eq!(disassemble(0x407302B3, 0), "sub t0, t1, t2");
+        assert_eq!(disassemble(0x12345537, 0), "lui a0, 0x12345");
+        assert_eq!(disassemble(0x00812503, 0), "lw a0, 8(sp)");
+        assert_eq!(disassemble(0x40355513, 0), "srai a0, a0, 3");
+        assert_eq!(disassemble(0xF1402573, 0), "csrr a0, mhartid");
+        assert_eq!(disassemble(InstructionBuilder.store(0xFFF, F3_SB, REG_ZERO, REG_SP), 0), "sb zero, -1(sp)");
+        assert_eq!(disassemble(InstructionBuilder.alu(F7_M_EXTENSION, F3_MULHU, 12, 11, 10), 0), "mulhu a0, a1, a2");
+    }
+
+    #[test]
+    fn test_disassemble_pseudo() {
+        assert_eq!(disassemble(0x00000013, 0), "nop");
+        assert_eq!(disassemble(0x00058513, 0), "mv a0, a1");
+        assert_eq!(disassemble(0xFFF5C513, 0), "not a0, a1");
+        assert_eq!(disassemble(0x40B00533, 0), "neg a0, a1");
+        assert_eq!(disassemble(0x00008067, 0), "ret");
+        assert_eq!(disassemble(0x00028067, 0), "jr t0");
+        assert_eq!(disassemble(0x0FF0000F, 0), "fence");
+        assert_eq!(disassemble(0x00100073, 0), "ebreak");
+    }
+
+    #[test]
+    fn test_disassemble_targets() {
+        assert_eq!(disassemble(0x010000EF, 0x100), "jal 0x110");
+        assert_eq!(disassemble(0xFF9FF06F, 0x100), "j 0xf8");
+        assert_eq!(disassemble(0x00B50463, 0x100), "beq a0, a1, 0x108");
+        assert_eq!(disassemble(0xFE051EE3, 0x100), "bnez a0, 0xfc");
+        assert_eq!(target(0xFE051EE3, 0x100), Some(0xFC));
+        assert_eq!(target(0x00100413, 0x100), None);
+    }
+
+    #[test]
+    fn test_disassemble_unknown() {
+        assert_eq!(disassemble(0x00000000, 0), ".word 0x00000000");
+        assert_eq!(disassemble(0xFFFFFFFF, 0), ".word 0xffffffff");
+    }
+
+    // Whatever the disassembler prints has to assemble back to the same word
+    #[test]
+    fn test_disassemble_round_trip() {
+        let source = "
+            nop; li a0, -2048; mv s1, a2; addi sp, sp, 16; slti t0, t1, -5; sltiu a0, a1, 1; xori a0, a0, -1
+            andi a0, a0, 255; ori a0, a0, 1; slli a0, a0, 31; srli a0, a0, 1; srai a0, a0, 7
+            add a0, a1, a2; sub a0, a1, a2; neg a0, a1; sll a0, a1, a2; slt a0, a1, zero; slt a0, zero, a1
+            sltu a0, zero, a1; xor a0, a1, a2; srl a0, a1, a2; sra a0, a1, a2; or a0, a1, a2; and a0, a1, a2
+            mul a0, a1, a2; mulh a0, a1, a2; mulhsu a0, a1, a2; div a0, a1, a2; divu a0, a1, a2; rem a0, a1, a2; remu a0, a1, a2
+            lui a0, 0xfffff; auipc t0, 0x1; lb a0, -4(sp); lh a0, 2(sp); lw a0, 0(sp); lbu a0, 1(a0); lhu a0, 2(a0)
+            sb a0, -1(sp); sh a0, 2(sp); sw ra, 12(sp)
+            loop: beq a0, a1, loop; bne a0, zero, loop; blt a0, a1, loop; bge zero, a0, loop; bltu a0, a1, end; bgeu a0, a1, end
+            jal loop; jal t0, end; j end; jalr t0; jalr a0, -8(t1); jr a0; ret
+            fence; fence rw, w; fence.i; ecall; ebreak
+            csrr a0, cycle; csrw mscratch, a0; csrs mstatus, a1; csrc mie, a2; csrwi mtvec, 4; csrrw a0, 0x7c0, a1; csrrci a0, mip, 31
+            end: .word 0xffffffff
+        ";
+        let program = assemble(source, 0x1000).unwrap();
+        let mut reassembled = String::new();
+        for (i, chunk) in program.image.chunks(4).enumerate() {
+            let word = u32::from_le_bytes(chunk.try_into().unwrap());
+            reassembled.push_str(&disassemble(word, 0x1000 + i as u32 * 4));
+            reassembled.push('\n');
+        }
+        let again = assemble(&reassembled, 0x1000).unwrap();
+        assert_eq!(again.image, program.image, "Round trip changed the code:\n{}", reassembled);
+    }
+
+    #[test]
+    fn test_listing() {
+        let program = assemble("_start: li a0, 1\nloop: j loop", 0x4).unwrap();
+        let text = listing(program.base, &program.image, &program.symbols);
+        assert_eq!(text, "\n00000004 <_start>:\n       4:\t00100513\tli a0, 1\n\n00000008 <loop>:\n       8:\t0000006f\tj 0x8 <loop>\n");
+    }
+}
@@ -0,0 +1,259 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Minimal ELF32 reader. We only need the loadable segments, the entry point,
+// the symbol table and the DWARF sections for coverage, so sections are only
+// looked at to find those.
+
+use std::collections::HashMap;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 0xF3;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+pub struct Segment {
+    pub address: u32, // Physical address, like spike loads them
+    pub data: Vec<u8>,
+    pub mem_size: u32, // Anything past data is zero filled (.bss)
+}
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: HashMap<String, u32>,
+    pub debug_sections: HashMap<String, Vec<u8>>, // .debug_* by name, empty without debug info
+}
+
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(&ELF_MAGIC)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, String> {
+    image.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| format!("ELF truncated at offset 0x{:x}", offset))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, String> {
+    image.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| format!("ELF truncated at offset 0x{:x}", offset))
+}
+
+fn read_bytes(image: &[u8], offset: u32, size: u32) -> Result<Vec<u8>, String> {
+    image.get(offset as usize..offset as usize + size as usize)
+        .map(|bytes| bytes.to_vec())
+        .ok_or_else(|| format!("ELF truncated at offset 0x{:x}", offset))
+}
+
+// Reads a zero terminated string out of a string table
+fn read_str(table: &[u8], offset: u32) -> String {
+    let bytes = table.get(offset as usize..).unwrap_or(&[]);
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+impl Elf {
+    pub fn parse(image: &[u8]) -> Result<Elf, String> {
+        if !is_elf(image) {
+            return Err("Not an ELF file".to_string());
+        }
+        if image.get(4) != Some(&ELFCLASS32) || image.get(5) != Some(&ELFDATA2LSB) {
+            return Err("Only little endian ELF32 files are supported".to_string());
+        }
+        if read_u16(image, 18)? != EM_RISCV {
+            return Err("ELF file is not a RISC-V executable".to_string());
+        }
+
+        let entry = read_u32(image, 24)?;
+        let ph_offset = read_u32(image, 28)? as usize;
+        let sh_offset = read_u32(image, 32)? as usize;
+        let ph_entry_size = read_u16(image, 42)? as usize;
+        let ph_count = read_u16(image, 44)? as usize;
+        let sh_entry_size = read_u16(image, 46)? as usize;
+        let sh_count = read_u16(image, 48)? as usize;
+        let sh_names = read_u16(image, 50)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..ph_count {
+            let header = ph_offset + i * ph_entry_size;
+            if read_u32(image, header)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(image, header + 4)?;
+            let address = read_u32(image, header + 12)?;
+            let file_size = read_u32(image, header + 16)?;
+            let mem_size = read_u32(image, header + 20)?;
+            segments.push(Segment {
+                address,
+                data: read_bytes(image, offset, file_size)?,
+                mem_size,
+            });
+        }
+
+        // Section headers: name, type, flags, address, offset, size, link, info, align, entry size
+        let mut headers = Vec::new();
+        for i in 0..sh_count {
+            let header = sh_offset + i * sh_entry_size;
+            let mut fields = [0u32; 10];
+            for (j, field) in fields.iter_mut().enumerate() {
+                *field = read_u32(image, header + j * 4)?;
+            }
+            headers.push(fields);
+        }
+
+        let mut symbols = HashMap::new();
+        for header in headers.iter().filter(|header| header[1] == SHT_SYMTAB) {
+            let table = read_bytes(image, header[4], header[5])?;
+            let strings = match headers.get(header[6] as usize) {
+                Some(strings) => read_bytes(image, strings[4], strings[5])?,
+                None => continue,
+            };
+            // Symbol entries: name, value, size, info, other, section index
+            for symbol in table.chunks_exact(16) {
+                let name = read_str(&strings, read_u32(symbol, 0)?);
+                if !name.is_empty() {
+                    symbols.insert(name, read_u32(symbol, 4)?);
+                }
+            }
+        }
+
+        let mut debug_sections = HashMap::new();
+        if let Some(names) = headers.get(sh_names) {
+            let names = read_bytes(image, names[4], names[5])?;
+            for header in &headers {
+                let name = read_str(&names, header[0]);
+                if name.starts_with(".debug_") {
+                    debug_sections.insert(name, read_bytes(image, header[4], header[5])?);
+                }
+            }
+        }
+
+        Ok(Elf { entry, segments, symbols, debug_sections })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    // Lowest address any segment gets loaded to
+    pub fn lowest_address(&self) -> u32 {
+        self.segments.iter().map(|segment| segment.address).min().unwrap_or(0)
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+pub mod tests {
+    use crate::elf::*;
+
+    // Builds a tiny ELF with one loadable segment and a symbol table
+    pub fn build_elf(entry: u32, address: u32, code: &[u8], symbols: &[(&str, u32)]) -> Vec<u8> {
+        build_elf_with_sections(entry, address, code, symbols, &[])
+    }
+
+    // Same, with more sections that aren't loaded, like debug info
+    pub fn build_elf_with_sections(entry: u32, address: u32, code: &[u8], symbols: &[(&str, u32)], extra: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 16]; // Symbol 0 is always the null symbol
+        for (name, value) in symbols {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&[0u8; 8]);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+
+        // Name, type, flags, address, link, info, align, entry size and contents of every section after the null one
+        let mut contents: Vec<(&str, [u32; 8], Vec<u8>)> = vec![
+            (".text", [1, 6, address, 0, 0, 4, 0, 0], code.to_vec()),
+            (".symtab", [SHT_SYMTAB, 0, 0, 3, 1, 4, 16, 0], symtab),
+            (".strtab", [3, 0, 0, 0, 0, 1, 0, 0], strtab),
+        ];
+        for (name, data) in extra {
+            contents.push((name, [1, 0, 0, 0, 0, 1, 0, 0], data.clone()));
+        }
+        let mut shstrtab = vec![0u8];
+        let mut name_offsets = Vec::new();
+        for (name, _, _) in contents.iter().map(|(name, fields, data)| (*name, fields, data)).chain([(".shstrtab", &[3, 0, 0, 0, 0, 1, 0, 0], &Vec::new())]) {
+            name_offsets.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+        contents.push((".shstrtab", [3, 0, 0, 0, 0, 1, 0, 0], shstrtab));
+
+        let code_offset = 52 + 32;
+        let mut offset = code_offset;
+        let mut sections = vec![[0u32; 10]];
+        for ((_, fields, data), name) in contents.iter().zip(&name_offsets) {
+            let [kind, flags, address, link, info, align, entry_size, _] = *fields;
+            sections.push([*name, kind, flags, address, offset as u32, data.len() as u32, link, info, align, entry_size]);
+            offset += data.len();
+        }
+        let sh_offset = offset;
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&ELF_MAGIC);
+        image.extend_from_slice(&[ELFCLASS32, ELFDATA2LSB, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        image.extend_from_slice(&EM_RISCV.to_le_bytes());
+        image.extend_from_slice(&1u32.to_le_bytes());
+        image.extend_from_slice(&entry.to_le_bytes());
+        image.extend_from_slice(&52u32.to_le_bytes()); // Program headers
+        image.extend_from_slice(&(sh_offset as u32).to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        for half in [52u16, 32, 1, 40, sections.len() as u16, sections.len() as u16 - 1] {
+            image.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [PT_LOAD, code_offset as u32, address, address, code.len() as u32, code.len() as u32 + 16, 5, 4] {
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+        for (_, _, data) in &contents {
+            image.extend_from_slice(data);
+        }
+        for section in sections {
+            for word in section {
+                image.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_parse() {
+        let image = build_elf(0x80000004, 0x80000000, &[0x13, 0, 0, 0, 0x6F, 0, 0, 0], &[("tohost", 0x80001000), ("_start", 0x80000000)]);
+        assert!(is_elf(&image));
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.entry, 0x80000004);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].address, 0x80000000);
+        assert_eq!(elf.segments[0].data, vec![0x13, 0, 0, 0, 0x6F, 0, 0, 0]);
+        assert_eq!(elf.segments[0].mem_size, 24);
+        assert_eq!(elf.symbol("tohost"), Some(0x80001000));
+        assert_eq!(elf.symbol("_start"), Some(0x80000000));
+        assert_eq!(elf.symbol("fromhost"), None);
+        assert_eq!(elf.lowest_address(), 0x80000000);
+        assert!(elf.debug_sections.is_empty());
+    }
+
+    #[test]
+    fn test_debug_sections() {
+        let image = build_elf_with_sections(0, 0, &[0; 4], &[], &[(".debug_line", vec![1, 2, 3]), (".comment", vec![4])]);
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.debug_sections.len(), 1);
+        assert_eq!(elf.debug_sections[".debug_line"], vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Elf::parse(b"not an elf").is_err());
+        let mut image = build_elf(0, 0, &[], &[]);
+        image[18] = 0x3E; // x86-64
+        assert!(Elf::parse(&image).is_err());
+        let image = build_elf(0, 0, &[], &[]);
+        assert!(Elf::parse(&image[..40]).is_err());
+    }
+}
@@ -0,0 +1,705 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// GDB remote serial protocol stub, so the VM can be debugged with
+//     riscv64-unknown-elf-gdb -ex "target remote localhost:1234" program.elf
+// Packets look like $<data>#<checksum>, every one gets acknowledged with '+'
+// until gdb switches to no-ack mode. A lone 0x03 byte is Ctrl-C.
+//
+// The target description only has the integer registers and pc: the VM has
+// no CSR file and no FP registers, so there is nothing else to show.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use crate::cpu::Trap;
+use crate::cpu::register::REG_ALIASES;
+use crate::cpu::memory::watch::{WatchKind, Watchpoint};
+use crate::machine::{Machine, StopReason};
+
+const REG_PC: usize = 32;
+const PACKET_SIZE: usize = 0x4000;
+
+// How many instructions run between checks for Ctrl-C while continuing
+const INTERRUPT_POLL: u64 = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// What the stub does after a packet
+#[derive(PartialEq, Debug)]
+pub enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+// The connection gdb talks over
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+pub struct GdbStub<'a> {
+    machine: &'a mut Machine,
+    last_stop: String,
+    exited: bool,
+    no_ack: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(machine: &'a mut Machine) -> Self {
+        Self {
+            machine,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            exited: false,
+            no_ack: false,
+        }
+    }
+
+    // Serves one gdb session. Returns true if gdb detached and the program should keep running.
+    pub fn serve<C: Connection>(&mut self, connection: &mut C) -> std::io::Result<bool> {
+        loop {
+            let packet = match read_packet(connection, self.no_ack)? {
+                Some(packet) => packet,
+                None => return Ok(false), // gdb went away
+            };
+            if packet == "\x03" {
+                // Ctrl-C while the target is already stopped
+                send_packet(connection, &format!("S{:02x}", SIGINT))?;
+                continue;
+            }
+            match self.handle(&packet) {
+                Action::Reply(reply) => {
+                    send_packet(connection, &reply)?;
+                    // The OK still gets acknowledged, everything after it doesn't
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                }
+                Action::Step => {
+                    let stop = self.resume(true, || false);
+                    send_packet(connection, &stop)?;
+                }
+                Action::Continue => {
+                    connection.set_nonblocking(true)?;
+                    let stop = self.resume(false, || interrupted(connection));
+                    connection.set_nonblocking(false)?;
+                    send_packet(connection, &stop)?;
+                }
+                Action::Detach => {
+                    send_packet(connection, "OK")?;
+                    return Ok(!self.exited);
+                }
+                Action::Kill => return Ok(false),
+            }
+        }
+    }
+
+    // Handles a single packet, without the framing
+    pub fn handle(&mut self, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        let (command, args) = match packet.char_indices().nth(1) {
+            Some((index, _)) => packet.split_at(index),
+            None => (packet, ""),
+        };
+        match command {
+            "?" => Action::Reply(self.last_stop.clone()),
+            "g" => {
+                let mut registers = String::new();
+                for i in 0..=REG_PC {
+                    registers.push_str(&hex_u32(self.read_register(i)));
+                }
+                Action::Reply(registers)
+            }
+            "G" => {
+                for i in 0..=REG_PC {
+                    match args.get(i * 8..i * 8 + 8).and_then(parse_register) {
+                        Some(value) => self.write_register(i, value),
+                        None => return reply("E01"),
+                    }
+                }
+                reply("OK")
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(register) if register <= REG_PC => Action::Reply(hex_u32(self.read_register(register))),
+                _ => reply("E01"),
+            },
+            "P" => {
+                let register = args.split_once('=')
+                    .and_then(|(register, value)| Some((usize::from_str_radix(register, 16).ok()?, parse_register(value)?)));
+                match register {
+                    Some((register, value)) if register <= REG_PC => {
+                        self.write_register(register, value);
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            // Reads don't count as guest accesses: no watchpoints, no access log, no devices
+            "m" => match parse_address_length(args) {
+                Some((address, length)) if self.is_mapped(address, length) => {
+                    Action::Reply((0..length).map(|i| format!("{:02x}", self.machine.cpu.memory.peek_u8(address + i))).collect())
+                }
+                _ => reply("E14"),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| Some((parse_address_length(range)?, decode_hex(data)?)));
+                match parsed {
+                    Some(((address, length), data)) if data.len() == length as usize && self.is_mapped(address, length) => {
+                        // ROM is mapped but refuses the store
+                        let memory = &mut self.machine.cpu.memory;
+                        let result = data.iter().enumerate().try_for_each(|(i, byte)| memory.store(address + i as u32, 1, *byte as u32));
+                        // A watchpoint the debugger's own write hit isn't the guest's
+                        memory.take_watch_hit();
+                        match result {
+                            Ok(()) => reply("OK"),
+                            Err(_) => reply("E14"),
+                        }
+                    }
+                    _ => reply("E14"),
+                }
+            }
+            "c" | "s" => {
+                if let Some(address) = parse_hex_u32(args) {
+                    self.machine.cpu.set_pc(address);
+                }
+                if command == "c" { Action::Continue } else { Action::Step }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            // Reverse step and continue, which don't need Ctrl-C since they can't go back forever
+            "b" => match args {
+                "s" => {
+                    let reason = self.machine.reverse_step(1);
+                    Action::Reply(self.stop_reply(reason))
+                }
+                "c" => {
+                    let reason = self.machine.reverse_continue();
+                    Action::Reply(self.stop_reply(reason))
+                }
+                _ => reply(""),
+            },
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "H" | "T" => reply("OK"), // There is only one thread
+            _ => self.handle_query(packet),
+        }
+    }
+
+    fn handle_query(&mut self, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        if packet.starts_with("qSupported") {
+            return Action::Reply(format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+;ReverseStep+;ReverseContinue+", PACKET_SIZE));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            return match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + length as usize).min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    Action::Reply(format!("{}{}", more, &xml[start..end]))
+                }
+                None => reply("E01"),
+            };
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            // Only one thread, so the first action is the one that counts
+            return match actions.chars().next() {
+                Some('c') | Some('C') => Action::Continue,
+                Some('s') | Some('S') => Action::Step,
+                _ => reply("E01"),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => reply("OK"),
+            "vCont?" => reply("vCont;c;C;s;S"),
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "vKill;1" => Action::Kill,
+            _ => reply(""), // Empty reply: not supported
+        }
+    }
+
+    // Z0/Z1 breakpoints, Z2 write, Z3 read and Z4 access watchpoints
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let mut fields = args.split([',', ';']);
+        let kind = fields.next();
+        let address = fields.next().and_then(parse_hex_u32);
+        let length = fields.next().and_then(parse_hex_u32);
+        let (Some(kind), Some(address), Some(length)) = (kind, address, length) else {
+            return Action::Reply("E01".to_string());
+        };
+        let watch = match kind {
+            "0" | "1" => {
+                // Software and hardware breakpoints work the same way here
+                if insert {
+                    self.machine.add_breakpoint(address);
+                } else {
+                    self.machine.remove_breakpoint(address);
+                }
+                return Action::Reply("OK".to_string());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Action::Reply(String::new()),
+        };
+        let watchpoint = Watchpoint::new(watch, address, length);
+        if insert {
+            self.machine.add_watchpoint(watchpoint);
+        } else {
+            self.machine.remove_watchpoint(watchpoint);
+        }
+        Action::Reply("OK".to_string())
+    }
+
+    // Runs until something stops the machine and returns the stop reply for gdb
+    pub fn resume(&mut self, single_step: bool, mut interrupted: impl FnMut() -> bool) -> String {
+        let reason = if single_step {
+            self.machine.step(1)
+        } else {
+            loop {
+                match self.machine.step(INTERRUPT_POLL) {
+                    StopReason::Stepped if interrupted() => break StopReason::Paused,
+                    StopReason::Stepped => {}
+                    reason => break reason,
+                }
+            }
+        };
+        self.stop_reply(reason)
+    }
+
+    // The stop reply for gdb, also remembered for '?'
+    fn stop_reply(&mut self, reason: StopReason) -> String {
+        let stop = match reason {
+            StopReason::Stepped => format!("S{:02x}", SIGTRAP),
+            StopReason::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Watchpoint(hit) => {
+                let name = match hit.watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.address)
+            }
+            StopReason::Trap(Trap::IllegalInstruction(_)) => format!("S{:02x}", SIGILL),
+            StopReason::Trap(_) => format!("S{:02x}", SIGSEGV),
+            StopReason::Halted(Some(exit_code)) => {
+                self.exited = true;
+                // GDB only takes 8 bits, a status past them mustn't turn into success
+                format!("W{:02x}", exit_code.min(0xff))
+            }
+            StopReason::Halted(None) => format!("S{:02x}", SIGTRAP), // ecall or ebreak
+            StopReason::BudgetExhausted | StopReason::Paused => format!("S{:02x}", SIGINT),
+            StopReason::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+        };
+        self.last_stop = stop.clone();
+        stop
+    }
+
+    fn read_register(&self, register: usize) -> u32 {
+        if register == REG_PC {
+            self.machine.cpu.get_pc()
+        } else {
+            self.machine.cpu.registers.get_register(register as u8)
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u32) {
+        if register == REG_PC {
+            self.machine.cpu.set_pc(value);
+        } else {
+            self.machine.cpu.registers.set_register(register as u8, value);
+        }
+    }
+
+    // Every byte in RAM or ROM, there can be gaps between regions
+    fn is_mapped(&self, address: u32, length: u32) -> bool {
+        (0..length).all(|i| address.checked_add(i).is_some_and(|address| self.machine.cpu.memory.contains(address)))
+    }
+}
+
+// Waits for gdb on a TCP address like "localhost:1234"
+pub fn serve_tcp(machine: &mut Machine, address: &str) -> std::io::Result<bool> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+    let (mut stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(machine).serve(&mut stream)
+}
+
+// Same, over a Unix socket at path
+#[cfg(unix)]
+pub fn serve_unix(machine: &mut Machine, path: &str) -> std::io::Result<bool> {
+    let _ = std::fs::remove_file(path);
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    eprintln!("Waiting for GDB on {}", path);
+    let (mut stream, _) = listener.accept()?;
+    let result = GdbStub::new(machine).serve(&mut stream);
+    let _ = std::fs::remove_file(path);
+    result
+}
+
+pub fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "<architecture>riscv:rv32</architecture>\n",
+        "<feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    ));
+    for (i, name) in REG_ALIASES.iter().enumerate() {
+        let kind = match i {
+            1 => "code_ptr",
+            2 => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n", name, kind, i));
+    }
+    xml.push_str(&format!("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n", REG_PC));
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+// Non blocking check for a Ctrl-C from gdb
+fn interrupted<C: Connection>(connection: &mut C) -> bool {
+    let mut byte = [0u8];
+    loop {
+        match connection.read(&mut byte) {
+            Ok(1) if byte[0] == 0x03 => return true,
+            Ok(1) => continue, // Stray acks
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            _ => return false,
+        }
+    }
+}
+
+// Reads the next packet. Returns None once the connection is closed.
+pub fn read_packet<C: Read + Write>(connection: &mut C, no_ack: bool) -> std::io::Result<Option<String>> {
+    let mut byte = [0u8];
+    loop {
+        if connection.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            0x03 => return Ok(Some("\x03".to_string())),
+            b'$' => {}
+            _ => continue, // Acks and noise between packets
+        }
+        let mut data = Vec::new();
+        loop {
+            if connection.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        connection.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+        let valid = expected == Some(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+        if !no_ack {
+            connection.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if valid {
+            return Ok(Some(unescape(&data)));
+        }
+    }
+}
+
+pub fn send_packet<C: Write>(connection: &mut C, data: &str) -> std::io::Result<()> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data.bytes() {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    let checksum = escaped.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    connection.write_all(b"$")?;
+    connection.write_all(&escaped)?;
+    connection.write_all(format!("#{:02x}", checksum).as_bytes())?;
+    connection.flush()
+}
+
+fn unescape(data: &[u8]) -> String {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for byte in data {
+        if escaped {
+            bytes.push(byte ^ 0x20);
+            escaped = false;
+        } else if *byte == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(*byte);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Registers go over the wire in target byte order, little endian for us
+fn hex_u32(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_register(text: &str) -> Option<u32> {
+    Some(u32::from_le_bytes(decode_hex(text)?.try_into().ok()?))
+}
+
+// Addresses and lengths are plain big endian hex numbers
+fn parse_hex_u32(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// "addr,length" as used by m, M and qXfer
+fn parse_address_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::gdb::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::cpu::htif::Htif;
+    use crate::cpu::register::*;
+
+    fn machine_with(source: &str) -> Machine {
+        let program = assemble(source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        Machine::new(cpu)
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            other => panic!("Expected a reply to {}, got {:?}", packet, other),
+        }
+    }
+
+    // In memory connection: reads come from input, writes go to output
+    struct Pipe {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buffer)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_packet_framing() {
+        let mut pipe = Pipe { input: std::io::Cursor::new(b"+$g#67$m0,4#00$m0,4#fd".to_vec()), output: Vec::new() };
+        assert_eq!(read_packet(&mut pipe, false).unwrap(), Some("g".to_string()));
+        // The one with the bad checksum gets a '-' and is skipped
+        assert_eq!(read_packet(&mut pipe, false).unwrap(), Some("m0,4".to_string()));
+        assert_eq!(pipe.output, b"+-+");
+        assert_eq!(read_packet(&mut pipe, false).unwrap(), None);
+
+        let mut output = Vec::new();
+        send_packet(&mut output, "OK").unwrap();
+        assert_eq!(output, b"$OK#9a");
+        output.clear();
+        send_packet(&mut output, "a#b").unwrap();
+        assert_eq!(output, b"$a}\x03b#43");
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut machine = machine_with("nop");
+        machine.cpu.registers.set_register(REG_SP, 0x12345678);
+        let mut stub = GdbStub::new(&mut machine);
+        let registers = reply(&mut stub, "g");
+        assert_eq!(registers.len(), 33 * 8);
+        assert_eq!(&registers[16..24], "78563412", "Registers should be sent little endian");
+        assert_eq!(&registers[256..264], "04000000", "pc comes last");
+
+        assert_eq!(reply(&mut stub, "P20=00010000"), "OK");
+        assert_eq!(reply(&mut stub, "pa"), "00000000");
+        assert_eq!(reply(&mut stub, "Pa=efbeadde"), "OK");
+        assert_eq!(reply(&mut stub, "pa"), "efbeadde");
+        assert_eq!(reply(&mut stub, "p21"), "E01");
+        assert_eq!(stub.machine.cpu.get_pc(), 0x100);
+        assert_eq!(stub.machine.cpu.registers.get_register(REG_A0), 0xDEADBEEF);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut machine = machine_with("nop");
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(reply(&mut stub, "m4,4"), "13000000");
+        assert_eq!(reply(&mut stub, "M100,3:616263"), "OK");
+        assert_eq!(reply(&mut stub, "m100,4"), "61626300");
+        assert_eq!(reply(&mut stub, "M100,2:61"), "E14", "Length has to match the data");
+        assert_eq!(reply(&mut stub, "mffffff00,4"), "E14");
+
+        // ROM reads but can't be written, the gap between it and RAM is neither
+        stub.machine.cpu.memory.bus.add_region("rom", 0x20_1000, 0x100, 8, true);
+        stub.machine.cpu.memory.bus.load_u8(0x20_1000, 0x5a);
+        assert_eq!(reply(&mut stub, "m201000,1"), "5a");
+        assert_eq!(reply(&mut stub, "M201000,1:00"), "E14");
+        assert_eq!(reply(&mut stub, "m201000,1"), "5a");
+        assert_eq!(reply(&mut stub, "m1ffffe,1003"), "E14");
+
+        // A debugger read isn't a guest access
+        let watchpoint = Watchpoint { kind: WatchKind::Access, address: 0x100, length: 4, size: None, value: None };
+        stub.machine.add_watchpoint(watchpoint);
+        assert_eq!(reply(&mut stub, "m100,4"), "61626300");
+        assert_eq!(stub.machine.cpu.memory.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn test_breakpoint_and_step() {
+        let mut machine = machine_with("li a0, 1\nli a0, 2\nli a0, 3\nebreak");
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(reply(&mut stub, "Z0,c,4"), "OK");
+        assert_eq!(stub.handle("c"), Action::Continue);
+        assert_eq!(stub.resume(false, || false), "T05swbreak:;");
+        assert_eq!(stub.machine.cpu.get_pc(), 0xC);
+        assert_eq!(stub.machine.cpu.registers.get_register(REG_A0), 2);
+        assert_eq!(reply(&mut stub, "?"), "T05swbreak:;");
+
+        // Stepping off the breakpoint executes it
+        assert_eq!(stub.handle("s"), Action::Step);
+        assert_eq!(stub.resume(true, || false), "S05");
+        assert_eq!(stub.machine.cpu.registers.get_register(REG_A0), 3);
+
+        assert_eq!(reply(&mut stub, "z0,c,4"), "OK");
+        assert_eq!(stub.resume(false, || false), "S05", "ebreak stops with SIGTRAP");
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut machine = machine_with("li a0, 5\nsw a0, 0x100(zero)\nlw a1, 0x100(zero)\nebreak");
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(reply(&mut stub, "Z2,100,4"), "OK");
+        assert_eq!(reply(&mut stub, "Z3,102,1"), "OK");
+        assert_eq!(stub.resume(false, || false), "T05watch:100;");
+        assert_eq!(stub.machine.cpu.get_pc(), 0xC, "Watchpoints stop after the access");
+        assert_eq!(stub.resume(false, || false), "T05rwatch:100;");
+        assert_eq!(stub.machine.cpu.registers.get_register(REG_A1), 5);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut machine = machine_with("li a0, 1\nli a0, 2\nli a0, 3\nebreak");
+        machine.enable_reverse(2, 16);
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(stub.resume(false, || false), "S05");
+        assert_eq!(reply(&mut stub, "bs"), "S05");
+        assert_eq!(stub.machine.cpu.get_pc(), 0xC);
+        assert_eq!(stub.machine.cpu.registers.get_register(REG_A0), 2);
+
+        assert_eq!(reply(&mut stub, "Z0,8,4"), "OK");
+        assert_eq!(reply(&mut stub, "bc"), "T05swbreak:;");
+        assert_eq!(stub.machine.cpu.get_pc(), 0x8);
+        assert_eq!(reply(&mut stub, "bc"), "T05replaylog:begin;");
+        assert_eq!(stub.machine.cpu.get_pc(), 0x4);
+        assert_eq!(reply(&mut stub, "?"), "T05replaylog:begin;");
+    }
+
+    #[test]
+    fn test_exit_and_interrupt() {
+        let mut machine = machine_with("spin: j spin");
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(stub.resume(false, || true), "S02");
+
+        let mut machine = machine_with("li a0, 7\nsw a0, 0x200(zero)\nsw zero, 0x204(zero)");
+        machine.cpu.enable_htif(Htif::new(0x200, None));
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(stub.resume(false, || false), "W03");
+
+        let mut machine = machine_with("li a0, 0x201\nsw a0, 0x200(zero)\nsw zero, 0x204(zero)");
+        machine.cpu.enable_htif(Htif::new(0x200, None));
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(stub.resume(false, || false), "Wff", "Status 256 shouldn't look like success");
+
+        let mut machine = machine_with(".word 0x7f");
+        let mut stub = GdbStub::new(&mut machine);
+        assert_eq!(stub.resume(true, || false), "S04", "Illegal instructions should stop with SIGILL");
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut machine = machine_with("nop");
+        let mut stub = GdbStub::new(&mut machine);
+        assert!(reply(&mut stub, "qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+        let xml = target_xml();
+        let first = reply(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..0x10]));
+        let rest = reply(&mut stub, &format!("qXfer:features:read:target.xml:10,{:x}", xml.len()));
+        assert_eq!(rest, format!("l{}", &xml[0x10..]));
+        assert!(xml.contains("<reg name=\"a0\" bitsize=\"32\" type=\"int\" regnum=\"10\"/>"));
+        assert_eq!(reply(&mut stub, "qUnknownThing"), "");
+    }
+
+    #[test]
+    fn test_serve_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut machine = machine_with("li a0, 42\nebreak");
+        std::thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (mut stream, _) = listener.accept().unwrap();
+                GdbStub::new(&mut machine).serve(&mut stream).unwrap()
+            });
+            let mut client = TcpStream::connect(address).unwrap();
+            let mut exchange = |packet: &str| {
+                send_packet(&mut client, packet).unwrap();
+                let mut ack = [0u8];
+                client.read_exact(&mut ack).unwrap();
+                assert_eq!(ack[0], b'+');
+                read_packet(&mut client, false).unwrap().unwrap()
+            };
+            assert_eq!(exchange("c"), "S05");
+            assert_eq!(exchange("pa"), "2a000000");
+            assert_eq!(exchange("D"), "OK");
+            assert!(server.join().unwrap(), "Detaching should let the program keep running");
+        });
+    }
+}
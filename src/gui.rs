@@ -1,55 +1,28 @@
 use eframe::egui;
 use eframe::egui::{Color32, Stroke};
-use crate::cpu::CPU;
+use tiny_vm::cpu::memory::watch::{WatchKind, Watchpoint};
+use tiny_vm::machine::{Machine, StopReason};
+use tiny_vm::cpu::register::REG_ALIASES;
+use tiny_vm::disasm::disassemble;
 
-pub(crate) fn gui(cpu: CPU) -> eframe::Result {
+pub(crate) fn gui(machine: Machine) -> eframe::Result {
     let options = eframe::NativeOptions::default();
     eframe::run_native(
         "VM Control Panel",
         options,
-        Box::new(|_cc| Ok(Box::new(VmApp::new(cpu)) as Box<dyn eframe::App>)),
+        Box::new(|_cc| Ok(Box::new(VmApp::new(machine)) as Box<dyn eframe::App>)),
     )
 }
 
-const REG_ALIASES: [&str; 32] = [
-    "zero",
-    "ra",
-    "sp",
-    "gp",
-    "tp",
-    "t0",
-    "t1",
-    "t2",
-    "s0",
-    "s1",
-    "s2",
-    "a0",
-    "a1",
-    "a2",
-    "a3",
-    "a4",
-    "a5",
-    "a6",
-    "a7",
-    "s3",
-    "s4",
-    "s5",
-    "s6",
-    "s7",
-    "s8",
-    "s9",
-    "s10",
-    "s11",
-    "t3",
-    "t4",
-    "t5",
-    "t6"
-];
-
 struct VmApp {
     register_aliases: bool,
     active_tab: Tab,
-    cpu: CPU,
+    machine: Machine,
+    running: bool,
+    last_stop: Option<StopReason>,
+    watch_text: String,
+    watch_kind: WatchKind,
+    watch_error: Option<String>,
 }
 
 // Define an enum to represent the tabs
@@ -57,13 +30,15 @@ struct VmApp {
 enum Tab {
     Registers,
     Memory,
+    Disassembly,
 }
 
 
 impl VmApp {
 
-    pub fn new(cpu: CPU) -> Self{
-        VmApp { register_aliases: true, active_tab: Tab::Registers, cpu }
+    pub fn new(machine: Machine) -> Self{
+        VmApp { register_aliases: true, active_tab: Tab::Registers, machine, running: false, last_stop: None,
+            watch_text: String::new(), watch_kind: WatchKind::Write, watch_error: None }
     }
 
     // TODO: Draw grid cell lines.
@@ -98,9 +73,9 @@ impl VmApp {
                 } else {
                     ui.label(format!(" x{} ", i));
                 }
-                let value = format!(" 0x{:0>4} ", self.cpu.registers.get_register(i as u8));
-                let ascii = format!(" {} ", self.cpu.registers.get_register(i as u8) as u8 as char);
-                let dec = format!(" {} ", self.cpu.registers.get_register(i as u8) as u8);
+                let value = format!(" 0x{:0>4} ", self.machine.cpu.registers.get_register(i as u8));
+                let ascii = format!(" {} ", self.machine.cpu.registers.get_register(i as u8) as u8 as char);
+                let dec = format!(" {} ", self.machine.cpu.registers.get_register(i as u8) as u8);
                 ui.label(value);
                 ui.label(ascii);
                 ui.label(dec);
@@ -155,7 +130,7 @@ impl VmApp {
         const PAGE_SIZE: usize = 16;
 
         // Total number of pages
-        let total_pages = self.cpu.memory.get_memory().len() / PAGE_SIZE;
+        let total_pages = self.machine.cpu.memory.get_memory().len() / PAGE_SIZE;
 
         // Height of one memory row in pixels
         let row_height = 18.0;
@@ -168,7 +143,7 @@ impl VmApp {
                     let end = start + PAGE_SIZE;
 
                     // Safely get the memory chunk for the current page
-                    if let Some(chunk) = self.cpu.memory.get_memory().get(start..end) {
+                    if let Some(chunk) = self.machine.cpu.memory.get_memory().get(start..end) {
                         ui.horizontal(|ui| {
                             ui.label(format!("{:04X}: ", start));
                             for mem_page in chunk {
@@ -183,12 +158,131 @@ impl VmApp {
     }
 }
 
+impl VmApp {
+    // Instructions run per frame while running, so the window stays responsive
+    const RUN_CHUNK: u64 = 100_000;
+
+    fn show_controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!self.running, egui::Button::new("Reverse continue")).clicked() {
+                self.last_stop = Some(self.machine.reverse_continue());
+            }
+            if ui.add_enabled(!self.running, egui::Button::new("Step back")).clicked() {
+                self.last_stop = Some(self.machine.reverse_step(1));
+            }
+            if ui.add_enabled(!self.running, egui::Button::new("Step")).clicked() {
+                self.last_stop = Some(self.machine.step(1));
+            }
+            if ui.add_enabled(!self.running, egui::Button::new("Run")).clicked() {
+                self.running = true;
+                self.last_stop = None;
+            }
+            if ui.add_enabled(self.running, egui::Button::new("Pause")).clicked() {
+                self.running = false;
+                self.last_stop = Some(StopReason::Paused);
+            }
+            ui.label(format!("PC: 0x{:08X}", self.machine.cpu.get_pc()));
+            ui.label(format!("Instructions: {}", self.machine.retired()));
+            match &self.last_stop {
+                Some(stop) if !self.running => ui.label(stop.to_string()),
+                _ if self.running => ui.label("Running"),
+                _ => ui.label(""),
+            };
+        });
+        self.show_watchpoints(ui);
+    }
+
+    // "0x8000", "0x8000..0x8010" or "*0x8000 == 0xdeadbeef"
+    fn show_watchpoints(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Watch:");
+            egui::ComboBox::from_id_salt("watch_kind")
+                .selected_text(format!("{:?}", self.watch_kind))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.watch_kind, WatchKind::Write, "Write");
+                    ui.selectable_value(&mut self.watch_kind, WatchKind::Read, "Read");
+                    ui.selectable_value(&mut self.watch_kind, WatchKind::Access, "Access");
+                });
+            ui.text_edit_singleline(&mut self.watch_text);
+            if ui.button("Add").clicked() {
+                match Watchpoint::parse(self.watch_kind, &self.watch_text) {
+                    Ok(watchpoint) => {
+                        self.machine.add_watchpoint(watchpoint);
+                        self.watch_text.clear();
+                        self.watch_error = None;
+                    }
+                    Err(error) => self.watch_error = Some(error),
+                }
+            }
+            if let Some(error) = &self.watch_error {
+                ui.colored_label(Color32::RED, error);
+            }
+        });
+        let mut removed = None;
+        for watchpoint in self.machine.cpu.memory.watchpoints() {
+            ui.horizontal(|ui| {
+                let mut text = format!("{:?} 0x{:08X}..0x{:08X}", watchpoint.kind, watchpoint.address, watchpoint.address.wrapping_add(watchpoint.length));
+                if let Some(value) = watchpoint.value {
+                    text.push_str(&format!(" == 0x{:X}", value));
+                }
+                ui.monospace(text);
+                if ui.small_button("Remove").clicked() {
+                    removed = Some(*watchpoint);
+                }
+            });
+        }
+        if let Some(watchpoint) = removed {
+            self.machine.remove_watchpoint(watchpoint);
+        }
+    }
+
+    fn run_chunk(&mut self, ctx: &egui::Context) {
+        if !self.running {
+            return;
+        }
+        match self.machine.step(Self::RUN_CHUNK) {
+            StopReason::Stepped => ctx.request_repaint(),
+            stop => {
+                self.running = false;
+                self.last_stop = Some(stop);
+            }
+        }
+    }
+
+    // TODO: Follow jumps / jump to address
+    fn show_disassembly(&mut self, ui: &mut egui::Ui) {
+        // Instructions shown before and after the PC
+        const BEFORE: u32 = 16;
+        const AFTER: u32 = 48;
+
+        let pc = self.machine.cpu.get_pc();
+        let start = pc.saturating_sub(BEFORE * 4);
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for address in (start..=pc.saturating_add(AFTER * 4)).step_by(4) {
+                if !self.machine.cpu.memory.contains(address) || !self.machine.cpu.memory.contains(address + 3) {
+                    continue;
+                }
+                let word = self.machine.cpu.memory.peek_u32(address);
+                let marker = if address == pc { "=>" } else { "  " };
+                let text = format!("{} {:08X}:  {:08X}  {}", marker, address, word, disassemble(word, address));
+                if address == pc {
+                    ui.label(egui::RichText::new(text).monospace().color(Color32::YELLOW));
+                } else {
+                    ui.monospace(text);
+                }
+            }
+        });
+    }
+}
+
 impl eframe::App for VmApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.run_chunk(ctx);
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.label("VM Control Panel");
             });
+            self.show_controls(ui);
 
             egui::TopBottomPanel::top("tabs_panel").show_inside(ui, |ui| {
                 ui.horizontal(|ui| {
@@ -198,6 +292,9 @@ impl eframe::App for VmApp {
                     if ui.button("Memory").clicked() {
                         self.active_tab = Tab::Memory;
                     }
+                    if ui.button("Disassembly").clicked() {
+                        self.active_tab = Tab::Disassembly;
+                    }
                 });
             });
 
@@ -210,6 +307,10 @@ impl eframe::App for VmApp {
                     ui.heading("Memory");
                     self.show_memory(ui);
                 }
+                Tab::Disassembly => {
+                    ui.heading("Disassembly");
+                    self.show_disassembly(ui);
+                }
             }
         });
     }
@@ -0,0 +1,50 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+//! An RV32IM virtual machine to embed in test harnesses and tools.
+//!
+//! The types re-exported here are the stable API: build a [`Machine`] with
+//! [`MachineBuilder`], run or step it, and look at the [`Cpu`] registers and [`Memory`]
+//! through it. Memory mapped devices implement [`Device`] and sit on the [`Bus`], tools that
+//! watch the guest run implement [`Plugin`].
+//!
+//! ```
+//! use tiny_vm::{MachineBuilder, StopReason};
+//!
+//! // li a0, 7; ebreak
+//! let program = vec![0x13, 0x05, 0x70, 0x00, 0x73, 0x00, 0x10, 0x00];
+//! let mut machine = MachineBuilder::new().ram(0x80000000, 0x1000).image(0x80000000, program).build().unwrap();
+//! assert_eq!(machine.run(), StopReason::Halted(None));
+//! assert_eq!(machine.cpu.registers.get_register(10), 7);
+//! ```
+//!
+//! The modules are public for the tiny-vm binary and can change between versions.
+
+#[doc(hidden)] pub mod asm;
+#[doc(hidden)] pub mod batch;
+#[doc(hidden)] pub mod builder;
+#[doc(hidden)] pub mod capi;
+#[doc(hidden)] pub mod compliance;
+#[doc(hidden)] pub mod config;
+#[doc(hidden)] pub mod coverage;
+#[doc(hidden)] pub mod cpu;
+#[doc(hidden)] pub mod disasm;
+#[doc(hidden)] pub mod elf;
+#[doc(hidden)] pub mod gdb;
+#[doc(hidden)] pub mod machine;
+#[doc(hidden)] pub mod monitor;
+#[doc(hidden)] pub mod parse;
+#[doc(hidden)] pub mod plugin;
+#[doc(hidden)] pub mod profile;
+#[doc(hidden)] pub mod stats;
+#[doc(hidden)] pub mod trace;
+
+pub use builder::MachineBuilder;
+pub use cpu::CPU as Cpu;
+pub use cpu::{Isa, Trap};
+pub use cpu::memory::{Access, Memory};
+pub use cpu::memory::bus::{Bus, Device};
+pub use cpu::syscon::{Syscon, SYSCON_SIZE};
+pub use cpu::uart::{Uart, UART_SIZE};
+pub use elf::Elf;
+pub use machine::{Machine, StopReason};
+pub use plugin::{Plugin, PluginId, Subscription};
@@ -0,0 +1,653 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Incremental execution on top of the CPU. Everything that drives the guest
+// (the GUI, the GDB stub, tests) goes through a Machine, which runs for as long as
+// it's asked to and says why it stopped. Unless something has to see every instruction,
+// like a breakpoint or a tracer, the CPU runs a basic block at a time and the machine
+// only checks for a pause in between.
+//
+// With reverse execution enabled the machine also snapshots the CPU every so many
+// instructions. Going back restores the last snapshot before the target and runs the
+// rest of the way again, which gives the same result because the run is deterministic:
+// console input and clock readings come from the CPU's input log the second time.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::cpu::{Snapshot, Trap, CPU};
+use crate::cpu::memory::watch::{WatchHit, Watchpoint};
+use crate::cpu::opcodes::{OP_BRANCH, OP_JAL, OP_JALR};
+use crate::coverage::Coverage;
+use crate::plugin::{Plugin, PluginId, Plugins};
+use crate::profile::Profiler;
+use crate::stats::InstructionMix;
+use crate::trace::CommitLog;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Stepped, // step(n) ran all n instructions
+    Breakpoint(u32),
+    Watchpoint(WatchHit),
+    Trap(Trap),
+    Halted(Option<u32>), // ecall, ebreak or a zero instruction. Holds the exit status if the guest reported one.
+    BudgetExhausted,
+    Paused,
+    HistoryStart, // Went back as far as the snapshots go
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Stepped => write!(f, "Stepped"),
+            StopReason::Breakpoint(address) => write!(f, "Breakpoint at 0x{:08x}", address),
+            StopReason::Watchpoint(hit) => {
+                let access = if hit.write { "Write" } else { "Read" };
+                write!(f, "{} of 0x{:x} at 0x{:08x} hit a watchpoint", access, hit.value, hit.address)
+            }
+            StopReason::Trap(trap) => write!(f, "{}", trap),
+            StopReason::Halted(Some(exit_code)) => write!(f, "Exited with status {}", exit_code),
+            StopReason::Halted(None) => write!(f, "Halted"),
+            StopReason::BudgetExhausted => write!(f, "Instruction budget exhausted"),
+            StopReason::Paused => write!(f, "Paused"),
+            StopReason::HistoryStart => write!(f, "Reached the start of the recorded history"),
+        }
+    }
+}
+
+// Snapshots are taken this often unless configured otherwise. Unchanged pages are
+// shared between snapshots, so keeping plenty of them is cheap.
+pub const SNAPSHOT_INTERVAL: u64 = 100_000;
+pub const SNAPSHOT_LIMIT: usize = 64;
+
+// Snapshots for reverse execution, oldest first
+struct History {
+    interval: u64, // Instructions between snapshots
+    limit: usize, // The oldest snapshot is dropped once there are more
+    snapshots: VecDeque<Snapshot>,
+}
+
+pub struct Machine {
+    pub cpu: CPU,
+    breakpoints: HashSet<u32>,
+    budget: Option<u64>, // Instructions left before run() gives up, None for no limit
+    pause: Arc<AtomicBool>,
+    resume_from: Option<u32>, // Breakpoint we last stopped on, so resuming doesn't hit it again
+    commit_log: Option<CommitLog>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    instruction_mix: Option<InstructionMix>,
+    history: Option<History>, // None unless reverse execution is enabled
+    plugins: Plugins,
+    block_start: bool, // The next instruction starts a basic block
+}
+
+#[allow(dead_code)]
+impl Machine {
+    pub fn new(cpu: CPU) -> Self {
+        Self {
+            cpu,
+            breakpoints: HashSet::new(),
+            budget: None,
+            pause: Arc::new(AtomicBool::new(false)),
+            resume_from: None,
+            commit_log: None,
+            profiler: None,
+            coverage: None,
+            instruction_mix: None,
+            history: None,
+            plugins: Plugins::default(),
+            block_start: true,
+        }
+    }
+
+    // Number of instructions executed so far
+    pub fn retired(&self) -> u64 {
+        self.cpu.instret()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u32) {
+        self.breakpoints.remove(&address);
+    }
+
+    // Sorted by address
+    pub fn breakpoints(&self) -> Vec<u32> {
+        let mut breakpoints: Vec<u32> = self.breakpoints.iter().copied().collect();
+        breakpoints.sort();
+        breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.cpu.memory.add_watchpoint(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.cpu.memory.remove_watchpoint(watchpoint);
+    }
+
+    pub fn set_budget(&mut self, budget: Option<u64>) {
+        self.budget = budget;
+    }
+
+    pub fn set_commit_log(&mut self, commit_log: Option<CommitLog>) {
+        self.commit_log = commit_log;
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn set_instruction_mix(&mut self, mix: Option<InstructionMix>) {
+        self.instruction_mix = mix;
+    }
+
+    pub fn instruction_mix(&self) -> Option<&InstructionMix> {
+        self.instruction_mix.as_ref()
+    }
+
+    // Instrumentation, see plugin.rs. The plugin stays with the machine until removed.
+    pub fn add_plugin<P: Plugin>(&mut self, plugin: P) -> PluginId {
+        let id = self.plugins.add(Box::new(plugin));
+        self.block_start = true;
+        self.cpu.memory.log_accesses(self.plugins.memory);
+        id
+    }
+
+    pub fn remove_plugin(&mut self, id: PluginId) -> Option<Box<dyn Plugin>> {
+        let plugin = self.plugins.remove(id);
+        self.cpu.memory.log_accesses(self.plugins.memory);
+        plugin
+    }
+
+    // None if the id is gone or the plugin isn't a P
+    pub fn plugin<P: Plugin>(&self, id: PluginId) -> Option<&P> {
+        self.plugins.get(id)
+    }
+
+    pub fn plugin_mut<P: Plugin>(&mut self, id: PluginId) -> Option<&mut P> {
+        self.plugins.get_mut(id)
+    }
+
+    // Snapshots every interval instructions from now on and keeps the last limit of them,
+    // so the machine can go back as far as interval * limit instructions.
+    pub fn enable_reverse(&mut self, interval: u64, limit: usize) {
+        let snapshot = self.cpu.snapshot(None);
+        self.history = Some(History { interval, limit: limit.max(1), snapshots: VecDeque::from([snapshot]) });
+    }
+
+    // Swaps in another CPU, like one loaded from a state file. Reverse execution starts
+    // over from here, the snapshots so far belong to the old one.
+    pub fn replace_cpu(&mut self, cpu: CPU) {
+        self.cpu = cpu;
+        self.cpu.memory.log_accesses(self.plugins.memory);
+        self.resume_from = None;
+        self.block_start = true;
+        if let Some(history) = self.history.take() {
+            self.enable_reverse(history.interval, history.limit);
+        }
+    }
+
+    // Setting the flag from another thread stops run() at the next instruction
+    pub fn pause_handle(&self) -> Arc<AtomicBool> {
+        self.pause.clone()
+    }
+
+    // Runs at most count instructions
+    pub fn step(&mut self, count: u64) -> StopReason {
+        self.execute(Some(count), None)
+    }
+
+    // Runs until something stops the machine
+    pub fn run(&mut self) -> StopReason {
+        self.execute(None, None)
+    }
+
+    // Runs until pc reaches address, which is reported like a breakpoint
+    pub fn run_until(&mut self, address: u32) -> StopReason {
+        self.execute(None, Some(address))
+    }
+
+    fn execute(&mut self, count: Option<u64>, until: Option<u32>) -> StopReason {
+        let mut executed = 0;
+        let resume_from = self.resume_from.take();
+        loop {
+            if let Some(exit_code) = self.cpu.exit_code() {
+                // There is nothing left to run once the guest exited
+                return StopReason::Halted(Some(exit_code));
+            }
+            if count == Some(executed) {
+                return StopReason::Stepped;
+            }
+            let pc = self.cpu.get_pc();
+            if (self.breakpoints.contains(&pc) || until == Some(pc)) && !(executed == 0 && resume_from == Some(pc)) {
+                self.resume_from = Some(pc);
+                return StopReason::Breakpoint(pc);
+            }
+            if self.budget == Some(0) {
+                return StopReason::BudgetExhausted;
+            }
+            if self.pause.swap(false, Ordering::Relaxed) {
+                return StopReason::Paused;
+            }
+
+            self.take_snapshot();
+
+            if until.is_none() && self.whole_blocks() {
+                let limit = count.map_or(u64::MAX, |count| count - executed).min(self.budget.unwrap_or(u64::MAX));
+                let before = self.cpu.instret();
+                let halted = self.cpu.step_block(limit);
+                let retired = self.cpu.instret() - before;
+                executed += retired;
+                if let Some(budget) = self.budget.as_mut() {
+                    *budget -= retired;
+                }
+                if halted {
+                    return match self.cpu.trap() {
+                        Some(trap) => StopReason::Trap(trap.clone()),
+                        None => StopReason::Halted(self.cpu.exit_code()),
+                    };
+                }
+                continue;
+            }
+
+            // Whatever the debugger looked at in between doesn't count
+            self.cpu.memory.take_watch_hit();
+            let pending = self.commit_log.as_ref().and_then(|log| log.prepare(&self.cpu, self.cpu.instret()));
+            let instruction = (self.profiler.is_some() || self.coverage.is_some() || self.instruction_mix.is_some() || !self.plugins.is_empty())
+                .then(|| self.cpu.memory.peek_u32(pc));
+            if self.plugins.memory {
+                self.cpu.memory.accesses().unwrap().clear();
+            }
+            if self.plugins.blocks && self.block_start {
+                self.plugins.block(pc);
+            }
+            let halted = self.cpu.step();
+            if let (Some(log), Some(pending)) = (self.commit_log.as_mut(), pending) {
+                // The store to tohost that ends a test still retires, ecall and ebreak don't
+                if !halted || (self.cpu.trap().is_none() && self.cpu.get_pc() != pc) {
+                    log.commit(&self.cpu, pending);
+                }
+            }
+            if halted {
+                return match self.cpu.trap() {
+                    Some(trap) => {
+                        if self.plugins.traps {
+                            self.plugins.trap(pc, trap);
+                        }
+                        StopReason::Trap(trap.clone())
+                    }
+                    None => StopReason::Halted(self.cpu.exit_code()),
+                };
+            }
+            executed += 1;
+            if let (false, Some(instruction)) = (self.plugins.is_empty(), instruction) {
+                self.instrument(pc, instruction);
+            }
+            if let (Some(profiler), Some(instruction)) = (self.profiler.as_mut(), instruction) {
+                profiler.record(pc, instruction, self.cpu.get_pc());
+            }
+            if let (Some(coverage), Some(instruction)) = (self.coverage.as_mut(), instruction) {
+                coverage.record(pc, instruction, self.cpu.get_pc());
+            }
+            if let (Some(mix), Some(instruction)) = (self.instruction_mix.as_mut(), instruction) {
+                mix.record(pc, instruction, self.cpu.get_pc());
+            }
+            if let Some(budget) = self.budget.as_mut() {
+                *budget -= 1;
+            }
+            if let Some(hit) = self.cpu.memory.take_watch_hit() {
+                return StopReason::Watchpoint(hit);
+            }
+        }
+    }
+
+    // Whether a basic block can run in one go, without the machine looking at every
+    // instruction. Pausing and the budget are only checked between blocks then.
+    fn whole_blocks(&self) -> bool {
+        self.breakpoints.is_empty()
+            && self.cpu.memory.watchpoints().is_empty()
+            && self.commit_log.is_none()
+            && self.profiler.is_none()
+            && self.coverage.is_none()
+            && self.instruction_mix.is_none()
+            && self.plugins.is_empty()
+    }
+
+    // Plugin events for the instruction at pc that just retired
+    fn instrument(&mut self, pc: u32, instruction: u32) {
+        if self.plugins.memory {
+            for access in self.cpu.memory.accesses().unwrap().drain(..) {
+                self.plugins.memory(pc, &access);
+            }
+        }
+        if self.plugins.instructions {
+            self.plugins.instruction(&self.cpu, pc, instruction);
+        }
+        let opcode = (instruction & 0x7F) as u8;
+        self.block_start = matches!(opcode, OP_BRANCH | OP_JAL | OP_JALR) || self.cpu.get_pc() != pc.wrapping_add(4);
+    }
+
+    fn take_snapshot(&mut self) {
+        let Some(history) = self.history.as_mut() else {
+            return;
+        };
+        let last = history.snapshots.back();
+        if last.is_some_and(|last| self.cpu.instret() < last.instret + history.interval) {
+            return;
+        }
+        let snapshot = self.cpu.snapshot(last);
+        history.snapshots.push_back(snapshot);
+        if history.snapshots.len() > history.limit {
+            history.snapshots.pop_front();
+        }
+    }
+
+    // Goes back count instructions, or as far as the history goes
+    pub fn reverse_step(&mut self, count: u64) -> StopReason {
+        let Some(start) = self.history.as_ref().map(|history| history.snapshots[0].instret) else {
+            return StopReason::HistoryStart;
+        };
+        let now = self.cpu.instret();
+        let target = now.saturating_sub(count).max(start);
+        let snapshot = self.snapshot_before(target);
+        self.cpu.restore(&snapshot);
+        self.rerun(target, 0);
+        self.resume_from = Some(self.cpu.get_pc());
+        if now >= start.saturating_add(count) { StopReason::Stepped } else { StopReason::HistoryStart }
+    }
+
+    // Goes back to the last point a breakpoint or watchpoint would have stopped the machine
+    pub fn reverse_continue(&mut self) -> StopReason {
+        if self.history.is_none() {
+            return StopReason::HistoryStart;
+        }
+        // Searches one stretch between snapshots at a time, latest first
+        let now = self.cpu.instret();
+        let mut end = now;
+        loop {
+            let snapshot = self.snapshot_before(end.saturating_sub(1));
+            self.cpu.restore(&snapshot);
+            if let Some((position, reason)) = self.rerun(end, now) {
+                self.cpu.restore(&snapshot);
+                self.rerun(position, 0);
+                self.resume_from = Some(self.cpu.get_pc());
+                return reason;
+            }
+            if self.history.as_ref().is_some_and(|history| history.snapshots.len() == 1) || snapshot.instret == end {
+                self.cpu.restore(&snapshot);
+                self.resume_from = Some(self.cpu.get_pc());
+                return StopReason::HistoryStart;
+            }
+            end = snapshot.instret;
+        }
+    }
+
+    // The latest snapshot at or before instret. Later ones are dropped, they describe a future
+    // that won't necessarily happen again if the debugger changes something.
+    fn snapshot_before(&mut self, instret: u64) -> Snapshot {
+        let history = self.history.as_mut().expect("Reverse execution isn't enabled");
+        while history.snapshots.len() > 1 && history.snapshots.back().is_some_and(|last| last.instret > instret) {
+            history.snapshots.pop_back();
+        }
+        history.snapshots.back().unwrap().clone()
+    }
+
+    // Runs the same instructions again up to instruction count target, quietly and without
+    // stopping. Returns the last breakpoint or watchpoint that would have stopped the machine
+    // before instruction count before, with the instruction count it would have stopped at.
+    fn rerun(&mut self, target: u64, before: u64) -> Option<(u64, StopReason)> {
+        let mut last = None;
+        self.cpu.inputs.silent = true;
+        self.cpu.memory.log_accesses(false);
+        while self.cpu.instret() < target {
+            let pc = self.cpu.get_pc();
+            if self.breakpoints.contains(&pc) && self.cpu.instret() < before {
+                last = Some((self.cpu.instret(), StopReason::Breakpoint(pc)));
+            }
+            self.cpu.memory.take_watch_hit();
+            if self.cpu.step() {
+                break;
+            }
+            if let Some(hit) = self.cpu.memory.take_watch_hit() {
+                if self.cpu.instret() < before {
+                    last = Some((self.cpu.instret(), StopReason::Watchpoint(hit)));
+                }
+            }
+        }
+        self.cpu.inputs.silent = false;
+        self.cpu.memory.log_accesses(self.plugins.memory);
+        self.block_start = true;
+        last
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::machine::*;
+    use crate::asm::assemble;
+    use crate::cpu::htif::Htif;
+    use crate::cpu::semihosting::Semihosting;
+    use crate::cpu::memory::watch::WatchKind;
+    use crate::cpu::register::*;
+
+    fn machine_with(source: &str) -> Machine {
+        let program = assemble(source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        Machine::new(cpu)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut machine = machine_with("li a0, 1\nli a0, 2\nli a0, 3\nebreak");
+        assert_eq!(machine.step(2), StopReason::Stepped);
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 2);
+        assert_eq!(machine.step(0), StopReason::Stepped);
+        assert_eq!(machine.step(5), StopReason::Halted(None));
+        assert_eq!(machine.cpu.get_pc(), 0x10);
+        assert_eq!(machine.retired(), 3);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut machine = machine_with("li a0, 1\nli a0, 2\nli a0, 3\nebreak");
+        machine.add_breakpoint(0x8);
+        machine.add_breakpoint(0xC);
+        assert_eq!(machine.run(), StopReason::Breakpoint(0x8));
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 1);
+        // Resuming runs the instruction under the breakpoint
+        assert_eq!(machine.step(1), StopReason::Stepped);
+        assert_eq!(machine.run(), StopReason::Breakpoint(0xC));
+        machine.remove_breakpoint(0xC);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut machine = machine_with("li a0, 1\nli a0, 2\nli a0, 3\nebreak");
+        assert_eq!(machine.run_until(0xC), StopReason::Breakpoint(0xC));
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 2);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+    }
+
+    #[test]
+    fn test_budget_and_pause() {
+        let mut machine = machine_with("spin: j spin");
+        machine.set_budget(Some(1000));
+        assert_eq!(machine.run(), StopReason::BudgetExhausted);
+        assert_eq!(machine.retired(), 1000);
+
+        machine.set_budget(None);
+        machine.pause_handle().store(true, Ordering::Relaxed);
+        assert_eq!(machine.run(), StopReason::Paused);
+        assert_eq!(machine.retired(), 1000);
+    }
+
+    #[test]
+    fn test_blocks() {
+        // Whole blocks or one instruction at a time, the result is the same
+        let source = "li a0, 5\nsw a0, 0x100(zero)\nbeq zero, zero, skip\nli a0, 1\nskip: lw a1, 0x100(zero)\nmul a1, a1, a0\nebreak";
+        let mut blocks = machine_with(source);
+        let mut single = machine_with(source);
+        single.add_breakpoint(0x1000);
+        assert_eq!(blocks.step(2), StopReason::Stepped, "A step count ends a block early");
+        assert_eq!(blocks.cpu.get_pc(), 0xC);
+        assert_eq!(blocks.run(), single.run());
+        assert_eq!((blocks.retired(), blocks.cpu.get_pc()), (single.retired(), single.cpu.get_pc()));
+        assert_eq!(blocks.cpu.registers.registers, single.cpu.registers.registers);
+        assert_eq!(blocks.cpu.registers.get_register(REG_A1), 25);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut machine = machine_with("li a0, 5\nsw a0, 0x100(zero)\nlw a1, 0x100(zero)\nlw a2, 0x200(zero)\nebreak");
+        let read = Watchpoint::new(WatchKind::Read, 0x102, 1);
+        machine.add_watchpoint(Watchpoint::new(WatchKind::Write, 0x100, 4));
+        machine.add_watchpoint(read);
+        let hit = |machine: &mut Machine| match machine.run() {
+            StopReason::Watchpoint(hit) => (hit.watchpoint.kind, hit.address, hit.value),
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        };
+        assert_eq!(hit(&mut machine), (WatchKind::Write, 0x100, 5));
+        assert_eq!(machine.cpu.get_pc(), 0xC, "Watchpoints stop after the access");
+        assert_eq!(hit(&mut machine), (WatchKind::Read, 0x100, 5));
+        assert_eq!(machine.cpu.registers.get_register(REG_A1), 5);
+        machine.remove_watchpoint(read);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+    }
+
+    #[test]
+    fn test_watchpoint_value() {
+        let source = "li a0, 1\nsw a0, 0x100(zero)\nli a0, 2\nsw a0, 0x100(zero)\nsw a0, 0x104(zero)\nebreak";
+        let mut machine = machine_with(source);
+        machine.add_watchpoint(Watchpoint::parse(WatchKind::Write, "*0x100 == 2").unwrap());
+        assert!(matches!(machine.run(), StopReason::Watchpoint(_)));
+        assert_eq!(machine.cpu.get_pc(), 0x14);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+    }
+
+    const REVERSE_SOURCE: &str = "li a0, 1\nli a0, 2\nsw a0, 0x100(zero)\nli a0, 3\nli a0, 4\nsw a0, 0x100(zero)\nli a0, 5\nebreak";
+
+    #[test]
+    fn test_reverse_step() {
+        let mut machine = machine_with(REVERSE_SOURCE);
+        machine.enable_reverse(2, 16);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        assert_eq!(machine.retired(), 7);
+
+        assert_eq!(machine.reverse_step(1), StopReason::Stepped);
+        assert_eq!(machine.cpu.get_pc(), 0x1C);
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 4);
+        assert_eq!(machine.reverse_step(3), StopReason::Stepped);
+        assert_eq!(machine.cpu.get_pc(), 0x10);
+        assert_eq!(machine.cpu.memory.get_u32(0x100), 2, "Memory should be rewound too");
+        assert_eq!(machine.retired(), 3);
+
+        // Going forward again gives the same result
+        assert_eq!(machine.step(3), StopReason::Stepped);
+        assert_eq!(machine.cpu.memory.get_u32(0x100), 4);
+
+        assert_eq!(machine.reverse_step(100), StopReason::HistoryStart);
+        assert_eq!(machine.cpu.get_pc(), 0x4);
+        assert_eq!(machine.retired(), 0);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut machine = machine_with(REVERSE_SOURCE);
+        machine.enable_reverse(2, 16);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+
+        machine.add_breakpoint(0x8);
+        machine.add_watchpoint(Watchpoint::new(WatchKind::Write, 0x100, 4));
+        // Stops right after the write, like going forward would
+        assert!(matches!(machine.reverse_continue(), StopReason::Watchpoint(hit) if hit.value == 4));
+        assert_eq!(machine.cpu.get_pc(), 0x1C);
+        assert!(matches!(machine.reverse_continue(), StopReason::Watchpoint(hit) if hit.value == 2));
+        assert_eq!(machine.cpu.get_pc(), 0x10);
+        assert_eq!(machine.reverse_continue(), StopReason::Breakpoint(0x8));
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 1);
+        assert_eq!(machine.reverse_continue(), StopReason::HistoryStart);
+        assert_eq!(machine.cpu.get_pc(), 0x4);
+
+        // Continuing forward doesn't stop at the breakpoint it went back to
+        assert_eq!(machine.run(), StopReason::Breakpoint(0x8));
+        assert!(matches!(machine.run(), StopReason::Watchpoint(hit) if hit.value == 2));
+    }
+
+    #[test]
+    fn test_reverse_past_exit() {
+        let call = "slli zero, zero, 0x1f\nebreak\nsrai zero, zero, 7\n";
+        let source = format!("li a0, 0x31\n{}li a0, 0x18\nli a1, 0x20026\n{}", call, call);
+        let mut machine = machine_with(&source);
+        machine.cpu.enable_semihosting(Semihosting::new(".", ""));
+        machine.enable_reverse(2, 16);
+        assert_eq!(machine.run(), StopReason::Halted(Some(0)));
+
+        // Back before SYS_TICKFREQ, which doesn't exit again
+        assert_eq!(machine.reverse_step(100), StopReason::HistoryStart);
+        assert_eq!(machine.cpu.exit_code(), None);
+        assert_eq!(machine.step(4), StopReason::Stepped);
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 1_000_000);
+        assert_eq!(machine.run(), StopReason::Halted(Some(0)));
+
+        let mut machine = machine_with("li a0, 1\nsw a0, 0x200(zero)\nsw zero, 0x204(zero)");
+        machine.cpu.enable_htif(Htif::new(0x200, None));
+        machine.enable_reverse(2, 16);
+        assert_eq!(machine.run(), StopReason::Halted(Some(0)));
+        assert_eq!(machine.reverse_step(100), StopReason::HistoryStart);
+        // tohost gets a syscall this time instead of the exit
+        machine.cpu.memory.set_u32(0x4, 0x30000513); // li a0, 0x300
+        assert_eq!(machine.step(3), StopReason::Stepped);
+    }
+
+    #[test]
+    fn test_reverse_without_history() {
+        let mut machine = machine_with(REVERSE_SOURCE);
+        machine.step(2);
+        assert_eq!(machine.reverse_step(1), StopReason::HistoryStart);
+        assert_eq!(machine.cpu.get_pc(), 0xC, "Nothing should change without snapshots");
+    }
+
+    #[test]
+    fn test_traps_and_exit() {
+        let mut machine = machine_with("nop\n.word 0x7f");
+        assert_eq!(machine.run(), StopReason::Trap(Trap::IllegalInstruction(0x7F)));
+        assert_eq!(machine.cpu.get_pc(), 0x8);
+
+        let mut machine = machine_with("lui a0, 0x80000\nlw a0, 0(a0)");
+        assert_eq!(machine.run(), StopReason::Trap(Trap::Fault(0x8)));
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 0x80000000, "The faulting load doesn't write rd");
+        assert_eq!(machine.retired(), 1);
+
+        // Also one instruction at a time, and for a word split across two pages
+        let mut machine = machine_with("li a0, 0xffe\nsw a0, 0(a0)");
+        machine.add_breakpoint(0x4);
+        assert_eq!(machine.run(), StopReason::Breakpoint(0x4));
+        assert_eq!(machine.run(), StopReason::Trap(Trap::Fault(0xC)));
+        assert_eq!(machine.cpu.memory.get_u16(0xffe), 0, "Nothing of the store was written");
+
+        let mut machine = machine_with("li a0, 7\nsw a0, 0x200(zero)\nsw zero, 0x204(zero)\nnop");
+        machine.cpu.enable_htif(Htif::new(0x200, None));
+        assert_eq!(machine.run(), StopReason::Halted(Some(3)));
+        assert_eq!(machine.run(), StopReason::Halted(Some(3)), "An exited guest should stay exited");
+    }
+}
@@ -1,22 +1,497 @@
-use std::io::Read;
+use std::collections::HashMap;
 use std::env;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Duration;
+use clap::{Parser, ValueEnum};
+use tiny_vm::{asm, batch, config, coverage, cpu, disasm, elf, gdb, machine, monitor, profile, stats, trace};
+use crate::cli::{AsmArgs, Cli, Command, DumpArgs, DumpFormat, Format, ImageArgs, LoadArgs, RunArgs, TraceArgs};
 
-mod cpu;
+mod cli;
 mod gui;
 
 // TODO: Check endianness
-fn read_image(filename: &str) -> Vec<u8> {
-    let mut file = std::fs::File::open(filename).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    buffer
+fn read_image(filename: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(filename).map_err(|error| format!("Can't read {}: {}", filename, error))
+}
+
+fn assemble(filename: &str, image: &[u8], base: u32) -> Result<asm::Program, String> {
+    let source = String::from_utf8_lossy(image);
+    asm::assemble(&source, base).map_err(|error| format!("{}: {}", filename, error))
+}
+
+// What an image turned out to be, before it's loaded into a CPU
+enum Image {
+    Elf(elf::Elf),
+    Asm(asm::Program),
+    Raw(Vec<u8>),
+    State(Vec<u8>),
+    Config(config::MachineConfig),
+}
+
+fn open_image(path: &str, args: &LoadArgs) -> Result<Image, String> {
+    let bytes = read_image(path)?;
+    let format = match args.format {
+        Format::Auto if cpu::state::is_state(&bytes) => Format::State,
+        Format::Auto if elf::is_elf(&bytes) => Format::Elf,
+        Format::Auto if path.ends_with(".s") || path.ends_with(".S") => Format::Asm,
+        Format::Auto if path.ends_with(".toml") => Format::Config,
+        Format::Auto => Format::Raw,
+        format => format,
+    };
+    Ok(match format {
+        Format::Elf => Image::Elf(elf::Elf::parse(&bytes).map_err(|error| format!("{}: {}", path, error))?),
+        Format::Asm => Image::Asm(assemble(path, &bytes, args.load_address)?),
+        Format::State => Image::State(bytes),
+        Format::Config => Image::Config(config::MachineConfig::parse(&String::from_utf8_lossy(&bytes))
+            .map_err(|error| format!("{}: {}", path, error))?),
+        _ => Image::Raw(bytes),
+    })
+}
+
+// Memory has to hold every byte that gets loaded
+fn check_fits(cpu: &cpu::CPU, address: u32, len: u32, what: &str) -> Result<(), String> {
+    let end = address as u64 + len.max(1) as u64 - 1;
+    if !cpu.memory.contains(address) || end > u32::MAX as u64 || !cpu.memory.contains(end as u32) {
+        if !cpu.memory.bus.regions.is_empty() {
+            return Err(format!("{} at 0x{:08x}..0x{:08x} isn't in the RAM or ROM of the machine", what, address, end + 1));
+        }
+        let size: usize = cpu.memory.get_memory().iter().map(|page| page.get_page().len()).sum();
+        return Err(format!("{} at 0x{:08x}..0x{:08x} doesn't fit in memory at 0x{:08x}..0x{:08x}, try a larger --memory",
+            what, address, end + 1, cpu.memory.base(), cpu.memory.base() as u64 + size as u64));
+    }
+    Ok(())
+}
+
+// A CPU with the image loaded, where it starts, and what the image says about its code
+struct Loaded {
+    cpu: cpu::CPU,
+    start: u32,
+    symbols: HashMap<String, u32>,
+    debug_sections: HashMap<String, Vec<u8>>,
+}
+
+// Loads an ELF, assembly or raw image into memory that's already set up and returns
+// where it starts. Symbols add up, the first debug info found is kept.
+fn boot(loaded: &mut Loaded, image: Image, path: &str, load_address: u32) -> Result<u32, String> {
+    match image {
+        Image::Elf(elf) => {
+            for segment in &elf.segments {
+                check_fits(&loaded.cpu, segment.address, segment.mem_size.max(segment.data.len() as u32), "Segment")?;
+            }
+            loaded.cpu.load_elf(&elf);
+            loaded.symbols.extend(elf.symbols);
+            if loaded.debug_sections.is_empty() {
+                loaded.debug_sections = elf.debug_sections;
+            }
+            Ok(elf.entry)
+        }
+        Image::Asm(program) => {
+            check_fits(&loaded.cpu, program.base, program.image.len() as u32, "Program")?;
+            loaded.cpu.load_image(program.base, &program.image);
+            loaded.symbols.extend(program.symbols);
+            Ok(program.entry)
+        }
+        Image::Raw(bytes) => {
+            check_fits(&loaded.cpu, load_address, bytes.len() as u32, "Image")?;
+            loaded.cpu.load_image(load_address, &bytes);
+            Ok(load_address)
+        }
+        Image::State(_) => Err(format!("{} is a state file, run it by itself", path)),
+        Image::Config(_) => Err(format!("{} is a machine configuration, pass it with --config", path)),
+    }
+}
+
+fn load(args: &RunArgs) -> Result<Loaded, String> {
+    let cmdline = env::args().skip(1).collect::<Vec<_>>().join(" ");
+    let mut loaded = match &args.config {
+        Some(path) => load_config(args, path, &cmdline)?,
+        None => {
+            let path = args.image.as_deref().ok_or("No image to run")?;
+            let page_base = args.load.load_address & !((1 << cpu::PAGE_OFFSET_BITS) - 1);
+            match open_image(path, &args.load)? {
+                // Picks up where --save-state left off, devices included
+                Image::State(bytes) => {
+                    let cpu = cpu::CPU::load_state(&bytes, ".").map_err(|error| format!("{}: {}", path, error))?;
+                    let start = cpu.get_pc();
+                    Loaded { cpu, start, symbols: HashMap::new(), debug_sections: HashMap::new() }
+                }
+                image => {
+                    let base = match &image {
+                        Image::Elf(elf) => elf.lowest_address(),
+                        _ => page_base,
+                    };
+                    if base as u64 + args.memory as u64 > 1 << 32 {
+                        return Err(format!("{} bytes of memory at 0x{:08x} go past the end of the address space", args.memory, base));
+                    }
+                    let cpu = cpu::CPU::with_ram(base, args.memory);
+                    let mut loaded = Loaded { cpu, start: 0, symbols: HashMap::new(), debug_sections: HashMap::new() };
+                    loaded.start = boot(&mut loaded, image, path, args.load.load_address)?;
+                    // Semihosted file access is confined to the working directory
+                    loaded.cpu.enable_semihosting(cpu::semihosting::Semihosting::new(".", &cmdline));
+                    loaded
+                }
+            }
+        }
+    };
+    if let Some(entry) = args.entry {
+        loaded.start = entry;
+    }
+    if let Some(isa) = args.isa {
+        loaded.cpu.set_isa(isa);
+    }
+    Ok(loaded)
+}
+
+// --config virt.toml builds the machine it describes and loads its boot images, then the
+// image on the command line if there is one. Boot image paths are relative to the file.
+fn load_config(args: &RunArgs, path: &str, cmdline: &str) -> Result<Loaded, String> {
+    let config = config::MachineConfig::read(path)?;
+    let layout = config.layout().map_err(|error| format!("{}: {}", path, error))?;
+    if args.print_map {
+        print!("{}", layout.memory_map());
+    }
+    let cpu = layout.build(cmdline);
+    let mut loaded = Loaded { cpu, start: layout.main_ram().base, symbols: HashMap::new(), debug_sections: HashMap::new() };
+    let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut images = Vec::new();
+    for boot in &config.boot {
+        let format = match &boot.format {
+            Some(format) => Format::from_str(format, true).map_err(|_| format!("{}: unknown format '{}' for {}", path, format, boot.file))?,
+            None => Format::Auto,
+        };
+        let load_address = boot.address.unwrap_or(layout.main_ram().base);
+        images.push((directory.join(&boot.file).to_string_lossy().into_owned(), LoadArgs { format, load_address }));
+    }
+    if let Some(image) = &args.image {
+        images.push((image.clone(), args.load.clone()));
+    }
+    let mut entry = None;
+    for (image, load) in images {
+        let start = boot(&mut loaded, open_image(&image, &load)?, &image, load.load_address)?;
+        entry.get_or_insert(start);
+    }
+    loaded.start = config.reset_vector.or(entry).unwrap_or(loaded.start);
+    Ok(loaded)
+}
+
+// tiny-vm disasm <image>: prints a listing instead of running the image
+fn disassemble(args: &ImageArgs) -> Result<(), String> {
+    match open_image(&args.image, &args.load)? {
+        Image::Elf(elf) => {
+            for segment in &elf.segments {
+                print!("{}", disasm::listing(segment.address, &segment.data, &elf.symbols));
+            }
+        }
+        Image::Asm(program) => print!("{}", disasm::listing(program.base, &program.image, &program.symbols)),
+        Image::Raw(bytes) => print!("{}", disasm::listing(args.load.load_address, &bytes, &HashMap::new())),
+        Image::State(_) => return Err(format!("{} is a state file, run it or inspect it instead", args.image)),
+        Image::Config(_) => return Err(format!("{} is a machine configuration, inspect it instead", args.image)),
+    }
+    Ok(())
+}
+
+// tiny-vm asm <source> -o <image>: writes the raw image `tiny-vm run` loads at the same base
+fn assemble_to_file(args: &AsmArgs) -> Result<(), String> {
+    let program = assemble(&args.source, &read_image(&args.source)?, args.base)?;
+    let output = args.output.clone().unwrap_or_else(|| {
+        let stem = args.source.strip_suffix(".s").or_else(|| args.source.strip_suffix(".S")).unwrap_or(&args.source);
+        format!("{}.bin", stem)
+    });
+    std::fs::write(&output, &program.image).map_err(|error| format!("Can't write {}: {}", output, error))?;
+    println!("Wrote {} bytes to {}, base 0x{:08x}, entry 0x{:08x}", program.image.len(), output, program.base, program.entry);
+    Ok(())
+}
+
+fn print_symbols(symbols: &HashMap<String, u32>) {
+    let mut sorted: Vec<(&u32, &String)> = symbols.iter().map(|(name, address)| (address, name)).collect();
+    sorted.sort();
+    println!("Symbols: {}", sorted.len());
+    for (address, name) in sorted {
+        println!("  0x{:08x}  {}", address, name);
+    }
+}
+
+// tiny-vm inspect <image>: what run would load, without running it
+fn inspect(args: &ImageArgs) -> Result<(), String> {
+    match open_image(&args.image, &args.load)? {
+        Image::Elf(elf) => {
+            println!("Format: ELF");
+            println!("Entry: 0x{:08x}", elf.entry);
+            println!("Segments:");
+            for segment in &elf.segments {
+                println!("  0x{:08x}  file 0x{:x} bytes, memory 0x{:x} bytes", segment.address, segment.data.len(), segment.mem_size);
+            }
+            print_symbols(&elf.symbols);
+            let mut debug: Vec<&String> = elf.debug_sections.keys().collect();
+            debug.sort();
+            match debug.is_empty() {
+                true => println!("Debug info: none"),
+                false => println!("Debug info: {}", debug.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", ")),
+            }
+        }
+        Image::Asm(program) => {
+            println!("Format: assembly");
+            println!("Base: 0x{:08x}", program.base);
+            println!("Entry: 0x{:08x}", program.entry);
+            println!("Size: 0x{:x} bytes", program.image.len());
+            print_symbols(&program.symbols);
+        }
+        Image::Raw(bytes) => {
+            println!("Format: raw");
+            println!("Load address: 0x{:08x}", args.load.load_address);
+            println!("Size: 0x{:x} bytes", bytes.len());
+        }
+        Image::State(bytes) => {
+            let cpu = cpu::CPU::load_state(&bytes, ".").map_err(|error| format!("{}: {}", args.image, error))?;
+            let pages = cpu.memory.get_memory();
+            let size: usize = pages.iter().map(|page| page.get_page().len()).sum();
+            let used = pages.iter().filter(|page| page.get_page().iter().any(|byte| *byte != 0)).count();
+            println!("Format: machine state");
+            println!("Pc: 0x{:08x}", cpu.get_pc());
+            println!("Instructions retired: {}", cpu.instret());
+            if let Some(exit_code) = cpu.exit_code() {
+                println!("Exit status: {}", exit_code);
+            }
+            println!("Memory: 0x{:08x}..0x{:08x}, {} of {} pages in use", cpu.memory.base(), cpu.memory.base() as u64 + size as u64, used, pages.len());
+            for region in &cpu.memory.bus.regions {
+                let kind = if region.read_only { "ROM" } else { "RAM" };
+                println!("{} {}: 0x{:08x}..0x{:08x}", kind, region.name, region.mmu.base(), region.mmu.base() as u64 + region.size());
+            }
+            let devices = cpu.devices();
+            println!("Devices: {}", if devices.is_empty() { "none".to_string() } else { devices.join(", ") });
+        }
+        Image::Config(config) => {
+            let layout = config.layout().map_err(|error| format!("{}: {}", args.image, error))?;
+            println!("Format: machine configuration");
+            println!("ISA: {}", layout.isa);
+            if let Some(reset_vector) = config.reset_vector {
+                println!("Reset vector: 0x{:08x}", reset_vector);
+            }
+            println!("Memory map:");
+            for line in layout.memory_map().lines() {
+                println!("  {}", line);
+            }
+            println!("Boot images: {}", config.boot.len());
+            for boot in &config.boot {
+                match boot.address {
+                    Some(address) => println!("  {} at 0x{:08x}", boot.file, address),
+                    None => println!("  {}", boot.file),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn serve_gdb(machine: &mut machine::Machine, address: &str) -> std::io::Result<bool> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        return gdb::serve_unix(machine, path);
+    }
+    gdb::serve_tcp(machine, address)
+}
+
+// --log-commits <file> writes a spike style commit log, --log-pc 0x100..0x200 and
+// --log-window 1000..2000 limit it to a pc range and to the n-th to m-th instruction
+fn commit_log(args: &TraceArgs) -> Result<Option<trace::CommitLog>, String> {
+    let Some(path) = &args.log_commits else {
+        return Ok(None);
+    };
+    let mut log = trace::CommitLog::create(path).map_err(|error| format!("Can't create {}: {}", path, error))?;
+    log.pc_range = args.log_pc.as_ref().map(|range| range.start as u32..range.end as u32);
+    log.window = args.log_window.clone();
+    Ok(Some(log))
+}
+
+// --record-inputs <file> logs console input and clock readings, --replay-inputs <file>
+// feeds them back so the run repeats exactly
+fn inputs(args: &TraceArgs) -> Result<Option<cpu::replay::Inputs>, String> {
+    if let Some(path) = &args.record_inputs {
+        return cpu::replay::Inputs::record_to(path).map(Some).map_err(|error| format!("Can't create {}: {}", path, error));
+    }
+    let Some(path) = &args.replay_inputs else {
+        return Ok(None);
+    };
+    std::fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|log| cpu::replay::Inputs::replay(&log))
+        .map(Some)
+        .map_err(|error| format!("Can't replay {}: {}", path, error))
+}
+
+// --profile out.folded writes the call stacks for flamegraph.pl or inferno and prints
+// the top functions and instructions, 20 of each unless --profile-top says otherwise
+fn write_profile(machine: &machine::Machine, symbols: &HashMap<String, u32>, path: &str, top: usize) {
+    let Some(profiler) = machine.profiler() else {
+        return;
+    };
+    let result = std::fs::File::create(path)
+        .map(std::io::BufWriter::new)
+        .and_then(|mut file| profiler.write_folded(&mut file, symbols));
+    if let Err(error) = result {
+        eprintln!("Can't write profile to {}: {}", path, error);
+    }
+    let memory = &machine.cpu.memory;
+    eprint!("{}", profiler.report(top, symbols, |pc| memory.contains(pc).then(|| memory.peek_u32(pc))));
+}
+
+// --coverage lcov.info maps the instructions and branches that ran to source lines,
+// which needs an ELF built with debug info
+fn write_coverage(machine: &machine::Machine, lines: &coverage::LineTable, path: &str) {
+    let Some(coverage) = machine.coverage() else {
+        return;
+    };
+    let memory = &machine.cpu.memory;
+    let result = std::fs::File::create(path)
+        .map(std::io::BufWriter::new)
+        .and_then(|mut file| coverage.write_lcov(&mut file, lines, |pc| memory.contains(pc).then(|| memory.peek_u32(pc))));
+    if let Err(error) = result {
+        eprintln!("Can't write coverage to {}: {}", path, error);
+    }
+}
+
+// Runs until the program stops, or pauses it once timeout has passed
+fn run_with_timeout(machine: &mut machine::Machine, timeout: Option<Duration>) -> (machine::StopReason, bool) {
+    let Some(timeout) = timeout else {
+        return (machine.run(), false);
+    };
+    let pause = machine.pause_handle();
+    let (done, finished) = mpsc::channel::<()>();
+    let timer = std::thread::spawn(move || {
+        // Dropping done ends the wait early, the run finished in time
+        let expired = finished.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout);
+        if expired {
+            pause.store(true, Ordering::Relaxed);
+        }
+        expired
+    });
+    let stop = machine.run();
+    drop(done);
+    let expired = timer.join().unwrap_or(false);
+    // The pause may have come in just after the program stopped by itself
+    machine.pause_handle().store(false, Ordering::Relaxed);
+    let timed_out = expired && stop == machine::StopReason::Paused;
+    (stop, timed_out)
+}
+
+// --dump regs.json writes registers and the --dump-memory ranges once the run stops
+fn write_dump(machine: &machine::Machine, stop: &machine::StopReason, timed_out: bool, args: &DumpArgs) -> Result<(), String> {
+    let Some(path) = &args.dump else {
+        return Ok(());
+    };
+    let dump = match args.dump_format {
+        DumpFormat::Json => batch::json(&machine.cpu, stop, timed_out, &args.dump_memory)?,
+        DumpFormat::Hex => batch::text(&machine.cpu, stop, timed_out, &args.dump_memory)?,
+    };
+    match path.as_str() {
+        "-" => {
+            print!("{}", dump);
+            Ok(())
+        }
+        _ => std::fs::write(path, dump).map_err(|error| format!("Can't write dump to {}: {}", path, error)),
+    }
+}
+
+// Returns the status to exit with, the guest's when it ran without the GUI
+fn run(args: &RunArgs) -> Result<i32, String> {
+    let Loaded { mut cpu, start, symbols, debug_sections } = load(args)?;
+    if let Some(inputs) = inputs(&args.trace)? {
+        cpu.set_inputs(inputs);
+    }
+    cpu.set_pc(start);
+    let mut machine = machine::Machine::new(cpu);
+    machine.set_commit_log(commit_log(&args.trace)?);
+    // Only a debugger can step back, a plain --no-gui run doesn't pay for the snapshots
+    if !args.no_gui || args.gdb.is_some() || args.monitor.is_some() {
+        machine.enable_reverse(machine::SNAPSHOT_INTERVAL, machine::SNAPSHOT_LIMIT);
+    }
+    if args.trace.profile.is_some() {
+        machine.set_profiler(Some(profile::Profiler::new(start)));
+    }
+    if args.trace.stats {
+        machine.set_instruction_mix(Some(stats::InstructionMix::new()));
+    }
+    let lines = match &args.trace.coverage {
+        Some(_) => Some(coverage::LineTable::from_dwarf(&debug_sections)
+            .map_err(|error| format!("Can't collect coverage: {}", error))?),
+        None => None,
+    };
+    if lines.is_some() {
+        machine.set_coverage(Some(coverage::Coverage::new()));
+    }
+    // --max-instructions 5000000 --save-state boot.state runs a long boot once, after
+    // which `tiny-vm boot.state` starts right where it stopped
+    machine.set_budget(args.max_instructions);
+
+    // --gdb localhost:1234 or --gdb unix:/tmp/vm.sock waits for a debugger before running,
+    // --pause opens the GUI without running anything so the program can be stepped through
+    // --monitor stdio runs the guest under a console, see monitor.rs, until it says quit
+    let (stop, timed_out) = match (&args.monitor, &args.gdb) {
+        (Some(address), _) => {
+            let (returned, stop) = monitor::serve(machine, address, args.stopped).map_err(|error| format!("Monitor failed: {}", error))?;
+            machine = returned;
+            (stop, false)
+        }
+        (None, Some(address)) => match serve_gdb(&mut machine, address) {
+            Ok(true) => {
+                // Detached, run the rest of the program
+                let (stop, timed_out) = run_with_timeout(&mut machine, args.timeout);
+                (Some(stop), timed_out)
+            }
+            Ok(false) => (None, false),
+            Err(error) => return Err(format!("GDB stub failed: {}", error)),
+        },
+        (None, None) if args.pause => (None, false),
+        (None, None) => {
+            let (stop, timed_out) = run_with_timeout(&mut machine, args.timeout);
+            (Some(stop), timed_out)
+        }
+    };
+    match &stop {
+        Some(machine::StopReason::Halted(Some(exit_code))) => eprintln!("Guest exited with status {}", exit_code),
+        Some(machine::StopReason::Trap(trap)) => eprintln!("Guest stopped: {}", trap),
+        Some(machine::StopReason::BudgetExhausted) => eprintln!("Guest stopped after {} instructions", machine.retired()),
+        Some(machine::StopReason::Paused) if timed_out => eprintln!("Guest timed out after {:?}", args.timeout.unwrap_or_default()),
+        _ => {}
+    }
+    if let (Some(_), Some(path)) = (&stop, &args.trace.profile) {
+        write_profile(&machine, &symbols, path, args.trace.profile_top);
+    }
+    if let (Some(_), Some(mix)) = (&stop, machine.instruction_mix()) {
+        eprint!("{}", mix.report());
+    }
+    if let (Some(_), Some(lines), Some(path)) = (&stop, &lines, &args.trace.coverage) {
+        write_coverage(&machine, lines, path);
+    }
+    if let (Some(_), Some(path)) = (&stop, &args.save_state) {
+        std::fs::write(path, machine.cpu.save_state()).map_err(|error| format!("Can't save state to {}: {}", path, error))?;
+    }
+    if let Some(stop) = &stop {
+        write_dump(&machine, stop, timed_out, &args.dump)?;
+    }
+    if !args.no_gui {
+        gui::gui(machine).map_err(|error| format!("GUI failed to initialize: {}", error))?;
+        return Ok(0);
+    }
+    Ok(stop.map_or(0, |stop| batch::exit_status(&stop, &machine.cpu)))
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let image = read_image(&*args[1]); // TODO make sure 1 isn't out of bounds. Can be fixed by using a flag parsing system :)
-    let mut cpu = cpu::CPU::new();
-    cpu.load_image(0x4, &image);
-    cpu.run(0x4);
-    gui::gui(cpu).expect("GUI failed to initialize"); // TODO add --no-gui flag
+    let cli = Cli::parse_from(cli::with_default_command(env::args().collect()));
+    let result = match &cli.command {
+        Command::Run(args) => run(args),
+        Command::Disasm(args) => disassemble(args).map(|_| 0),
+        Command::Asm(args) => assemble_to_file(args).map(|_| 0),
+        Command::Inspect(args) => inspect(args).map(|_| 0),
+    };
+    match result {
+        Ok(0) => {}
+        Ok(status) => std::process::exit(status),
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,556 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Monitor console in the style of QEMU's, to control the machine while it runs:
+//     tiny-vm program.elf --no-gui --monitor stdio
+//     tiny-vm program.elf --no-gui --monitor localhost:4444     (or unix:/tmp/vm.mon)
+// then `info registers`, `x/16wx 0x80000000`, `stop`, `step`, `break 0x100`, `cont`...
+// `help` lists everything. Addresses can be numbers or registers like $sp.
+//
+// The guest runs on the thread that called serve, in chunks of RUN_CHUNK instructions.
+// The machine is released between chunks, so commands are handled while the guest runs
+// and always see it between two instructions. The console runs on its own thread; a
+// socket takes one connection after the other until one of them says `quit`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::parse::{parse_address, parse_number};
+use crate::cpu::CPU;
+use crate::cpu::register::REG_ALIASES;
+use crate::disasm;
+use crate::machine::{Machine, StopReason};
+
+const RUN_CHUNK: u64 = 0x1000;
+const PROMPT: &str = "(tiny-vm) ";
+const REG_PC: usize = 32;
+
+const HELP: &str = "\
+info registers          pc and the integer registers
+info status             whether the guest runs, and why it stopped
+info mtree              memory regions and memory mapped devices
+info devices            devices and their registers
+info break              breakpoints
+x/NFU ADDRESS           N units of memory, U is b, h or w and F is x, d, u or i (instructions)
+stop                    stop the guest
+cont, c                 let the guest run
+step, s [N]             run N instructions, 1 by default
+break ADDRESS           stop when pc gets there
+delete ADDRESS          remove a breakpoint
+set REGISTER VALUE      write a register, pc included
+write/U ADDRESS VALUE   write a byte, half or word of RAM
+savevm FILE             write a machine state file
+loadvm FILE             carry on from a machine state file
+quit, q                 end the run
+";
+
+pub enum Reply {
+    Text(String),
+    Quit,
+}
+
+struct Control {
+    running: bool,
+    quit: bool,
+    last_stop: Option<StopReason>,
+}
+
+pub struct Monitor {
+    machine: Mutex<Machine>,
+    waiting: AtomicUsize, // Commands waiting for the machine, the guest lets them in first
+    control: Mutex<Control>,
+    changed: Condvar,
+    pause: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    pub fn new(machine: Machine, running: bool) -> Self {
+        let pause = machine.pause_handle();
+        Self {
+            machine: Mutex::new(machine),
+            waiting: AtomicUsize::new(0),
+            control: Mutex::new(Control { running, quit: false, last_stop: None }),
+            changed: Condvar::new(),
+            pause,
+        }
+    }
+
+    // The machine and why it last stopped, None if it never did
+    pub fn into_parts(self) -> (Machine, Option<StopReason>) {
+        let control = self.control.into_inner().unwrap_or_else(|error| error.into_inner());
+        (self.machine.into_inner().unwrap_or_else(|error| error.into_inner()), control.last_stop)
+    }
+
+    fn machine(&self) -> MutexGuard<'_, Machine> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let machine = self.machine.lock().unwrap_or_else(|error| error.into_inner());
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        machine
+    }
+
+    fn control(&self) -> MutexGuard<'_, Control> {
+        self.control.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    // Runs the guest whenever the console lets it, until `quit`
+    pub fn run_guest(&self) {
+        loop {
+            {
+                let mut control = self.control();
+                while !control.running && !control.quit {
+                    control = self.changed.wait(control).unwrap_or_else(|error| error.into_inner());
+                }
+                if control.quit {
+                    return;
+                }
+            }
+            let stop = self.machine.lock().unwrap_or_else(|error| error.into_inner()).step(RUN_CHUNK);
+            if stop != StopReason::Stepped {
+                let mut control = self.control();
+                control.running = false;
+                control.last_stop = Some(stop);
+            }
+            while self.waiting.load(Ordering::SeqCst) > 0 {
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    pub fn quit(&self) {
+        let mut control = self.control();
+        control.quit = true;
+        control.running = false;
+        self.pause.store(true, Ordering::Relaxed);
+        self.changed.notify_all();
+    }
+
+    fn set_running(&self, running: bool) {
+        let mut control = self.control();
+        if running {
+            self.pause.store(false, Ordering::Relaxed);
+        } else if control.running {
+            // Ends the chunk that's running now
+            self.pause.store(true, Ordering::Relaxed);
+            control.last_stop = Some(StopReason::Paused);
+        }
+        control.running = running;
+        self.changed.notify_all();
+    }
+
+    pub fn execute(&self, line: &str) -> Reply {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let text = |text: String| Reply::Text(text);
+        let Some((command, args)) = words.split_first() else {
+            return text(String::new());
+        };
+        let result = match (*command, args) {
+            ("quit" | "q", _) => {
+                self.quit();
+                return Reply::Quit;
+            }
+            ("help" | "?", _) => Ok(HELP.to_string()),
+            ("info", ["registers" | "reg"]) => Ok(registers(&self.machine().cpu)),
+            ("info", ["status"]) => Ok(self.status()),
+            ("info", ["mtree"]) => Ok(mtree(&self.machine().cpu)),
+            ("info", ["devices"]) => Ok(devices(&self.machine().cpu)),
+            ("info", ["break"]) => Ok(self.machine().breakpoints().iter().map(|address| format!("0x{:08x}\n", address)).collect()),
+            ("stop", []) => {
+                self.set_running(false);
+                Ok(String::new())
+            }
+            ("cont" | "c", []) => {
+                self.set_running(true);
+                Ok(String::new())
+            }
+            ("step" | "s", [] | [_]) => self.step(args.first().copied()),
+            ("break", [address]) => self.address(address).map(|address| {
+                self.machine().add_breakpoint(address);
+                String::new()
+            }),
+            ("delete", [address]) => self.address(address).map(|address| {
+                self.machine().remove_breakpoint(address);
+                String::new()
+            }),
+            ("set", [register, value]) => self.set_register(register, value),
+            ("savevm", [path]) => std::fs::write(path, self.machine().cpu.save_state())
+                .map(|_| String::new())
+                .map_err(|error| format!("Can't write {}: {}", path, error)),
+            ("loadvm", [path]) => self.load_state(path),
+            (command, [address]) if command.starts_with("x") => self.examine(command, address),
+            (command, [address, value]) if command.starts_with("write") => self.write(command, address, value),
+            ("info", _) => Err("info registers|status|mtree|devices|break".to_string()),
+            (command, _) => Err(format!("unknown command or wrong arguments: '{}', try 'help'", command)),
+        };
+        text(result.unwrap_or_else(|error| format!("Error: {}\n", error)))
+    }
+
+    fn status(&self) -> String {
+        let control = self.control();
+        match (&control.last_stop, control.running) {
+            (_, true) => "VM status: running\n".to_string(),
+            (Some(stop), false) => format!("VM status: stopped, {}\n", stop),
+            (None, false) => "VM status: stopped\n".to_string(),
+        }
+    }
+
+    fn step(&self, count: Option<&str>) -> Result<String, String> {
+        let count = count.map_or(Ok(1), parse_number)?;
+        if self.control().running {
+            return Err("the guest is running, stop it first".to_string());
+        }
+        let mut machine = self.machine();
+        self.pause.store(false, Ordering::Relaxed);
+        let stop = machine.step(count);
+        let pc = machine.cpu.get_pc();
+        let mut text = match stop {
+            StopReason::Stepped => String::new(),
+            ref stop => format!("{}\n", stop),
+        };
+        if machine.cpu.memory.contains(pc) {
+            text.push_str(&format!("0x{:08x}:  {}\n", pc, disasm::disassemble(machine.cpu.memory.peek_u32(pc), pc)));
+        }
+        self.control().last_stop = Some(stop);
+        Ok(text)
+    }
+
+    // A number, or a register like $sp or $pc
+    fn address(&self, text: &str) -> Result<u32, String> {
+        match text.strip_prefix('$') {
+            Some(name) => {
+                let register = register(name).ok_or_else(|| format!("no register {}", name))?;
+                let machine = self.machine();
+                Ok(match register {
+                    REG_PC => machine.cpu.get_pc(),
+                    _ => machine.cpu.registers.get_register(register as u8),
+                })
+            }
+            None => parse_address(text),
+        }
+    }
+
+    fn set_register(&self, name: &str, value: &str) -> Result<String, String> {
+        let register = register(name.trim_start_matches('$')).ok_or_else(|| format!("no register {}", name))?;
+        let value = self.address(value)?;
+        let mut machine = self.machine();
+        match register {
+            REG_PC => machine.cpu.set_pc(value),
+            _ => machine.cpu.registers.set_register(register as u8, value),
+        }
+        Ok(String::new())
+    }
+
+    fn load_state(&self, path: &str) -> Result<String, String> {
+        let bytes = std::fs::read(path).map_err(|error| format!("Can't read {}: {}", path, error))?;
+        let cpu = CPU::load_state(&bytes, ".").map_err(|error| format!("{}: {}", path, error))?;
+        self.machine().replace_cpu(cpu);
+        self.control().last_stop = None;
+        Ok(String::new())
+    }
+
+    // x/16wx 0x80000000: count, unit and format like gdb's and QEMU's
+    fn examine(&self, command: &str, address: &str) -> Result<String, String> {
+        let (count, format, size) = parse_format(command.strip_prefix('x').unwrap(), 'x')?;
+        let start = self.address(address)?;
+        let machine = self.machine();
+        let memory = &machine.cpu.memory;
+        let size = if format == 'i' { 4 } else { size };
+        let mut text = String::new();
+        let per_line = if format == 'i' { 1 } else { 16 / size };
+        for index in 0..count {
+            let address = start.wrapping_add(index * size);
+            if index % per_line == 0 {
+                if index > 0 {
+                    text.push('\n');
+                }
+                text.push_str(&format!("{:08x}:", address));
+            }
+            if !(0..size).all(|offset| memory.contains(address.wrapping_add(offset))) {
+                text.push_str(&format!("\nCannot access memory at 0x{:08x}\n", address));
+                return Ok(text);
+            }
+            // Byte by byte, so nothing panics at a page boundary
+            let value = (0..size).rev().fold(0, |value, offset| value << 8 | memory.get_u8(address.wrapping_add(offset)) as u32);
+            let shift = 32 - size * 8;
+            text.push_str(&match format {
+                'i' => format!(" {}", disasm::disassemble(value, address)),
+                'd' => format!(" {}", ((value << shift) as i32) >> shift),
+                'u' => format!(" {}", value),
+                _ => format!(" 0x{:0width$x}", value, width = size as usize * 2),
+            });
+        }
+        text.push('\n');
+        Ok(text)
+    }
+
+    // write/w 0x80000000 0x13 writes to RAM, not to ROM or devices
+    fn write(&self, command: &str, address: &str, value: &str) -> Result<String, String> {
+        let (_, _, size) = parse_format(command.strip_prefix("write").unwrap(), 'x')?;
+        let address = self.address(address)?;
+        let value = self.address(value)?;
+        let mut machine = self.machine();
+        let memory = &mut machine.cpu.memory;
+        for offset in 0..size {
+            let byte = address.wrapping_add(offset);
+            let rom = memory.bus.regions.iter().any(|region| region.read_only && region.mmu.contains(byte));
+            if !memory.contains(byte) || rom {
+                return Err(format!("0x{:08x} isn't RAM", byte));
+            }
+        }
+        for offset in 0..size {
+            memory.set_u8(address.wrapping_add(offset), (value >> (offset * 8)) as u8);
+        }
+        Ok(String::new())
+    }
+}
+
+// "/16wx" into count, format and unit size in bytes; everything is optional
+fn parse_format(text: &str, default_format: char) -> Result<(u32, char, u32), String> {
+    let Some(text) = text.strip_prefix('/') else {
+        return match text {
+            "" => Ok((1, default_format, 4)),
+            _ => Err(format!("expected /NFU after the command, got '{}'", text)),
+        };
+    };
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let count = match digits {
+        0 => 1,
+        _ => text[..digits].parse().map_err(|_| format!("bad count '{}'", &text[..digits]))?,
+    };
+    let (mut format, mut size) = (default_format, 4);
+    for letter in text[digits..].chars() {
+        match letter {
+            'b' => size = 1,
+            'h' => size = 2,
+            'w' => size = 4,
+            'x' | 'd' | 'u' | 'i' => format = letter,
+            _ => return Err(format!("unknown format or unit '{}'", letter)),
+        }
+    }
+    Ok((count, format, size))
+}
+
+// Register number from an ABI name, xN or pc
+fn register(name: &str) -> Option<usize> {
+    match name {
+        "pc" => Some(REG_PC),
+        "fp" => Some(8),
+        _ => REG_ALIASES.iter().position(|alias| *alias == name).or_else(|| {
+            name.strip_prefix('x').and_then(|number| number.parse().ok()).filter(|number| *number < 32)
+        }),
+    }
+}
+
+fn registers(cpu: &CPU) -> String {
+    let mut text = format!("pc   {:08x}\n", cpu.get_pc());
+    for (index, name) in REG_ALIASES.iter().enumerate() {
+        text.push_str(&format!("{:<4} {:08x}", name, cpu.registers.get_register(index as u8)));
+        text.push_str(if index % 4 == 3 { "\n" } else { "  " });
+    }
+    text
+}
+
+// Address ranges in order, the way `info mtree` shows them
+fn mtree(cpu: &CPU) -> String {
+    let memory = &cpu.memory;
+    let size: u64 = memory.get_memory().iter().map(|page| page.get_page().len() as u64).sum();
+    let mut entries = vec![(memory.base(), size, "ram", "main RAM".to_string())];
+    for region in &memory.bus.regions {
+        entries.push((region.mmu.base(), region.size(), if region.read_only { "rom" } else { "ram" }, region.name.clone()));
+    }
+    for device in &memory.bus.devices {
+        let irq = device.irq.map_or(String::new(), |irq| format!(", irq {}", irq));
+        entries.push((device.base, device.size as u64, device.kind(), format!("{}{}", device.name, irq)));
+    }
+    entries.sort_by_key(|entry| entry.0);
+    entries.iter().map(|(base, size, kind, name)| format!("{:08x}-{:08x} ({}): {}\n", base, *base as u64 + size - 1, kind, name)).collect()
+}
+
+fn devices(cpu: &CPU) -> String {
+    let devices = cpu.devices();
+    let mut text = format!("Devices: {}\n", if devices.is_empty() { "none".to_string() } else { devices.join(", ") });
+    for device in &cpu.memory.bus.devices {
+        text.push_str(&format!("{} ({}) at 0x{:08x}", device.name, device.kind(), device.base));
+        if let Some(irq) = device.irq {
+            text.push_str(&format!(", irq {}", irq));
+        }
+        text.push('\n');
+        let registers: Vec<String> = device.registers().iter().map(|(name, value)| format!("{}={:02x}", name, value)).collect();
+        if !registers.is_empty() {
+            text.push_str(&format!("  {}\n", registers.join(" ")));
+        }
+    }
+    text
+}
+
+// Reads commands until `quit` or the end of input. Returns true for `quit`.
+fn serve_console(monitor: &Monitor, input: impl BufRead, mut output: impl Write) -> std::io::Result<bool> {
+    write!(output, "tiny-vm monitor, type 'help' for the commands\n{}", PROMPT)?;
+    output.flush()?;
+    for line in input.lines() {
+        match monitor.execute(&line?) {
+            Reply::Text(text) => write!(output, "{}{}", text, PROMPT)?,
+            Reply::Quit => return Ok(true),
+        }
+        output.flush()?;
+    }
+    Ok(false)
+}
+
+enum Console {
+    Stdio,
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, String),
+}
+
+impl Console {
+    // Serves connections one after the other until one of them quits
+    fn serve(&self, monitor: &Monitor) -> std::io::Result<()> {
+        loop {
+            let quit = match self {
+                Console::Stdio => {
+                    serve_console(monitor, std::io::stdin().lock(), std::io::stdout())?;
+                    return Ok(());
+                }
+                Console::Tcp(listener) => {
+                    let (stream, _) = listener.accept()?;
+                    serve_console(monitor, BufReader::new(stream.try_clone()?), stream)
+                }
+                #[cfg(unix)]
+                Console::Unix(listener, _) => {
+                    let (stream, _) = listener.accept()?;
+                    serve_console(monitor, BufReader::new(stream.try_clone()?), stream)
+                }
+            };
+            // A connection that went away doesn't end the run
+            if quit.unwrap_or(false) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Runs the machine with a monitor on stdio, host:port or unix:/path until it says quit.
+// Returns the machine and why it last stopped.
+pub fn serve(machine: Machine, address: &str, stopped: bool) -> std::io::Result<(Machine, Option<StopReason>)> {
+    let console = match address {
+        "stdio" => Console::Stdio,
+        #[cfg(unix)]
+        _ if address.starts_with("unix:") => {
+            let path = &address["unix:".len()..];
+            let _ = std::fs::remove_file(path);
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            eprintln!("Monitor on {}", path);
+            Console::Unix(listener, path.to_string())
+        }
+        _ => {
+            let listener = TcpListener::bind(address)?;
+            eprintln!("Monitor on {}", listener.local_addr()?);
+            Console::Tcp(listener)
+        }
+    };
+    let monitor = Arc::new(Monitor::new(machine, !stopped));
+    let console_thread = {
+        let monitor = monitor.clone();
+        std::thread::spawn(move || {
+            let result = console.serve(&monitor);
+            #[cfg(unix)]
+            if let Console::Unix(_, path) = &console {
+                let _ = std::fs::remove_file(path);
+            }
+            // End of input on stdio ends the run like quit does
+            monitor.quit();
+            result
+        })
+    };
+    monitor.run_guest();
+    let result = console_thread.join().unwrap_or(Ok(()));
+    let monitor = Arc::into_inner(monitor).expect("The console thread has finished");
+    result.map(|_| monitor.into_parts())
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::monitor::*;
+    use crate::cpu::register::REG_A0;
+
+    fn monitor(source: &str) -> Monitor {
+        let program = crate::asm::assemble(source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        Monitor::new(Machine::new(cpu), false)
+    }
+
+    fn output(monitor: &Monitor, line: &str) -> String {
+        match monitor.execute(line) {
+            Reply::Text(text) => text,
+            Reply::Quit => panic!("{} quit", line),
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let monitor = monitor("li a0, 7\nli a1, 0x100\nsw a0, 0(a1)\nebreak");
+        assert_eq!(output(&monitor, "step"), "0x00000008:  li a1, 256\n");
+        assert!(output(&monitor, "info registers").contains("a0   00000007"));
+        assert_eq!(output(&monitor, "x/2wx 4"), "00000004: 0x00700513 0x10000593\n");
+        assert_eq!(output(&monitor, "x/2i $pc"), "00000008: li a1, 256\n0000000c: sw a0, 0(a1)\n");
+        assert_eq!(output(&monitor, "x/3bd 0x4"), "00000004: 19 5 112\n");
+        assert!(output(&monitor, "x/w 0x200000").contains("Cannot access memory at 0x00200000"));
+
+        assert_eq!(output(&monitor, "break 0x10"), "");
+        assert_eq!(output(&monitor, "info break"), "0x00000010\n");
+        assert_eq!(output(&monitor, "s 5"), "Breakpoint at 0x00000010\n0x00000010:  ebreak\n");
+        assert_eq!(output(&monitor, "x/hx 0x100"), "00000100: 0x0007\n");
+        assert_eq!(output(&monitor, "info status"), "VM status: stopped, Breakpoint at 0x00000010\n");
+
+        assert_eq!(output(&monitor, "set a0 0x2a"), "");
+        assert_eq!(output(&monitor, "write/h 0x102 $a0"), "");
+        assert_eq!(output(&monitor, "x/wx 0x100"), "00000100: 0x002a0007\n");
+        assert!(output(&monitor, "write 0x200000 1").starts_with("Error: 0x00200000 isn't RAM"));
+        assert!(output(&monitor, "frobnicate").starts_with("Error: unknown command"));
+        assert_eq!(output(&monitor, "info mtree"), "00000000-001fffff (ram): main RAM\n");
+        assert!(matches!(monitor.execute("quit"), Reply::Quit));
+    }
+
+    #[test]
+    fn test_running() {
+        // Spins in place until stopped from the console
+        let monitor = Arc::new(monitor("li a0, 1\nspin: j spin"));
+        let guest = {
+            let monitor = monitor.clone();
+            std::thread::spawn(move || monitor.run_guest())
+        };
+        monitor.execute("cont");
+        while monitor.machine().retired() < 10 * RUN_CHUNK {
+            assert_eq!(output(&monitor, "info status"), "VM status: running\n");
+        }
+        assert!(output(&monitor, "step").contains("stop it first"));
+        monitor.execute("stop");
+        assert_eq!(output(&monitor, "info status"), "VM status: stopped, Paused\n");
+        let retired = monitor.machine().retired();
+        assert_eq!(output(&monitor, "step 2"), "0x00000008:  j 0x8\n");
+        assert_eq!(monitor.machine().retired(), retired + 2);
+        monitor.execute("quit");
+        guest.join().unwrap();
+        let (machine, stop) = Arc::into_inner(monitor).unwrap().into_parts();
+        assert_eq!(machine.cpu.registers.get_register(REG_A0), 1);
+        assert_eq!(stop, Some(StopReason::Stepped));
+    }
+
+    #[test]
+    fn test_state() {
+        let monitor = monitor("li a0, 7\nebreak");
+        let path = std::env::temp_dir().join(format!("tiny-vm-monitor-{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(output(&monitor, &format!("savevm {}", path)), "");
+        output(&monitor, "step");
+        assert!(output(&monitor, "info registers").contains("a0   00000007"));
+        assert_eq!(output(&monitor, &format!("loadvm {}", path)), "");
+        assert!(output(&monitor, "info registers").contains("a0   00000000"));
+        assert!(output(&monitor, "loadvm /nonexistent/state").starts_with("Error: Can't read"));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,85 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Numbers the command line, the monitor and machine configurations take. Numbers can be
+// decimal or hex, sizes take K, M and G suffixes and durations s, ms and m.
+
+use std::ops::Range;
+use std::time::Duration;
+use crate::cpu::PAGE_OFFSET_BITS;
+
+pub fn parse_number(text: &str) -> Result<u64, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => text.replace('_', "").parse(),
+    };
+    parsed.map_err(|_| format!("'{}' isn't a decimal or 0x hex number", text))
+}
+
+pub fn parse_address(text: &str) -> Result<u32, String> {
+    let number = parse_number(text)?;
+    u32::try_from(number).map_err(|_| format!("0x{:x} is outside of the 32-bit address space", number))
+}
+
+// "start..end" with decimal or hex bounds
+pub fn parse_range(text: &str) -> Result<Range<u64>, String> {
+    let (start, end) = text.split_once("..").ok_or_else(|| format!("'{}' isn't a range like 0x100..0x200", text))?;
+    Ok(parse_number(start)?..parse_number(end)?)
+}
+
+// Bytes, or with a K, M or G suffix. Memory comes in whole pages and has to fit in 32 bits.
+pub fn parse_size(text: &str) -> Result<usize, String> {
+    let (number, shift) = match text.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&text[..index], 10),
+        Some((index, 'm' | 'M')) => (&text[..index], 20),
+        Some((index, 'g' | 'G')) => (&text[..index], 30),
+        _ => (text, 0),
+    };
+    let size = parse_number(number)?.checked_mul(1 << shift).filter(|size| *size <= 1 << 32)
+        .ok_or_else(|| format!("{} is more memory than 32-bit addresses reach", text))?;
+    let page_size = 1u64 << PAGE_OFFSET_BITS;
+    if size == 0 || size % page_size != 0 {
+        return Err(format!("Memory has to be a non-zero multiple of the {} byte page size, got {}", page_size, text));
+    }
+    Ok(size as usize)
+}
+
+// Seconds, or with an ms, s or m suffix
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("'{}' isn't a duration like 10s or 500ms", text);
+    let (number, scale) = if let Some(number) = text.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = text.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = text.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (text, 1.0)
+    };
+    let seconds: f64 = number.parse().map_err(|_| invalid())?;
+    Duration::try_from_secs_f64(seconds * scale).map_err(|_| invalid())
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::parse::*;
+
+    #[test]
+    fn test_values() {
+        assert_eq!(parse_number("0x1_000"), Ok(0x1000));
+        assert_eq!(parse_number("42"), Ok(42));
+        assert!(parse_number("0xg").is_err());
+        assert_eq!(parse_range("0x100..0x200"), Ok(0x100..0x200));
+        assert!(parse_range("100").is_err());
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert_eq!(parse_size("512k"), Ok(512 << 10));
+        assert_eq!(parse_size("4G"), Ok(1 << 32));
+        assert_eq!(parse_size("0x1000"), Ok(0x1000));
+        assert!(parse_size("5G").is_err());
+        assert!(parse_size("0").is_err());
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("-1s").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Instrumentation plugins, along the lines of QEMU's TCG plugins: cache models, tracers
+// and coverage tools that watch the guest from outside the CPU code. A plugin says which
+// events it wants when it's added to a machine, and the machine only does the work for
+// events somebody subscribed to, so a machine without plugins runs as before.
+//
+// Events, all for instructions that actually ran:
+//  - instruction: after the instruction retired, with the CPU state it left behind
+//  - memory: every load and store the instruction made, devices included
+//  - block: before the first instruction of a basic block. Blocks end with every jump and
+//    branch, taken or not, and a new one starts wherever pc didn't just fall through.
+//  - trap: the instruction at pc didn't retire
+// ecall, ebreak and the zero instruction halt the machine without retiring and aren't
+// reported. Neither is anything reverse execution runs again.
+
+use std::any::Any;
+use std::ops::Range;
+use crate::cpu::{Trap, CPU};
+use crate::cpu::memory::Access;
+
+// Which events a plugin gets. None skips the callback altogether, a range limits it to
+// instructions at those addresses, or for memory to accesses of those addresses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Subscription {
+    pub instructions: Option<Range<u64>>,
+    pub memory: Option<Range<u64>>,
+    pub blocks: Option<Range<u64>>,
+    pub traps: bool,
+}
+
+pub const ALL_ADDRESSES: Range<u64> = 0..1 << 32;
+
+impl Subscription {
+    pub fn instructions(mut self, range: Range<u64>) -> Self {
+        self.instructions = Some(range);
+        self
+    }
+
+    pub fn memory(mut self, range: Range<u64>) -> Self {
+        self.memory = Some(range);
+        self
+    }
+
+    pub fn blocks(mut self, range: Range<u64>) -> Self {
+        self.blocks = Some(range);
+        self
+    }
+
+    pub fn traps(mut self) -> Self {
+        self.traps = true;
+        self
+    }
+}
+
+fn wants(range: &Option<Range<u64>>, address: u32) -> bool {
+    range.as_ref().is_some_and(|range| range.contains(&(address as u64)))
+}
+
+pub trait Plugin: Any + Send {
+    // Asked once, when the plugin is added
+    fn subscription(&self) -> Subscription;
+
+    fn instruction(&mut self, _cpu: &CPU, _pc: u32, _instruction: u32) {}
+
+    // pc of the instruction that made the access
+    fn memory(&mut self, _pc: u32, _access: &Access) {}
+
+    fn block(&mut self, _pc: u32) {}
+
+    fn trap(&mut self, _pc: u32, _trap: &Trap) {}
+}
+
+// Handed out by Machine::add_plugin to get at the plugin again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PluginId(usize);
+
+// The plugins of a machine, with what they subscribed to
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<(PluginId, Subscription, Box<dyn Plugin>)>,
+    next_id: usize,
+    // Whether anybody wants each kind of event
+    pub instructions: bool,
+    pub memory: bool,
+    pub blocks: bool,
+    pub traps: bool,
+}
+
+impl Plugins {
+    pub fn add(&mut self, plugin: Box<dyn Plugin>) -> PluginId {
+        let id = PluginId(self.next_id);
+        self.next_id += 1;
+        self.plugins.push((id, plugin.subscription(), plugin));
+        self.update();
+        id
+    }
+
+    pub fn remove(&mut self, id: PluginId) -> Option<Box<dyn Plugin>> {
+        let index = self.plugins.iter().position(|(existing, _, _)| *existing == id)?;
+        let (_, _, plugin) = self.plugins.remove(index);
+        self.update();
+        Some(plugin)
+    }
+
+    pub fn get<P: Plugin>(&self, id: PluginId) -> Option<&P> {
+        let (_, _, plugin) = self.plugins.iter().find(|(existing, _, _)| *existing == id)?;
+        (plugin.as_ref() as &dyn Any).downcast_ref()
+    }
+
+    pub fn get_mut<P: Plugin>(&mut self, id: PluginId) -> Option<&mut P> {
+        let (_, _, plugin) = self.plugins.iter_mut().find(|(existing, _, _)| *existing == id)?;
+        (plugin.as_mut() as &mut dyn Any).downcast_mut()
+    }
+
+    // Nothing subscribed to anything
+    pub fn is_empty(&self) -> bool {
+        !(self.instructions || self.memory || self.blocks || self.traps)
+    }
+
+    fn update(&mut self) {
+        let subscriptions = || self.plugins.iter().map(|(_, subscription, _)| subscription);
+        self.instructions = subscriptions().any(|subscription| subscription.instructions.is_some());
+        self.memory = subscriptions().any(|subscription| subscription.memory.is_some());
+        self.blocks = subscriptions().any(|subscription| subscription.blocks.is_some());
+        self.traps = subscriptions().any(|subscription| subscription.traps);
+    }
+
+    pub fn instruction(&mut self, cpu: &CPU, pc: u32, instruction: u32) {
+        for (_, subscription, plugin) in &mut self.plugins {
+            if wants(&subscription.instructions, pc) {
+                plugin.instruction(cpu, pc, instruction);
+            }
+        }
+    }
+
+    pub fn memory(&mut self, pc: u32, access: &Access) {
+        for (_, subscription, plugin) in &mut self.plugins {
+            if wants(&subscription.memory, access.address) {
+                plugin.memory(pc, access);
+            }
+        }
+    }
+
+    pub fn block(&mut self, pc: u32) {
+        for (_, subscription, plugin) in &mut self.plugins {
+            if wants(&subscription.blocks, pc) {
+                plugin.block(pc);
+            }
+        }
+    }
+
+    pub fn trap(&mut self, pc: u32, trap: &Trap) {
+        for (_, subscription, plugin) in &mut self.plugins {
+            if subscription.traps {
+                plugin.trap(pc, trap);
+            }
+        }
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::plugin::*;
+    use crate::asm::assemble;
+    use crate::machine::{Machine, StopReason};
+
+    #[derive(Default)]
+    struct Recorder {
+        subscription: Subscription,
+        instructions: Vec<u32>,
+        accesses: Vec<(u32, Access)>,
+        blocks: Vec<u32>,
+        traps: Vec<(u32, Trap)>,
+    }
+
+    impl Plugin for Recorder {
+        fn subscription(&self) -> Subscription {
+            self.subscription.clone()
+        }
+
+        fn instruction(&mut self, _cpu: &CPU, pc: u32, _instruction: u32) {
+            self.instructions.push(pc);
+        }
+
+        fn memory(&mut self, pc: u32, access: &Access) {
+            self.accesses.push((pc, *access));
+        }
+
+        fn block(&mut self, pc: u32) {
+            self.blocks.push(pc);
+        }
+
+        fn trap(&mut self, pc: u32, trap: &Trap) {
+            self.traps.push((pc, trap.clone()));
+        }
+    }
+
+    fn machine_with(source: &str) -> Machine {
+        let program = assemble(source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        Machine::new(cpu)
+    }
+
+    // A taken branch at 0xC and one not taken at 0x18
+    const SOURCE: &str = "li a0, 5\nsw a0, 0x100(zero)\nbeq zero, zero, skip\nli a0, 1\nskip: lw a1, 0x100(zero)\nbne zero, zero, end\nsw a1, 0x200(zero)\nend: ebreak";
+
+    #[test]
+    fn test_events() {
+        let mut machine = machine_with(SOURCE);
+        let all = Recorder {
+            subscription: Subscription::default().instructions(ALL_ADDRESSES).memory(ALL_ADDRESSES).blocks(ALL_ADDRESSES).traps(),
+            ..Default::default()
+        };
+        let all = machine.add_plugin(all);
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        let recorder = machine.plugin::<Recorder>(all).unwrap();
+        assert_eq!(recorder.instructions, [0x4, 0x8, 0xC, 0x14, 0x18, 0x1C]);
+        assert_eq!(recorder.blocks, [0x4, 0x14, 0x1C]);
+        let access = |address, write| Access { address, size: 4, value: 5, write };
+        assert_eq!(recorder.accesses, [(0x8, access(0x100, true)), (0x14, access(0x100, false)), (0x1C, access(0x200, true))]);
+        assert!(recorder.traps.is_empty());
+        assert!(machine.remove_plugin(all).is_some());
+        assert!(machine.plugin::<Recorder>(all).is_none());
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut machine = machine_with(SOURCE);
+        let filtered = Recorder {
+            subscription: Subscription::default().instructions(0x8..0x18).memory(0x200..0x204),
+            ..Default::default()
+        };
+        let filtered = machine.add_plugin(filtered);
+        assert_eq!(machine.step(2), StopReason::Stepped);
+        machine.plugin_mut::<Recorder>(filtered).unwrap().instructions.clear();
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        let recorder = machine.plugin::<Recorder>(filtered).unwrap();
+        assert_eq!(recorder.instructions, [0xC, 0x14]);
+        assert_eq!(recorder.accesses, [(0x1C, Access { address: 0x200, size: 4, value: 5, write: true })]);
+        assert!(recorder.blocks.is_empty(), "Nobody asked for blocks");
+    }
+
+    #[test]
+    fn test_traps() {
+        let traps = |source: &str| {
+            let mut machine = machine_with(source);
+            let id = machine.add_plugin(Recorder { subscription: Subscription::default().traps(), ..Default::default() });
+            machine.run();
+            machine.remove_plugin(id).map(|plugin| (plugin as Box<dyn Any>).downcast::<Recorder>().unwrap().traps).unwrap()
+        };
+        assert_eq!(traps("nop\nlui a0, 0x80000\nlw a1, 0(a0)"), [(0xC, Trap::Fault(0xC))]);
+        assert_eq!(traps("nop\n.word 0x7f"), [(0x8, Trap::IllegalInstruction(0x7F))]);
+        assert!(traps("nop\nebreak").is_empty());
+    }
+}
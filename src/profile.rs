@@ -0,0 +1,237 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Exact guest profiler: counts every retired instruction by pc and by call stack.
+// Calls and returns are recognised by the usual patterns, jal/jalr writing ra or t0
+// is a call, jalr x0 through ra or t0 is a return. Where a call went is taken from
+// the pc afterwards, so it doesn't matter how the jump computed it.
+// The stacks come out in the folded format flamegraph.pl and inferno read:
+//     _start;main;memcpy 1234
+// Names come from the symbol table, code outside of any symbol shows up as its address.
+
+use std::collections::HashMap;
+use std::io::Write;
+use crate::cpu::opcodes::{OP_JAL, OP_JALR};
+use crate::cpu::register::{REG_RA, REG_T0};
+use crate::disasm::disassemble;
+
+// One function on one call stack
+struct Frame {
+    parent: usize,
+    function: u32, // Address the call went to
+    children: HashMap<u32, usize>,
+    count: u64, // Instructions retired in this frame itself
+}
+
+pub struct Profiler {
+    counts: HashMap<u32, u64>, // By pc
+    frames: Vec<Frame>, // frames[0] is whatever the program started in
+    current: usize,
+}
+
+// Addresses to names, for the reports
+struct Symbols<'a> {
+    sorted: Vec<(u32, &'a str)>,
+}
+
+impl<'a> Symbols<'a> {
+    fn new(symbols: &'a HashMap<String, u32>) -> Self {
+        let mut sorted: Vec<(u32, &str)> = symbols.iter().map(|(name, address)| (*address, name.as_str())).collect();
+        // Several names for one address: keep the same one every time
+        sorted.sort();
+        sorted.dedup_by_key(|(address, _)| *address);
+        Self { sorted }
+    }
+
+    // The symbol an address falls under
+    fn containing(&self, address: u32) -> Option<(u32, &'a str)> {
+        let index = self.sorted.partition_point(|(start, _)| *start <= address);
+        index.checked_sub(1).map(|index| self.sorted[index])
+    }
+
+    fn name(&self, address: u32) -> String {
+        match self.containing(address) {
+            Some((start, name)) if start == address => name.to_string(),
+            Some((start, name)) => format!("{}+0x{:x}", name, address - start),
+            None => format!("0x{:08x}", address),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new(entry: u32) -> Self {
+        Self {
+            counts: HashMap::new(),
+            frames: vec![Frame { parent: 0, function: entry, children: HashMap::new(), count: 0 }],
+            current: 0,
+        }
+    }
+
+    // Called for every retired instruction with the pc it ran at and the pc after it
+    pub fn record(&mut self, pc: u32, instruction: u32, next_pc: u32) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        self.frames[self.current].count += 1;
+
+        let opcode = (instruction & 0x7F) as u8;
+        if opcode != OP_JAL && opcode != OP_JALR {
+            return;
+        }
+        let rd = ((instruction >> 7) & 0x1F) as u8;
+        let rs1 = ((instruction >> 15) & 0x1F) as u8;
+        if rd == REG_RA || rd == REG_T0 {
+            let current = self.current;
+            let count = self.frames.len();
+            let child = *self.frames[current].children.entry(next_pc).or_insert(count);
+            if child == count {
+                self.frames.push(Frame { parent: current, function: next_pc, children: HashMap::new(), count: 0 });
+            }
+            self.current = child;
+        } else if opcode == OP_JALR && rd == 0 && (rs1 == REG_RA || rs1 == REG_T0) {
+            // Returning from the outermost frame just stays there
+            self.current = self.frames[self.current].parent;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    // One line per call stack that retired instructions itself
+    pub fn write_folded(&self, out: &mut impl Write, symbols: &HashMap<String, u32>) -> std::io::Result<()> {
+        let symbols = Symbols::new(symbols);
+        let mut lines: Vec<(String, u64)> = Vec::new();
+        for (index, frame) in self.frames.iter().enumerate() {
+            if frame.count == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut walk = index;
+            loop {
+                names.push(symbols.name(self.frames[walk].function));
+                if walk == 0 {
+                    break;
+                }
+                walk = self.frames[walk].parent;
+            }
+            names.reverse();
+            lines.push((names.join(";"), frame.count));
+        }
+        // Recursion can reach the same stack of names through different frames
+        lines.sort();
+        lines.dedup_by(|line, previous| {
+            let same = line.0 == previous.0;
+            if same {
+                previous.1 += line.1;
+            }
+            same
+        });
+        for (stack, count) in lines {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+
+    // The top functions by instructions retired in them, then the top instructions.
+    // image reads instruction words for the listing, None where there is no memory.
+    pub fn report(&self, top: usize, symbols: &HashMap<String, u32>, image: impl Fn(u32) -> Option<u32>) -> String {
+        let symbols = Symbols::new(symbols);
+        let total = self.total().max(1) as f64;
+        let mut functions: HashMap<String, u64> = HashMap::new();
+        for (pc, count) in &self.counts {
+            let name = match symbols.containing(*pc) {
+                Some((_, name)) => name.to_string(),
+                None => "??".to_string(),
+            };
+            *functions.entry(name).or_insert(0) += count;
+        }
+        let mut functions: Vec<(String, u64)> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut text = format!("{} instructions retired\n\n{:>12} {:>7}  function\n", self.total(), "count", "%");
+        for (name, count) in functions.iter().take(top) {
+            text.push_str(&format!("{:>12} {:>6.2}%  {}\n", count, *count as f64 * 100.0 / total, name));
+        }
+
+        let mut pcs: Vec<(u32, u64)> = self.counts.iter().map(|(pc, count)| (*pc, *count)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        text.push_str(&format!("\n{:>12} {:>7}  pc\n", "count", "%"));
+        for (pc, count) in pcs.iter().take(top) {
+            let instruction = image(*pc).map(|word| disassemble(word, *pc)).unwrap_or_default();
+            let line = format!("{:>12} {:>6.2}%  {:08x} <{}>  {}", count, *count as f64 * 100.0 / total, pc, symbols.name(*pc), instruction);
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::profile::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::machine::{Machine, StopReason};
+
+    const NOP: u32 = 0x00000013;
+    const CALL: u32 = 0x000000EF; // jal ra, only the registers matter here
+    const CALL_T1: u32 = 0x000300E7; // jalr ra, 0(t1)
+    const RET: u32 = 0x00008067; // jalr zero, 0(ra)
+
+    fn symbols() -> HashMap<String, u32> {
+        [("main", 0x100), ("twice", 0x200), ("leaf", 0x300)].iter().map(|(name, address)| (name.to_string(), *address)).collect()
+    }
+
+    // main calls twice, which calls leaf twice
+    fn profiler() -> Profiler {
+        let mut profiler = Profiler::new(0x100);
+        for (pc, instruction, next_pc) in [
+            (0x100, NOP, 0x104),
+            (0x104, CALL, 0x200),
+            (0x200, CALL, 0x300),
+            (0x300, NOP, 0x304),
+            (0x304, RET, 0x204),
+            (0x204, CALL_T1, 0x300),
+            (0x300, NOP, 0x304),
+            (0x304, RET, 0x208),
+            (0x208, RET, 0x108),
+            (0x108, RET, 0x10C), // Returning out of the first frame stays in it
+            (0x10C, NOP, 0x110),
+        ] {
+            profiler.record(pc, instruction, next_pc);
+        }
+        profiler
+    }
+
+    #[test]
+    fn test_folded() {
+        let mut folded = Vec::new();
+        profiler().write_folded(&mut folded, &symbols()).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 4\nmain;twice 3\nmain;twice;leaf 4\n");
+    }
+
+    #[test]
+    fn test_report() {
+        let report = profiler().report(2, &symbols(), |pc| (pc != 0x304).then_some(NOP));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "11 instructions retired");
+        assert_eq!(lines[3], "           4  36.36%  leaf");
+        assert_eq!(lines[4], "           4  36.36%  main");
+        assert_eq!(lines.len(), 9, "{}", report);
+        assert_eq!(lines[7], "           2  18.18%  00000300 <leaf>  nop");
+        assert_eq!(lines[8], "           2  18.18%  00000304 <leaf+0x4>");
+    }
+
+    #[test]
+    fn test_machine() {
+        let program = assemble("li a0, 1\nli a0, 2\nebreak", 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        let mut machine = Machine::new(cpu);
+        machine.set_profiler(Some(Profiler::new(program.entry)));
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        let mut folded = Vec::new();
+        machine.profiler().unwrap().write_folded(&mut folded, &HashMap::new()).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "0x00000004 2\n");
+    }
+}
@@ -0,0 +1,262 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Instruction mix: how often each mnemonic and each extension retired, how wide the loads
+// and stores were and how often branches were taken. Instructions are classified the
+// way exec_inst dispatches them, by opcode and then funct3/funct7, so what's counted is
+// what the VM actually ran. The VM only executes RV32IM; the other extensions are
+// recognised anyway so a report shows they weren't needed rather than leaving them out.
+// Anything from them stops the machine as an illegal instruction, so it never retires.
+
+use std::collections::HashMap;
+use crate::cpu::opcodes::*;
+
+// Extensions in the order the report lists them
+pub const EXTENSIONS: [&str; 6] = ["I", "M", "A", "C", "F", "Zb*"];
+
+const OP_AMO: u8 = 0x2F;
+const OP_LOAD_FP: u8 = 0x07;
+const OP_STORE_FP: u8 = 0x27;
+const OP_FP: u8 = 0x53;
+const OP_FMADD: u8 = 0x43; // Up to 0x4F, the four fused multiply-adds
+
+pub struct InstructionMix {
+    mnemonics: HashMap<&'static str, u64>,
+    extensions: [u64; EXTENSIONS.len()],
+    loads: [u64; 3], // Bytes, halves, words
+    stores: [u64; 3],
+    taken: u64,
+    not_taken: u64,
+}
+
+// Mnemonic and index into EXTENSIONS of an instruction word, None if it decodes to nothing
+pub fn classify(word: u32) -> Option<(&'static str, usize)> {
+    let opcode = (word & 0x7F) as u8;
+    let funct3 = ((word >> 12) & 0x7) as u8;
+    let funct7 = (word >> 25) as u8;
+    let base = |name| Some((name, 0));
+    match opcode {
+        OP_LUI => base("lui"),
+        OP_AUIPC => base("auipc"),
+        OP_JAL => base("jal"),
+        OP_JALR => base("jalr"),
+        OP_BRANCH => match funct3 {
+            F3_BEQ => base("beq"),
+            F3_BNE => base("bne"),
+            F3_BLT => base("blt"),
+            F3_BGE => base("bge"),
+            F3_BLTU => base("bltu"),
+            F3_BGEU => base("bgeu"),
+            _ => None,
+        },
+        OP_LOAD => match funct3 {
+            F3_LB => base("lb"),
+            F3_LH => base("lh"),
+            F3_LW => base("lw"),
+            F3_LBU => base("lbu"),
+            F3_LHU => base("lhu"),
+            _ => None,
+        },
+        OP_STORE => match funct3 {
+            F3_SB => base("sb"),
+            F3_SH => base("sh"),
+            F3_SW => base("sw"),
+            _ => None,
+        },
+        OP_ALUI => match funct3 {
+            F3_ADDI => base("addi"),
+            F3_SLTI => base("slti"),
+            F3_SLTIU => base("sltiu"),
+            F3_XORI => base("xori"),
+            F3_ORI => base("ori"),
+            F3_ANDI => base("andi"),
+            F3_SLLI => base("slli"),
+            _ if funct7 == F7_SRA => base("srai"),
+            _ => base("srli"),
+        },
+        OP_ALU => match (funct7, funct3) {
+            (F7_ADD, F3_ADD_SUB) => base("add"),
+            (F7_SUB, F3_ADD_SUB) => base("sub"),
+            (0, F3_SLL) => base("sll"),
+            (0, F3_SLT) => base("slt"),
+            (0, F3_SLTU) => base("sltu"),
+            (0, F3_XOR) => base("xor"),
+            (F7_SRL, F3_SRL_SLA) => base("srl"),
+            (F7_SRA, F3_SRL_SLA) => base("sra"),
+            (0, F3_OR) => base("or"),
+            (0, F3_AND) => base("and"),
+            (F7_M_EXTENSION, _) => {
+                let names = ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"];
+                Some((names[funct3 as usize], 1))
+            }
+            // andn, orn, xnor, rol, ror, min, max, the bit set/clear/invert/extract family
+            (0x20 | 0x30 | 0x05 | 0x14 | 0x24 | 0x34, _) => Some(("zb", 5)),
+            _ => None,
+        },
+        OP_FENCE => base("fence"),
+        OP_E_C => match (funct3, word >> 20) {
+            (F3_ECALL_EBREAK, F12_ECALL) => base("ecall"),
+            (F3_ECALL_EBREAK, F12_EBREAK) => base("ebreak"),
+            _ => base("csr"),
+        },
+        OP_AMO => Some(("amo", 2)),
+        OP_LOAD_FP | OP_STORE_FP | OP_FP => Some(("fp", 4)),
+        _ if opcode & 0x73 == OP_FMADD => Some(("fp", 4)),
+        _ if word & 0x3 != 0x3 => Some(("compressed", 3)),
+        _ => None,
+    }
+}
+
+impl Default for InstructionMix {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl InstructionMix {
+    pub fn new() -> Self {
+        Self {
+            mnemonics: HashMap::new(),
+            extensions: [0; EXTENSIONS.len()],
+            loads: [0; 3],
+            stores: [0; 3],
+            taken: 0,
+            not_taken: 0,
+        }
+    }
+
+    // Called for every retired instruction with the pc it ran at and the pc after it
+    pub fn record(&mut self, pc: u32, instruction: u32, next_pc: u32) {
+        let Some((mnemonic, extension)) = classify(instruction) else {
+            return;
+        };
+        *self.mnemonics.entry(mnemonic).or_insert(0) += 1;
+        self.extensions[extension] += 1;
+        let width = match ((instruction >> 12) & 0x3) as usize {
+            3 => 2, // Only reached through invalid encodings
+            width => width,
+        };
+        match (instruction & 0x7F) as u8 {
+            OP_LOAD => self.loads[width] += 1,
+            OP_STORE => self.stores[width] += 1,
+            OP_BRANCH if next_pc == pc.wrapping_add(4) => self.not_taken += 1,
+            OP_BRANCH => self.taken += 1,
+            _ => {}
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.extensions.iter().sum()
+    }
+
+    pub fn count(&self, mnemonic: &str) -> u64 {
+        self.mnemonics.get(mnemonic).copied().unwrap_or(0)
+    }
+
+    pub fn extension(&self, name: &str) -> u64 {
+        EXTENSIONS.iter().position(|extension| *extension == name).map_or(0, |index| self.extensions[index])
+    }
+
+    // Taken and not taken
+    pub fn branches(&self) -> (u64, u64) {
+        (self.taken, self.not_taken)
+    }
+
+    pub fn report(&self) -> String {
+        let total = self.total().max(1) as f64;
+        let percent = |count: u64, of: f64| count as f64 * 100.0 / of.max(1.0);
+        let mut text = format!("{} instructions retired\n\n{:>12} {:>7}  extension\n", self.total(), "count", "%");
+        for (name, count) in EXTENSIONS.iter().zip(self.extensions) {
+            text.push_str(&format!("{:>12} {:>6.2}%  {}\n", count, percent(count, total), name));
+        }
+
+        let mut mnemonics: Vec<(&str, u64)> = self.mnemonics.iter().map(|(name, count)| (*name, *count)).collect();
+        mnemonics.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        text.push_str(&format!("\n{:>12} {:>7}  mnemonic\n", "count", "%"));
+        for (name, count) in mnemonics {
+            text.push_str(&format!("{:>12} {:>6.2}%  {}\n", count, percent(count, total), name));
+        }
+
+        text.push('\n');
+        for (kind, widths) in [("loads", self.loads), ("stores", self.stores)] {
+            let all: u64 = widths.iter().sum();
+            text.push_str(&format!("{:>12}  {}:", all, kind));
+            for (name, count) in ["byte", "half", "word"].iter().zip(widths) {
+                text.push_str(&format!(" {} {} ({:.2}%)", count, name, percent(count, all as f64)));
+            }
+            text.push('\n');
+        }
+        let branches = self.taken + self.not_taken;
+        text.push_str(&format!("{:>12}  branches: {} taken ({:.2}%), {} not taken\n",
+            branches, self.taken, percent(self.taken, branches as f64), self.not_taken));
+        text
+    }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use crate::stats::*;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::machine::{Machine, StopReason};
+
+    #[test]
+    fn test_classify() {
+        for (word, expected) in [
+            (0x00000013, Some(("addi", 0))), // nop
+            ((F7_M_EXTENSION as u32) << 25 | 0x00C58533, Some(("mul", 1))), // mul a0, a1, a2
+            (0x100122AF, Some(("amo", 2))), // lr.w t0, (sp)
+            (0x00004501, Some(("compressed", 3))), // c.li a0, 0
+            (0x00052007, Some(("fp", 4))), // flw ft0, 0(a0)
+            (0x40B50533, Some(("sub", 0))),
+            (0x40B57533, Some(("zb", 5))), // andn a0, a0, a1
+            (0x00100073, Some(("ebreak", 0))),
+            (0x0000007F, None),
+        ] {
+            assert_eq!(classify(word), expected, "{:08x}", word);
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        const BEQ: u32 = 0x00050463; // beq a0, zero, 8
+        const LBU: u32 = 0x00054503; // lbu a0, 0(a0)
+        let mut mix = InstructionMix::new();
+        mix.record(0x100, BEQ, 0x108);
+        mix.record(0x100, BEQ, 0x104);
+        mix.record(0x100, BEQ, 0x108);
+        mix.record(0x104, LBU, 0x108);
+        mix.record(0x108, 0x0000007F, 0x10C); // Nothing the VM knows, not counted
+        assert_eq!(mix.total(), 4);
+        assert_eq!(mix.count("beq"), 3);
+        assert_eq!(mix.count("lbu"), 1);
+        assert_eq!(mix.extension("I"), 4);
+        assert_eq!(mix.extension("M"), 0);
+        assert_eq!(mix.branches(), (2, 1));
+
+        let report = mix.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "4 instructions retired");
+        assert_eq!(lines[3], "           4 100.00%  I");
+        assert_eq!(lines[8], "           0   0.00%  Zb*");
+        assert_eq!(lines[11], "           3  75.00%  beq");
+        assert_eq!(lines[14], "           1  loads: 1 byte (100.00%) 0 half (0.00%) 0 word (0.00%)");
+        assert_eq!(lines[16], "           3  branches: 2 taken (66.67%), 1 not taken");
+    }
+
+    #[test]
+    fn test_machine() {
+        let program = assemble("li a0, 6\nli a1, 7\nmul a2, a0, a1\nebreak", 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        let mut machine = Machine::new(cpu);
+        machine.set_instruction_mix(Some(InstructionMix::new()));
+        assert_eq!(machine.run(), StopReason::Halted(None));
+        let mix = machine.instruction_mix().unwrap();
+        assert_eq!(mix.total(), 3);
+        assert_eq!(mix.count("addi"), 2);
+        assert_eq!(mix.extension("M"), 1);
+    }
+}
@@ -0,0 +1,224 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Instruction commit log in the format of `spike -l --log-commits`, so a run can be
+// diffed line by line against spike or an RTL simulation:
+//     core   0: 0x00000004 (0x00100513) li      a0, 1
+//     core   0: 3 0x00000004 (0x00100513) x10 0x00000001
+//     core   0: 3 0x00000008 (0x00a02002) mem 0x00000000 0x00000001
+// The first line is the disassembly, the second what the instruction committed:
+// privilege level (always machine mode here), register writes and memory accesses.
+// Loads only show their address, stores their address and value.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use crate::cpu::CPU;
+use crate::cpu::opcodes::*;
+use crate::disasm::disassemble;
+
+const PRIVILEGE_MACHINE: u8 = 3;
+
+pub struct CommitLog {
+    output: Box<dyn Write + Send>,
+    pub pc_range: Option<Range<u32>>, // Only instructions in this range
+    pub window: Option<Range<u64>>, // Only the n-th to m-th retired instruction, counting from 0
+    pub disassembly: bool, // Without it the output matches plain --log-commits
+}
+
+// State from before an instruction executes, needed to tell what it did
+pub struct Pending {
+    pc: u32,
+    instruction: u32,
+    registers: [u32; 32],
+    access: Option<(u32, u32, bool)>, // Address, size and whether it's a store
+}
+
+impl CommitLog {
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Self {
+            output,
+            pc_range: None,
+            window: None,
+            disassembly: true,
+        }
+    }
+
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Ok(Self::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    // Called before the index-th instruction executes. None if it's filtered out.
+    pub fn prepare(&self, cpu: &CPU, index: u64) -> Option<Pending> {
+        let pc = cpu.get_pc();
+        if self.window.as_ref().is_some_and(|window| !window.contains(&index))
+            || self.pc_range.as_ref().is_some_and(|range| !range.contains(&pc))
+            || !cpu.memory.contains(pc) {
+            return None;
+        }
+        let instruction = cpu.memory.peek_u32(pc);
+        Some(Pending {
+            pc,
+            instruction,
+            registers: cpu.registers.registers,
+            access: memory_access(cpu, instruction),
+        })
+    }
+
+    // Called once the instruction retired
+    pub fn commit(&mut self, cpu: &CPU, pending: Pending) {
+        let mut line = String::new();
+        if self.disassembly {
+            let text = disassemble(pending.instruction, pending.pc);
+            // spike pads the mnemonic to 8 columns
+            let text = match text.split_once(' ') {
+                Some((mnemonic, operands)) => format!("{:<7} {}", mnemonic, operands),
+                None => text,
+            };
+            line.push_str(&format!("core   0: 0x{:08x} (0x{:08x}) {}\n", pending.pc, pending.instruction, text));
+        }
+        line.push_str(&format!("core   0: {} 0x{:08x} (0x{:08x})", PRIVILEGE_MACHINE, pending.pc, pending.instruction));
+
+        // The destination register is logged even if its value didn't change, anything
+        // else the instruction changed is logged too
+        let rd = destination(pending.instruction);
+        for register in 1..32u8 {
+            let value = cpu.registers.get_register(register);
+            if Some(register) == rd || value != pending.registers[register as usize] {
+                line.push_str(&format!(" x{:<2} 0x{:08x}", register, value));
+            }
+        }
+        match pending.access {
+            Some((address, size, true)) if cpu.memory.contains(address) => {
+                let value = cpu.memory.peek_u32(address) & mask(size);
+                line.push_str(&format!(" mem 0x{:08x} 0x{:0width$x}", address, value, width = size as usize * 2));
+            }
+            Some((address, _, false)) => line.push_str(&format!(" mem 0x{:08x}", address)),
+            _ => {}
+        }
+        line.push('\n');
+        let _ = self.output.write_all(line.as_bytes());
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.output.flush();
+    }
+}
+
+impl Drop for CommitLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// Register the instruction writes by definition
+fn destination(instruction: u32) -> Option<u8> {
+    let rd = ((instruction >> 7) & 0x1F) as u8;
+    match (instruction & 0x7F) as u8 {
+        OP_LUI | OP_AUIPC | OP_JAL | OP_JALR | OP_LOAD | OP_ALUI | OP_ALU if rd != 0 => Some(rd),
+        OP_E_C if (instruction >> 12) & 0x7 != 0 && rd != 0 => Some(rd), // CSR instructions
+        _ => None,
+    }
+}
+
+// Address, size and direction of a load or store, computed before it executes
+fn memory_access(cpu: &CPU, instruction: u32) -> Option<(u32, u32, bool)> {
+    let rs1 = cpu.registers.get_register(((instruction >> 15) & 0x1F) as u8);
+    let size = match (instruction >> 12) & 0x3 {
+        0 => 1,
+        1 => 2,
+        _ => 4,
+    };
+    match (instruction & 0x7F) as u8 {
+        OP_LOAD => Some((rs1.wrapping_add(((instruction as i32) >> 20) as u32), size, false)),
+        OP_STORE => {
+            let offset = ((instruction as i32) >> 25) << 5 | ((instruction >> 7) & 0x1F) as i32;
+            Some((rs1.wrapping_add(offset as u32), size, true))
+        }
+        _ => None,
+    }
+}
+
+fn mask(size: u32) -> u32 {
+    if size >= 4 { u32::MAX } else { (1 << (size * 8)) - 1 }
+}
+
+///// TESTS /////
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::trace::*;
+    use crate::asm::assemble;
+    use crate::machine::Machine;
+
+    // Collects the log so the test can look at it
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(source: &str, configure: impl FnOnce(&mut CommitLog)) -> String {
+        let program = assemble(source, 0x4).unwrap();
+        let mut cpu = CPU::new();
+        cpu.load_image(program.base, &program.image);
+        cpu.set_pc(program.entry);
+        let output = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut log = CommitLog::new(Box::new(output.clone()));
+        configure(&mut log);
+        let mut machine = Machine::new(cpu);
+        machine.set_commit_log(Some(log));
+        machine.run();
+        let text = output.0.lock().unwrap().clone();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn test_commit_log() {
+        let log = trace("li a0, 1\nsw a0, 0x100(zero)\nlb a1, 0x100(zero)\nnop\nebreak", |log| log.disassembly = false);
+        assert_eq!(log, concat!(
+            "core   0: 3 0x00000004 (0x00100513) x10 0x00000001\n",
+            "core   0: 3 0x00000008 (0x10a02023) mem 0x00000100 0x00000001\n",
+            "core   0: 3 0x0000000c (0x10000583) x11 0x00000001 mem 0x00000100\n",
+            "core   0: 3 0x00000010 (0x00000013)\n",
+        ));
+    }
+
+    #[test]
+    fn test_disassembly_lines() {
+        let log = trace("li a0, 1\nebreak", |_| {});
+        assert_eq!(log, "core   0: 0x00000004 (0x00100513) li      a0, 1\ncore   0: 3 0x00000004 (0x00100513) x10 0x00000001\n");
+    }
+
+    #[test]
+    fn test_filters() {
+        let source = "li a0, 1\nli a0, 2\nli a0, 3\nli a0, 4\nebreak";
+        let log = trace(source, |log| {
+            log.disassembly = false;
+            log.window = Some(1..3);
+        });
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.starts_with("core   0: 3 0x00000008"));
+
+        let log = trace(source, |log| {
+            log.disassembly = false;
+            log.pc_range = Some(0xC..0x100);
+        });
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.starts_with("core   0: 3 0x0000000c"));
+    }
+
+    #[test]
+    fn test_branch_writes() {
+        // Taken branches write no register
+        let log = trace("beq zero, zero, next\nnext: ebreak", |log| log.disassembly = false);
+        assert_eq!(log, "core   0: 3 0x00000004 (0x00000263)\n");
+    }
+}
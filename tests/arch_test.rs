@@ -0,0 +1,19 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Runs the official RISC-V architectural tests (riscv-arch-test) from RISCV_ARCH_TEST_DIR,
+// e.g. a riscof work directory, against their Sail reference signatures. They aren't
+// checked in, so without the variable there's nothing to run. See tests/isa-tests/README.md.
+
+use std::path::Path;
+use tiny_vm::compliance::run_suite;
+
+#[test]
+fn test_arch_suite() {
+    let Ok(dir) = std::env::var("RISCV_ARCH_TEST_DIR") else {
+        println!("RISCV_ARCH_TEST_DIR isn't set, skipping riscv-arch-test");
+        return;
+    };
+    if let Err(error) = run_suite(Path::new(&dir)) {
+        panic!("{}", error);
+    }
+}
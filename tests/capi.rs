@@ -0,0 +1,34 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Compiles tests/capi/smoke.c against include/tiny_vm.h with the system C compiler ($CC,
+// cc by default), links it to the libtiny_vm shared library cargo built for this test run
+// and runs it.
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn test_c_api_smoke() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // The test binary lives next to the library in target/<profile>/deps
+    let exe = std::env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap();
+    let smoke = Path::new(env!("CARGO_TARGET_TMPDIR")).join("capi_smoke");
+    let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let output = Command::new(&compiler)
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+        .arg(root.join("include"))
+        .arg(root.join("tests/capi/smoke.c"))
+        .arg("-o").arg(&smoke)
+        .arg("-L").arg(lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .arg("-ltiny_vm")
+        .output()
+        .unwrap_or_else(|error| panic!("Can't run {}: {}", compiler, error));
+    assert!(output.status.success(), "Compiling smoke.c failed:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = Command::new(&smoke).output().unwrap();
+    assert!(output.status.success(), "smoke.c failed:\n{}{}",
+        String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,70 @@
+/* RISC-V Tiny VM - Ivi Ballou / Amechania */
+
+/*
+ * Builds against include/tiny_vm.h and runs a program through the C API: a store to a
+ * device, then ebreak. Exits with 1 and says what failed if anything is off.
+ */
+
+#include <stdio.h>
+#include <string.h>
+#include "tiny_vm.h"
+
+#define CHECK(condition) do { \
+        if (!(condition)) { \
+            fprintf(stderr, "%s:%d: %s failed\n", __FILE__, __LINE__, #condition); \
+            return 1; \
+        } \
+    } while (0)
+
+struct Device {
+    uint32_t offset;
+    uint32_t size;
+    uint32_t value;
+};
+
+static uint32_t device_load(void *user, uint32_t offset, uint32_t size) {
+    (void)user;
+    (void)offset;
+    (void)size;
+    return 0;
+}
+
+static void device_store(void *user, uint32_t offset, uint32_t size, uint32_t value) {
+    struct Device *device = user;
+    device->offset = offset;
+    device->size = size;
+    device->value = value;
+}
+
+int main(void) {
+    /* lui t0, 0x40000; li a0, 42; sw a0, 4(t0); ebreak */
+    const uint8_t program[] = {
+        0xb7, 0x02, 0x00, 0x40,
+        0x13, 0x05, 0xa0, 0x02,
+        0x23, 0xa2, 0xa2, 0x00,
+        0x73, 0x00, 0x10, 0x00,
+    };
+    struct Device device = { 0, 0, 0 };
+    uint8_t bytes[sizeof(program)];
+
+    CHECK(tvm_create(0x80000000, 100) == NULL);
+    TvmMachine *machine = tvm_create(0x80000000, 0x10000);
+    CHECK(machine != NULL);
+    CHECK(tvm_load_image(machine, 0x80000000, program, sizeof(program)) == TVM_OK);
+    CHECK(tvm_add_device(machine, "device", 0x40000000, 0x100, device_load, device_store, &device) == TVM_OK);
+
+    CHECK(tvm_get_register(machine, TVM_REG_PC) == 0x80000000);
+    CHECK(tvm_run(machine) == TVM_STOP_HALTED);
+    CHECK(tvm_instret(machine) == 3);
+    CHECK(tvm_get_register(machine, 10) == 42);
+    CHECK(device.offset == 4 && device.size == 4 && device.value == 42);
+
+    CHECK(tvm_read_memory(machine, 0x80000000, bytes, sizeof(bytes)) == TVM_OK);
+    CHECK(memcmp(bytes, program, sizeof(program)) == 0);
+    CHECK(tvm_read_memory(machine, 0x40000000, bytes, 4) == TVM_ERROR);
+    CHECK(strstr(tvm_last_error(machine), "isn't in memory") != NULL);
+
+    tvm_destroy(machine);
+    printf("C API smoke test passed\n");
+    return 0;
+}
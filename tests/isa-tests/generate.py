@@ -0,0 +1,354 @@
+#!/usr/bin/env python3
+# RISC-V Tiny VM - Ivi Ballou / Amechania
+
+# Generates the ISA tests checked in under I/ and M/: an ELF and the reference signature
+# for every test. They're this repository's own, not riscv-arch-test. See README.md.
+#
+# The sources are assembled with llvm-mc (no linker needed, nothing is relocated) and wrapped
+# into an ELF here. The reference signatures are computed by the small RV32IM model below,
+# written from the definitions in the ISA manual and independent of the VM.
+#
+#     python3 tests/isa-tests/generate.py
+
+import os
+import struct
+import subprocess
+import tempfile
+
+# Laid out in this order, the code comes last so everything else has a fixed address
+BASE = 0x80000000
+TOHOST = BASE + 0x10
+DATA = BASE + 0x20
+SIGNATURE = BASE + 0x40
+CANARY = 0xDEADBEEF
+MASK = 0xFFFFFFFF
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+# Loaded by the load tests, 16 bytes around DATA + 8
+DATA_WORDS = [0x80FF7F01, 0x12345678, 0xFEDCBA98, 0x00FF8000]
+DATA_BYTES = b"".join(struct.pack("<I", word) for word in DATA_WORDS)
+
+
+def signed(value):
+    value &= MASK
+    return value - (1 << 32) if value & 0x80000000 else value
+
+
+def sext(value, bits):
+    value &= (1 << bits) - 1
+    return value - (1 << bits) if value >> (bits - 1) else value
+
+
+def div(a, b):
+    a, b = signed(a), signed(b)
+    if b == 0:
+        return -1
+    if a == -(1 << 31) and b == -1:
+        return a
+    quotient = abs(a) // abs(b)
+    return -quotient if (a < 0) != (b < 0) else quotient
+
+
+def rem(a, b):
+    a, b = signed(a), signed(b)
+    if b == 0:
+        return a
+    return a - div(a, b) * b
+
+
+def divu(a, b):
+    return MASK if b == 0 else a // b
+
+
+def remu(a, b):
+    return a if b == 0 else a % b
+
+
+# rd = f(rs1, rs2) with both as unsigned 32-bit values
+REGISTER_OPS = {
+    "I": {
+        "add": lambda a, b: a + b,
+        "sub": lambda a, b: a - b,
+        "sll": lambda a, b: a << (b & 31),
+        "slt": lambda a, b: int(signed(a) < signed(b)),
+        "sltu": lambda a, b: int(a < b),
+        "xor": lambda a, b: a ^ b,
+        "srl": lambda a, b: a >> (b & 31),
+        "sra": lambda a, b: signed(a) >> (b & 31),
+        "or": lambda a, b: a | b,
+        "and": lambda a, b: a & b,
+    },
+    "M": {
+        "mul": lambda a, b: a * b,
+        "mulh": lambda a, b: (signed(a) * signed(b)) >> 32,
+        "mulhsu": lambda a, b: (signed(a) * b) >> 32,
+        "mulhu": lambda a, b: (a * b) >> 32,
+        "div": div,
+        "divu": divu,
+        "rem": rem,
+        "remu": remu,
+    },
+}
+
+# rd = f(rs1, imm) with imm as the signed 12-bit immediate
+IMMEDIATE_OPS = {
+    "addi": lambda a, imm: a + imm,
+    "slti": lambda a, imm: int(signed(a) < imm),
+    "sltiu": lambda a, imm: int(a < (imm & MASK)),
+    "xori": lambda a, imm: a ^ imm,
+    "ori": lambda a, imm: a | imm,
+    "andi": lambda a, imm: a & imm,
+}
+
+SHIFT_OPS = {
+    "slli": lambda a, shamt: a << shamt,
+    "srli": lambda a, shamt: a >> shamt,
+    "srai": lambda a, shamt: signed(a) >> shamt,
+}
+
+BRANCH_OPS = {
+    "beq": lambda a, b: a == b,
+    "bne": lambda a, b: a != b,
+    "blt": lambda a, b: signed(a) < signed(b),
+    "bge": lambda a, b: signed(a) >= signed(b),
+    "bltu": lambda a, b: a < b,
+    "bgeu": lambda a, b: a >= b,
+}
+
+# Loads from DATA + 8 + offset
+LOAD_OPS = {
+    "lb": (1, True),
+    "lbu": (1, False),
+    "lh": (2, True),
+    "lhu": (2, False),
+    "lw": (4, True),
+}
+
+STORE_OPS = {"sb": 1, "sh": 2, "sw": 4}
+
+VALUES = [0, 1, 2, MASK, 0x7FFFFFFF, 0x80000000, 0x12345678, 0xFEDCBA98, 31, 33, (-33) & MASK]
+IMMEDIATES = [0, 1, -1, 2047, -2048, 0x555, -0x556]
+SHIFTS = [0, 1, 4, 31]
+
+
+class Test:
+    def __init__(self):
+        self.lines = []
+        self.signature = []
+
+    def emit(self, *lines):
+        self.lines.extend(lines)
+
+    # Stores rd into the next signature word
+    def result(self, register, value):
+        self.emit(f"    sw {register}, 0(x2)", "    addi x2, x2, 4")
+        self.signature.append(value & MASK)
+
+    def source(self):
+        words = len(self.signature)
+        return "\n".join([
+            "    .text",
+            "_start:",
+            "    j code",
+            f"    .org 0x{TOHOST - BASE:x}",
+            "tohost: .word 0, 0",
+            "fromhost: .word 0, 0",
+            f"    .org 0x{DATA - BASE:x}",
+            "data: .word " + ", ".join(f"0x{word:08x}" for word in DATA_WORDS),
+            f"    .org 0x{SIGNATURE - BASE:x}",
+            "begin_signature:",
+            f"    .fill {words}, 4, 0x{CANARY:08x}",
+            "end_signature:",
+            "code:",
+            f"    li x2, 0x{SIGNATURE:08x}",
+            *self.lines,
+            "    # Halt through HTIF",
+            "    li x5, 1",
+            f"    li x6, 0x{TOHOST:08x}",
+            "    sw x5, 0(x6)",
+            "    sw x0, 4(x6)",
+            "1:  j 1b",
+            "",
+        ])
+
+    def reference(self):
+        return "".join(f"{word:08x}\n" for word in self.signature)
+
+
+def register_test(name, function):
+    test = Test()
+    for a in VALUES:
+        for b in VALUES:
+            test.emit(f"    li x5, 0x{a:08x}", f"    li x6, 0x{b:08x}", f"    {name} x7, x5, x6")
+            test.result("x7", function(a, b))
+    # Writes to x0 are dropped, and rd may be a source
+    test.emit(f"    {name} x0, x5, x6")
+    test.result("x0", 0)
+    test.emit(f"    {name} x5, x5, x6")
+    test.result("x5", function(VALUES[-1], VALUES[-1]))
+    return test
+
+
+def immediate_test(name, function):
+    test = Test()
+    for a in VALUES:
+        for imm in IMMEDIATES:
+            test.emit(f"    li x5, 0x{a:08x}", f"    {name} x7, x5, {imm}")
+            test.result("x7", function(a, imm))
+    return test
+
+
+def shift_test(name, function):
+    test = Test()
+    for a in VALUES:
+        for shamt in SHIFTS:
+            test.emit(f"    li x5, 0x{a:08x}", f"    {name} x7, x5, {shamt}")
+            test.result("x7", function(a, shamt))
+    return test
+
+
+def branch_test(name, function):
+    test = Test()
+    test.emit("    li x1, 0")
+    for a in VALUES[:8]:
+        for b in VALUES[:8]:
+            # x7 stays 1 if the branch is taken, forward or backward
+            test.emit(
+                f"    li x5, 0x{a:08x}", f"    li x6, 0x{b:08x}", "    li x7, 1",
+                f"    {name} x5, x6, 1f",
+                "    li x7, 0",
+                "    j 3f",
+                "2:  j 4f",
+                "1:  li x8, 0",
+                f"    {name} x5, x6, 2b",
+                "    li x7, 2",
+                "3:  nop",
+                "4:  nop",
+            )
+            test.result("x7", 1 if function(a, b) else 0)
+    test.result("x1", 0)
+    return test
+
+
+def jump_test():
+    test = Test()
+    # jal links pc + 4 and jumps relative to pc, forward and backward
+    test.emit("    auipc x8, 0", "    jal x1, 1f", "    li x7, 1", "1:  sub x9, x1, x8")
+    test.result("x9", 8)
+    test.emit("    li x7, 0", "    j 2f", "1:  addi x7, x7, 1", "    j 3f", "2:  addi x7, x7, 2", "    j 1b", "3:  nop")
+    test.result("x7", 3)
+    # jalr adds the sign extended offset to rs1 and clears bit 0
+    test.emit("    auipc x8, 0", "    addi x10, x8, 0x21", "    jalr x1, -0x8(x10)", "    nop", "    nop", "    li x7, 1", "    li x7, 2")
+    test.result("x7", 2)
+    test.emit("    sub x9, x1, x8")
+    test.result("x9", 12)
+    # rs1 is read before rd is written
+    test.emit("    li x7, 0", "    auipc x8, 0", "    addi x5, x8, 16", "    jalr x5, 0(x5)", "    li x7, 1", "    sub x9, x5, x8")
+    test.result("x9", 12)
+    test.result("x7", 0)
+    test.emit("    jal x0, 1f", "    li x9, 1", "1:  nop")
+    test.result("x9", 12)
+    # lui and auipc
+    for imm in [0, 1, 0x7FFFF, 0x80000, 0xFFFFF, 0x12345]:
+        test.emit(f"    lui x7, 0x{imm:x}")
+        test.result("x7", imm << 12)
+        test.emit(f"    auipc x7, 0x{imm:x}", "    auipc x8, 0", "    sub x7, x7, x8")
+        test.result("x7", (imm << 12) - 4)
+    return test
+
+
+def load_test(name, width, sign):
+    test = Test()
+    test.emit(f"    li x10, 0x{DATA + 8:08x}")
+    for offset in range(-8, 8, width):
+        test.emit(f"    {name} x7, {offset}(x10)")
+        value = int.from_bytes(DATA_BYTES[8 + offset:8 + offset + width], "little")
+        test.result("x7", sext(value, width * 8) if sign else value)
+    # Loads into x0 are dropped
+    test.emit(f"    {name} x0, 0(x10)")
+    test.result("x0", 0)
+    return test
+
+
+def store_test(name, width):
+    test = Test()
+    # Every case writes over two canary words of the signature, through a base 4 bytes in
+    for value in [0x80FF7F01, 0x12345678, 0xFEDCBA98]:
+        for offset in range(-4, 4, width):
+            test.emit(f"    li x5, 0x{value:08x}", "    addi x11, x2, 4", f"    {name} x5, {offset}(x11)", "    addi x2, x2, 8")
+            slot = bytearray(struct.pack("<II", CANARY, CANARY))
+            slot[4 + offset:4 + offset + width] = value.to_bytes(4, "little")[:width]
+            test.signature.extend(struct.unpack("<II", bytes(slot)))
+    return test
+
+
+def tests():
+    for extension, ops in REGISTER_OPS.items():
+        for name, function in ops.items():
+            yield extension, name, register_test(name, function)
+    for name, function in IMMEDIATE_OPS.items():
+        yield "I", name, immediate_test(name, function)
+    for name, function in SHIFT_OPS.items():
+        yield "I", name, shift_test(name, function)
+    for name, function in BRANCH_OPS.items():
+        yield "I", name, branch_test(name, function)
+    yield "I", "jump", jump_test()
+    for name, (width, sign) in LOAD_OPS.items():
+        yield "I", name, load_test(name, width, sign)
+    for name, width in STORE_OPS.items():
+        yield "I", name, store_test(name, width)
+
+
+def assemble(source):
+    with tempfile.TemporaryDirectory() as work:
+        path = os.path.join(work, "test.S")
+        with open(path, "w") as file:
+            file.write(source)
+        subprocess.run(["llvm-mc", "-triple=riscv32", "-mattr=+m,-relax", "-filetype=obj", path, "-o", path + ".o"], check=True)
+        relocations = subprocess.run(["llvm-objdump", "-r", path + ".o"], check=True, capture_output=True, text=True).stdout
+        assert "R_RISCV" not in relocations, relocations
+        subprocess.run(["llvm-objcopy", "-O", "binary", "--only-section=.text", path + ".o", path + ".bin"], check=True)
+        with open(path + ".bin", "rb") as file:
+            return file.read()
+
+
+# An executable with the code in one loaded segment and the symbols the runner looks for
+def build_elf(code, symbols):
+    strtab = b"\0"
+    symtab = b"\0" * 16
+    for name, value in symbols:
+        symtab += struct.pack("<IIIBBH", len(strtab), value, 0, 0, 0, 1)
+        strtab += name.encode() + b"\0"
+    shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0"
+
+    code_offset = 52 + 32
+    sections = [(1, 1, 6, BASE, code, 0, 0, 4, 0), (7, 2, 0, 0, symtab, 3, 1, 4, 16),
+                (15, 3, 0, 0, strtab, 0, 0, 1, 0), (23, 3, 0, 0, shstrtab, 0, 0, 1, 0)]
+    contents = b""
+    headers = b"\0" * 40
+    for name, kind, flags, address, data, link, info, align, entry_size in sections:
+        headers += struct.pack("<10I", name, kind, flags, address, code_offset + len(contents), len(data), link, info, align, entry_size)
+        contents += data
+    header = b"\x7fELF" + bytes([1, 1, 1]) + b"\0" * 9
+    header += struct.pack("<HHIIIIIHHHHHH", 2, 243, 1, BASE, 52, code_offset + len(contents), 0, 52, 32, 1, 40, len(sections) + 1, len(sections))
+    program = struct.pack("<8I", 1, code_offset, BASE, BASE, len(code), len(code), 7, 4)
+    return header + program + contents + headers
+
+
+def main():
+    for extension, name, test in tests():
+        directory = os.path.join(HERE, extension)
+        os.makedirs(directory, exist_ok=True)
+        base = os.path.join(directory, name)
+        code = assemble(test.source())
+        end = SIGNATURE + 4 * len(test.signature)
+        symbols = [("_start", BASE), ("tohost", TOHOST), ("fromhost", TOHOST + 8), ("begin_signature", SIGNATURE), ("end_signature", end)]
+        with open(base + ".elf", "wb") as file:
+            file.write(build_elf(code, symbols))
+        with open(base + ".reference_output", "w") as file:
+            file.write(test.reference())
+
+
+if __name__ == "__main__":
+    main()
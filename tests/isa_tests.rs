@@ -0,0 +1,15 @@
+// RISC-V Tiny VM - Ivi Ballou / Amechania
+
+// Runs the ISA tests generated in this repository, tests/isa-tests, and compares every
+// signature with its reference. They aren't riscv-arch-test, tests/arch_test.rs runs that.
+
+use std::path::Path;
+use tiny_vm::compliance::run_suite;
+
+#[test]
+fn test_isa_suite() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/isa-tests");
+    if let Err(error) = run_suite(&dir) {
+        panic!("{}", error);
+    }
+}